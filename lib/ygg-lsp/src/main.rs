@@ -0,0 +1,363 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+//
+// A language server for .ygg files, spoken over stdin and stdout. Editors
+// start it themselves; e.g. for vim-lsp, register the command `ygg-lsp` for
+// the filetype ygg.
+mod rpc;
+
+use failure::Fallible;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    process,
+};
+use yggdrasil::{Document, Span};
+
+// https://microsoft.github.io/language-server-protocol/specification
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SEVERITY_ERROR: i64 = 1;
+const COMPLETION_KIND_FIELD: i64 = 5;
+const SYNC_FULL: i64 = 1;
+
+#[derive(Default)]
+struct Server {
+    // Every open document, by uri.
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    // Handle one message from the editor, returning the messages to send back.
+    fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let mut out = Vec::new();
+        let result = match method {
+            "initialize" => Some(Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": SYNC_FULL,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {"triggerCharacters": ["/"]},
+                },
+                "serverInfo": {"name": "ygg-lsp"},
+            }))),
+            "shutdown" => Some(Ok(Value::Null)),
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str();
+                let text = params["textDocument"]["text"].as_str();
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.documents.insert(uri.to_owned(), Document::new(text));
+                    out.push(self.diagnostics(uri));
+                }
+                None
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                // We ask for the full text, so the last change holds all of it.
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(doc), Some(text)) = (self.documents.get_mut(uri), text) {
+                    doc.update(text);
+                    out.push(self.diagnostics(uri));
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                }
+                None
+            }
+            "textDocument/definition" => Some(self.definition(params)),
+            "textDocument/hover" => Some(self.hover(params)),
+            "textDocument/completion" => Some(self.completion(params)),
+            _ => None,
+        };
+
+        // Notifications have no id and get no response.
+        let id = &message["id"];
+        if id.is_null() {
+            return out;
+        }
+        out.push(match result {
+            Some(Ok(result)) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Some(Err(error)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": INVALID_PARAMS, "message": error},
+            }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": METHOD_NOT_FOUND, "message": format!("unknown method: {}", method)},
+            }),
+        });
+        out
+    }
+
+    fn diagnostics(&self, uri: &str) -> Value {
+        let doc = &self.documents[uri];
+        let diagnostics = doc
+            .errors()
+            .iter()
+            .map(|err| {
+                let span = err.span().unwrap_or(Span { line: 1, column: 1 });
+                json!({
+                    "range": line_range(doc.source(), span),
+                    "severity": SEVERITY_ERROR,
+                    "source": "ygg",
+                    "message": err.message(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        })
+    }
+
+    // The document and the place in it that a request is about.
+    fn locate<'a>(&'a self, params: &'a Value) -> Result<(&'a str, &'a Document, Span), String> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let doc = match self.documents.get(uri) {
+            Some(doc) => doc,
+            None => return Err(format!("document is not open: {}", uri)),
+        };
+        match to_span(doc.source(), &params["position"]) {
+            Some(span) => Ok((uri, doc, span)),
+            None => Err("position is not in the document".to_owned()),
+        }
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, String> {
+        let (uri, doc, at) = self.locate(params)?;
+        let locations = doc
+            .definitions(at)
+            .into_iter()
+            .map(|span| {
+                let position = to_position(doc.source(), span);
+                json!({"uri": uri, "range": {"start": position, "end": position}})
+            })
+            .collect::<Vec<_>>();
+        Ok(json!(locations))
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value, String> {
+        let (_, doc, at) = self.locate(params)?;
+        Ok(match doc.hover(at) {
+            Some(text) => json!({"contents": {"kind": "markdown", "value": text}}),
+            None => Value::Null,
+        })
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value, String> {
+        let (_, doc, at) = self.locate(params)?;
+        let items = doc
+            .completions(at)
+            .into_iter()
+            .map(|name| json!({"label": name, "kind": COMPLETION_KIND_FIELD}))
+            .collect::<Vec<_>>();
+        Ok(json!(items))
+    }
+}
+
+// The protocol counts lines from 0 and columns in UTF-16 code units; spans
+// count both from 1, and columns in chars.
+fn to_span(text: &str, position: &Value) -> Option<Span> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let mut column = 1;
+    let mut units = 0;
+    if let Some(content) = text.lines().nth(line) {
+        for c in content.chars() {
+            if units >= character {
+                break;
+            }
+            units += c.len_utf16();
+            column += 1;
+        }
+    } else if line > text.lines().count() {
+        return None;
+    }
+    Some(Span {
+        line: line + 1,
+        column,
+    })
+}
+
+fn to_position(text: &str, span: Span) -> Value {
+    let line = span.line.saturating_sub(1);
+    let character = text
+        .lines()
+        .nth(line)
+        .unwrap_or_default()
+        .chars()
+        .take(span.column.saturating_sub(1))
+        .map(char::len_utf16)
+        .sum::<usize>();
+    json!({"line": line, "character": character})
+}
+
+// From `span` to the end of its line.
+fn line_range(text: &str, span: Span) -> Value {
+    let end = Span {
+        line: span.line,
+        column: text
+            .lines()
+            .nth(span.line.saturating_sub(1))
+            .unwrap_or_default()
+            .chars()
+            .count()
+            + 1,
+    };
+    json!({"start": to_position(text, span), "end": to_position(text, end)})
+}
+
+// Answer messages from the input until it ends or the editor says to exit.
+fn serve(input: &mut impl BufRead, output: &mut impl Write) -> Fallible<()> {
+    let mut server = Server::default();
+    while let Some(message) = rpc::read_message(input)? {
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                let reply = json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": PARSE_ERROR, "message": error.to_string()},
+                });
+                rpc::write_message(output, &reply)?;
+                continue;
+            }
+        };
+        if message["method"] == "exit" {
+            break;
+        }
+        for reply in server.handle(&message) {
+            rpc::write_message(output, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+fn run() -> Fallible<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    serve(&mut stdin.lock(), &mut stdout.lock())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const URI: &str = "file:///home.ygg";
+
+    fn open(server: &mut Server, text: &str) -> Value {
+        let mut out = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": URI, "languageId": "ygg", "version": 1, "text": text}},
+        }));
+        assert_eq!(out.len(), 1);
+        out.remove(0)
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let mut out = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": method,
+            "params": {
+                "textDocument": {"uri": URI},
+                "position": {"line": line, "character": character},
+            },
+        }));
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0]["id"], 7);
+        out.remove(0)
+    }
+
+    #[test]
+    fn test_lsp_diagnostics() {
+        let mut server = Server::default();
+        let note = open(&mut server, "a <- 1\nb <- (2 +\n");
+        assert_eq!(note["method"], "textDocument/publishDiagnostics");
+        let diagnostics = note["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({"start": {"line": 1, "character": 5}, "end": {"line": 1, "character": 9}})
+        );
+        let note = open(&mut server, "a <- 1\n");
+        assert_eq!(note["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn test_lsp_requests() {
+        let mut server = Server::default();
+        open(&mut server, "a\n    b <- 1\nc <- /a/b\n");
+        let reply = request(&mut server, "textDocument/definition", 2, 7);
+        assert_eq!(
+            reply["result"],
+            json!([{"uri": URI, "range": {
+                "start": {"line": 1, "character": 4},
+                "end": {"line": 1, "character": 4},
+            }}])
+        );
+        let reply = request(&mut server, "textDocument/hover", 1, 4);
+        let hover = reply["result"]["contents"]["value"].as_str().unwrap();
+        assert!(hover.starts_with("**/a/b**"));
+        let reply = request(&mut server, "textDocument/completion", 2, 8);
+        assert_eq!(
+            reply["result"],
+            json!([{"label": "b", "kind": COMPLETION_KIND_FIELD}])
+        );
+        let reply = request(&mut server, "textDocument/rename", 2, 8);
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_lsp_malformed_message() -> Fallible<()> {
+        let mut input = Vec::new();
+        input.extend_from_slice(b"Content-Length: 5\r\n\r\n{oops");
+        rpc::write_message(
+            &mut input,
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/rename"}),
+        )?;
+        let mut output = Vec::new();
+        serve(&mut io::Cursor::new(input), &mut output)?;
+
+        let mut output = io::Cursor::new(output);
+        let reply = rpc::read_message(&mut output)?.unwrap()?;
+        assert_eq!(reply["id"], Value::Null);
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+        let reply = rpc::read_message(&mut output)?.unwrap()?;
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+        assert!(rpc::read_message(&mut output)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsp_positions() {
+        let text = "a\n  \u{1F600}b <- 1\n";
+        let span = to_span(text, &json!({"line": 1, "character": 4})).unwrap();
+        assert_eq!(span, Span { line: 2, column: 4 });
+        assert_eq!(to_position(text, span), json!({"line": 1, "character": 4}));
+        assert!(to_span(text, &json!({"line": 9, "character": 0})).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+//
+// The base protocol: each message is JSON-RPC, preceded by HTTP-like headers
+// that give its length in bytes.
+use failure::{ensure, format_err, Fallible};
+use serde_json::Value;
+use std::io::{BufRead, Write};
+
+// Read the next message, or None at the end of the input. A message that
+// cannot be understood is an error inside the Some, so that the server can
+// answer it and go on reading; only a failure to read ends the input.
+pub fn read_message(input: &mut impl BufRead) -> Fallible<Option<Fallible<Value>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim();
+        let value = parts.next().unwrap_or_default().trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.parse::<usize>().map_err(|_| value.to_owned()));
+        }
+    }
+    // Without a length there is no body to skip; whatever follows is read as
+    // the headers of the next message.
+    let length = match length {
+        Some(Ok(length)) => length,
+        Some(Err(value)) => return Ok(Some(Err(format_err!("bad Content-Length: {}", value)))),
+        None => {
+            return Ok(Some(Err(format_err!(
+                "message without a Content-Length header"
+            ))))
+        }
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(parse_body(&body)))
+}
+
+fn parse_body(body: &[u8]) -> Fallible<Value> {
+    let message = serde_json::from_slice::<Value>(body)?;
+    ensure!(message.is_object(), "message is not an object: {}", message);
+    Ok(message)
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> Fallible<()> {
+    let body = serde_json::to_string(message)?;
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_rpc_round_trip() -> Fallible<()> {
+        let messages = vec![
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize"}),
+            json!({"jsonrpc": "2.0", "method": "exit", "params": {"text": "ü\r\n"}}),
+        ];
+        let mut buffer = Vec::new();
+        for message in &messages {
+            write_message(&mut buffer, message)?;
+        }
+        let mut input = Cursor::new(buffer);
+        for message in &messages {
+            assert_eq!(&read_message(&mut input)?.unwrap()?, message);
+        }
+        assert!(read_message(&mut input)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_malformed() -> Fallible<()> {
+        let mut input = Cursor::new(
+            b"Content-Type: x\r\n\r\n\
+              Content-Length: x\r\n\r\n\
+              Content-Length: 2\r\n\r\n[]\
+              Content-Length: 1\r\n\r\n{\
+              Content-Length: 2\r\n\r\n{}"
+                .to_vec(),
+        );
+        for _ in 0..4 {
+            assert!(read_message(&mut input)?.unwrap().is_err());
+        }
+        assert_eq!(read_message(&mut input)?.unwrap()?, json!({}));
+        assert!(read_message(&mut input)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_truncated() {
+        let mut input = Cursor::new(b"Content-Length: 9\r\n\r\n{}".to_vec());
+        assert!(read_message(&mut input).is_err());
+    }
+}
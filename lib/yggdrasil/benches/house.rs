@@ -0,0 +1,61 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::str::FromStr;
+use yggdrasil::{ConcretePath, TreeBuilder, Value};
+
+// Each room is 10 nodes: the room, a switch with domain and default, a
+// color, and 5 lights that look their color up in the palette.
+const ROOMS: usize = 1000;
+
+fn synthetic_house(rooms: usize) -> String {
+    let mut s = String::from(
+        r#"
+palette
+    hue
+        on <- "bhs(254, 34495, 254)"
+        low <- "bhs(64, 34495, 254)"
+        off <- "none"
+"#,
+    );
+    s += "rooms\n";
+    for i in 0..rooms {
+        s += &format!("    room{}\n", i);
+        s += "        switch ^button\n";
+        s += "            domain <- [\"on\", \"low\", \"off\"]\n";
+        s += "            default <- \"off\"\n";
+        s += "        color <- ./switch\n";
+        for j in 0..5 {
+            s += &format!("        light{} $hue <- /palette/hue/{{./color}}\n", j);
+        }
+    }
+    s
+}
+
+fn build(c: &mut Criterion) {
+    let s = synthetic_house(ROOMS);
+    let mut group = c.benchmark_group("house");
+    group.sample_size(10);
+    group.bench_function("build 10k nodes", |b| {
+        b.iter(|| TreeBuilder::default().build_from_str(&s).unwrap())
+    });
+    group.finish();
+}
+
+fn handle_event(c: &mut Criterion) {
+    let s = synthetic_house(ROOMS);
+    let mut tree = TreeBuilder::default().build_from_str(&s).unwrap();
+    let path = ConcretePath::from_str("/rooms/room500/switch").unwrap();
+    let values = [Value::new_str("on"), Value::new_str("low")];
+    let mut i = 0;
+    c.bench_function("house/handle_event 10k nodes", |b| {
+        b.iter(|| {
+            i += 1;
+            tree.handle_event(&path, values[i % 2].clone()).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, build, handle_event);
+criterion_main!(benches);
@@ -0,0 +1,210 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::{NativeFunc, Signature},
+    tokenizer::Token,
+    tree::Tree,
+    value::{Value, ValueType},
+};
+use failure::Fallible;
+
+/// Reduces a list, such as the values of every node that a glob matches, to
+/// a single value. `any`, `all`, and `count` take booleans; `count` is how
+/// many are true. `min`, `max`, and `sum` take numbers, or strings for `min`
+/// and `max`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Aggregate {
+    Any,
+    All,
+    Count,
+    Min,
+    Max,
+    Sum,
+}
+
+impl Aggregate {
+    pub const ALL: &'static [Aggregate] = &[
+        Aggregate::Any,
+        Aggregate::All,
+        Aggregate::Count,
+        Aggregate::Min,
+        Aggregate::Max,
+        Aggregate::Sum,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aggregate::Any => "any",
+            Aggregate::All => "all",
+            Aggregate::Count => "count",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Sum => "sum",
+        }
+    }
+
+    // The item that is first in the order given by `tok`.
+    fn extreme(self, tok: Token, items: &[Value]) -> Fallible<Value> {
+        let (first, rest) = match items.split_first() {
+            Some(split) => split,
+            None => raise!(Runtime, "{} of an empty list", self.name()),
+        };
+        let mut best = first;
+        for item in rest {
+            if item.apply(&tok, best)?.as_boolean()? {
+                best = item;
+            }
+        }
+        Ok(best.to_owned())
+    }
+}
+
+impl NativeFunc for Aggregate {
+    fn signature(&self) -> Signature {
+        Signature {
+            args: &[ValueType::List],
+            result: match self {
+                Aggregate::Any | Aggregate::All => Some(ValueType::Boolean),
+                Aggregate::Count => Some(ValueType::Integer),
+                Aggregate::Min | Aggregate::Max | Aggregate::Sum => None,
+            },
+            pure: true,
+        }
+    }
+
+    fn compute(&self, value: Value, _tree: &Tree) -> Fallible<Value> {
+        let items = value.as_list()?;
+        let out = match self {
+            Aggregate::Any | Aggregate::All | Aggregate::Count => {
+                let mut count = 0;
+                for item in items {
+                    if item.as_boolean()? {
+                        count += 1;
+                    }
+                }
+                match self {
+                    Aggregate::Any => Value::from_boolean(count > 0),
+                    Aggregate::All => Value::from_boolean(count == items.len()),
+                    _ => Value::from_integer(count as i64),
+                }
+            }
+            Aggregate::Min => self.extreme(Token::LessThan, items)?,
+            Aggregate::Max => self.extreme(Token::GreaterThan, items)?,
+            Aggregate::Sum => {
+                let mut total = Value::from_integer(0);
+                for item in items {
+                    total = total.apply(&Token::Add, item)?;
+                }
+                total
+            }
+        };
+        Ok(out.with_generation(value.generation()))
+    }
+
+    fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::Error,
+        float::Float,
+        path::ConcretePath,
+        tree::{Tree, TreeBuilder},
+        value::Value,
+    };
+    use failure::Fallible;
+    use std::str::FromStr;
+
+    const ROOMS: &str = r#"
+rooms
+    bedroom
+        color ^switch
+            domain <- ["on", "off"]
+            default <- "off"
+        watts <- 20
+    kitchen
+        color <- "on"
+        watts <- 60
+    office
+        watts <- 40.5
+hall
+    any-on <- any(/rooms/*/color == "on")
+    all-on <- all(/rooms/*/color == "on")
+    lit <- count(/rooms/*/color == "on")
+    watts <- sum(/rooms/*/watts)
+    least <- min(/rooms/*/watts)
+    most <- max(/rooms/*/watts)
+"#;
+
+    fn value(tree: &Tree, path: &str) -> Fallible<Value> {
+        tree.lookup(path)?.compute(tree)
+    }
+
+    #[test]
+    fn test_aggregate_over_glob() -> Fallible<()> {
+        let mut tree = TreeBuilder::default().build_from_str(ROOMS)?;
+        assert_eq!(value(&tree, "/hall/any-on")?, Value::from_boolean(true));
+        assert_eq!(value(&tree, "/hall/all-on")?, Value::from_boolean(false));
+        assert_eq!(value(&tree, "/hall/lit")?, Value::from_integer(1));
+        assert_eq!(
+            value(&tree, "/hall/watts")?,
+            Value::from_float(Float::new(120.5)?)
+        );
+        assert_eq!(value(&tree, "/hall/least")?, Value::from_integer(20));
+        assert_eq!(value(&tree, "/hall/most")?, Value::from_integer(60));
+
+        tree.handle_event(
+            &ConcretePath::from_str("/rooms/bedroom/color")?,
+            Value::new_str("on"),
+        )?;
+        assert_eq!(value(&tree, "/hall/all-on")?, Value::from_boolean(true));
+        assert_eq!(value(&tree, "/hall/lit")?, Value::from_integer(2));
+
+        // A new room joins every glob over the rooms.
+        tree.add_nodes(
+            &ConcretePath::from_str("/rooms")?,
+            "study\n    color <- \"off\"\n    watts <- 100\n",
+        )?;
+        assert_eq!(value(&tree, "/hall/all-on")?, Value::from_boolean(false));
+        assert_eq!(
+            value(&tree, "/hall/watts")?,
+            Value::from_float(Float::new(220.5)?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate_possible_values() -> Fallible<()> {
+        let tree = TreeBuilder::default().build_from_str(ROOMS)?;
+        let possible = |path: &str| -> Fallible<Vec<Value>> {
+            let values = tree.lookup(path)?.possible_values(&tree, &mut Vec::new())?;
+            Ok(values.expect("a pure aggregate"))
+        };
+        assert_eq!(possible("/hall/any-on")?, vec![Value::from_boolean(true)]);
+        let lit = possible("/hall/lit")?;
+        assert_eq!(lit.len(), 2);
+        assert!(lit.contains(&Value::from_integer(1)));
+        assert!(lit.contains(&Value::from_integer(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate_errors() -> Fallible<()> {
+        let build = |s: &str| TreeBuilder::default().build_from_str(s);
+        let err = build("a <- any(1)").err().unwrap();
+        assert!(matches!(Error::find(&err), Some(Error::Type(_))));
+        let err = build("a <- str(/b/*)\nb\n    c <- 1\n").err().unwrap();
+        assert!(matches!(Error::find(&err), Some(Error::Type(_))));
+
+        let tree = build("a <- min(/b/*)\nb\nc <- sum(/b/*)\nd <- any(/e/*)\ne\n    f <- 1\n")?;
+        let err = value(&tree, "/a").err().unwrap();
+        assert_eq!(Error::find(&err).unwrap().message(), "min of an empty list");
+        assert_eq!(value(&tree, "/c")?, Value::from_integer(0));
+        assert!(value(&tree, "/d").is_err());
+        Ok(())
+    }
+}
@@ -1,21 +1,84 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+pub(super) mod aggregate;
 pub(super) mod tostr;
 
-use crate::{path::ConcretePath, tree::Tree, value::Value};
+use crate::{
+    tree::Tree,
+    value::{Value, ValueType},
+};
+use aggregate::Aggregate;
 use failure::Fallible;
-use std::fmt;
+use std::{collections::HashMap, fmt};
+use tostr::ToStr;
+
+/// What a native function takes and gives, declared up front so that the
+/// tree can reason through calls before it runs them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Signature {
+    // The types that the argument may have.
+    pub args: &'static [ValueType],
+    // The type of the result, or None if it depends on the argument.
+    pub result: Option<ValueType>,
+    // A pure function's result depends only on its argument, so it may be
+    // computed ahead of time, when working out what values a node may take.
+    pub pure: bool,
+}
 
 pub trait NativeFunc {
+    fn signature(&self) -> Signature;
     fn compute(&self, value: Value, tree: &Tree) -> Fallible<Value>;
-    fn find_all_possible_inputs(
-        &self,
-        value_type: (),
-        tree: &Tree,
-        out: &mut Vec<ConcretePath>,
-    ) -> Fallible<()>;
     fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync>;
+
+    /// Map every value that the argument may take to every value that the
+    /// result may take, or None if that is not knowable ahead of time. By
+    /// default, a pure function computes each, skipping any that fail, and a
+    /// stateful function knows nothing.
+    fn map_domain(&self, domain: Vec<Value>, tree: &Tree) -> Fallible<Option<Vec<Value>>> {
+        if !self.signature().pure {
+            return Ok(None);
+        }
+        let mut out = Vec::new();
+        for arg in domain {
+            if let Ok(v) = self.compute(arg, tree) {
+                if !out.contains(&v) {
+                    out.push(v);
+                }
+            }
+        }
+        Ok(Some(out))
+    }
+}
+
+impl dyn NativeFunc + Send + Sync {
+    // The type of the result, given the type of the argument, where known.
+    pub(crate) fn check_arg_type(&self, arg: Option<ValueType>) -> Fallible<Option<ValueType>> {
+        let sig = self.signature();
+        if let Some(ty) = arg {
+            if !sig.args.contains(&ty) {
+                let expect = sig
+                    .args
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" or ");
+                raise!(Type, "expected {} argument, but found a {}", expect, ty);
+            }
+        }
+        Ok(sig.result)
+    }
+}
+
+// Add the functions that every tree may call, unless its builder opts out.
+// A function of the embedding replaces the builtin of the same name.
+pub(crate) fn add_builtins(nifs: &mut HashMap<String, Box<dyn NativeFunc + Send + Sync>>) {
+    nifs.entry("str".to_owned())
+        .or_insert_with(|| Box::new(ToStr));
+    for &aggregate in Aggregate::ALL {
+        nifs.entry(aggregate.name().to_owned())
+            .or_insert_with(|| Box::new(aggregate));
+    }
 }
 
 impl Clone for Box<dyn NativeFunc + Send + Sync> {
@@ -26,6 +89,72 @@ impl Clone for Box<dyn NativeFunc + Send + Sync> {
 
 impl fmt::Debug for Box<dyn NativeFunc + Send + Sync> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<Unknown NativeFunc>")
+        write!(f, "<NativeFunc {:?}>", self.signature())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{error::Error, tree::TreeBuilder};
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    // Counts its calls, so cannot be computed ahead of time.
+    #[derive(Debug)]
+    struct Counter(AtomicI64);
+
+    impl NativeFunc for Counter {
+        fn signature(&self) -> Signature {
+            Signature {
+                args: &[ValueType::Integer],
+                result: Some(ValueType::Integer),
+                pure: false,
+            }
+        }
+
+        fn compute(&self, value: Value, _tree: &Tree) -> Fallible<Value> {
+            let count = self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Value::from_integer(value.as_integer()? + count))
+        }
+
+        fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
+            Box::new(Counter(AtomicI64::new(self.0.load(Ordering::SeqCst))))
+        }
+    }
+
+    fn builder() -> Fallible<TreeBuilder> {
+        TreeBuilder::default().add_native_function("count", Box::new(Counter(AtomicI64::new(0))))
+    }
+
+    #[test]
+    fn test_nif_argument_types() -> Fallible<()> {
+        assert!(builder()?.build_from_str("a <- count(str(1))").is_err());
+        let err = builder()?
+            .build_from_str("a <- count(1 < 2)")
+            .err()
+            .unwrap();
+        let err = Error::find(&err).unwrap();
+        assert!(matches!(err, Error::Type(_)));
+        assert_eq!(
+            err.message(),
+            "expected integer argument, but found a boolean"
+        );
+        builder()?.build_from_str("a <- count(1 + count(1))")?;
+        assert!(builder()?.build_from_str("a <- str([1, 2])").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nif_stateful_domain_is_unknown() -> Fallible<()> {
+        let s = r#"
+a <- count(1)
+b <- str(1)
+"#;
+        let tree = builder()?.build_from_str(s)?;
+        let a = tree.lookup("/a")?.possible_values(&tree, &mut Vec::new())?;
+        assert_eq!(a, None);
+        let b = tree.lookup("/b")?.possible_values(&tree, &mut Vec::new())?;
+        assert_eq!(b, Some(vec![Value::new_str("1")]));
+        Ok(())
     }
 }
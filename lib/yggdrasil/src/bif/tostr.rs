@@ -2,17 +2,30 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
-    bif::NativeFunc,
-    path::ConcretePath,
+    bif::{NativeFunc, Signature},
     tree::Tree,
-    value::{Value, ValueData},
+    value::{Value, ValueData, ValueType},
 };
-use failure::{bail, Fallible};
+use failure::Fallible;
 
 #[derive(Clone, Debug)]
 pub(crate) struct ToStr;
 
 impl NativeFunc for ToStr {
+    fn signature(&self) -> Signature {
+        Signature {
+            args: &[
+                ValueType::Boolean,
+                ValueType::Float,
+                ValueType::Integer,
+                ValueType::Path,
+                ValueType::String,
+            ],
+            result: Some(ValueType::String),
+            pure: true,
+        }
+    }
+
     fn compute(&self, value: Value, tree: &Tree) -> Fallible<Value> {
         Ok(Value::from_string(match value.data {
             ValueData::String(s) => s,
@@ -23,20 +36,41 @@ impl NativeFunc for ToStr {
                 let (noderef, _gen) = tree.lookup_dynamic_path(0, &p)?;
                 self.compute(noderef.compute(tree)?, tree)?.as_string()?
             }
-            ValueData::InputFlag => bail!("runtime error: InputFlag in ToStr"),
+            ValueData::List(_) => raise!(Type, "a list cannot be converted to a string"),
+            ValueData::InputFlag => raise!(Type, "InputFlag in ToStr"),
         }))
     }
 
-    fn find_all_possible_inputs(
-        &self,
-        _value_type: (),
-        _tree: &Tree,
-        _out: &mut Vec<ConcretePath>,
-    ) -> Fallible<()> {
-        Ok(())
-    }
-
     fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
         Box::new((*self).clone())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{path::ConcretePath, tree::TreeBuilder, value::Value};
+    use failure::Fallible;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_tostr_reads_and_maps_its_argument() -> Fallible<()> {
+        let s = r#"
+a ^button
+    domain <- [1, 2]
+b <- str(/a * 10)
+c
+    10 <- "ten"
+    20 <- "twenty"
+d <- /c/{/b}
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let b = tree.lookup("/b")?.possible_values(&tree, &mut Vec::new())?;
+        assert_eq!(b, Some(vec![Value::new_str("10"), Value::new_str("20")]));
+
+        tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(1))?;
+        assert_eq!(tree.lookup("/d")?.compute(&tree)?, Value::new_str("ten"));
+        tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(2))?;
+        assert_eq!(tree.lookup("/d")?.compute(&tree)?, Value::new_str("twenty"));
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use failure::Fallible;
+use std::{fs, path::PathBuf, process};
+use structopt::StructOpt;
+use yggdrasil::{format_source, LintKind, Linter, TreeBuilder};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "ygg", about = "Tools for working with Yggdrasil trees")]
+enum Opt {
+    /// Rewrite .ygg files in canonical form.
+    #[structopt(name = "fmt")]
+    Fmt {
+        /// Do not write anything; fail if any file is not already formatted.
+        #[structopt(long = "check")]
+        check: bool,
+
+        #[structopt(parse(from_os_str), required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    /// Report likely mistakes in .ygg files.
+    #[structopt(name = "lint")]
+    Lint {
+        /// Do not report this lint; may be given more than once.
+        #[structopt(long = "allow", number_of_values = 1)]
+        allow: Vec<LintKind>,
+
+        #[structopt(parse(from_os_str), required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    /// Show what building .ygg files folds ahead of time, and which nodes no
+    /// sink reads.
+    #[structopt(name = "folds")]
+    Folds {
+        #[structopt(parse(from_os_str), required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+// Returns false if `check` is set and any file would change.
+fn fmt(check: bool, files: &[PathBuf]) -> Fallible<bool> {
+    let mut clean = true;
+    for file in files {
+        let original = fs::read_to_string(file)?;
+        let formatted = format_source(&original)?;
+        if formatted == original {
+            continue;
+        }
+        if check {
+            println!("would reformat {}", file.display());
+            clean = false;
+        } else {
+            fs::write(file, formatted)?;
+            println!("reformatted {}", file.display());
+        }
+    }
+    Ok(clean)
+}
+
+// Returns false if any file has lints.
+fn lint(allow: &[LintKind], files: &[PathBuf]) -> Fallible<bool> {
+    let mut linter = Linter::default();
+    for kind in allow {
+        linter = linter.allow(*kind);
+    }
+    let mut clean = true;
+    for file in files {
+        let tree = TreeBuilder::default().build_from_file(file)?;
+        for lint in linter.check(&tree)? {
+            println!("{}:{}", file.display(), lint);
+            clean = false;
+        }
+    }
+    Ok(clean)
+}
+
+fn folds(files: &[PathBuf]) -> Fallible<bool> {
+    for file in files {
+        let tree = TreeBuilder::default().build_from_file(file)?;
+        for line in tree.optimizations()? {
+            println!("{}:{}", file.display(), line);
+        }
+    }
+    Ok(true)
+}
+
+fn run() -> Fallible<bool> {
+    Ok(match Opt::from_args() {
+        Opt::Fmt { check, files } => fmt(check, &files)?,
+        Opt::Lint { allow, files } => lint(&allow, &files)?,
+        Opt::Folds { files } => folds(&files)?,
+    })
+}
+
+fn main() {
+    match run() {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
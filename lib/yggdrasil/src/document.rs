@@ -0,0 +1,357 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    error::{Error, ErrorContext, Span},
+    path::{ConcretePath, ScriptPath},
+    tokenizer::{quote_path_name, LineTokenizer, SpannedToken, Token},
+    tree::{NodeRef, Tree, TreeBuilder},
+};
+use failure::Fallible;
+use std::fmt::Write;
+
+/// A .ygg source text being edited, and what an editor needs to know about
+/// it: the errors in it and what its names and paths refer to.
+///
+/// Text that is half typed rarely builds, so lookups fall back to the last
+/// version of the text that did. Positions in that tree may have drifted from
+/// the current text by however many lines have been added since.
+pub struct Document {
+    source: String,
+    errors: Vec<Error>,
+    tree: Option<Tree>,
+    // The line that each node in `tree` is declared on, in order.
+    declarations: Vec<(usize, ConcretePath)>,
+}
+
+impl Document {
+    pub fn new(source: &str) -> Self {
+        let mut doc = Document {
+            source: String::new(),
+            errors: Vec::new(),
+            tree: None,
+            declarations: Vec::new(),
+        };
+        doc.update(source);
+        doc
+    }
+
+    /// Replace the text, rebuilding the tree from it if it is valid.
+    pub fn update(&mut self, source: &str) {
+        self.source = source.to_owned();
+        match TreeBuilder::default().build_from_str(source) {
+            Ok(tree) => {
+                self.errors.clear();
+                self.declarations = Self::find_declarations(&tree);
+                self.tree = Some(tree);
+            }
+            Err(err) => {
+                let found = Error::find_all(&err);
+                self.errors = if found.is_empty() {
+                    vec![Error::Parse(ErrorContext::new(err.to_string()))]
+                } else {
+                    found.into_iter().cloned().collect()
+                };
+            }
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Every error in the current text.
+    pub fn errors(&self) -> &[Error] {
+        &self.errors
+    }
+
+    /// Where the nodes named by the path at `at` are declared: for a path
+    /// with a {...} lookup, every node that the lookup may reach. Within a
+    /// lookup, the path inside the braces is the one used.
+    pub fn definitions(&self, at: Span) -> Vec<Span> {
+        let (tree, path) = match self.path_at(at) {
+            Some(found) => found,
+            None => return Vec::new(),
+        };
+        let mut spans = Vec::new();
+        for concrete in path.devirtualize(tree).unwrap_or_default() {
+            if let Some(span) = tree
+                .lookup_path(&concrete)
+                .ok()
+                .and_then(|n| n.span().ok().flatten())
+            {
+                spans.push(span);
+            }
+        }
+        spans
+    }
+
+    /// A description, in markdown, of the node named or declared at `at`.
+    pub fn hover(&self, at: Span) -> Option<String> {
+        let tree = self.tree.as_ref()?;
+        let (token, line) = self.token_at(at)?;
+        let node = match token.token {
+            Token::PathTerm(_) => {
+                let (tree, path) = self.path_at(at)?;
+                let paths = path.devirtualize(tree).ok()?;
+                if paths.len() != 1 {
+                    let mut out = format!("`{}` may reach:\n", path);
+                    for path in &paths {
+                        writeln!(out, "- `{}`", path).ok()?;
+                    }
+                    return Some(out);
+                }
+                tree.lookup_path(&paths[0]).ok()?
+            }
+            // Only the name that starts a line declares a node.
+            _ if token.span.column == LineTokenizer::leading_whitespace(&line) + 1 => {
+                let node = self.node_on_line(tree, at.line)?;
+                if node.span().ok()?? != token.span {
+                    return None;
+                }
+                node
+            }
+            _ => return None,
+        };
+        Self::describe(tree, &node).ok()
+    }
+
+    /// The names of the children that may follow the `/` before `at`, or
+    /// nothing if `at` does not follow a `/` in a path.
+    pub fn completions(&self, at: Span) -> Vec<String> {
+        self.complete(at).unwrap_or_default()
+    }
+
+    fn complete(&self, at: Span) -> Option<Vec<String>> {
+        let tree = self.tree.as_ref()?;
+        let line = self.source.lines().nth(at.line.checked_sub(1)?)?;
+        let before = line
+            .chars()
+            .take(at.column.checked_sub(1)?)
+            .collect::<Vec<_>>();
+
+        // Walk back over the path being typed to the start of it, or of the
+        // lookup that we are inside of.
+        let mut start = before.len();
+        let mut depth = 0;
+        while start > 0 {
+            match before[start - 1] {
+                '}' => depth += 1,
+                '{' if depth == 0 => break,
+                '{' => depth -= 1,
+                c if c.is_whitespace() || "()[],".contains(c) => break,
+                _ => {}
+            }
+            start -= 1;
+        }
+        let typed = before[start..].iter().collect::<String>();
+        let parent = &typed[..typed.rfind('/')?];
+
+        let parents = if parent.is_empty() {
+            vec![tree.root()]
+        } else {
+            let owner = self.node_on_line(tree, at.line)?;
+            let path = ScriptPath::from_str_at_path(&owner.path_str().ok()?, parent).ok()?;
+            let paths = path.devirtualize(tree).ok()?;
+            paths
+                .iter()
+                .filter_map(|p| tree.lookup_path(p).ok())
+                .collect()
+        };
+        let mut names = parents
+            .iter()
+            .flat_map(|node| node.child_names().unwrap_or_default())
+            .map(|name| quote_path_name(&name))
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        Some(names)
+    }
+
+    // The path under `at`, along with the tree it is a path in.
+    fn path_at(&self, at: Span) -> Option<(&Tree, ScriptPath)> {
+        let tree = self.tree.as_ref()?;
+        let (token, _) = self.token_at(at)?;
+        let text = match token.token {
+            Token::PathTerm(ref text) => text.chars().collect::<Vec<_>>(),
+            _ => return None,
+        };
+
+        // Narrow to the innermost lookup that holds `at`, if any.
+        let offset = at.column - token.span.column;
+        let mut range = (0, text.len());
+        let mut open = Vec::new();
+        for (i, &c) in text.iter().enumerate() {
+            match c {
+                '{' => open.push(i),
+                '}' => {
+                    if let Some(start) = open.pop() {
+                        if start < offset && offset <= i && start + 1 > range.0 {
+                            range = (start + 1, i);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let text = text[range.0..range.1].iter().collect::<String>();
+
+        let owner = self.node_on_line(tree, at.line)?;
+        let path = ScriptPath::from_str_at_path(&owner.path_str().ok()?, &text).ok()?;
+        Some((tree, path))
+    }
+
+    // The token on the line at `at`, along with the line it is on.
+    fn token_at(&self, at: Span) -> Option<(SpannedToken, String)> {
+        let raw = self.source.lines().nth(at.line.checked_sub(1)?)?;
+        let line = LineTokenizer::trim_comment(&raw.replace('\t', "    "));
+        let (tokens, _) = LineTokenizer::tokenize_line(&line, at.line).ok()?;
+        let token = tokens
+            .into_iter()
+            .take_while(|t| t.span.column <= at.column)
+            .last()?;
+        if let Token::PathTerm(ref text) = token.token {
+            if at.column > token.span.column + text.chars().count() {
+                return None;
+            }
+        }
+        Some((token, line))
+    }
+
+    // The node that the given line belongs to: the last one declared at or
+    // before it, as anything after a node's name, up to the next node, is
+    // part of that node.
+    fn node_on_line(&self, tree: &Tree, line: usize) -> Option<NodeRef> {
+        let at = self
+            .declarations
+            .iter()
+            .take_while(|(declared, _)| *declared <= line)
+            .last()?;
+        tree.lookup_path(&at.1).ok()
+    }
+
+    fn find_declarations(tree: &Tree) -> Vec<(usize, ConcretePath)> {
+        let mut nodes = Vec::new();
+        if tree.root().collect_subtree(&mut nodes).is_err() {
+            return Vec::new();
+        }
+        let mut out = Vec::new();
+        for node in nodes {
+            if let (Ok(Some(span)), Ok(path)) = (node.span(), node.path()) {
+                out.push((span.line, path));
+            }
+        }
+        out.sort();
+        out
+    }
+
+    fn describe(tree: &Tree, node: &NodeRef) -> Fallible<String> {
+        let mut out = format!("**{}**\n", node.path()?);
+        if let Some(kind) = node.maybe_source_kind()? {
+            out += &format!("\nsource: `^{}`\n", kind);
+        }
+        if let Some(kind) = node.maybe_sink_kind()? {
+            out += &format!("\nsink: `${}`\n", kind);
+        }
+        if node.is_source()? || node.has_script()? {
+            let label = if node.is_constant()? {
+                "constant"
+            } else {
+                "value"
+            };
+            out += &match node.compute(tree) {
+                Ok(value) => format!("\n{}: `{}`\n", label, value),
+                Err(err) => format!("\n{}: unknown ({})\n", label, err),
+            };
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure::Fallible;
+    use std::str::FromStr;
+
+    const SOURCE: &str = r#"
+palette
+    on <- "bright"
+    off <- "dark"
+room
+    switch ^button
+        domain <- ["on", "off"]
+        default <- "off"
+    light $hue <- /palette/{./switch}
+    copy <- ../room/light
+"#;
+
+    fn at(line: usize, column: usize) -> Span {
+        Span { line, column }
+    }
+
+    #[test]
+    fn test_document_errors() {
+        let doc = Document::new("a <- (1 +\nb <- ,\nc\n");
+        assert_eq!(doc.errors().len(), 1);
+        assert_eq!(doc.errors()[0].span(), Some(at(1, 6)));
+
+        let doc = Document::new("a <- 1\nb\n    c <- /d\n");
+        assert_eq!(doc.errors().len(), 1);
+        assert!(matches!(doc.errors()[0], Error::Link(_)));
+        assert_eq!(doc.errors()[0].span(), Some(at(3, 5)));
+    }
+
+    #[test]
+    fn test_document_definitions() {
+        let doc = Document::new(SOURCE);
+        // The whole path reaches every entry in the palette.
+        assert_eq!(doc.definitions(at(9, 20)), vec![at(3, 5), at(4, 5)]);
+        // Inside the lookup, only the path in the braces.
+        assert_eq!(doc.definitions(at(9, 33)), vec![at(6, 5)]);
+        assert_eq!(doc.definitions(at(10, 18)), vec![at(9, 5)]);
+        assert_eq!(doc.definitions(at(10, 5)), Vec::<Span>::new());
+    }
+
+    #[test]
+    fn test_document_hover() -> Fallible<()> {
+        let doc = Document::new(SOURCE);
+        let hover = doc.hover(at(9, 5)).unwrap();
+        assert!(hover.starts_with("**/room/light**"));
+        assert!(hover.contains("sink: `$hue`"));
+        assert!(hover.contains("value: `\"dark\"`"));
+        let hover = doc.hover(at(6, 6)).unwrap();
+        assert!(hover.contains("source: `^button`"));
+        assert!(hover.contains("value: `\"off\"`"));
+        let hover = doc.hover(at(3, 5)).unwrap();
+        assert!(hover.contains("constant: `\"bright\"`"));
+        let hover = doc.hover(at(9, 20)).unwrap();
+        assert!(hover.contains("- `/palette/on`"));
+        assert!(doc.hover(at(9, 11)).is_none());
+
+        let path = ConcretePath::from_str("/room/light")?;
+        assert_eq!(
+            doc.tree.as_ref().unwrap().lookup_path(&path)?.span()?,
+            Some(at(9, 5))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_completions() {
+        let mut doc = Document::new(SOURCE);
+        let edited = SOURCE.replace("copy <- ../room/light", "copy <- /palette/");
+        doc.update(&edited);
+        assert!(!doc.errors().is_empty());
+        assert_eq!(doc.completions(at(10, 22)), vec!["off", "on"]);
+        assert_eq!(doc.completions(at(10, 14)), vec!["palette", "room"]);
+        assert_eq!(doc.completions(at(10, 9)), Vec::<String>::new());
+
+        let edited = SOURCE.replace("copy <- ../room/light", "copy <- ./switch/");
+        doc.update(&edited);
+        assert_eq!(doc.completions(at(10, 22)), vec!["default", "domain"]);
+        let edited = SOURCE.replace("copy <- ../room/light", "copy <- /palette/{./");
+        doc.update(&edited);
+        assert_eq!(doc.completions(at(10, 25)), vec!["copy", "light", "switch"]);
+    }
+}
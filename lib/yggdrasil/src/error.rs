@@ -0,0 +1,281 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{path::ConcretePath, value::Value};
+use std::{error, fmt};
+
+// As failure's bail!, but raising an Error of the named kind, e.g.
+// `raise!(Runtime, "no default for {}", path)`.
+macro_rules! raise {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err($crate::error::Error::$kind(
+            $crate::error::ErrorContext::new(format!($($arg)+))
+        ).into())
+    };
+}
+
+// As failure's ensure!, but raising an Error of the named kind.
+macro_rules! require {
+    ($cond:expr, $kind:ident, $($arg:tt)+) => {
+        if !$cond {
+            raise!($kind, $($arg)+);
+        }
+    };
+}
+
+/// A position in the source text, counting from 1.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// What an error is about, where that is known.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorContext {
+    pub message: String,
+    pub path: Option<ConcretePath>,
+    pub span: Option<Span>,
+    pub value: Option<Value>,
+}
+
+impl ErrorContext {
+    pub fn new(message: String) -> Self {
+        Self {
+            message,
+            ..Default::default()
+        }
+    }
+}
+
+/// Every failure raised by this crate. These are returned inside of a
+/// `failure::Error`; use `Error::find` to get one back out, or
+/// `Error::find_all` where several may have been found at once.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    // The text could not be split into tokens.
+    Tokenize(ErrorContext),
+    // The tokens do not make a valid tree, script, or path.
+    Parse(ErrorContext),
+    // A script could not be attached to the nodes that it reads.
+    Link(ErrorContext),
+    // A value was used where some other type of value was required.
+    Type(ErrorContext),
+    // The nodes cannot be evaluated together: e.g. they form a cycle.
+    Dataflow(ErrorContext),
+    // Something went wrong while computing values or handling an event.
+    Runtime(ErrorContext),
+}
+
+impl Error {
+    /// The typed error inside an error returned from this crate, if any.
+    pub fn find(err: &failure::Error) -> Option<&Error> {
+        match err.downcast_ref::<Errors>() {
+            Some(errors) => errors.0.first(),
+            None => err.downcast_ref::<Error>(),
+        }
+    }
+
+    /// Every typed error inside an error returned from this crate, in the
+    /// order that they were found.
+    pub fn find_all(err: &failure::Error) -> Vec<&Error> {
+        match err.downcast_ref::<Errors>() {
+            Some(errors) => errors.0.iter().collect(),
+            None => err.downcast_ref::<Error>().into_iter().collect(),
+        }
+    }
+
+    // Return every error found in one pass together, so that a caller can fix
+    // them all at once. A lone error is returned as it is.
+    pub(crate) fn combine(mut errs: Vec<failure::Error>) -> failure::Error {
+        if errs.len() == 1 {
+            return errs.remove(0);
+        }
+        let errors = errs
+            .into_iter()
+            .flat_map(|err| match err.downcast::<Errors>() {
+                Ok(errors) => errors.0,
+                Err(err) => vec![match err.downcast::<Error>() {
+                    Ok(e) => e,
+                    Err(err) => Error::Parse(ErrorContext::new(err.to_string())),
+                }],
+            })
+            .collect();
+        Errors(errors).into()
+    }
+
+    /// Runtime and type errors are caused by the values moving through a tree,
+    /// not by the tree itself, which is left as valid as it was before. An
+    /// embedder can drop the event or value that caused one and carry on.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Error::Runtime(_) | Error::Type(_))
+    }
+
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            Error::Tokenize(ctx)
+            | Error::Parse(ctx)
+            | Error::Link(ctx)
+            | Error::Type(ctx)
+            | Error::Dataflow(ctx)
+            | Error::Runtime(ctx) => ctx,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext {
+        match self {
+            Error::Tokenize(ctx)
+            | Error::Parse(ctx)
+            | Error::Link(ctx)
+            | Error::Type(ctx)
+            | Error::Dataflow(ctx)
+            | Error::Runtime(ctx) => ctx,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.context().message
+    }
+
+    pub fn path(&self) -> Option<ConcretePath> {
+        self.context().path.clone()
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.context().span
+    }
+
+    pub fn value(&self) -> Option<&Value> {
+        self.context().value.as_ref()
+    }
+
+    pub(crate) fn at(mut self, path: ConcretePath) -> Self {
+        self.context_mut().path = Some(path);
+        self
+    }
+
+    pub(crate) fn with_value(mut self, value: Value) -> Self {
+        self.context_mut().value = Some(value);
+        self
+    }
+
+    // Fill in the node an error passed through on its way out, unless a node
+    // nearer to the problem already claimed it.
+    pub(crate) fn annotate_path(mut err: failure::Error, path: ConcretePath) -> failure::Error {
+        if let Some(e) = err.downcast_mut::<Error>() {
+            let ctx = e.context_mut();
+            if ctx.path.is_none() {
+                ctx.path = Some(path);
+            }
+        }
+        err
+    }
+
+    // As annotate_path, for the place in the source text.
+    pub(crate) fn annotate_span(mut err: failure::Error, span: Span) -> failure::Error {
+        if let Some(e) = err.downcast_mut::<Error>() {
+            let ctx = e.context_mut();
+            if ctx.span.is_none() {
+                ctx.span = Some(span);
+            }
+        }
+        err
+    }
+
+    fn kind_name(&self) -> &'static str {
+        match self {
+            Error::Tokenize(_) => "tokenize error",
+            Error::Parse(_) => "parse error",
+            Error::Link(_) => "link error",
+            Error::Type(_) => "type error",
+            Error::Dataflow(_) => "dataflow error",
+            Error::Runtime(_) => "runtime error",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind_name(), self.message())?;
+        if let Some(span) = self.span() {
+            write!(f, " (at {})", span)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
+/// More than one error, found together; e.g. every syntax error in a file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Errors(Vec<Error>);
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, err) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Errors {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{float::Float, tree::TreeBuilder};
+    use failure::Fallible;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_error_kinds() -> Fallible<()> {
+        let find = |s: &str| {
+            let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+            Error::find(&err).cloned().unwrap()
+        };
+
+        let err = find("a <- 1 ? 2");
+        assert!(matches!(err, Error::Tokenize(_)));
+        assert_eq!(err.span(), Some(Span { line: 1, column: 8 }));
+
+        let err = find("a\n    b <- /c");
+        assert!(matches!(err, Error::Link(_)));
+        assert_eq!(err.path(), Some(ConcretePath::from_str("/a/b")?));
+
+        let err = find("a <- ./b\nb <- ./a");
+        assert!(matches!(err, Error::Dataflow(_)));
+        assert!(!err.is_recoverable());
+
+        let s = r#"
+c
+    1 <- "one"
+a ^button
+b <- /c/{/a}
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let err = tree.lookup("/b")?.compute(&tree).err().unwrap();
+        let err = Error::find(&err).unwrap();
+        assert!(matches!(err, Error::Runtime(_)));
+        assert_eq!(err.path(), Some(ConcretePath::from_str("/a")?));
+
+        let value = Value::from_float(Float::new(1.5)?);
+        tree.handle_event(&ConcretePath::from_str("/a")?, value.clone())?;
+        let err = tree.lookup("/b")?.compute(&tree).err().unwrap();
+        let err = Error::find(&err).unwrap();
+        assert!(matches!(err, Error::Type(_)));
+        assert!(err.is_recoverable());
+        assert_eq!(err.path(), Some(ConcretePath::from_str("/b")?));
+        assert_eq!(err.value(), Some(&value));
+        Ok(())
+    }
+}
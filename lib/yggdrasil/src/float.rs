@@ -1,22 +1,22 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
-use failure::{ensure, Fallible};
-use std::{
-    cmp::Ordering,
-    fmt,
-    ops::{Add, Div, Mul, Neg, Sub},
-};
-
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+use failure::Fallible;
+use std::{cmp::Ordering, fmt};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Float {
     pub value: f64,
 }
 
 impl Float {
     pub fn new(value: f64) -> Fallible<Float> {
-        ensure!(!value.is_infinite(), "numerical error: glimpsed infinity");
-        ensure!(!value.is_nan(), "numerical error: not a number");
+        require!(
+            !value.is_infinite(),
+            Runtime,
+            "numerical error: glimpsed infinity"
+        );
+        require!(!value.is_nan(), Runtime, "numerical error: not a number");
         Ok(Float { value })
     }
 
@@ -39,48 +39,30 @@ impl Float {
     pub fn checked_sub(self, rhs: Float) -> Fallible<Float> {
         Float::new(self.value - rhs.value)
     }
-}
 
-impl Add for Float {
-    type Output = Float;
-    fn add(self, rhs: Float) -> Self::Output {
-        self.checked_add(rhs).unwrap()
-    }
-}
-
-impl Div for Float {
-    type Output = Float;
-    fn div(self, rhs: Float) -> Self::Output {
-        self.checked_div(rhs).unwrap()
+    // As for integers, the remainder takes the sign of the divisor, so that
+    // e.g. -30 % 360 is 330.
+    pub fn checked_rem(self, rhs: Float) -> Fallible<Float> {
+        let rem = self.value % rhs.value;
+        if rem != 0. && (rem < 0.) != (rhs.value < 0.) {
+            return Float::new(rem + rhs.value);
+        }
+        Float::new(rem)
     }
 }
 
 impl Eq for Float {}
 
-impl Mul for Float {
-    type Output = Float;
-    fn mul(self, rhs: Float) -> Self::Output {
-        self.checked_mul(rhs).unwrap()
-    }
-}
-
-impl Neg for Float {
-    type Output = Float;
-    fn neg(self) -> Self::Output {
-        self.checked_neg().unwrap()
-    }
-}
-
 impl Ord for Float {
     fn cmp(&self, other: &Float) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        // Float::new rejects NaN, so all values are comparable.
+        self.value.partial_cmp(&other.value).unwrap()
     }
 }
 
-impl Sub for Float {
-    type Output = Float;
-    fn sub(self, rhs: Float) -> Self::Output {
-        self.checked_sub(rhs).unwrap()
+impl PartialOrd for Float {
+    fn partial_cmp(&self, other: &Float) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -94,6 +76,21 @@ impl fmt::Display for Float {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_float_order() -> Fallible<()> {
+        let mut floats = vec![Float::new(2.5)?, Float::new(-1.)?, Float::new(0.)?];
+        floats.sort();
+        assert_eq!(
+            floats,
+            vec![Float::new(-1.)?, Float::new(0.)?, Float::new(2.5)?]
+        );
+        assert_eq!(
+            Float::new(1.)?.partial_cmp(&Float::new(2.)?),
+            Some(Ordering::Less)
+        );
+        Ok(())
+    }
+
     #[test]
     #[should_panic]
     fn test_float_inf() {
@@ -108,15 +105,33 @@ mod test {
     }
 
     #[test]
-    fn test_float_arith() {
-        let two = Float::new(2.0f64).unwrap();
-        let three = Float::new(3.0f64).unwrap();
-        let four = Float::new(4.0f64).unwrap();
-        let five = Float::new(5.0f64).unwrap();
-        let six = Float::new(6.0f64).unwrap();
-        assert_eq!(two + three, five);
-        assert_eq!(two * three, six);
-        assert_eq!(six - two, four);
-        assert_eq!(six / two, three);
+    fn test_float_arith() -> Fallible<()> {
+        type Op = fn(Float, Float) -> Fallible<Float>;
+        let f = |v: f64| Float::new(v).unwrap();
+        let expect: Vec<(Op, f64, f64, Option<f64>)> = vec![
+            (Float::checked_add, 2., 3., Some(5.)),
+            (Float::checked_add, f64::MAX, f64::MAX, None),
+            (Float::checked_sub, 6., 2., Some(4.)),
+            (Float::checked_sub, -f64::MAX, f64::MAX, None),
+            (Float::checked_mul, 2., 3., Some(6.)),
+            (Float::checked_mul, f64::MAX, 2., None),
+            (Float::checked_div, 6., 2., Some(3.)),
+            (Float::checked_div, 1., 0., None),
+            (Float::checked_div, 0., 0., None),
+            (Float::checked_rem, 7.5, 2., Some(1.5)),
+            (Float::checked_rem, -7.5, 2., Some(0.5)),
+            (Float::checked_rem, 7.5, -2., Some(-0.5)),
+            (Float::checked_rem, -7.5, -2., Some(-1.5)),
+            (Float::checked_rem, -4., 2., Some(0.)),
+            (Float::checked_rem, 1., 0., None),
+        ];
+        for (op, a, b, result) in expect {
+            match result {
+                Some(r) => assert_eq!(op(f(a), f(b))?, f(r), "{} ? {}", a, b),
+                None => assert!(op(f(a), f(b)).is_err(), "{} ? {}", a, b),
+            }
+        }
+        assert!(f(f64::MAX).checked_neg().is_ok());
+        Ok(())
     }
 }
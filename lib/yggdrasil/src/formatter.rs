@@ -0,0 +1,525 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    error::{Error, Span},
+    tokenizer::{quote_name, Comments, LineTokenizer, Token, TreeTokenizer},
+};
+use failure::Fallible;
+
+const INDENT: &str = "    ";
+
+/// Re-emit a .ygg source in canonical form.
+///
+/// Every line is re-indented by four spaces per level, sigils are placed in
+/// the order `@`, `<>`, `^`, `$`, `!`, `import`, `<-`, operators get a single
+/// space on either side, and dimensions are written in their canonical form.
+/// Comments are kept where they are; runs of blank lines collapse to one.
+///
+/// The output is checked against the input before it is returned: both must
+/// tokenize to the same stream once sigils are put in canonical order, so the
+/// formatted file always builds the same tree as the original.
+pub fn format_source(s: &str) -> Fallible<String> {
+    let sanitized = s.replace('\t', INDENT);
+    let mut lines = Vec::new();
+    let mut indent = vec![0];
+    let mut pending_comments = Vec::new();
+    let mut pending_blank = false;
+    let mut continuing = None;
+    let mut open = 0;
+    let mut comments = Comments::default();
+    for (number, line_raw) in sanitized.lines().enumerate() {
+        let number = number + 1;
+        let (code, comment) = split_comment(line_raw, &mut comments);
+        if code.is_empty() {
+            match comment {
+                Some(c) => pending_comments.push(c.to_owned()),
+                None => {
+                    // A blank line ends any comment block above it.
+                    flush_comments(&mut lines, &mut pending_comments, indent.len() - 1);
+                    pending_blank = !lines.is_empty();
+                }
+            }
+            continue;
+        }
+
+        // Continued lines hang one level in from the line they continue.
+        let level = match continuing {
+            Some(level) => level + 1,
+            None => {
+                indent_level(&mut indent, LineTokenizer::leading_whitespace(code)).map_err(|e| {
+                    Error::annotate_span(
+                        e,
+                        Span {
+                            line: number,
+                            column: 1,
+                        },
+                    )
+                })?
+            }
+        };
+        if pending_blank {
+            lines.push(String::new());
+            pending_blank = false;
+        }
+        flush_comments(&mut lines, &mut pending_comments, level);
+        let mut line = INDENT.repeat(level);
+        let (formatted, continues) = format_line(code, number, &mut open)?;
+        comments.end_line(code, continues);
+        line += &formatted;
+        continuing = match (continues, continuing) {
+            (false, _) => None,
+            (true, Some(level)) => Some(level),
+            (true, None) => Some(level),
+        };
+        if let Some(c) = comment {
+            line += "  ";
+            line += c;
+        }
+        lines.push(line);
+    }
+    if pending_blank && !pending_comments.is_empty() {
+        lines.push(String::new());
+    }
+    flush_comments(&mut lines, &mut pending_comments, 0);
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+
+    require!(
+        canonical_tokens(TreeTokenizer::tokenize(&sanitized)?)
+            == canonical_tokens(TreeTokenizer::tokenize(&out)?),
+        Parse,
+        "format error: formatted output does not match the input"
+    );
+    Ok(out)
+}
+
+// Split a raw line into its code, without trailing whitespace, and any
+// comment. This uses the same rule as the tokenizer, so that #tags stay in
+// the code.
+fn split_comment<'a>(line_raw: &'a str, comments: &mut Comments) -> (&'a str, Option<&'a str>) {
+    match comments.start(line_raw) {
+        Some(offset) => (
+            line_raw[..offset].trim_end(),
+            Some(line_raw[offset..].trim_end()),
+        ),
+        None => (line_raw.trim_end(), None),
+    }
+}
+
+// Comments attach to the next line of code, so take its indentation.
+fn flush_comments(lines: &mut Vec<String>, comments: &mut Vec<String>, level: usize) {
+    for comment in comments.drain(..) {
+        lines.push(INDENT.repeat(level) + &comment);
+    }
+}
+
+// Track indentation the same way the tokenizer does, returning the level of
+// the current line.
+fn indent_level(indent: &mut Vec<usize>, current: usize) -> Fallible<usize> {
+    let last = *indent.last().expect("indent stack is never empty");
+    if current > last {
+        indent.push(current);
+    } else if current < last {
+        match indent.binary_search(&current) {
+            Ok(offset) => indent.truncate(offset + 1),
+            Err(_) => raise!(Tokenize, "dedent not aligned with a prior indent level"),
+        }
+    }
+    Ok(indent.len() - 1)
+}
+
+// Format one line of code, the given line of the source, tracking how many
+// brackets are open, and return whether the line continues onto the next, as
+// it does in the tokenizer.
+fn format_line(code: &str, number: usize, open: &mut usize) -> Fallible<(String, bool)> {
+    let (tokens, explicit) = LineTokenizer::tokenize_line(code, number)?;
+    let tokens = tokens.into_iter().map(|t| t.token).collect::<Vec<_>>();
+    for token in &tokens {
+        match token {
+            Token::LeftParen | Token::LeftBracket => *open += 1,
+            Token::RightParen | Token::RightBracket => *open = open.saturating_sub(1),
+            _ => {}
+        }
+    }
+    let continues = explicit || *open > 0;
+
+    // Templates are not tokenized in a way that we can rebuild, so keep them as written.
+    if tokens.contains(&Token::Template) {
+        return Ok((code.trim().to_owned(), continues));
+    }
+
+    let (prefix, groups) = split_sigils(tokens);
+    let mut parts = Vec::new();
+    if !prefix.is_empty() {
+        parts.push(format_expr(&prefix));
+    }
+    for group in &groups {
+        parts.push(match &group[0] {
+            Token::ComesFromInline => format!("<- {}", format_expr(&group[1..])),
+            // Anything after another sigil, such as the name of an import, stays with it.
+            _ => format_expr(group),
+        });
+    }
+    if explicit {
+        parts.push("\\".to_owned());
+    }
+    Ok((parts.join(" "), continues))
+}
+
+fn sigil_rank(token: &Token) -> Option<usize> {
+    Some(match token {
+        Token::Location(_) => 0,
+        Token::Size(_) => 1,
+        Token::Source(_) => 2,
+        Token::Sink(_) => 3,
+        Token::Tag(_) => 4,
+        Token::UseTemplate(_) => 5,
+        Token::ImportTerm(_) => 6,
+        Token::ComesFromInline | Token::ComesFromBlock => 7,
+        _ => return None,
+    })
+}
+
+// Split one line of tokens into the leading name (or expression, for lines
+// in a script block) and its sigils in canonical order. An inline comes-from
+// takes the rest of the line with it.
+fn split_sigils(tokens: Vec<Token>) -> (Vec<Token>, Vec<Vec<Token>>) {
+    let mut prefix = Vec::new();
+    let mut groups: Vec<Vec<Token>> = Vec::new();
+    for token in tokens {
+        let in_script = groups
+            .last()
+            .map(|g| g[0] == Token::ComesFromInline)
+            .unwrap_or(false);
+        if in_script {
+            groups.last_mut().unwrap().push(token);
+        } else if sigil_rank(&token).is_some() {
+            groups.push(vec![token]);
+        } else if groups.is_empty() {
+            prefix.push(token);
+        } else {
+            // Stray tokens after a sigil are a parse error; keep them in place.
+            groups.last_mut().unwrap().push(token);
+        }
+    }
+    groups.sort_by_key(|g| sigil_rank(&g[0]));
+    (prefix, groups)
+}
+
+// Put the sigils on every line in canonical order so that token streams can
+// be compared without regard to sigil order, which the parser ignores.
+fn canonical_tokens(tokens: Vec<Token>) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut line = Vec::new();
+    for token in tokens {
+        match token {
+            Token::Newline | Token::Indent | Token::Dedent => {
+                let (prefix, groups) = split_sigils(std::mem::take(&mut line));
+                out.extend(prefix);
+                out.extend(groups.into_iter().flatten());
+                out.push(token);
+            }
+            t => line.push(t),
+        }
+    }
+    out.extend(line);
+    out
+}
+
+fn is_operator(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Add
+            | Token::And
+            | Token::Subtract
+            | Token::Divide
+            | Token::Multiply
+            | Token::Modulo
+            | Token::Equals
+            | Token::NotEquals
+            | Token::LessThan
+            | Token::LessThanOrEquals
+            | Token::GreaterThan
+            | Token::GreaterThanOrEquals
+            | Token::Or
+            | Token::Latch
+    )
+}
+
+fn is_keyword(token: &Token) -> bool {
+    matches!(token.maybe_name(), Some("if") | Some("elif") | Some("else"))
+}
+
+// A subtract is a negation when it cannot be the second half of a binary expression.
+fn is_unary(prev: Option<&Token>) -> bool {
+    match prev {
+        None => true,
+        Some(t) => {
+            is_operator(t) || is_keyword(t) || *t == Token::LeftParen || *t == Token::StartOfBlock
+        }
+    }
+}
+
+fn format_expr(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut prev: Option<&Token> = None;
+    let mut prev_unary = false;
+    for token in tokens {
+        let space = match prev {
+            None => false,
+            Some(p) => {
+                if *token == Token::RightParen
+                    || *token == Token::RightBracket
+                    || *token == Token::Comma
+                    || *token == Token::StartOfBlock
+                    || *p == Token::LeftParen
+                    || *p == Token::LeftBracket
+                {
+                    false
+                } else if prev_unary {
+                    // -/path must stay together, but - 1 must not become -1.
+                    !matches!(token, Token::PathTerm(_))
+                } else {
+                    // Calls bind to their name: str(...).
+                    !(*token == Token::LeftParen && p.maybe_name().is_some() && !is_keyword(p))
+                }
+            }
+        };
+        if space {
+            out.push(' ');
+        }
+        out += &format_token(token);
+        prev_unary = *token == Token::Subtract && is_unary(prev);
+        prev = Some(token);
+    }
+    out
+}
+
+pub(crate) fn format_token(token: &Token) -> String {
+    match token {
+        Token::Newline => "\n".to_owned(),
+        Token::Indent | Token::Dedent | Token::Template => "".to_owned(),
+        Token::StartOfBlock => ":".to_owned(),
+        Token::Location(dim) => format!("@{}", dim),
+        Token::Size(dim) => format!("<>{}", dim),
+        Token::Source(s) => format!("^{}", s),
+        Token::Sink(s) => format!("${}", s),
+        Token::ComesFromInline => "<-".to_owned(),
+        Token::ComesFromBlock => "<-\\".to_owned(),
+        Token::UseTemplate(s) => format!("!{}", s),
+        Token::Tag(s) => format!("#{}", s),
+        Token::Add => "+".to_owned(),
+        Token::And => "&&".to_owned(),
+        Token::Subtract => "-".to_owned(),
+        Token::Divide => "/".to_owned(),
+        Token::Multiply => "*".to_owned(),
+        Token::Modulo => "%".to_owned(),
+        Token::Equals => "==".to_owned(),
+        Token::NotEquals => "!=".to_owned(),
+        Token::LessThan => "<".to_owned(),
+        Token::LessThanOrEquals => "<=".to_owned(),
+        Token::GreaterThan => ">".to_owned(),
+        Token::GreaterThanOrEquals => ">=".to_owned(),
+        Token::Or => "||".to_owned(),
+        Token::Latch => "::".to_owned(),
+        Token::LeftParen => "(".to_owned(),
+        Token::RightParen => ")".to_owned(),
+        Token::LeftBracket => "[".to_owned(),
+        Token::RightBracket => "]".to_owned(),
+        Token::Comma => ",".to_owned(),
+        Token::NameTerm(s) => quote_name(s),
+        Token::StringTerm(s) => format!("\"{}\"", escape_string(s)),
+        Token::IntegerTerm(i) => format!("{}", i),
+        // Debug formatting always includes a decimal point, so we stay a float.
+        Token::FloatTerm(f) => format!("{:?}", f.value),
+        Token::BooleanTerm(b) => format!("{}", b),
+        Token::PathTerm(s) => s.to_owned(),
+        Token::ImportTerm(s) => format!("import({})", s),
+    }
+}
+
+// The inverse of the escapes that the tokenizer reads in strings.
+fn escape_string(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\t' => out += "\\t",
+            c if c.is_control() => out += &format!("\\u{{{:x}}}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::{NodeRef, Tree, TreeBuilder};
+    use std::{fs, path::Path};
+
+    fn assert_same_tree(a: &Tree, b: &Tree) -> Fallible<()> {
+        fn same_node(a: &NodeRef, b: &NodeRef) -> Fallible<()> {
+            assert_eq!(a.path()?, b.path()?);
+            assert_eq!(a.location()?, b.location()?);
+            assert_eq!(a.dimensions()?, b.dimensions()?);
+            assert_eq!(a.maybe_source_kind()?, b.maybe_source_kind()?);
+            assert_eq!(a.maybe_sink_kind()?, b.maybe_sink_kind()?);
+            let mut names = a.child_names()?;
+            let mut other_names = b.child_names()?;
+            names.sort();
+            other_names.sort();
+            assert_eq!(names, other_names);
+            for name in &names {
+                same_node(&a.child(name)?, &b.child(name)?)?;
+            }
+            Ok(())
+        }
+        same_node(&a.root(), &b.root())
+    }
+
+    #[test]
+    fn test_format_sigil_order_and_spacing() -> Fallible<()> {
+        let s = r#"
+a   $hue  #ceiling  @1x1 <-./b+1
+b <-"bar"   # trailing
+c <>2'0"x6" ^src @1.0mx2
+    default<-str(/b)+"x"
+"#;
+        let expect = r#"a @1mx1m $hue #ceiling <- ./b + 1
+b <- "bar"  # trailing
+c @1mx2m <>2'x6" ^src
+    default <- str(/b) + "x"
+"#;
+        assert_eq!(format_source(s)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_error_spans() {
+        for (s, line, column) in &[("a <- 1\nb <- 1 ~ 2\n", 2, 8), ("a\n    b\n  c\n", 3, 1)] {
+            let err = format_source(s).expect_err(s);
+            let span = Error::find(&err).and_then(|e| e.span());
+            assert_eq!(
+                span,
+                Some(Span {
+                    line: *line,
+                    column: *column
+                }),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_indent_and_comments() -> Fallible<()> {
+        let s = r#"
+# header
+
+
+a
+  # about b
+  b <- -/c - -1
+  c <-\
+        if ./a == 1 && (./b < 2.5):
+                "x"
+        else:
+                "y"
+# footer
+"#;
+        let expect = r#"# header
+
+a
+    # about b
+    b <- -/c - -1
+    c <-\
+        if ./a == 1 && (./b < 2.5):
+            "x"
+        else:
+            "y"
+# footer
+"#;
+        assert_eq!(format_source(s)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_tags_and_comments() -> Fallible<()> {
+        let s = r#"
+a $hue   #ceiling <- 1 #note
+b <-\
+    ./a #x
+`c #d` #e
+"#;
+        let expect = r#"a $hue #ceiling <- 1  #note
+b <-\
+    ./a  #x
+`c #d` #e
+"#;
+        assert_eq!(format_source(s)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_strings_and_floats() -> Fallible<()> {
+        let s = "a <- \"say \\\"hi\\\"\" + str(1.) + str(-2.50)\n";
+        let expect = "a <- \"say \\\"hi\\\"\" + str(1.0) + str(-2.5)\n";
+        assert_eq!(format_source(s)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_lists() -> Fallible<()> {
+        let s = "domain <- [ \"up\",\"down\" ,  0 ]\n";
+        let expect = "domain <- [\"up\", \"down\", 0]\n";
+        assert_eq!(format_source(s)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_continued_lines() -> Fallible<()> {
+        let s = "a\n  b <- (1 +\n 2) *\\\n           3\n  c <- [1,\n2]\n";
+        let expect = "a\n    b <- (1 +\n        2) * \\\n        3\n    c <- [1,\n        2]\n";
+        assert_eq!(format_source(s)?, expect);
+        assert_eq!(format_source(expect)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_quoting() -> Fallible<()> {
+        let s = "`living room` <- \"a\\u{1}\\n\" + /`living room`/x + /` a`/b\n`x` <- `true`\n";
+        let expect = "`living room` <- \"a\\u{1}\\n\" + /`living room`/x + /` a`/b\nx <- `true`\n";
+        assert_eq!(format_source(s)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_generators_and_imports() -> Fallible<()> {
+        let s = "for i in range( 1,3 ) :\n  private light-{i}  import( lib.ygg )  as  lib\n";
+        let expect = "for i in range(1, 3):\n    private light-{i} import(lib.ygg) as lib\n";
+        assert_eq!(format_source(s)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_bad_dedent() {
+        assert!(format_source("a\n    b\n  c\n").is_err());
+    }
+
+    #[test]
+    fn test_format_eyrie_round_trip() -> Fallible<()> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../examples/eyrie.ygg");
+        let original = fs::read_to_string(path)?;
+        let formatted = format_source(&original)?;
+        assert_eq!(format_source(&formatted)?, formatted);
+        assert_same_tree(
+            &TreeBuilder::default().build_from_str(&original)?,
+            &TreeBuilder::default().build_from_str(&formatted)?,
+        )
+    }
+}
@@ -0,0 +1,281 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::NativeFunc,
+    script::{Scope, Script, Stmt},
+    tokenizer::Token,
+};
+use failure::Fallible;
+use std::{collections::HashMap, sync::Arc};
+
+/// A pure function defined in a tree file:
+///
+/// ```text
+/// def scale(c, f):
+///     c * f
+/// ```
+///
+/// Its body is written as the block of a `<-\` script. It may read its
+/// parameters by name and nodes by absolute path, and may call any function
+/// that was defined before it, so it can never recurse.
+#[derive(Debug)]
+pub(crate) struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Stmt,
+    // The body as written, to tell whether two definitions are the same.
+    tokens: Vec<Token>,
+}
+
+impl Function {
+    pub fn from_tokens(
+        name: &str,
+        params: Vec<String>,
+        tokens: &[Token],
+        functions: &Functions,
+    ) -> Fallible<Self> {
+        for (i, param) in params.iter().enumerate() {
+            require!(
+                !params[..i].contains(param),
+                Parse,
+                "parameter {} of {} is named twice",
+                param,
+                name
+            );
+        }
+        let scope = Scope::function(functions, &params);
+        let body = Script::stmt_from_tokens("/".to_owned(), tokens, &scope)?;
+        // Check what we can of the body before knowing what it is passed.
+        body.check_types(&vec![None; params.len()])?;
+        Ok(Self {
+            name: name.to_owned(),
+            params,
+            body,
+            tokens: tokens.to_vec(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    pub(super) fn body(&self) -> &Stmt {
+        &self.body
+    }
+
+    fn same_as(&self, other: &Function) -> bool {
+        self.name == other.name && self.params == other.params && self.tokens == other.tokens
+    }
+}
+
+/// Every function that a script may call: those native to the embedding,
+/// and those defined in tree files with `def`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Functions {
+    native: HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+    defined: HashMap<String, Arc<Function>>,
+}
+
+impl Functions {
+    pub fn new(native: HashMap<String, Box<dyn NativeFunc + Send + Sync>>) -> Self {
+        Self {
+            native,
+            defined: HashMap::new(),
+        }
+    }
+
+    // Only the native functions, as seen by a file being imported, which
+    // cannot call what the file importing it defines.
+    pub fn natives_only(&self) -> Functions {
+        Functions::new(self.native.clone())
+    }
+
+    pub fn native(&self, name: &str) -> Option<&(dyn NativeFunc + Send + Sync)> {
+        self.native.get(name).map(|f| f.as_ref())
+    }
+
+    pub fn defined(&self, name: &str) -> Option<&Arc<Function>> {
+        self.defined.get(name)
+    }
+
+    pub fn define(&mut self, function: Arc<Function>) -> Fallible<()> {
+        let name = function.name();
+        require!(
+            !self.native.contains_key(name),
+            Parse,
+            "cannot define {}; it is a native function",
+            name
+        );
+        if let Some(prior) = self.defined.get(name) {
+            // Importing the same file twice defines nothing new.
+            require!(
+                prior.same_as(&function),
+                Parse,
+                "function {} is already defined",
+                name
+            );
+        }
+        self.defined.insert(name.to_owned(), function);
+        Ok(())
+    }
+
+    // Define everything that `other` defines, as when importing a file.
+    pub fn import(&mut self, other: &Functions) -> Fallible<()> {
+        for function in other.defined.values() {
+            self.define(function.to_owned())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::Error,
+        float::Float,
+        path::ConcretePath,
+        tree::{Tree, TreeBuilder},
+        value::Value,
+    };
+    use failure::Fallible;
+    use std::str::FromStr;
+
+    const PALETTE: &str = r#"
+def scale(c, f):
+    c * f
+def brightness(level):
+    if /room/dim:
+        scale(level, /settings/dimmed)
+    else:
+        level
+settings
+    dimmed ^dial
+        default <- 0.5
+"#;
+
+    fn build(s: &str) -> Fallible<Tree> {
+        TreeBuilder::default().build_from_str(&format!("{}{}", PALETTE, s))
+    }
+
+    #[test]
+    fn test_function_call() -> Fallible<()> {
+        let mut tree = build(
+            r#"
+room
+    dim ^switch
+        default <- false
+    light $hue <- brightness(100)
+    half <- scale(2, 0.25) + scale(1, 1)
+"#,
+        )?;
+        assert_eq!(
+            tree.lookup("/room/half")?.compute(&tree)?,
+            Value::from_float(Float::new(1.5)?)
+        );
+        assert_eq!(
+            tree.lookup("/room/half")?
+                .possible_values(&tree, &mut Vec::new())?,
+            Some(vec![Value::from_float(Float::new(1.5)?)])
+        );
+        assert_eq!(
+            tree.lookup("/room/light")?.compute(&tree)?,
+            Value::from_integer(100)
+        );
+        let changes = tree.handle_event(
+            &ConcretePath::from_str("/room/dim")?,
+            Value::from_boolean(true),
+        )?;
+        assert_eq!(
+            changes["hue"],
+            vec![(
+                ConcretePath::from_str("/room/light")?,
+                Value::from_float(Float::new(50.0)?)
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_reads_are_inputs() -> Fallible<()> {
+        let mut tree = build(
+            r#"
+room
+    dim ^switch
+        default <- true
+    light $hue <- brightness(100)
+"#,
+        )?;
+        let changes = tree.handle_event(
+            &ConcretePath::from_str("/settings/dimmed")?,
+            Value::from_float(Float::new(0.25)?),
+        )?;
+        assert_eq!(
+            changes["hue"],
+            vec![(
+                ConcretePath::from_str("/room/light")?,
+                Value::from_float(Float::new(25.0)?)
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_errors() {
+        let cases = [
+            // Wrong number of arguments.
+            ("a <- scale(1)", "scale takes 2 arguments, but was given 1"),
+            // Arguments of the wrong type, found through the body.
+            (
+                "def label(x):\n    str(x)\na <- label([1, 2])\n",
+                "but found a list",
+            ),
+            ("def f(x):\n    x + y\n", "unknown name y"),
+            ("def f(x, x):\n    x\n", "parameter x of f is named twice"),
+            (
+                "def f(x):\n    ./a\n",
+                "paths in functions must be absolute",
+            ),
+            ("def f(x):\n    f(x)\n", "no such function f"),
+            (
+                "def scale(x):\n    x\n",
+                "function scale is already defined",
+            ),
+            (
+                "def str(x):\n    x\n",
+                "cannot define str; it is a native function",
+            ),
+        ];
+        for (s, message) in &cases {
+            let err = build(s).err().expect(s);
+            let found = Error::find_all(&err);
+            assert!(
+                found.iter().any(|e| e.message().contains(message)),
+                "{}: {}",
+                s,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_function_import() -> Fallible<()> {
+        let tree = TreeBuilder::default()
+            .intercept_import("palette.ygg", PALETTE)?
+            .build_from_str(
+                r#"
+import(palette.ygg)
+lib
+    import(palette.ygg)
+a <- scale(3, 4)
+"#,
+            )?;
+        assert_eq!(tree.lookup("/a")?.compute(&tree)?, Value::from_integer(12));
+        assert!(tree.lookup("/lib/settings/dimmed").is_ok());
+        Ok(())
+    }
+}
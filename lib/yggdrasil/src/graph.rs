@@ -1,107 +1,423 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
-use crate::tree::NodeRef;
-use failure::{ensure, Fallible};
-use std::collections::{HashMap, HashSet};
+use crate::tree::{NodeId, NodeRef};
+use failure::Fallible;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+// Sort nodes by their paths, reading each path once.
+fn sort_by_path(nodes: Vec<NodeRef>) -> Fallible<Vec<NodeRef>> {
+    let mut keyed = nodes
+        .into_iter()
+        .map(|node| Ok((node.path()?, node)))
+        .collect::<Fallible<Vec<_>>>()?;
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(keyed.into_iter().map(|(_, node)| node).collect())
+}
 
 /// A simplified graph that we can use to find paths from all inputs to the outputs they affect.
+///
+/// Nodes are interned to dense ids when they are added, and edges are stored
+/// as adjacency lists indexed by id, both forwards and backwards, so a
+/// traversal in either direction is linear in the size of the part of the
+/// graph that it visits.
 pub struct Graph {
-    nodes: HashMap<String, NodeRef>,
-    edges: Vec<Edge>,
-}
+    ids: HashMap<NodeId, usize>,
+    nodes: Vec<NodeRef>,
+    edges: Vec<Vec<usize>>,
+    back_edges: Vec<Vec<usize>>,
+    // Dense ids of removed nodes, to be given to the next nodes added.
+    free: Vec<usize>,
 
-struct Edge {
-    start: String,
-    end: String,
+    // Edges that only exist through some possible value of a {...} lookup.
+    dynamic: HashSet<(usize, usize)>,
 }
 
 impl Graph {
     pub fn new_empty() -> Self {
         Self {
-            nodes: HashMap::new(),
+            ids: HashMap::new(),
+            nodes: Vec::new(),
             edges: Vec::new(),
+            back_edges: Vec::new(),
+            free: Vec::new(),
+            dynamic: HashSet::new(),
         }
     }
 
     pub fn add_node(&mut self, node: &NodeRef) {
-        let path = node.path_str();
-        if self.nodes.contains_key(&path) {
-            return;
-        }
-        self.nodes.insert(path, node.to_owned());
+        self.intern(node);
     }
 
     pub fn add_edge(&mut self, src_node: &NodeRef, tgt_node: &NodeRef) {
-        self.edges.push(Edge {
-            start: src_node.path_str(),
-            end: tgt_node.path_str(),
-        })
-    }
-
-    pub fn invert(mut self) -> Fallible<Self> {
-        let mut next_edges = Vec::new();
-        for edge in self.edges.drain(..) {
-            next_edges.push(Edge {
-                start: edge.end,
-                end: edge.start,
-            });
+        let (start, end) = self.insert_edge(src_node, tgt_node);
+        self.dynamic.remove(&(start, end));
+    }
+
+    // Add an edge that is only taken for some values of a lookup. If the same
+    // edge also exists statically, it stays static.
+    pub fn add_dynamic_edge(&mut self, src_node: &NodeRef, tgt_node: &NodeRef) {
+        if let Some(start) = self.ids.get(&src_node.id()) {
+            if let Some(end) = self.ids.get(&tgt_node.id()) {
+                if self.edges[*start].contains(end) {
+                    return;
+                }
+            }
         }
-        Ok(Self {
-            nodes: self.nodes,
-            edges: next_edges,
-        })
+        let edge = self.insert_edge(src_node, tgt_node);
+        self.dynamic.insert(edge);
     }
 
-    pub fn connected_nodes(&self, from: &NodeRef, to: &[NodeRef]) -> Fallible<Vec<NodeRef>> {
-        let mut found = HashSet::new();
-        let mut visited = HashSet::new();
-        let mut targets = HashMap::new();
-        for node in to.iter() {
-            targets.insert(node.path_str(), node.to_owned());
+    fn insert_edge(&mut self, src_node: &NodeRef, tgt_node: &NodeRef) -> (usize, usize) {
+        let start = self.intern(src_node);
+        let end = self.intern(tgt_node);
+        if !self.edges[start].contains(&end) {
+            self.edges[start].push(end);
+            self.back_edges[end].push(start);
+        }
+        (start, end)
+    }
+
+    pub fn is_dynamic_edge(&self, src_node: &NodeRef, tgt_node: &NodeRef) -> Fallible<bool> {
+        Ok(self
+            .dynamic
+            .contains(&(self.id_of(src_node)?, self.id_of(tgt_node)?)))
+    }
+
+    fn intern(&mut self, node: &NodeRef) -> usize {
+        if let Some(&id) = self.ids.get(&node.id()) {
+            return id;
+        }
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node.to_owned();
+                id
+            }
+            None => {
+                self.nodes.push(node.to_owned());
+                self.edges.push(Vec::new());
+                self.back_edges.push(Vec::new());
+                self.nodes.len() - 1
+            }
+        };
+        self.ids.insert(node.id(), id);
+        id
+    }
+
+    fn id_of(&self, node: &NodeRef) -> Fallible<usize> {
+        let id = self.ids.get(&node.id());
+        require!(
+            id.is_some(),
+            Dataflow,
+            "node {:?} is not in the graph",
+            node
+        );
+        Ok(*id.unwrap())
+    }
+
+    pub fn contains(&self, node: &NodeRef) -> bool {
+        self.ids.contains_key(&node.id())
+    }
+
+    // Drop every edge that ends at one of `targets`, so that their inputs can
+    // be added again after they are relinked.
+    pub fn remove_edges_into(&mut self, targets: &HashSet<NodeId>) {
+        for id in targets {
+            if let Some(&end) = self.ids.get(id) {
+                self.remove_edges_into_id(end);
+            }
         }
-        self._connected_at(from, &targets, &mut visited, &mut found)?;
+    }
+
+    fn remove_edges_into_id(&mut self, end: usize) {
+        for start in mem::take(&mut self.back_edges[end]) {
+            self.edges[start].retain(|&e| e != end);
+            self.dynamic.remove(&(start, end));
+        }
+    }
+
+    // Forget a node that was removed from the tree, along with every edge
+    // into or out of it.
+    pub fn remove_node(&mut self, node: NodeId) {
+        let id = match self.ids.remove(&node) {
+            Some(id) => id,
+            None => return,
+        };
+        self.remove_edges_into_id(id);
+        for end in mem::take(&mut self.edges[id]) {
+            self.back_edges[end].retain(|&s| s != id);
+            self.dynamic.remove(&(id, end));
+        }
+        self.free.push(id);
+    }
+
+    /// The nodes that read `node` directly.
+    pub fn successors(&self, node: &NodeRef) -> Fallible<Vec<NodeRef>> {
+        Ok(self.edges[self.id_of(node)?]
+            .iter()
+            .map(|&end| self.nodes[end].to_owned())
+            .collect())
+    }
+
+    /// Return every node that can be reached by following edges out of `from`,
+    /// not including `from` itself. Results are sorted by path.
+    pub fn reachable_nodes(&self, from: &NodeRef) -> Fallible<Vec<NodeRef>> {
+        self.visited_nodes(from, &self.edges)
+    }
+
+    /// Return every node that `from` can be reached from, not including `from`
+    /// itself: everything it reads, directly or not. Results are sorted by path.
+    pub fn upstream_nodes(&self, from: &NodeRef) -> Fallible<Vec<NodeRef>> {
+        self.visited_nodes(from, &self.back_edges)
+    }
+
+    fn visited_nodes(&self, from: &NodeRef, edges: &[Vec<usize>]) -> Fallible<Vec<NodeRef>> {
+        let start = self.id_of(from)?;
+        let visited = Self::visit_from(start, edges);
+        let out = visited
+            .iter()
+            .enumerate()
+            .filter(|&(id, &seen)| seen && id != start)
+            .map(|(id, _)| self.nodes[id].to_owned())
+            .collect::<Vec<_>>();
+        sort_by_path(out)
+    }
+
+    pub fn connected_nodes(&self, from: &NodeRef, to: &[NodeRef]) -> Fallible<Vec<NodeRef>> {
+        let visited = Self::visit_from(self.id_of(from)?, &self.edges);
         let mut out = Vec::new();
         for node in to.iter() {
-            if found.contains(&node.path_str()) {
-                out.push(node.to_owned());
+            if let Some(&id) = self.ids.get(&node.id()) {
+                if visited[id] {
+                    out.push(node.to_owned());
+                }
             }
         }
         Ok(out)
     }
 
-    fn _connected_at(
-        &self,
-        current: &NodeRef,
-        targets: &HashMap<String, NodeRef>,
-        visited: &mut HashSet<String>,
-        out: &mut HashSet<String>,
-    ) -> Fallible<()> {
-        let current_path = current.path_str();
+    /// Every node that can be reached from `from`, not including `from`
+    /// itself, ordered so that each node comes after all of the reachable
+    /// nodes that it reads.
+    pub fn evaluation_order(&self, from: &NodeRef) -> Fallible<Vec<NodeRef>> {
+        let start = self.id_of(from)?;
+        let mut visited = vec![false; self.nodes.len()];
+        let mut post_order = Vec::new();
+
+        // Iterative depth-first search, emitting each node after its targets.
+        let mut stack = vec![(start, 0)];
+        visited[start] = true;
+        while let Some((id, next_edge)) = stack.pop() {
+            if next_edge < self.edges[id].len() {
+                stack.push((id, next_edge + 1));
+                let target = self.edges[id][next_edge];
+                if !visited[target] {
+                    visited[target] = true;
+                    stack.push((target, 0));
+                }
+            } else {
+                post_order.push(id);
+            }
+        }
+
+        post_order.pop(); // `from` finishes last
+        Ok(post_order
+            .iter()
+            .rev()
+            .map(|&id| self.nodes[id].to_owned())
+            .collect())
+    }
 
-        if visited.contains(&current_path) {
-            return Ok(());
+    /// Find cycles with a depth-first search over the whole graph. Each cycle
+    /// is listed once, following edges, starting from its least path. This
+    /// finds at least one cycle in every strongly connected component that
+    /// has any, but not every distinct cycle through it.
+    pub fn find_cycles(&self) -> Fallible<Vec<Vec<NodeRef>>> {
+        // Visit in path order, so that reports are stable between runs.
+        let mut order = self
+            .ids
+            .values()
+            .map(|&id| Ok((self.nodes[id].path()?, id)))
+            .collect::<Fallible<Vec<_>>>()?;
+        order.sort();
+        let order = order.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+        let mut rank = vec![0; self.nodes.len()];
+        for (i, &id) in order.iter().enumerate() {
+            rank[id] = i;
         }
-        visited.insert(current_path.clone());
+        let sorted_edges = self
+            .edges
+            .iter()
+            .map(|ends| {
+                let mut ends = ends.to_owned();
+                ends.sort_by_key(|&end| rank[end]);
+                ends
+            })
+            .collect::<Vec<_>>();
 
-        if targets.contains_key(&current_path) {
-            out.insert(current_path.clone());
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            New,
+            OnStack,
+            Done,
+        }
+        let mut marks = vec![Mark::New; self.nodes.len()];
+        let mut cycles = Vec::new();
+        for &root in &order {
+            if marks[root] != Mark::New {
+                continue;
+            }
+            let mut stack = vec![(root, 0)];
+            marks[root] = Mark::OnStack;
+            while let Some((id, next_edge)) = stack.pop() {
+                if next_edge == sorted_edges[id].len() {
+                    marks[id] = Mark::Done;
+                    continue;
+                }
+                stack.push((id, next_edge + 1));
+                let target = sorted_edges[id][next_edge];
+                match marks[target] {
+                    Mark::New => {
+                        marks[target] = Mark::OnStack;
+                        stack.push((target, 0));
+                    }
+                    Mark::OnStack => {
+                        let start = stack.iter().position(|&(n, _)| n == target).unwrap();
+                        let mut cycle = stack[start..].iter().map(|&(n, _)| n).collect::<Vec<_>>();
+                        let least = (0..cycle.len()).min_by_key(|&i| rank[cycle[i]]).unwrap();
+                        cycle.rotate_left(least);
+                        cycles.push(cycle);
+                    }
+                    Mark::Done => {}
+                }
+            }
         }
+        cycles.sort_by_key(|cycle| rank[cycle[0]]);
+        Ok(cycles
+            .iter()
+            .map(|cycle| cycle.iter().map(|&id| self.nodes[id].to_owned()).collect())
+            .collect())
+    }
 
-        // Find all targets of current.
-        for edge in &self.edges {
-            if edge.start == current_path {
-                // FIXME: is there a way to check for existance up front? Otherwise we need to propogate this error.
-                ensure!(
-                    self.nodes.contains_key(&edge.end),
-                    "dataflow error: source node {} does not exist",
-                    &edge.end
-                );
-                let next = &self.nodes[&edge.end];
-                self._connected_at(next, targets, visited, out)?;
+    fn visit_from(start: usize, edges: &[Vec<usize>]) -> Vec<bool> {
+        let mut visited = vec![false; edges.len()];
+        let mut stack = vec![start];
+        visited[start] = true;
+        while let Some(id) = stack.pop() {
+            for &next in &edges[id] {
+                if !visited[next] {
+                    visited[next] = true;
+                    stack.push(next);
+                }
             }
         }
+        visited
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::TreeBuilder;
+
+    #[test]
+    fn test_graph_evaluation_order() -> Fallible<()> {
+        // src -> a -> c, src -> b -> c, c -> sink, unrelated -> sink
+        let root = TreeBuilder::empty().root();
+        let node = |name| root.add_child(name).unwrap();
+        let (src, a, b, c, sink, unrelated) = (
+            node("src"),
+            node("a"),
+            node("b"),
+            node("c"),
+            node("sink"),
+            node("unrelated"),
+        );
+        let mut graph = Graph::new_empty();
+        graph.add_edge(&c, &sink);
+        graph.add_edge(&b, &c);
+        graph.add_edge(&src, &a);
+        graph.add_edge(&a, &c);
+        graph.add_edge(&src, &b);
+        graph.add_edge(&unrelated, &sink);
+
+        let order = graph
+            .evaluation_order(&src)?
+            .iter()
+            .map(|n| n.path_str())
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(order.len(), 4);
+        let pos = |p: &str| order.iter().position(|o| o == p).unwrap();
+        assert!(pos("/a") < pos("/c"));
+        assert!(pos("/b") < pos("/c"));
+        assert!(pos("/c") < pos("/sink"));
+
+        assert_eq!(
+            graph.reachable_nodes(&src)?.len(),
+            graph.evaluation_order(&src)?.len()
+        );
+        assert_eq!(graph.connected_nodes(&src, &[sink, unrelated])?.len(), 1);
+        assert!(graph.find_cycles()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_find_cycles() -> Fallible<()> {
+        let root = TreeBuilder::empty().root();
+        let node = |name| root.add_child(name).unwrap();
+        let (a, b, c, d) = (node("a"), node("b"), node("c"), node("d"));
+        let mut graph = Graph::new_empty();
+        graph.add_edge(&c, &b);
+        graph.add_dynamic_edge(&b, &a);
+        graph.add_edge(&a, &c);
+        graph.add_edge(&c, &d);
+        graph.add_dynamic_edge(&a, &c); // already static
+
+        let cycles = graph.find_cycles()?;
+        assert_eq!(cycles.len(), 1);
+        let paths = cycles[0]
+            .iter()
+            .map(|n| n.path_str())
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(paths, vec!["/a", "/c", "/b"]);
+        assert!(graph.is_dynamic_edge(&b, &a)?);
+        assert!(!graph.is_dynamic_edge(&a, &c)?);
+        assert!(!graph.is_dynamic_edge(&a, &b)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_remove() -> Fallible<()> {
+        let root = TreeBuilder::empty().root();
+        let node = |name| root.add_child(name).unwrap();
+        let (a, b, c, d) = (node("a"), node("b"), node("c"), node("d"));
+        let mut graph = Graph::new_empty();
+        graph.add_edge(&a, &b);
+        graph.add_dynamic_edge(&b, &c);
+        graph.add_edge(&c, &a);
+        let paths = |nodes: Vec<NodeRef>| {
+            nodes
+                .iter()
+                .map(|n| n.path_str().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(graph.upstream_nodes(&b)?), vec!["/a", "/c"]);
+
+        graph.remove_edges_into(&[a.id()].iter().copied().collect());
+        assert_eq!(paths(graph.upstream_nodes(&c)?), vec!["/a", "/b"]);
+        assert!(graph.find_cycles()?.is_empty());
 
+        // A removed node's slot goes to the next node, without its edges.
+        graph.remove_node(b.id());
+        assert!(!graph.contains(&b));
+        assert!(graph.upstream_nodes(&c)?.is_empty());
+        graph.add_edge(&d, &a);
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(paths(graph.reachable_nodes(&d)?), vec!["/a"]);
+        assert!(graph.reachable_nodes(&a)?.is_empty());
         Ok(())
     }
 }
@@ -1,19 +1,39 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+#[macro_use]
+mod error;
+
 mod bif;
+mod document;
 mod float;
+mod formatter;
+mod function;
 mod graph;
+mod lint;
 mod parser;
 mod path;
 mod physical;
+mod program;
+mod query;
+mod scenario;
 mod script;
+#[cfg(feature = "serde")]
+mod serialize;
+mod stdlib;
 mod tokenizer;
 mod tree;
 mod value;
 
-pub use self::bif::NativeFunc;
+pub use self::bif::{NativeFunc, Signature};
+pub use self::document::Document;
+pub use self::error::{Error, ErrorContext, Errors, Span};
 pub use self::float::Float;
+pub use self::formatter::format_source;
+pub use self::lint::{Lint, LintKind, Linter};
 pub use self::path::ConcretePath;
-pub use self::tree::{Tree, TreeBuilder};
-pub use self::value::Value;
+pub use self::query::Query;
+pub use self::scenario::Scenario;
+pub use self::stdlib::STD_VERSION;
+pub use self::tree::{Node, NodeId, NodeRef, Tree, TreeBuilder};
+pub use self::value::{Value, ValueType};
@@ -0,0 +1,426 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    path::ConcretePath,
+    tree::{NodeRef, Tree},
+};
+use failure::{Error, Fallible};
+use std::{collections::HashSet, fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum LintKind {
+    // A script whose value never reaches any sink.
+    UnreadScript,
+    // A source that is not connected to any sink.
+    DisconnectedSource,
+    // A source without a `default` child; computing it before the first
+    // event arrives is an error.
+    SourceWithoutDefault,
+    // An `if`, `elif`, or `else` arm that no input can select.
+    UnreachableArm,
+    // A {...} lookup that may produce a key with no matching child.
+    UnmatchedLookupKey,
+    // A subtree of constants that nothing reads.
+    UnusedPalette,
+}
+
+impl LintKind {
+    pub fn all() -> &'static [LintKind] {
+        &[
+            LintKind::UnreadScript,
+            LintKind::DisconnectedSource,
+            LintKind::SourceWithoutDefault,
+            LintKind::UnreachableArm,
+            LintKind::UnmatchedLookupKey,
+            LintKind::UnusedPalette,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LintKind::UnreadScript => "unread-script",
+            LintKind::DisconnectedSource => "disconnected-source",
+            LintKind::SourceWithoutDefault => "source-without-default",
+            LintKind::UnreachableArm => "unreachable-arm",
+            LintKind::UnmatchedLookupKey => "unmatched-lookup-key",
+            LintKind::UnusedPalette => "unused-palette",
+        }
+    }
+}
+
+impl FromStr for LintKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for kind in Self::all() {
+            if kind.name() == s {
+                return Ok(*kind);
+            }
+        }
+        raise!(Parse, "unknown lint: {}", s)
+    }
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lint {
+    pub kind: LintKind,
+    pub path: ConcretePath,
+    pub message: String,
+}
+
+impl Lint {
+    fn new(kind: LintKind, path: ConcretePath, message: String) -> Self {
+        Self {
+            kind,
+            path,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} [{}]", self.path, self.message, self.kind)
+    }
+}
+
+/// Checks a built tree for configurations that load fine, but probably do
+/// not do what the author intended.
+#[derive(Default)]
+pub struct Linter {
+    allowed: HashSet<LintKind>,
+}
+
+impl Linter {
+    pub fn allow(mut self, kind: LintKind) -> Self {
+        self.allowed.insert(kind);
+        self
+    }
+
+    pub fn check(&self, tree: &Tree) -> Fallible<Vec<Lint>> {
+        let mut nodes = Vec::new();
+        collect_nodes(&tree.root(), &mut nodes)?;
+        let mut sinks = Vec::new();
+        tree.root().find_all_sinks(&mut sinks)?;
+
+        let mut lints = Vec::new();
+        let palettes = self.check_palettes(tree, &sinks, &mut lints)?;
+        for node in &nodes {
+            if node.is_source()? {
+                self.check_source(tree, node, &sinks, &mut lints)?;
+            }
+            if node.has_script()? {
+                let path = node.path()?;
+                if !palettes.iter().any(|p| is_within(&path, p)) {
+                    self.check_script_is_read(tree, node, &sinks, &mut lints)?;
+                }
+                for arm in node.find_unreachable_arms(tree)? {
+                    self.report(&mut lints, LintKind::UnreachableArm, path.clone(), arm);
+                }
+                let mut unmatched = Vec::new();
+                for lookup in node.dynamic_paths()? {
+                    lookup.find_unmatched_keys(tree, &mut unmatched)?;
+                }
+                for key in unmatched {
+                    self.report(
+                        &mut lints,
+                        LintKind::UnmatchedLookupKey,
+                        path.clone(),
+                        key.to_string(),
+                    );
+                }
+            }
+        }
+        lints.sort_by(|a, b| (&a.path, a.kind).cmp(&(&b.path, b.kind)));
+        lints.dedup();
+        Ok(lints)
+    }
+
+    fn report(&self, lints: &mut Vec<Lint>, kind: LintKind, path: ConcretePath, message: String) {
+        if !self.allowed.contains(&kind) {
+            lints.push(Lint::new(kind, path, message));
+        }
+    }
+
+    fn check_source(
+        &self,
+        tree: &Tree,
+        node: &NodeRef,
+        sinks: &[NodeRef],
+        lints: &mut Vec<Lint>,
+    ) -> Fallible<()> {
+        let kind = node.maybe_source_kind()?.unwrap_or_default();
+        if node.child_at("default")?.is_none() {
+            self.report(
+                lints,
+                LintKind::SourceWithoutDefault,
+                node.path()?,
+                format!(
+                    "source ^{} has no default; reading it before the first event will fail",
+                    kind
+                ),
+            );
+        }
+        if tree.graph().connected_nodes(node, sinks)?.is_empty() {
+            self.report(
+                lints,
+                LintKind::DisconnectedSource,
+                node.path()?,
+                format!("source ^{} is not connected to any sinks", kind),
+            );
+        }
+        Ok(())
+    }
+
+    fn check_script_is_read(
+        &self,
+        tree: &Tree,
+        node: &NodeRef,
+        sinks: &[NodeRef],
+        lints: &mut Vec<Lint>,
+    ) -> Fallible<()> {
+        // Scripts directly under a source or sink configure that device, so are
+        // read by the embedding rather than by the tree.
+        let parent = tree.lookup_path(&node.path()?.parent())?;
+        if parent.is_source()? || parent.maybe_sink_kind()?.is_some() {
+            return Ok(());
+        }
+        if tree.graph().connected_nodes(node, sinks)?.is_empty() {
+            self.report(
+                lints,
+                LintKind::UnreadScript,
+                node.path()?,
+                "script is not read by any sink".to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    // Report the largest subtrees made only of constants that do not reach any
+    // sink. Returns their paths, so that we do not also report each constant.
+    fn check_palettes(
+        &self,
+        tree: &Tree,
+        sinks: &[NodeRef],
+        lints: &mut Vec<Lint>,
+    ) -> Fallible<Vec<ConcretePath>> {
+        let mut unused = Vec::new();
+        let mut pending = tree.root().sorted_children()?;
+        while let Some(node) = pending.pop() {
+            let mut constants = Vec::new();
+            if is_palette(&node, &mut constants)? {
+                let mut read = false;
+                for constant in &constants {
+                    if !tree.graph().connected_nodes(constant, sinks)?.is_empty() {
+                        read = true;
+                        break;
+                    }
+                }
+                if !read {
+                    self.report(
+                        lints,
+                        LintKind::UnusedPalette,
+                        node.path()?,
+                        format!(
+                            "none of the {} values in this palette are read",
+                            constants.len()
+                        ),
+                    );
+                    unused.push(node.path()?);
+                    continue;
+                }
+            }
+            pending.append(&mut node.sorted_children()?);
+        }
+        Ok(unused)
+    }
+}
+
+fn collect_nodes(node: &NodeRef, out: &mut Vec<NodeRef>) -> Fallible<()> {
+    out.push(node.to_owned());
+    for child in node.sorted_children()? {
+        collect_nodes(&child, out)?;
+    }
+    Ok(())
+}
+
+fn is_within(path: &ConcretePath, subtree: &ConcretePath) -> bool {
+    path.starts_with(subtree)
+}
+
+// A palette is a structural node whose leaves are all constants.
+fn is_palette(node: &NodeRef, constants: &mut Vec<NodeRef>) -> Fallible<bool> {
+    if node.has_script()? || node.is_source()? || node.maybe_sink_kind()?.is_some() {
+        return Ok(false);
+    }
+    let children = node.sorted_children()?;
+    if children.is_empty() {
+        return Ok(false);
+    }
+    for child in children {
+        if child.is_constant()? && child.child_names()?.is_empty() {
+            constants.push(child);
+        } else if !is_palette(&child, constants)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::TreeBuilder;
+
+    fn lint(s: &str) -> Fallible<Vec<(LintKind, String)>> {
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        Ok(Linter::default()
+            .check(&tree)?
+            .drain(..)
+            .map(|l| (l.kind, l.path.to_string()))
+            .collect())
+    }
+
+    #[test]
+    fn test_lint_clean() -> Fallible<()> {
+        let s = r#"
+switch ^button
+    default <- "off"
+light $hue <- ./switch
+"#;
+        assert!(lint(s)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_sources() -> Fallible<()> {
+        let s = r#"
+switch ^button
+light $hue <- ./switch
+loose ^button
+    default <- "off"
+"#;
+        assert_eq!(
+            lint(s)?,
+            vec![
+                (LintKind::DisconnectedSource, "/loose".to_owned()),
+                (LintKind::SourceWithoutDefault, "/switch".to_owned()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_unread_script() -> Fallible<()> {
+        let s = r#"
+switch ^button
+    default <- "off"
+    ip <- "10.0.0.1"
+light $hue <- ./switch
+mirror <- ./switch
+"#;
+        assert_eq!(
+            lint(s)?,
+            vec![(LintKind::UnreadScript, "/mirror".to_owned())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_unreachable_arm() -> Fallible<()> {
+        let s = r#"
+mode <- "on"
+switch ^button
+    default <- "off"
+light $hue <-\
+    if ./mode == "off":
+        ./switch
+    elif ./mode == "on":
+        "on"
+    else:
+        "low"
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let lints = Linter::default().check(&tree)?;
+        let arms = lints
+            .iter()
+            .filter(|l| l.kind == LintKind::UnreachableArm)
+            .map(|l| l.message.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            arms,
+            vec![
+                "`if` arm is unreachable: its condition is always false",
+                "`else` arm is unreachable: the condition of arm 1 is always true",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_lookup_keys() -> Fallible<()> {
+        let s = r#"
+palette
+    on <- "bright"
+    off <- "dark"
+semantics
+    0 <- "on"
+    1 <- "off"
+    2 <- "low"
+switch ^button
+    default <- 0
+light $hue <- /palette/{/semantics/{/switch}}
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let lints = Linter::default().check(&tree)?;
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].kind, LintKind::UnmatchedLookupKey);
+        assert_eq!(lints[0].path.to_string(), "/light");
+        assert!(lints[0].message.contains("'low'"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_unused_palette() -> Fallible<()> {
+        let s = r#"
+palette
+    used
+        on <- "bright"
+    unused
+        a
+            on <- "bright"
+        b
+            on <- "dim"
+switch ^button
+    default <- "on"
+light $hue <- /palette/used/{/switch}
+"#;
+        assert_eq!(
+            lint(s)?,
+            vec![(LintKind::UnusedPalette, "/palette/unused".to_owned())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_allow() -> Fallible<()> {
+        let s = r#"
+switch ^button
+light $hue <- ./switch
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let lints = Linter::default()
+            .allow(LintKind::from_str("source-without-default")?)
+            .check(&tree)?;
+        assert!(lints.is_empty());
+        assert!(LintKind::from_str("no-such-lint").is_err());
+        Ok(())
+    }
+}
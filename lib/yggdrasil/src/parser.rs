@@ -2,21 +2,36 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
-    bif::NativeFunc,
+    error::{Error, ErrorContext, Span},
+    function::{Function, Functions},
     script::Script,
+    stdlib,
     tokenizer::{Token, TreeTokenizer},
     tree::{NodeRef, Tree},
+    value::{Value, ValueType},
 };
-use failure::{bail, ensure, format_err, Fallible};
-use std::collections::HashMap;
+use failure::Fallible;
+use std::{collections::HashMap, ops::Range, sync::Arc};
 use tracing::trace;
 
+// The most items that a `for` may repeat its lines for; each copy of the lines
+// is parsed, so a mistyped range would otherwise take all of memory.
+const MAX_FOR_ITEMS: i64 = 10_000;
+
 pub struct TreeParser<'a> {
-    nifs: &'a HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
-    import_interceptors: &'a HashMap<String, Tree>,
+    // The functions that scripts may call, including those defined so far.
+    functions: Functions,
+    import_interceptors: &'a HashMap<String, String>,
+    // The files being imported on the way to this one, outermost first.
+    importing: Vec<String>,
     templates: HashMap<String, NodeRef>,
     tokens: Vec<Token>,
+    // Where in the source each token came from, in step with tokens.
+    spans: Vec<Span>,
     position: usize,
+    // Every error found so far; parsing carries on past each one at the end
+    // of the line or block that it was found in.
+    errors: Vec<failure::Error>,
 }
 
 impl<'a> TreeParser<'a> {
@@ -38,57 +53,417 @@ impl<'a> TreeParser<'a> {
     pub fn from_str(
         tree: Tree,
         s: &str,
-        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
-        import_interceptors: &HashMap<String, Tree>,
+        functions: &Functions,
+        import_interceptors: &HashMap<String, String>,
+    ) -> Fallible<Tree> {
+        Self::from_str_importing(tree, s, functions, import_interceptors, Vec::new())
+    }
+
+    fn from_str_importing(
+        mut tree: Tree,
+        s: &str,
+        functions: &Functions,
+        import_interceptors: &HashMap<String, String>,
+        importing: Vec<String>,
     ) -> Fallible<Tree> {
         let sanitized = s.replace('\t', "    ");
 
         {
-            let tokens = TreeTokenizer::tokenize(&sanitized)?;
+            let (tokens, spans) = TreeTokenizer::tokenize_spanned(&sanitized)?
+                .into_iter()
+                .map(|t| (t.token, t.span))
+                .unzip();
             let mut parser = TreeParser {
-                nifs,
+                functions: functions.to_owned(),
                 import_interceptors,
+                importing,
                 templates: HashMap::new(),
                 tokens,
+                spans,
                 position: 0,
+                errors: Vec::new(),
             };
-            parser.consume_root(&tree.root())?;
+            parser.consume_root(&tree.root());
+            if !parser.errors.is_empty() {
+                return Err(Error::combine(parser.errors));
+            }
+            tree.set_functions(parser.functions);
         }
 
         Ok(tree)
     }
 
-    fn consume_root(&mut self, root: &NodeRef) -> Fallible<()> {
+    fn consume_root(&mut self, root: &NodeRef) {
         while !self.out_of_input() {
-            match self.peek()? {
-                Token::NameTerm(_n) => {
-                    self.consume_tree(root)?;
+            let start = self.position;
+            let result = match &self.tokens[start] {
+                Token::NameTerm(n)
+                    if n == "def"
+                        && matches!(self.tokens.get(start + 1), Some(Token::NameTerm(_))) =>
+                {
+                    self.consume_def()
                 }
+                Token::NameTerm(_) if self.at_generator() => self.consume_generator(),
+                Token::NameTerm(_n) => self.consume_tree(root),
                 Token::ImportTerm(filename) => {
-                    self.do_import(&filename, root)?;
-                    self.pop()?;
-                    ensure!(
-                        self.pop()? == Token::Newline,
-                        "parse error: import must be the last thing in the line"
-                    );
+                    let filename = filename.to_owned();
+                    self.consume_import(&filename, root)
                 }
-                _ => bail!(
-                    "parse error: expected name at top level, not: {:?}",
-                    self.peek()?
+                t => {
+                    let message = format!("expected name at top level, not: {:?}", t);
+                    self.position += 1;
+                    Err(Error::Parse(ErrorContext::new(message)).into())
+                }
+            };
+            self.recover(result, start);
+        }
+    }
+
+    fn consume_import(&mut self, filename: &str, parent: &NodeRef) -> Fallible<()> {
+        self.pop()?;
+        self.do_import(filename, parent)?;
+        require!(
+            self.pop()? == Token::Newline,
+            Parse,
+            "import must be the last thing in the line"
+        );
+        Ok(())
+    }
+
+    // def name(param, ...):
+    //     body
+    fn consume_def(&mut self) -> Fallible<()> {
+        self.pop()?;
+        let name = self.consume_node_name()?;
+        require!(
+            self.pop()? == Token::LeftParen,
+            Parse,
+            "expected ( after def {}",
+            name
+        );
+        let mut params = Vec::new();
+        while self.peek()? != Token::RightParen {
+            match self.pop()? {
+                Token::NameTerm(param) => params.push(param),
+                t => raise!(
+                    Parse,
+                    "expected a parameter name in def {}, not {:?}",
+                    name,
+                    t
                 ),
             }
+            match self.peek()? {
+                Token::Comma => self.position += 1,
+                Token::RightParen => {}
+                t => raise!(Parse, "expected , or ) in def {}, not {:?}", name, t),
+            }
+        }
+        self.pop()?;
+        let start = Script::expect_block(&self.tokens, self.position)?;
+        self.position = start;
+        let end = self.find_next_matching_dedent();
+        let function =
+            Function::from_tokens(&name, params, &self.tokens[start..end], &self.functions)?;
+        self.position = end;
+        self.functions.define(Arc::new(function))
+    }
+
+    // Whether the next line is a `for` or `if` that generates the nodes in its
+    // block when the tree is built, rather than a node of that name.
+    fn at_generator(&self) -> bool {
+        match self.tokens.get(self.position).and_then(Token::maybe_name) {
+            Some("for") | Some("if") => {}
+            _ => return false,
         }
+        let end = self.line_end(self.position);
+        end > self.position + 1 && self.tokens[end - 1] == Token::StartOfBlock
+    }
+
+    // Replace a `for` or `if` and its blocks with the lines that they
+    // generate, which are then parsed in its place.
+    fn consume_generator(&mut self) -> Fallible<()> {
+        let start = self.position;
+        let (tokens, spans, end) = if self.peek()?.maybe_name() == Some("for") {
+            self.expand_for(start)?
+        } else {
+            let (body, end) = self.expand_if(start)?;
+            (
+                self.tokens[body.clone()].to_vec(),
+                self.spans[body].to_vec(),
+                end,
+            )
+        };
+        self.tokens.splice(start..end, tokens);
+        self.spans.splice(start..end, spans);
         Ok(())
     }
 
+    // for name in [item, ...]:
+    //     lines
+    //
+    // The lines are repeated for each item, which may also come from range(n)
+    // or range(start, end). In the lines, a bare `name` in an expression is
+    // the item itself, and {name} in a node name or path is the item as text:
+    // light-{name}. A node that is called `name` keeps its name.
+    fn expand_for(&self, start: usize) -> Fallible<(Vec<Token>, Vec<Span>, usize)> {
+        let header_end = self.line_end(start) - 1;
+        let (name, iterable) = match &self.tokens[start + 1..header_end] {
+            [Token::NameTerm(name), Token::NameTerm(keyword), iterable @ ..]
+                if keyword == "in" && !iterable.is_empty() =>
+            {
+                (name, iterable)
+            }
+            _ => raise!(Parse, "expected for name in [item, ...]:"),
+        };
+        let items = self.for_items(iterable)?;
+        let (body, end) = self.block_at(header_end)?;
+        let mut tokens = Vec::new();
+        let mut spans = Vec::new();
+        for item in &items {
+            let literal = match item.value_type() {
+                Some(ValueType::Boolean) => Token::BooleanTerm(item.as_boolean()?),
+                Some(ValueType::Integer) => Token::IntegerTerm(item.as_integer()?),
+                Some(ValueType::Float) => Token::FloatTerm(item.as_float()?),
+                Some(ValueType::String) => Token::StringTerm(item.as_string()?),
+                _ => raise!(
+                    Type,
+                    "for {} can only take booleans, numbers and strings, not {}",
+                    name,
+                    item
+                ),
+            };
+            let pattern = format!("{{{}}}", name);
+            // How deep the lines are nested, and whether the token is in a
+            // script: to the end of the line after <-, or to the end of the
+            // block after <-\, which ends when it dedents to the given depth.
+            let mut depth = 0isize;
+            let mut script = None;
+            for i in body.clone() {
+                let line_start = i == body.start
+                    || matches!(
+                        self.tokens[i - 1],
+                        Token::Newline | Token::Indent | Token::Dedent
+                    );
+                let in_name = line_start && script.is_none();
+                match &self.tokens[i] {
+                    Token::ComesFromInline if script.is_none() => script = Some(None),
+                    Token::ComesFromBlock if script.is_none() => script = Some(Some(depth)),
+                    Token::Newline if script == Some(None) => script = None,
+                    Token::Indent => depth += 1,
+                    Token::Dedent => {
+                        depth -= 1;
+                        if script == Some(Some(depth)) {
+                            script = None;
+                        }
+                    }
+                    _ => {}
+                }
+                tokens.push(match &self.tokens[i] {
+                    Token::NameTerm(s) if s == name && !in_name => literal.clone(),
+                    Token::NameTerm(s) if s.contains(&pattern) => {
+                        Token::NameTerm(s.replace(&pattern, &item.as_path_component()?))
+                    }
+                    Token::PathTerm(s) if s.contains(&pattern) => {
+                        Token::PathTerm(s.replace(&pattern, &item.as_path_component()?))
+                    }
+                    t => t.to_owned(),
+                });
+                spans.push(self.spans[i]);
+            }
+            // A block at the end of the input is not closed, but each copy
+            // of it must be.
+            let open = self.tokens[body.clone()].iter().fold(0, |open, t| match t {
+                Token::Indent => open + 1,
+                Token::Dedent => open - 1,
+                _ => open,
+            });
+            for _ in 0..open {
+                tokens.push(Token::Dedent);
+                spans.push(self.spans[body.end - 1]);
+            }
+        }
+        Ok((tokens, spans, end))
+    }
+
+    fn for_items(&self, iterable: &[Token]) -> Fallible<Vec<Value>> {
+        if let [Token::NameTerm(range), Token::LeftParen, args @ .., Token::RightParen] = iterable {
+            if range == "range" {
+                let mut list = vec![Token::LeftBracket];
+                list.extend_from_slice(args);
+                list.push(Token::RightBracket);
+                let bounds = Script::constant_from_tokens(&list, &self.functions)?
+                    .as_list()?
+                    .iter()
+                    .map(Value::as_integer)
+                    .collect::<Fallible<Vec<_>>>()?;
+                let (lo, hi) = match bounds[..] {
+                    [hi] => (0, hi),
+                    [lo, hi] => (lo, hi),
+                    _ => raise!(Parse, "range takes an end, or a start and an end"),
+                };
+                require!(
+                    hi.saturating_sub(lo) <= MAX_FOR_ITEMS,
+                    Parse,
+                    "range({}, {}) has more than {} items",
+                    lo,
+                    hi,
+                    MAX_FOR_ITEMS
+                );
+                return Ok((lo..hi).map(Value::from_integer).collect());
+            }
+        }
+        let items = Script::constant_from_tokens(iterable, &self.functions)?;
+        require!(
+            items.is_list(),
+            Type,
+            "for takes a list or a range, not {}",
+            items
+        );
+        Ok(items.as_list()?.to_vec())
+    }
+
+    // if condition:
+    //     lines
+    // elif condition:
+    //     lines
+    // else:
+    //     lines
+    //
+    // Only the lines under the first condition that holds are kept, or those
+    // under the else, if any. Conditions are known before the tree is built,
+    // such as the item of a `for`.
+    fn expand_if(&self, start: usize) -> Fallible<(Range<usize>, usize)> {
+        let mut chosen = None;
+        let mut offset = start;
+        let mut keyword = "if";
+        loop {
+            let header_end = self.line_end(offset) - 1;
+            let holds = match keyword {
+                "else" => {
+                    require!(
+                        header_end == offset + 1,
+                        Parse,
+                        "expected a colon directly after else"
+                    );
+                    true
+                }
+                _ => {
+                    let condition = Script::constant_from_tokens(
+                        &self.tokens[offset + 1..header_end],
+                        &self.functions,
+                    )?;
+                    require!(
+                        condition.is_boolean(),
+                        Type,
+                        "the condition of {} must be a boolean, not {}",
+                        keyword,
+                        condition
+                    );
+                    condition.as_boolean()?
+                }
+            };
+            let (body, end) = self.block_at(header_end)?;
+            if holds && chosen.is_none() {
+                chosen = Some(body);
+            }
+            offset = end;
+            keyword = match self.tokens.get(offset).and_then(Token::maybe_name) {
+                Some("elif") if keyword != "else" => "elif",
+                Some("else") if keyword != "else" => "else",
+                _ => break,
+            };
+            if self.tokens[self.line_end(offset) - 1] != Token::StartOfBlock {
+                break;
+            }
+        }
+        Ok((chosen.unwrap_or(offset..offset), offset))
+    }
+
+    // The lines of the block opened by the colon at `colon`, without the
+    // indent and dedent around them, and the end of the block.
+    fn block_at(&self, colon: usize) -> Fallible<(Range<usize>, usize)> {
+        let start = Script::expect_block(&self.tokens, colon)?;
+        let end = start + Self::find_matching_dedent(&self.tokens[start..]);
+        let body_end = match self.tokens.get(end - 1) {
+            Some(Token::Dedent) => end - 1,
+            _ => end,
+        };
+        Ok((start..body_end, end))
+    }
+
+    // The offset of the newline that ends the line at `start`.
+    fn line_end(&self, start: usize) -> usize {
+        let mut end = start;
+        while end < self.tokens.len() && self.tokens[end] != Token::Newline {
+            end += 1;
+        }
+        end
+    }
+
+    // As consume_tree, but on failure, record the error and skip the rest of
+    // the tree so that parsing can carry on with its next sibling.
+    fn consume_tree_or_recover(&mut self, parent: &NodeRef) {
+        let start = self.position;
+        let result = self.consume_tree(parent);
+        self.recover(result, start);
+    }
+
+    // If the line or block starting at `start` failed to parse, record where
+    // and why, then skip to the end of it.
+    fn recover(&mut self, result: Fallible<()>, start: usize) {
+        let err = match result {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        let err = match self.last_span() {
+            Some(span) => Error::annotate_span(err, span),
+            None => err,
+        };
+        self.errors.push(err);
+
+        // Skip to the end of the line, and then past anything nested under it.
+        let mut end = start;
+        if self.tokens.get(end) != Some(&Token::Indent) {
+            while end < self.tokens.len() && self.tokens[end] != Token::Newline {
+                end += 1;
+            }
+            end += 1;
+        }
+        if self.tokens.get(end) == Some(&Token::Indent) {
+            end += 1 + Self::find_matching_dedent(&self.tokens[end + 1..]);
+        }
+        while self.tokens.get(end) == Some(&Token::Newline) {
+            end += 1;
+        }
+        self.position = end.max(self.position).min(self.tokens.len());
+    }
+
     fn consume_tree(&mut self, parent: &NodeRef) -> Fallible<()> {
+        // private name ...
+        let private = self.tokens.get(self.position).and_then(Token::maybe_name) == Some("private")
+            && matches!(
+                self.tokens.get(self.position + 1),
+                Some(Token::NameTerm(_))
+                    | Some(Token::BooleanTerm(_))
+                    | Some(Token::IntegerTerm(_))
+            );
+        if private {
+            self.pop()?;
+        }
+        let span = self.spans.get(self.position).copied();
         let name = self.consume_node_name()?;
         trace!(
             "Consuming tree at: {} under parent: {}",
             name,
-            parent.name()
+            parent.name()?
         );
         let child = parent.add_child(&name)?;
+        if let Some(span) = span {
+            child.set_span(span)?;
+        }
+        if private {
+            child.set_private()?;
+        }
         self.consume_inline_suite(&child)?;
         if self.out_of_input() || self.peek()? != Token::Indent {
             trace!("finished tree {}", name);
@@ -100,17 +475,28 @@ impl<'a> TreeParser<'a> {
         self.consume_block_suite(&child)?;
         while !self.out_of_input() {
             match self.peek()? {
-                Token::NameTerm(ref _s) => self.consume_tree(&child)?,
-                Token::BooleanTerm(ref _b) => self.consume_tree(&child)?,
-                Token::IntegerTerm(ref _i) => self.consume_tree(&child)?,
+                Token::NameTerm(_) if self.at_generator() => {
+                    let start = self.position;
+                    let result = self.consume_generator();
+                    self.recover(result, start);
+                }
+                Token::NameTerm(ref _s) => self.consume_tree_or_recover(&child),
+                Token::BooleanTerm(ref _b) => self.consume_tree_or_recover(&child),
+                Token::IntegerTerm(ref _i) => self.consume_tree_or_recover(&child),
                 Token::Dedent => {
                     self.pop()?;
                     return Ok(());
                 }
-                _ => bail!(
-                    "parse error: unexpected token after child block: {:?}",
-                    self.peek()?
-                ),
+                t => {
+                    let start = self.position;
+                    let result = Err(Error::Parse(ErrorContext::new(format!(
+                        "unexpected token after child block: {:?}",
+                        t
+                    )))
+                    .into());
+                    self.position += 1;
+                    self.recover(result, start);
+                }
             };
         }
         Ok(())
@@ -144,13 +530,11 @@ impl<'a> TreeParser<'a> {
                 Token::BooleanTerm(_v) => return Ok(()),
                 Token::IntegerTerm(_i) => return Ok(()),
                 Token::Dedent => return Ok(()),
-                Token::Indent => bail!("parse error: expected a sigil before another indent"),
+                Token::Indent => raise!(Parse, "expected a sigil before another indent"),
                 _ => {
-                    self.consume_sigil(node)?;
-                    ensure!(
-                        self.pop()? == Token::Newline,
-                        "parse error: expected a newline after every block sigil"
-                    );
+                    let start = self.position;
+                    let result = self.consume_block_sigil(node);
+                    self.recover(result, start);
                 }
             }
         }
@@ -158,6 +542,18 @@ impl<'a> TreeParser<'a> {
         Ok(())
     }
 
+    fn consume_block_sigil(&mut self, node: &NodeRef) -> Fallible<()> {
+        let path = node.path()?;
+        self.consume_sigil(node)
+            .map_err(|e| Error::annotate_path(e, path))?;
+        require!(
+            self.pop()? == Token::Newline,
+            Parse,
+            "expected a newline after every block sigil"
+        );
+        Ok(())
+    }
+
     fn find_next_token(&self, tok: &Token) -> Fallible<usize> {
         let mut i = self.position;
         while i < self.tokens.len() {
@@ -166,7 +562,7 @@ impl<'a> TreeParser<'a> {
             }
             i += 1;
         }
-        bail!("Did not find a matching token for: {:?}", tok)
+        raise!(Parse, "did not find a matching token for: {:?}", tok)
     }
 
     fn find_next_matching_dedent(&self) -> usize {
@@ -197,81 +593,158 @@ impl<'a> TreeParser<'a> {
             Token::Size(dim) => node.set_dimensions(dim)?,
             Token::Source(ref s) => node.set_source(s)?,
             Token::Sink(ref s) => node.set_sink(s)?,
+            Token::Tag(ref s) => node.add_tag(s)?,
             Token::ComesFromInline => {
                 let end = self.find_next_token(&Token::Newline)?;
                 let s = Script::inline_from_tokens(
-                    node.path_str(),
+                    node.path_str()?,
                     &self.tokens[self.position..end],
-                    self.nifs,
+                    &self.functions,
                 )?;
                 self.position = end;
                 node.set_script(s)?
             }
             Token::ComesFromBlock => {
-                ensure!(self.pop()? == Token::Newline, "expected newline after <-\\");
-                ensure!(self.pop()? == Token::Indent, "expected indent after <-\\");
+                require!(
+                    self.pop()? == Token::Newline,
+                    Parse,
+                    "expected newline after <-\\"
+                );
+                require!(
+                    self.pop()? == Token::Indent,
+                    Parse,
+                    "expected indent after <-\\"
+                );
                 let end = self.find_next_matching_dedent();
                 let block_tokens = &self.tokens[self.position..end];
                 trace!("comes-from-block tokens: {:?}", block_tokens);
-                let s = Script::block_from_tokens(node.path_str(), block_tokens, self.nifs)?;
+                let s = Script::block_from_tokens(node.path_str()?, block_tokens, &self.functions)?;
                 self.position = end;
                 // Since this is parsed as a sigil, we expect to end with a newline, but since
                 // we were indented the Dedent happened after the closing Newline, so inject
                 // an extra one here.
                 self.tokens.insert(self.position, Token::Newline);
+                let span = self.spans[self.position - 1];
+                self.spans.insert(self.position, span);
                 node.set_script(s)?
             }
             Token::ImportTerm(filename) => self.do_import(&filename, node)?,
             Token::UseTemplate(ref s) => {
-                let template: &NodeRef = self
-                    .templates
-                    .get(s)
-                    .ok_or_else(|| format_err!("parse error: unknown template: {}", s))?;
+                let template: &NodeRef = match self.templates.get(s) {
+                    Some(template) => template,
+                    None => raise!(Parse, "unknown template: {}", s),
+                };
                 node.apply_template(template)?
             }
-            _ => bail!("parse error: expected to find a sigil-delimited token"),
+            _ => raise!(Parse, "expected to find a sigil-delimited token"),
         }
         Ok(())
     }
 
+    // import(file)
+    // import(file) as name
+    //
+    // Without a name, the nodes of the file are merged into `parent`. With
+    // one, they are mounted under a new node of that name. Either way, the
+    // file is parsed where its nodes land, so its relative paths are relative
+    // to them, and it is a module of its own, so only its nodes may read those
+    // of them that are private. Files not supplied by the builder may come
+    // from the standard library, as import(std/room.ygg).
     fn do_import(&mut self, filename: &str, parent: &NodeRef) -> Fallible<()> {
-        if let Some(subtree) = self.import_interceptors.get(filename) {
-            return parent.insert_subtree(&subtree.root());
+        require!(
+            !self.importing.iter().any(|f| f == filename),
+            Parse,
+            "{} imports itself, through {}",
+            filename,
+            self.importing.join(", ")
+        );
+        let source = match self.import_interceptors.get(filename) {
+            Some(source) => source.as_str(),
+            None => match stdlib::module(filename)? {
+                Some(source) => source,
+                None => raise!(Parse, "would import {} from file", filename),
+            },
+        };
+        let mut mount = parent.to_owned();
+        if self.tokens.get(self.position).and_then(Token::maybe_name) == Some("as") {
+            self.pop()?;
+            let span = self.spans.get(self.position).copied();
+            let name = match self.pop()? {
+                Token::NameTerm(name) => name,
+                t => raise!(
+                    Parse,
+                    "expected a name after import({}) as, not {:?}",
+                    filename,
+                    t
+                ),
+            };
+            mount = parent.add_child(&name)?;
+            if let Some(span) = span {
+                mount.set_span(span)?;
+            }
         }
-        bail!("would import {} from file", filename)
+        let mut importing = self.importing.clone();
+        importing.push(filename.to_owned());
+        let subtree = Self::from_str_importing(
+            Tree::new_at(mount.path()?),
+            source,
+            &self.functions.natives_only(),
+            self.import_interceptors,
+            importing,
+        )?;
+        self.functions.import(subtree.functions())?;
+        mount.import_subtree(&subtree.root())
     }
 
     fn consume_node_name(&mut self) -> Fallible<String> {
-        ensure!(
+        require!(
             !self.out_of_input(),
-            "parse error: no tokens to consume when looking for name"
+            Parse,
+            "no tokens to consume when looking for name"
         );
         Ok(match self.pop()? {
-            Token::NameTerm(s) => s,
+            Token::NameTerm(s) => {
+                require!(
+                    !s.contains(['{', '}']),
+                    Parse,
+                    "{} has braces that are not the {{name}} of an enclosing for",
+                    s
+                );
+                s
+            }
             Token::BooleanTerm(b) => {
                 let v = if b { "true" } else { "false" };
                 v.to_owned()
             }
             Token::IntegerTerm(i) => format!("{}", i),
-            _ => bail!("parse error: did not find a name in expected position"),
+            _ => raise!(Parse, "did not find a name in expected position"),
         })
     }
 
+    // The span of the last token consumed, which is where an error was found.
+    fn last_span(&self) -> Option<Span> {
+        self.spans
+            .get(self.position.saturating_sub(1))
+            .or_else(|| self.spans.last())
+            .copied()
+    }
+
     fn out_of_input(&self) -> bool {
         self.position >= self.tokens.len()
     }
 
     fn pop(&mut self) -> Fallible<Token> {
-        ensure!(!self.out_of_input(), "parse error: no tokens to pop");
+        require!(!self.out_of_input(), Parse, "no tokens to pop");
         let out = self.tokens[self.position].clone();
         self.position += 1;
         Ok(out)
     }
 
     fn peek(&self) -> Fallible<Token> {
-        ensure!(
+        require!(
             self.position < self.tokens.len(),
-            "parse error: enexpected end of input"
+            Parse,
+            "enexpected end of input"
         );
         Ok(self.tokens[self.position].clone())
     }
@@ -295,15 +768,15 @@ mod test {
     #[test]
     fn test_parse_minimal() -> Fallible<()> {
         let tree = TreeBuilder::default().build_from_str("a")?;
-        assert!(tree.lookup("/a")?.location().is_none());
+        assert!(tree.lookup("/a")?.location()?.is_none());
         Ok(())
     }
 
     #[test]
     fn test_parse_siblings() -> Fallible<()> {
         let tree = TreeBuilder::default().build_from_str("a\nb")?;
-        assert!(tree.lookup("/a")?.location().is_none());
-        assert!(tree.lookup("/b")?.location().is_none());
+        assert!(tree.lookup("/a")?.location()?.is_none());
+        assert!(tree.lookup("/b")?.location()?.is_none());
         Ok(())
     }
 
@@ -315,15 +788,15 @@ a @1x1
 c @3x3";
         let tree = TreeBuilder::default().build_from_str(s)?;
         assert_eq!(
-            tree.lookup("/a")?.location().unwrap(),
+            tree.lookup("/a")?.location()?.unwrap(),
             Dimension2::from_str("1x1")?
         );
         assert_eq!(
-            tree.lookup("/a/b")?.location().unwrap(),
+            tree.lookup("/a/b")?.location()?.unwrap(),
             Dimension2::from_str("2x2")?
         );
         assert_eq!(
-            tree.lookup("/c")?.location().unwrap(),
+            tree.lookup("/c")?.location()?.unwrap(),
             Dimension2::from_str("3x3")?
         );
         Ok(())
@@ -337,15 +810,15 @@ a @1x1
     c @3x3";
         let tree = TreeBuilder::default().build_from_str(s)?;
         assert_eq!(
-            tree.lookup("/a")?.location().unwrap(),
+            tree.lookup("/a")?.location()?.unwrap(),
             Dimension2::from_str("1x1")?
         );
         assert_eq!(
-            tree.lookup("/a/b")?.location().unwrap(),
+            tree.lookup("/a/b")?.location()?.unwrap(),
             Dimension2::from_str("2x2")?
         );
         assert_eq!(
-            tree.lookup("/a/c")?.location().unwrap(),
+            tree.lookup("/a/c")?.location()?.unwrap(),
             Dimension2::from_str("3x3")?
         );
         Ok(())
@@ -362,20 +835,20 @@ c @3x3
 "#;
         let tree = TreeBuilder::default().build_from_str(s)?;
         assert_eq!(
-            tree.lookup("/a")?.location().unwrap(),
+            tree.lookup("/a")?.location()?.unwrap(),
             Dimension2::from_str("1x1")?
         );
         assert_eq!(
-            tree.lookup("/a")?.dimensions().unwrap(),
+            tree.lookup("/a")?.dimensions()?.unwrap(),
             Dimension2::from_str("1x1")?
         );
         assert_eq!(tree.lookup("/a")?.compute(&tree)?, Value::new_str("foo"));
         assert_eq!(
-            tree.lookup("/a/b")?.location().unwrap(),
+            tree.lookup("/a/b")?.location()?.unwrap(),
             Dimension2::from_str("2x2")?
         );
         assert_eq!(
-            tree.lookup("/c")?.location().unwrap(),
+            tree.lookup("/c")?.location()?.unwrap(),
             Dimension2::from_str("3x3")?
         );
         assert_eq!(tree.lookup("/c")?.compute(&tree)?, Value::new_str("bar"));
@@ -391,19 +864,19 @@ a @1x1
 d @4x4";
         let tree = TreeBuilder::default().build_from_str(s)?;
         assert_eq!(
-            tree.lookup("/a")?.location().unwrap(),
+            tree.lookup("/a")?.location()?.unwrap(),
             Dimension2::from_str("1x1")?
         );
         assert_eq!(
-            tree.lookup("/a/b")?.location().unwrap(),
+            tree.lookup("/a/b")?.location()?.unwrap(),
             Dimension2::from_str("2x2")?
         );
         assert_eq!(
-            tree.lookup("/a/b/c")?.location().unwrap(),
+            tree.lookup("/a/b/c")?.location()?.unwrap(),
             Dimension2::from_str("3x3")?
         );
         assert_eq!(
-            tree.lookup("/d")?.location().unwrap(),
+            tree.lookup("/d")?.location()?.unwrap(),
             Dimension2::from_str("4x4")?
         );
         Ok(())
@@ -423,11 +896,11 @@ d @4x4";
     // ";
     //         let tree = TreeBuilder::default().build_from_str(s)?;
     //         assert_eq!(
-    //             tree.lookup("/a")?.location().unwrap(),
+    //             tree.lookup("/a")?.location()?.unwrap(),
     //             Dimension2::from_str("1x1")?
     //         );
     //         assert_eq!(
-    //             tree.lookup("/b")?.location().unwrap(),
+    //             tree.lookup("/b")?.location()?.unwrap(),
     //             Dimension2::from_str("2x2")?
     //         );
     //     }
@@ -438,7 +911,7 @@ d @4x4";
         TreeParser::from_str(
             TreeBuilder::empty(),
             "a b",
-            &HashMap::new(),
+            &Functions::default(),
             &HashMap::new(),
         )
         .unwrap();
@@ -619,6 +1092,131 @@ bar <-/foo/true + " " + /foo/false
         Ok(())
     }
 
+    #[test]
+    fn test_parse_quoted_names() -> Fallible<()> {
+        let s = r#"
+rooms
+    `living room`
+        color <- "red"
+    `true` <- 1
+a <- /rooms/`living room`/color + str(/rooms/`true`)
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(tree.lookup("/a")?.compute(&tree)?, Value::new_str("red1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_continued_lines() -> Fallible<()> {
+        let s = r#"
+a
+    b <- (./c +
+        ./c) * \
+        2
+    c <- (1 +
+  2)
+    d <- ["x",
+        "y"]
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/a/b")?.compute(&tree)?,
+            Value::from_integer(12)
+        );
+        assert_eq!(
+            tree.lookup("/a/d")?.compute(&tree)?,
+            Value::from_list(vec![Value::new_str("x"), Value::new_str("y")])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_error_span() {
+        let s = "a\n    b <- 1\n    c <- \\\n        -\n";
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        let err = Error::find(&err).unwrap();
+        assert_eq!(err.span(), Some(Span { line: 4, column: 9 }));
+    }
+
+    #[test]
+    fn test_parse_reports_every_error() {
+        let s = r#"
+a <- 1 +
+b
+    c <- (1
+    d ^src
+    e <- ,
+f <-\
+    if 1:
+        2
+g <- 2
+"#;
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        let lines = Error::find_all(&err)
+            .iter()
+            .map(|e| e.span().unwrap().line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![4]);
+
+        let s = s.replace("(1", "(1)");
+        let err = TreeBuilder::default().build_from_str(&s).err().unwrap();
+        let errs = Error::find_all(&err);
+        let lines = errs
+            .iter()
+            .map(|e| e.span().unwrap().line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![2, 6, 8]);
+        assert!(errs.iter().all(|e| matches!(e, Error::Parse(_))));
+        assert_eq!(err.to_string().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_parse_is_total() {
+        // Break a source that uses every construct in every way that we can
+        // think of; none of them may panic.
+        let s = r#"
+template t
+    x <- 1
+a @1'x2" <>3mx4 ^src $sink
+    domain <- [1, 2.5, "s\n", /b/c]
+    t <- !t
+    v <- -(./w + 1) * 2 / 3 % 4 :: "x" == str(./w) && true || false
+    w <- \
+        1 != 2
+    `q r` <-\
+        if ./w >= 1 && ./w <= 2:
+            /a/{/a/w}/`q r`
+        elif ./w < 0 || ./w > 5:
+            "low"
+        else:
+            "high"
+b
+    c <- 1
+"#;
+        let chars = s.chars().collect::<Vec<_>>();
+        let noise = [
+            '(', ')', '[', ']', '{', '}', '`', '"', '\\', '\n', ' ', '/', '-', '<', '@',
+        ];
+        let mut inputs = Vec::new();
+        for i in 0..=chars.len() {
+            inputs.push(chars[..i].iter().collect::<String>());
+            if i < chars.len() {
+                let mut deleted = chars.clone();
+                deleted.remove(i);
+                inputs.push(deleted.iter().collect());
+            }
+            for c in &noise {
+                let mut inserted = chars.clone();
+                inserted.insert(i, *c);
+                inputs.push(inserted.iter().collect());
+            }
+        }
+        for input in &inputs {
+            let _ = TreeBuilder::default().build_from_str(input);
+            let _ = crate::format_source(input);
+        }
+    }
+
     #[test]
     fn test_multiline_comesfrom() -> Fallible<()> {
         let s = r#"
@@ -733,4 +1331,213 @@ quux <-\
     //             ValueType::INTEGER
     //         );
     //     }
+
+    #[test]
+    fn test_parse_for() -> Fallible<()> {
+        let s = r#"
+kitchen
+    for spot in ["sink", "stove"]:
+        light-{spot} <- ./level-{spot}
+        level-{spot} <- spot
+    for i in range(1, 4):
+        switch-{i}
+            on <- i * 10
+            if i > 2:
+                bright <- true
+            else:
+                dim <- i
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let value = |path: &str| tree.lookup(path)?.compute(&tree);
+        assert_eq!(value("/kitchen/light-sink")?, Value::new_str("sink"));
+        assert_eq!(value("/kitchen/light-stove")?, Value::new_str("stove"));
+        assert_eq!(value("/kitchen/switch-1/on")?, Value::from_integer(10));
+        assert_eq!(value("/kitchen/switch-3/on")?, Value::from_integer(30));
+        assert_eq!(value("/kitchen/switch-1/dim")?, Value::from_integer(1));
+        assert_eq!(
+            value("/kitchen/switch-3/bright")?,
+            Value::from_boolean(true)
+        );
+        assert!(tree.lookup("/kitchen/switch-4").is_err());
+        assert!(tree.lookup("/kitchen/switch-3/dim").is_err());
+
+        // A node named for the item keeps its name; in scripts, also those in
+        // blocks, the name is still the item.
+        let s = r#"
+for i in [7]:
+    n-{i}
+        i <- 2
+        j <- i + 1
+        k <-\
+            if i > 2:
+                i * 2
+            else:
+                0
+    m-{i} <- ./n-{i}/i
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let value = |path: &str| tree.lookup(path)?.compute(&tree);
+        assert_eq!(value("/n-7/i")?, Value::from_integer(2));
+        assert_eq!(value("/n-7/j")?, Value::from_integer(8));
+        assert_eq!(value("/n-7/k")?, Value::from_integer(14));
+        assert_eq!(value("/m-7")?, Value::from_integer(2));
+        assert!(tree.lookup("/n-7/7").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_if() -> Fallible<()> {
+        let s = r#"
+for n in [1, 2, 3]:
+    if n == 1:
+        one
+    elif n == 2:
+        two
+    else:
+        other-{n}
+if false:
+    never
+for <- 1
+if
+    else <- 2
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        for path in &["/one", "/two", "/other-3", "/for", "/if/else"] {
+            assert!(tree.lookup(path).is_ok(), "{}", path);
+        }
+        assert!(tree.lookup("/never").is_err());
+        assert!(tree.lookup("/other-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_generator_errors() {
+        let cases = [
+            (
+                "a <- 1\nfor x in /a:\n    b\n",
+                "is not known until the tree is built",
+            ),
+            (
+                "for x in 3:\n    b\n",
+                "for takes a list or a range, not 3i64",
+            ),
+            ("for x in [[1]]:\n    b-{x}\n", "can only take booleans"),
+            ("for x [1]:\n    b\n", "expected for name in"),
+            ("if 1:\n    b\n", "the condition of if must be a boolean"),
+            ("for x in range(1, 2, 3):\n    b\n", "range takes an end"),
+            (
+                "for x in range(-1, 10000):\n    b\n",
+                "has more than 10000 items",
+            ),
+            ("light-{x} <- 1\n", "braces that are not the {name}"),
+            (
+                "for x in [1]:\n    a-{y} <- x\n",
+                "braces that are not the {name}",
+            ),
+        ];
+        for (s, message) in &cases {
+            let err = TreeBuilder::default().build_from_str(s).err().expect(s);
+            assert!(
+                Error::find_all(&err)
+                    .iter()
+                    .any(|e| e.message().contains(message)),
+                "{}: {}",
+                s,
+                err
+            );
+        }
+    }
+
+    const LIGHTING: &str = r#"
+private level <- 40
+lamp <- ./level + 1
+shade <- ./lamp * 2
+"#;
+
+    fn with_lighting(s: &str) -> Fallible<Tree> {
+        TreeBuilder::default()
+            .intercept_import("lighting.ygg", LIGHTING)?
+            .build_from_str(s)
+    }
+
+    #[test]
+    fn test_parse_import_as() -> Fallible<()> {
+        let tree = with_lighting(
+            r#"
+import(lighting.ygg) as lighting
+porch import(lighting.ygg) as lights
+private base <- 1
+top <- /base + /lighting/lamp
+"#,
+        )?;
+        let value = |path: &str| tree.lookup(path)?.compute(&tree);
+        assert_eq!(value("/lighting/lamp")?, Value::from_integer(41));
+        assert_eq!(value("/porch/lights/shade")?, Value::from_integer(82));
+        assert_eq!(value("/top")?, Value::from_integer(42));
+        assert!(tree.lookup("/lighting/level")?.is_private()?);
+        assert!(tree.lookup("/lamp").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_import_paths() -> Fallible<()> {
+        // The file is parsed where it is mounted: relative paths resolve from
+        // there, and absolute paths name nodes of the importing tree.
+        let tree = TreeBuilder::default()
+            .intercept_import("dimmer.ygg", "scale <- 3\nlevel <- /master * ./scale\n")?
+            .build_from_str("master <- 2\nhall import(dimmer.ygg) as dimmer\n")?;
+        assert_eq!(
+            tree.lookup("/hall/dimmer/level")?.compute(&tree)?,
+            Value::from_integer(6)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_import_cycle() -> Fallible<()> {
+        let err = TreeBuilder::default()
+            .intercept_import("a.ygg", "import(b.ygg)\n")?
+            .intercept_import("b.ygg", "import(a.ygg)\n")?
+            .build_from_str("import(a.ygg)\n")
+            .err()
+            .expect("a cycle of imports");
+        assert!(Error::find_all(&err)
+            .iter()
+            .any(|e| e.message() == "a.ygg imports itself, through a.ygg, b.ygg"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_module_errors() {
+        let cases = [
+            (
+                "import(lighting.ygg) as lighting\npeek <- /lighting/level\n",
+                "reads /lighting/level, which is private to the file it is in",
+            ),
+            (
+                "import(lighting.ygg) as a\na <- 1\n",
+                "there is already a node at /a",
+            ),
+            (
+                "import(lighting.ygg)\nimport(lighting.ygg)\n",
+                "there is already a node at /lamp",
+            ),
+            ("a <- 1\na <- 2\n", "there is already a node at /a"),
+            (
+                "import(lighting.ygg) as \"x\"\n",
+                "expected a name after import(lighting.ygg) as",
+            ),
+        ];
+        for (s, message) in &cases {
+            let err = with_lighting(s).err().expect(s);
+            assert!(
+                Error::find_all(&err)
+                    .iter()
+                    .any(|e| e.message().contains(message)),
+                "{}: {}",
+                s,
+                err
+            );
+        }
+    }
 }
@@ -1,104 +1,166 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
-use crate::tree::Tree;
-use failure::{bail, ensure, Error, Fallible};
-use std::{fmt, ops::Div, str::FromStr};
+use crate::{tokenizer::quote_path_name, tree::Tree, value::Value};
+use failure::{Error, Fallible};
+use lazy_static::lazy_static;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Div,
+    ptr,
+    str::FromStr,
+    sync::{Arc, Mutex, Weak},
+};
 use tracing::trace;
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum PathComponent {
     Name(String),
     Lookup(ScriptPath),
+    // Every child, written *.
+    Glob,
 }
 
 impl fmt::Display for PathComponent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            PathComponent::Name(name) => write!(f, "{}", name),
+            PathComponent::Name(name) => write!(f, "{}", quote_path_name(name)),
             PathComponent::Lookup(script_path) => write!(f, "{{{}}}", script_path),
+            PathComponent::Glob => write!(f, "*"),
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+/// A path as written in a script, which may look up parts of itself in the
+/// tree. Its leading names are kept as an interned ConcretePath, so that a
+/// path with no lookups compares, hashes and resolves as cheaply as one.
+#[derive(Clone, Debug)]
 pub struct ScriptPath {
-    pub components: Vec<PathComponent>,
+    components: Vec<PathComponent>,
+    prefix: ConcretePath,
     dynamic: bool,
 }
 
+impl PartialEq for ScriptPath {
+    fn eq(&self, other: &ScriptPath) -> bool {
+        let n = self.prefix.depth();
+        self.prefix == other.prefix && self.components[n..] == other.components[n..]
+    }
+}
+
+impl Eq for ScriptPath {}
+
+impl Hash for ScriptPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.prefix.hash(state);
+        self.components[self.prefix.depth()..].hash(state);
+    }
+}
+
 impl ScriptPath {
     pub fn from_str_at_path(base_path: &str, s: &str) -> Fallible<Self> {
-        assert!(base_path.starts_with('/'));
+        require!(
+            base_path.starts_with('/'),
+            Parse,
+            "invalid path: base path '{}' must start at /",
+            base_path
+        );
 
         let (start, mut components) = if s.starts_with('/') {
             (1, Vec::new())
         } else {
-            let mut comps = (&base_path[1..])
+            let mut comps = base_path[1..]
                 .split('/')
                 .map(|c| PathComponent::Name(c.to_owned()))
                 .collect::<Vec<PathComponent>>();
             comps.pop();
             (0, comps)
         };
-        let dynamic = Self::parse_parts(&mut components, base_path, &s[start..])?;
-        Ok(ScriptPath {
+        Self::parse_parts(&mut components, base_path, &s[start..])?;
+        Ok(Self::from_components(components))
+    }
+
+    pub fn from_components(components: Vec<PathComponent>) -> Self {
+        let mut prefix = ConcretePath::new_root();
+        for component in &components {
+            match component {
+                PathComponent::Name(name) => prefix = prefix.new_child(name),
+                PathComponent::Lookup(_) | PathComponent::Glob => break,
+            }
+        }
+        let dynamic = prefix.depth() < components.len();
+        ScriptPath {
             components,
+            prefix,
             dynamic,
-        })
+        }
     }
 
-    fn parse_parts(
-        components: &mut Vec<PathComponent>,
-        base_path: &str,
-        s: &str,
-    ) -> Fallible<bool> {
-        let mut dynamic = false;
-        let parts = Self::tokenize_path(s)?;
-        for part in &parts {
-            if Self::parse_part(components, base_path, part)? {
-                dynamic = true;
-            }
+    pub fn components(&self) -> &[PathComponent] {
+        &self.components
+    }
+
+    fn parse_parts(components: &mut Vec<PathComponent>, base_path: &str, s: &str) -> Fallible<()> {
+        for part in &Self::tokenize_path(s)? {
+            Self::parse_part(components, base_path, part)?;
         }
-        Ok(dynamic)
+        Ok(())
     }
 
     fn parse_part(
         components: &mut Vec<PathComponent>,
         base_path: &str,
         part: &str,
-    ) -> Fallible<bool> {
+    ) -> Fallible<()> {
         match part {
-            "" => bail!(
-                "parse error: empty path component under '{}' in '{:?}'",
+            "" => raise!(
+                Parse,
+                "empty path component under '{}' in '{:?}'",
                 base_path,
                 components
             ),
-            "." => Ok(false),
+            "." => Ok(()),
+            "*" => {
+                components.push(PathComponent::Glob);
+                Ok(())
+            }
             ".." => {
-                ensure!(
+                require!(
                     !components.is_empty(),
-                    "parse error: looked up parent dir (..) past start of path at '{}' in '{:?}'",
+                    Parse,
+                    "looked up parent dir (..) past start of path at '{}' in '{:?}'",
                     base_path,
                     components
                 );
                 components.pop();
-                Ok(false)
+                Ok(())
             }
             s => {
-                if s.starts_with('{') && s.ends_with('}') {
-                    let c = PathComponent::Lookup(Self::from_str_at_path(
-                        base_path,
-                        &s[1..s.len() - 1],
-                    )?);
-                    components.push(c);
-                    Ok(true)
+                if s.len() > 2 && s.starts_with('`') && s.ends_with('`') {
+                    let name = &s[1..s.len() - 1];
+                    require!(!name.contains('`'), Parse, "found ` in quoted name");
+                    components.push(PathComponent::Name(name.to_owned()));
+                    Ok(())
+                } else if s.starts_with('{') && s.ends_with('}') {
+                    let inner = Self::from_str_at_path(base_path, &s[1..s.len() - 1])?;
+                    require!(
+                        !inner.is_glob(),
+                        Parse,
+                        "a lookup must read a single node, not the glob {}",
+                        inner
+                    );
+                    components.push(PathComponent::Lookup(inner));
+                    Ok(())
                 } else {
-                    ensure!(!s.contains('{'), "parse error: found { in path part");
-                    ensure!(!s.contains('}'), "parse error: found } in path part");
+                    require!(!s.contains('`'), Parse, "found ` in path part");
+                    require!(!s.contains('{'), Parse, "found {{ in path part");
+                    require!(!s.contains('}'), Parse, "found }} in path part");
                     let c = PathComponent::Name(s.to_owned());
                     components.push(c);
-                    Ok(false)
+                    Ok(())
                 }
             }
         }
@@ -106,33 +168,29 @@ impl ScriptPath {
 
     fn tokenize_path(s: &str) -> Fallible<Vec<String>> {
         let mut brace_depth = 0;
-        let mut part_start = 0;
-        let mut offset = 0;
+        let mut quoted = false;
+        let mut part = String::new();
         let mut parts = Vec::new();
         for c in s.chars() {
             match c {
-                '/' => {
-                    if brace_depth == 0 {
-                        parts.push(s[part_start..offset].chars().collect::<String>());
-                        part_start = offset + 1;
-                    }
+                '`' => quoted = !quoted,
+                '/' if brace_depth == 0 && !quoted => {
+                    parts.push(std::mem::take(&mut part));
+                    continue;
                 }
-                '{' => {
+                '{' if !quoted => {
                     brace_depth += 1;
                 }
-                '}' => {
+                '}' if !quoted => {
                     brace_depth -= 1;
                 }
                 _ => {}
             }
-            offset += 1;
+            part.push(c);
         }
-        ensure!(
-            brace_depth == 0,
-            "parse error: mismatched braces in path '{}'",
-            s
-        );
-        parts.push(s[part_start..offset].chars().collect::<String>());
+        require!(!quoted, Parse, "unmatched ` in path '{}'", s);
+        require!(brace_depth == 0, Parse, "mismatched braces in path '{}'", s);
+        parts.push(part);
         Ok(parts)
     }
 
@@ -140,27 +198,29 @@ impl ScriptPath {
         !self.dynamic
     }
 
-    pub fn as_concrete(&self) -> ConcretePath {
-        let mut concrete = Vec::new();
-        for component in &self.components {
-            match component {
-                PathComponent::Name(name) => concrete.push(name.clone()),
-                PathComponent::Lookup(_) => {
-                    panic!("path error: dynamic is set, but lookups in path")
-                }
-            }
-        }
-        ConcretePath::from_components(concrete)
+    // Whether the path has a * in it, and so names any number of nodes.
+    pub fn is_glob(&self) -> bool {
+        self.components.contains(&PathComponent::Glob)
+    }
+
+    pub fn as_concrete(&self) -> Fallible<ConcretePath> {
+        require!(!self.dynamic, Parse, "path is not concrete: {}", self);
+        Ok(self.prefix.clone())
+    }
+
+    // The leading names of the path, up to its first lookup or glob.
+    pub fn static_prefix(&self) -> ConcretePath {
+        self.prefix.clone()
     }
 
     pub fn find_concrete_inputs(&self, inputs: &mut Vec<ConcretePath>) -> Fallible<()> {
         if self.is_concrete() {
-            inputs.push(self.as_concrete());
+            inputs.push(self.as_concrete()?);
             return Ok(());
         }
         for component in &self.components {
             match component {
-                PathComponent::Name(_) => {}
+                PathComponent::Name(_) | PathComponent::Glob => {}
                 PathComponent::Lookup(path) => {
                     path.find_concrete_inputs(inputs)?;
                 }
@@ -172,11 +232,24 @@ impl ScriptPath {
     // All inputs to a path must ultimately have a constrained domain, either
     // because they come from constants or from a switch or button. This lets us
     // use virtual interpretation of all intermediate scripts to get a set of
-    // possible values, even if large.
+    // possible values, even if large. Where the values of a lookup are not
+    // knowable, e.g. it reads a source without a declared domain, we fall back
+    // to every child of the node being looked up in. A glob matches every
+    // child that exists now.
     pub fn devirtualize(&self, tree: &Tree) -> Fallible<Vec<ConcretePath>> {
+        self.devirtualize_within(tree, &mut Vec::new())
+    }
+
+    // As devirtualize, but while already computing the possible values of the
+    // nodes in `visiting`.
+    pub(crate) fn devirtualize_within(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+    ) -> Fallible<Vec<ConcretePath>> {
         if self.is_concrete() {
             trace!("Path::devirtualize(concrete: {})", self);
-            return Ok(vec![self.as_concrete()]);
+            return Ok(vec![self.as_concrete()?]);
         }
         trace!("Path::devirtualize(dynamic: {})", self);
         let mut working_set = Vec::new();
@@ -186,8 +259,11 @@ impl ScriptPath {
                     // Append to all in-progress path fragments.
                     working_set = Self::explode_paths_1(working_set, name);
                 }
-                PathComponent::Lookup(_script_path) => {
-                    working_set = Self::explode_paths_2(working_set, tree)?;
+                PathComponent::Lookup(script_path) => {
+                    working_set = Self::explode_paths_2(working_set, script_path, tree, visiting)?;
+                }
+                PathComponent::Glob => {
+                    working_set = Self::explode_paths_3(working_set, tree)?;
                 }
             }
             trace!(
@@ -200,27 +276,115 @@ impl ScriptPath {
         Ok(working_set)
     }
 
+    // Walk the path the same way devirtualization does, but where we know the
+    // domain of a lookup, check that every key in it names a child.
+    pub fn find_unmatched_keys(&self, tree: &Tree, out: &mut Vec<UnmatchedKey>) -> Fallible<()> {
+        let mut bases = vec![tree.root()];
+        for component in &self.components {
+            let mut next = Vec::new();
+            match component {
+                PathComponent::Name(name) => {
+                    for base in &bases {
+                        next.extend(base.child_at(name)?);
+                    }
+                }
+                PathComponent::Glob => {
+                    for base in &bases {
+                        next.extend(base.sorted_children()?);
+                    }
+                }
+                PathComponent::Lookup(inner) => {
+                    inner.find_unmatched_keys(tree, out)?;
+                    let domain = Value::from_path(inner.to_owned())
+                        .possible_values(tree, &mut Vec::new())?;
+                    for base in &bases {
+                        let keys = match domain {
+                            Some(ref keys) => keys,
+                            None => {
+                                next.extend(base.sorted_children()?);
+                                continue;
+                            }
+                        };
+                        for key in keys {
+                            let child = match key.as_path_component() {
+                                Ok(name) => base.child_at(&name)?,
+                                Err(_) => None,
+                            };
+                            match child {
+                                Some(child) => next.push(child),
+                                None => out.push(UnmatchedKey {
+                                    lookup: inner.to_owned(),
+                                    base: base.path()?,
+                                    key: key.to_owned(),
+                                }),
+                            }
+                        }
+                    }
+                }
+            }
+            bases = next;
+        }
+        Ok(())
+    }
+
     fn explode_paths_1(mut paths: Vec<ConcretePath>, name: &str) -> Vec<ConcretePath> {
         if paths.is_empty() {
-            paths.push(ConcretePath::from_components(vec![name.to_owned()]));
+            paths.push(ConcretePath::new_root().new_child(name));
         } else {
             for concrete in &mut paths {
-                concrete.components.push(name.to_owned());
+                *concrete = concrete.new_child(name);
             }
         }
         paths
     }
 
-    fn explode_paths_2(mut paths: Vec<ConcretePath>, tree: &Tree) -> Fallible<Vec<ConcretePath>> {
+    fn explode_paths_2(
+        mut paths: Vec<ConcretePath>,
+        lookup: &ScriptPath,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+    ) -> Fallible<Vec<ConcretePath>> {
         if paths.is_empty() {
-            paths.push(ConcretePath::from_components(vec![]));
+            paths.push(ConcretePath::new_root());
         }
+        let keys = Value::from_path(lookup.to_owned())
+            .possible_values(tree, visiting)?
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.as_path_component().ok())
+                    .collect::<Vec<String>>()
+            });
         let mut next_working_set = Vec::new();
         for base_path in &paths {
-            let noderef = tree.lookup_path(base_path)?;
-            for child_name in noderef.child_names() {
-                let extended_path = base_path.new_child(&child_name);
-                next_working_set.push(extended_path);
+            // Bases that do not exist come from keys that will fail at runtime.
+            let noderef = match tree.lookup_path(base_path) {
+                Ok(noderef) => noderef,
+                Err(_) => continue,
+            };
+            let names = match keys {
+                Some(ref keys) => keys.to_owned(),
+                None => noderef.child_names()?,
+            };
+            for child_name in names {
+                if noderef.child_at(&child_name)?.is_some() {
+                    next_working_set.push(base_path.new_child(&child_name));
+                }
+            }
+        }
+        Ok(next_working_set)
+    }
+
+    fn explode_paths_3(mut paths: Vec<ConcretePath>, tree: &Tree) -> Fallible<Vec<ConcretePath>> {
+        if paths.is_empty() {
+            paths.push(ConcretePath::new_root());
+        }
+        let mut next_working_set = Vec::new();
+        for base_path in &paths {
+            if let Ok(noderef) = tree.lookup_path(base_path) {
+                for child_name in noderef.child_names()? {
+                    next_working_set.push(base_path.new_child(&child_name));
+                }
             }
         }
         Ok(next_working_set)
@@ -239,67 +403,222 @@ impl fmt::Display for ScriptPath {
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct ConcretePath {
-    pub components: Vec<String>,
+/// A key that a dynamic lookup may produce, but which does not name any child
+/// of the node that it is looked up in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnmatchedKey {
+    pub lookup: ScriptPath,
+    pub base: ConcretePath,
+    pub key: Value,
+}
+
+impl fmt::Display for UnmatchedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.key.as_path_component() {
+            Ok(name) => write!(
+                f,
+                "lookup {{{}}} may produce '{}', but {} has no child named '{}'",
+                self.lookup, name, self.base, name
+            ),
+            Err(_) => write!(
+                f,
+                "lookup {{{}}} may produce {}, which cannot name a child",
+                self.lookup, self.key
+            ),
+        }
+    }
+}
+
+/// An absolute path to a node. Paths are interned: every ConcretePath naming
+/// the same node shares one entry, which links to its parent and is freed with
+/// the last handle to it. So a path hashes and compares by identity, finds its
+/// parent and children without copying any names, and is cheap to clone and
+/// to send between threads. Use Display and FromStr to convert to and from
+/// text at the edges.
+#[derive(Clone)]
+pub struct ConcretePath(Arc<PathEntry>);
+
+struct PathEntry {
+    parent: Option<ConcretePath>,
+    name: Box<str>,
+    depth: usize,
+    // The children of this path that are still in use, so that asking for one
+    // again finds the same entry. An entry takes itself out when it is freed.
+    children: Mutex<HashMap<Box<str>, Weak<PathEntry>>>,
+}
+
+impl Drop for PathEntry {
+    fn drop(&mut self) {
+        let parent = match self.parent {
+            Some(ref parent) => parent,
+            None => return,
+        };
+        if let Ok(mut children) = parent.0.children.lock() {
+            // The name may already have gone to a new entry, if it was asked
+            // for while this one was being freed.
+            if children
+                .get(&self.name)
+                .is_some_and(|w| w.strong_count() == 0)
+            {
+                children.remove(&self.name);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref ROOT: ConcretePath = ConcretePath(Arc::new(PathEntry {
+        parent: None,
+        name: "".into(),
+        depth: 0,
+        children: Mutex::new(HashMap::new()),
+    }));
+}
+
+impl PartialEq for ConcretePath {
+    fn eq(&self, other: &ConcretePath) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ConcretePath {}
+
+impl Hash for ConcretePath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        ptr::hash(Arc::as_ptr(&self.0), state)
+    }
+}
+
+// Order by components, so that sorting paths is stable across runs.
+impl Ord for ConcretePath {
+    fn cmp(&self, other: &ConcretePath) -> Ordering {
+        // Climb to the same depth; if the paths meet there, one is a prefix of
+        // the other. Otherwise climb until the next step up would meet, and
+        // compare the names that differ.
+        let (mut a, mut b) = (self, other);
+        while a.depth() > b.depth() {
+            a = a.parent_ref();
+        }
+        while b.depth() > a.depth() {
+            b = b.parent_ref();
+        }
+        if a == b {
+            return self.depth().cmp(&other.depth());
+        }
+        while a.parent_ref() != b.parent_ref() {
+            a = a.parent_ref();
+            b = b.parent_ref();
+        }
+        a.basename().cmp(b.basename())
+    }
+}
+
+impl PartialOrd for ConcretePath {
+    fn partial_cmp(&self, other: &ConcretePath) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Debug for ConcretePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConcretePath({})", self)
+    }
 }
 
 impl FromStr for ConcretePath {
     type Err = Error;
 
     fn from_str(path: &str) -> Result<Self, Self::Err> {
-        ensure!(
+        require!(
             path.starts_with('/'),
+            Parse,
             "invalid path: tree lookups must start at /"
         );
         let relative: &str = &path[1..];
+        let mut concrete = Self::new_root();
         if relative.is_empty() {
-            return Ok(Self::new_root());
+            return Ok(concrete);
         }
-        let mut components = Vec::new();
         for part in relative.split('/') {
-            ensure!(!part.is_empty(), "invalid path: empty path component");
-            components.push(part.to_owned());
+            require!(
+                !part.is_empty(),
+                Parse,
+                "invalid path: empty path component"
+            );
+            concrete = concrete.new_child(part);
         }
-        Ok(Self::from_components(components))
+        Ok(concrete)
     }
 }
 
 impl ConcretePath {
-    fn from_components(components: Vec<String>) -> Self {
-        Self { components }
+    pub fn new_root() -> Self {
+        ROOT.clone()
     }
 
-    pub fn new_root() -> Self {
-        Self {
-            components: Vec::new(),
+    pub fn new_child(&self, name: &str) -> Self {
+        let mut children = self.0.children.lock().unwrap();
+        if let Some(child) = children.get(name).and_then(Weak::upgrade) {
+            return ConcretePath(child);
         }
+        let child = Arc::new(PathEntry {
+            parent: Some(self.clone()),
+            name: name.into(),
+            depth: self.depth() + 1,
+            children: Mutex::new(HashMap::new()),
+        });
+        children.insert(name.into(), Arc::downgrade(&child));
+        ConcretePath(child)
+    }
+
+    // The names from the root down to this path.
+    pub fn components(&self) -> Vec<&str> {
+        let mut components = Vec::with_capacity(self.depth());
+        let mut at = self;
+        while let Some(ref parent) = at.0.parent {
+            components.push(at.basename());
+            at = parent;
+        }
+        components.reverse();
+        components
     }
 
-    pub fn new_child(&self, name: &str) -> Self {
-        let mut components = self.components.clone();
-        components.push(name.to_owned());
-        Self { components }
+    pub fn depth(&self) -> usize {
+        self.0.depth
     }
 
     pub fn basename(&self) -> &str {
-        if self.components.is_empty() {
-            return "";
-        }
-        &self.components[self.components.len() - 1]
+        &self.0.name
     }
 
     pub fn parent(&self) -> ConcretePath {
-        if self.components.len() <= 1 {
-            return ConcretePath::new_root();
+        self.parent_ref().clone()
+    }
+
+    // The root is its own parent.
+    fn parent_ref(&self) -> &ConcretePath {
+        match self.0.parent {
+            Some(ref parent) => parent,
+            None => self,
+        }
+    }
+
+    pub fn starts_with(&self, prefix: &ConcretePath) -> bool {
+        let mut at = self;
+        while at.depth() > prefix.depth() {
+            at = at.parent_ref();
         }
-        ConcretePath::from_components(self.components[0..self.components.len() - 1].to_owned())
+        at == prefix
     }
 }
 
 impl fmt::Display for ConcretePath {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "/{}", self.components.join("/"))
+        match self.0.parent {
+            None => write!(f, "/"),
+            Some(ref parent) if parent.depth() == 0 => write!(f, "/{}", self.basename()),
+            Some(ref parent) => write!(f, "{}/{}", parent, self.basename()),
+        }
     }
 }
 
@@ -323,6 +642,39 @@ impl Div<&str> for &ConcretePath {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_concrete_path_interning() -> Fallible<()> {
+        let a = ConcretePath::from_str("/a/b")?;
+        assert!(Arc::ptr_eq(&a.0, &(ConcretePath::new_root() / "a" / "b").0));
+        assert_eq!(a, a.parent() / "b");
+        assert_ne!(a, ConcretePath::from_str("/a/c")?);
+        assert!(a < ConcretePath::from_str("/a/c")?);
+        assert!(a > ConcretePath::from_str("/a")?);
+        assert!(a < ConcretePath::from_str("/a/b/a")?);
+        assert!(a > ConcretePath::from_str("/a/a/z")?);
+        assert!(a.starts_with(&a.parent()));
+        assert!(!a.starts_with(&ConcretePath::from_str("/a/c")?));
+        assert_eq!(a.components(), vec!["a", "b"]);
+        assert_eq!(a.to_string(), "/a/b");
+        assert_eq!(a.basename(), "b");
+        assert_eq!(ConcretePath::from_str("/")?, ConcretePath::new_root());
+        assert_eq!(ConcretePath::new_root().parent(), ConcretePath::new_root());
+
+        // A path is freed with its last handle, and named afresh after that.
+        let parent = ConcretePath::from_str("/interning")?;
+        let in_use = |name: &str| parent.0.children.lock().unwrap().contains_key(name);
+        let child = parent.new_child("freed");
+        assert!(in_use("freed"));
+        drop(child);
+        assert!(!in_use("freed"));
+        assert_eq!(parent.new_child("freed").to_string(), "/interning/freed");
+
+        let script = ScriptPath::from_str_at_path("/a/x", "./b")?;
+        assert_eq!(script.as_concrete()?, a);
+        assert_eq!(script, ScriptPath::from_str_at_path("/", "/a/b")?);
+        Ok(())
+    }
+
     #[test]
     #[should_panic]
     fn test_parse_invalid_path_embedded_empty() {
@@ -345,11 +697,8 @@ mod test {
         PathComponent::Name(p.to_owned())
     }
 
-    fn p(v: Vec<PathComponent>, d: bool) -> PathComponent {
-        PathComponent::Lookup(ScriptPath {
-            components: v,
-            dynamic: d,
-        })
+    fn p(v: Vec<PathComponent>) -> PathComponent {
+        PathComponent::Lookup(ScriptPath::from_components(v))
     }
 
     #[test]
@@ -359,7 +708,7 @@ mod test {
             path.components,
             vec![
                 n("a"),
-                p(vec![n("0"), p(vec![n("A"), n("B")], false), n("2")], true),
+                p(vec![n("0"), p(vec![n("A"), n("B")]), n("2")]),
                 n("c"),
             ]
         )
@@ -382,17 +731,14 @@ mod test {
         let path = ScriptPath::from_str_at_path("/", "/foo/{/baz/./bep}/bar").unwrap();
         assert_eq!(
             path.components,
-            vec![n("foo"), p(vec![n("baz"), n("bep")], false), n("bar")]
+            vec![n("foo"), p(vec![n("baz"), n("bep")]), n("bar")]
         )
     }
 
     #[test]
     fn test_parse_abs_embedded_abs_parent() {
         let path = ScriptPath::from_str_at_path("/", "/foo/{/baz/../bep}/bar").unwrap();
-        assert_eq!(
-            path.components,
-            vec![n("foo"), p(vec![n("bep")], false), n("bar")]
-        )
+        assert_eq!(path.components, vec![n("foo"), p(vec![n("bep")]), n("bar")])
     }
 
     #[test]
@@ -416,9 +762,35 @@ mod test {
     #[test]
     fn test_parse_rel_embedded_rel_parent() {
         let path = ScriptPath::from_str_at_path("/a/b", "../c/{../e}/d").unwrap();
+        assert_eq!(path.components, vec![n("c"), p(vec![n("e")]), n("d")])
+    }
+
+    #[test]
+    fn test_parse_quoted_names() -> Fallible<()> {
+        let path = ScriptPath::from_str_at_path("/a/x", "./`living room`/`..`/b")?;
         assert_eq!(
             path.components,
-            vec![n("c"), p(vec![n("e")], false), n("d")]
-        )
+            vec![n("a"), n("living room"), n(".."), n("b")]
+        );
+        assert_eq!(path.to_string(), "/a/`living room`/`..`/b");
+        assert!(ScriptPath::from_str_at_path("/", "/a`b`").is_err());
+        assert!(ScriptPath::from_str_at_path("/", "/`a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_glob() -> Fallible<()> {
+        let path = ScriptPath::from_str_at_path("/hall/on", "../rooms/*/color")?;
+        assert_eq!(
+            path.components,
+            vec![n("rooms"), PathComponent::Glob, n("color")]
+        );
+        assert!(path.is_glob());
+        assert_eq!(path.to_string(), "/rooms/*/color");
+        let err = ScriptPath::from_str_at_path("/", "/a/{/rooms/*/name}")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("a lookup must read a single node"));
+        Ok(())
     }
 }
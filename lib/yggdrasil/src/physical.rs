@@ -2,7 +2,8 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use approx::relative_eq;
-use failure::{ensure, Fallible};
+use failure::Fallible;
+use std::{fmt, str::FromStr};
 
 #[derive(Clone, Copy, Debug)]
 pub enum Length {
@@ -14,23 +15,23 @@ impl Length {
     pub fn from_str(s: &str) -> Fallible<Self> {
         if s.contains('\'') {
             let parts = s.splitn(2, '\'').collect::<Vec<&str>>();
-            let ft = parts[0].parse::<i64>()?;
+            let ft = number(parts[0], s)?;
             if parts.len() == 1 || parts[1].is_empty() {
                 Ok(Length::Imperial(ft, 0.))
             } else {
-                ensure!(s.ends_with('"'), "expected \" to be at end");
+                require!(s.ends_with('"'), Parse, "expected \" to be at end of {}", s);
                 let stripped = &parts[1][0..parts[1].len() - 1];
-                Ok(Length::Imperial(ft, stripped.parse::<f64>()?))
+                Ok(Length::Imperial(ft, number(stripped, s)?))
             }
         } else if s.contains('"') {
-            ensure!(s.ends_with('"'), "expected \" to be at end");
+            require!(s.ends_with('"'), Parse, "expected \" to be at end of {}", s);
             let stripped = &s[0..s.len() - 1];
-            Ok(Length::Imperial(0, stripped.parse::<f64>()?))
+            Ok(Length::Imperial(0, number(stripped, s)?))
         } else if s.ends_with('m') {
             let stripped = &s[0..s.len() - 1];
-            Ok(Length::Meters(stripped.parse::<f64>()?))
+            Ok(Length::Meters(number(stripped, s)?))
         } else {
-            Ok(Length::Meters(s.parse::<f64>()?))
+            Ok(Length::Meters(number(s, s)?))
         }
     }
 
@@ -41,6 +42,27 @@ impl Length {
         }
     }
 }
+// Canonical form: meters always carry their unit and imperial lengths omit
+// any zero part, so 2'0" prints as 2' and 0'6" prints as 6".
+// Parse one number out of the length `whole`.
+fn number<T: FromStr>(s: &str, whole: &str) -> Fallible<T> {
+    match s.parse::<T>() {
+        Ok(n) => Ok(n),
+        Err(_) => raise!(Parse, "invalid length: {}", whole),
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Length::Meters(meters) => write!(f, "{}m", meters),
+            Length::Imperial(feet, 0.) => write!(f, "{}'", feet),
+            Length::Imperial(0, inches) => write!(f, "{}\"", inches),
+            Length::Imperial(feet, inches) => write!(f, "{}'{}\"", feet, inches),
+        }
+    }
+}
+
 impl PartialEq for Length {
     fn eq(&self, other: &Length) -> bool {
         relative_eq!(self.meters(), other.meters())
@@ -56,18 +78,39 @@ pub struct Dimension2 {
 
 impl Dimension2 {
     pub fn from_str(s: &str) -> Fallible<Self> {
-        assert!(!s.starts_with('@'));
-        assert!(!s.starts_with('<'));
-        assert!(!s.starts_with('>'));
+        require!(
+            !s.starts_with(['@', '<', '>']),
+            Parse,
+            "invalid dimension: {}",
+            s
+        );
         let parts = s.splitn(2, 'x').collect::<Vec<&str>>();
-        ensure!(parts.len() == 2, "invalid dimension: no x in middle");
-        ensure!(!parts[0].is_empty(), "invalid dimension: empty X part");
-        ensure!(!parts[1].is_empty(), "invalid dimension: empty Y part");
+        require!(parts.len() == 2, Parse, "invalid dimension: no x in middle");
+        require!(
+            !parts[0].is_empty(),
+            Parse,
+            "invalid dimension: empty X part"
+        );
+        require!(
+            !parts[1].is_empty(),
+            Parse,
+            "invalid dimension: empty Y part"
+        );
         Ok(Dimension2 {
             x_len: Length::from_str(parts[0])?,
             y_len: Length::from_str(parts[1])?,
         })
     }
+
+    pub fn meters(&self) -> (f64, f64) {
+        (self.x_len.meters(), self.y_len.meters())
+    }
+}
+
+impl fmt::Display for Dimension2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.x_len, self.y_len)
+    }
 }
 
 #[cfg(test)]
@@ -159,4 +202,30 @@ mod test {
         assert_eq!(Dimension2::from_str("-2'-1.0\"x-1\"").unwrap(), d);
         assert_eq!(Dimension2::from_str("-2'-1.0000\"x-1\"").unwrap(), d);
     }
+
+    #[test]
+    fn test_display_round_trip() -> Fallible<()> {
+        let expect = [
+            ("1x1", "1mx1m"),
+            ("1.0mx-2.5", "1mx-2.5m"),
+            ("12'x0'", "12'x0'"),
+            ("2'0\"x6\"", "2'x6\""),
+            ("7'6\"x-2'-1.0\"", "7'6\"x-2'-1\""),
+        ];
+        for (input, canonical) in expect.iter() {
+            let dim = Dimension2::from_str(input)?;
+            assert_eq!(dim.to_string(), *canonical);
+            assert_eq!(Dimension2::from_str(canonical)?, dim);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for s in &[
+            "@1x1", "<1x1", ">1x1", "1", "x1", "1x", "1'2x1", "'x\"", "1\"2x1",
+        ] {
+            assert!(Dimension2::from_str(s).is_err(), "{}", s);
+        }
+    }
 }
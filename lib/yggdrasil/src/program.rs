@@ -0,0 +1,218 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::NativeFunc,
+    error::{Error, ErrorContext},
+    path::{PathComponent, ScriptPath},
+    tokenizer::Token,
+    tree::{NodeId, Tree},
+    value::Value,
+};
+use failure::Fallible;
+use tracing::trace;
+
+/// One step of a compiled script. Programs run on a stack of values: each
+/// instruction pops its operands and pushes its result.
+#[derive(Clone, Debug)]
+pub(super) enum Op {
+    // Push a constant.
+    Push(Value),
+    // Push the value of a node whose path was resolved when compiling, at no
+    // less than the given generation.
+    Load(NodeId, usize),
+    // Walk the remaining components of a path with {...} lookups from the
+    // node its static prefix resolved to, then push the value found there.
+    LoadDynamic(NodeId, Vec<PathComponent>, usize),
+    // As LoadDynamic, but for a path with globs: push a list of the values of
+    // every node it matches.
+    LoadGlob(NodeId, Vec<PathComponent>, usize),
+    // Pop rhs, then lhs, and push lhs `op` rhs.
+    Apply(Token),
+    // Pop a number and push its negation.
+    Negate,
+    // Pop the argument and push the function's result.
+    Call(Box<dyn NativeFunc + Send + Sync>),
+    // Pop this many values and push them as a list, in push order.
+    List(usize),
+    // Pop this many arguments, in push order, into a new frame for the body
+    // of a function.
+    Enter(usize),
+    // Push the argument at this index in the innermost frame.
+    LoadParam(usize),
+    // Drop the innermost frame, on returning from a function.
+    Leave,
+    // Pop a boolean and continue at the given offset if it is false.
+    JumpUnless(usize),
+    Jump(usize),
+}
+
+/// A script lowered to a flat sequence of instructions.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Program {
+    ops: Vec<Op>,
+}
+
+impl Program {
+    pub fn push(&mut self, op: Op) -> usize {
+        self.ops.push(op);
+        self.ops.len() - 1
+    }
+
+    // The offset of the next instruction to be pushed.
+    pub fn next_offset(&self) -> usize {
+        self.ops.len()
+    }
+
+    // Point the jump at `at` to `target`, once the target is known.
+    pub fn patch_jump(&mut self, at: usize, target: usize) {
+        match self.ops[at] {
+            Op::Jump(ref mut t) | Op::JumpUnless(ref mut t) => *t = target,
+            _ => panic!("compile error: patching a non-jump at {}", at),
+        }
+    }
+
+    // Lower a path literal, resolving as much of it as possible now.
+    pub fn push_path(&mut self, path: &ScriptPath, generation: usize, tree: &Tree) -> Fallible<()> {
+        if let Ok(concrete) = path.as_concrete() {
+            if let Ok(node) = tree.lookup_path(&concrete) {
+                self.push(Op::Load(node.id(), generation));
+                return Ok(());
+            }
+        }
+        let mut base = tree.root();
+        let mut rest = path.components();
+        while let Some(PathComponent::Name(name)) = rest.first() {
+            match base.child_at(name)? {
+                Some(child) => base = child,
+                None => break,
+            }
+            rest = &rest[1..];
+        }
+        if rest.is_empty() {
+            // A static path that does not exist; looking it up again at
+            // runtime reports the error.
+            base = tree.root();
+            rest = path.components();
+        }
+        if path.is_glob() {
+            self.push(Op::LoadGlob(base.id(), rest.to_vec(), generation));
+        } else {
+            self.push(Op::LoadDynamic(base.id(), rest.to_vec(), generation));
+        }
+        Ok(())
+    }
+
+    pub fn run(&self, tree: &Tree) -> Fallible<Value> {
+        let mut stack: Vec<Value> = Vec::new();
+        let mut frames: Vec<Vec<Value>> = Vec::new();
+        let mut pc = 0;
+        while pc < self.ops.len() {
+            match &self.ops[pc] {
+                Op::Push(value) => stack.push(value.to_owned()),
+                Op::Load(id, generation) => {
+                    let value = tree.node(*id).compute(tree)?;
+                    stack.push(value.with_generation(*generation));
+                }
+                Op::LoadDynamic(base, parts, generation) => {
+                    let (node, path_gen) =
+                        tree.node(*base)
+                            .lookup_dynamic_path(*generation, parts, tree)?;
+                    stack.push(node.compute(tree)?.with_generation(path_gen));
+                }
+                Op::LoadGlob(base, parts, generation) => {
+                    stack.push(
+                        tree.node(*base)
+                            .compute_glob_path(*generation, parts, tree)?,
+                    );
+                }
+                Op::Apply(tok) => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.pop().unwrap();
+                    trace!("compute: reduce {:?} {:?} {:?}", lhs, tok, rhs);
+                    stack.push(lhs.apply(tok, &rhs)?);
+                }
+                Op::Negate => {
+                    let value = stack.pop().unwrap();
+                    stack.push(value.negate()?);
+                }
+                Op::Call(fun) => {
+                    let arg = stack.pop().unwrap();
+                    stack.push(fun.compute(arg, tree)?);
+                }
+                Op::List(len) => {
+                    let items = stack.split_off(stack.len() - len);
+                    stack.push(Value::from_list(items));
+                }
+                Op::Enter(len) => frames.push(stack.split_off(stack.len() - len)),
+                Op::LoadParam(i) => stack.push(frames.last().unwrap()[*i].to_owned()),
+                Op::Leave => {
+                    frames.pop();
+                }
+                Op::JumpUnless(target) => {
+                    let cond = stack.pop().unwrap();
+                    if !cond.is_boolean() {
+                        return Err(Error::Type(ErrorContext::new(
+                            "if statement conditions must be boolean".to_owned(),
+                        ))
+                        .with_value(cond)
+                        .into());
+                    }
+                    if cond != Value::from_boolean(true) {
+                        pc = *target;
+                        continue;
+                    }
+                }
+                Op::Jump(target) => {
+                    pc = *target;
+                    continue;
+                }
+            }
+            pc += 1;
+        }
+        require!(
+            stack.len() == 1,
+            Runtime,
+            "script left {} values on the stack",
+            stack.len()
+        );
+        Ok(stack.pop().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::TreeBuilder;
+
+    #[test]
+    fn test_program_resolves_static_paths() -> Fallible<()> {
+        let s = r#"
+a
+    b
+        on <- 1
+        off <- 0
+    key <- "on"
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let mut program = Program::default();
+        program.push_path(&ScriptPath::from_str_at_path("/", "/a/b/on")?, 0, &tree)?;
+        program.push_path(
+            &ScriptPath::from_str_at_path("/", "/a/b/{/a/key}")?,
+            0,
+            &tree,
+        )?;
+        program.push(Op::Apply(Token::Add));
+
+        match (&program.ops[0], &program.ops[1]) {
+            (Op::Load(id, _), Op::LoadDynamic(base, rest, _)) => {
+                assert_eq!(*id, tree.lookup("/a/b/on")?.id());
+                assert_eq!(*base, tree.lookup("/a/b")?.id());
+                assert_eq!(rest.len(), 1);
+            }
+            ops => panic!("unexpected ops: {:?}", ops),
+        }
+        assert_eq!(program.run(&tree)?, Value::from_integer(2));
+        Ok(())
+    }
+}
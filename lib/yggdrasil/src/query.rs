@@ -0,0 +1,451 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+//
+// Queries select nodes by what they are rather than where they are, so that
+// integrations and UIs can find devices without hardcoding paths:
+//
+//     sinks(hue) & tag(ceiling) & within(/rooms/kitchen)
+//
+// Terms are combined with & and |, negated with !, and grouped with parens;
+// & binds tighter than |. The terms are:
+//
+//     sinks, sinks(kind)      nodes with any sink, or a sink of this kind
+//     sources, sources(kind)  likewise for sources
+//     tag(name)               nodes tagged #name
+//     path(/rooms/*/lamp)     paths matching a glob: * matches any one name
+//                             and ** matches any number of them
+//     within(/rooms/kitchen)  nodes under the given node
+//     within(@0x0 <>12'x10')  nodes whose location, relative to the root,
+//                             falls in the given box
+//     value == "on"           nodes whose value compares true; the operators
+//                             are those of scripts, and a node whose value
+//                             cannot be compared does not match
+use crate::{
+    path::ConcretePath,
+    physical::Dimension2,
+    tokenizer::{LineTokenizer, Token},
+    tree::{NodeRef, Tree},
+    value::Value,
+};
+use failure::Fallible;
+use std::str::FromStr;
+
+// Locations are summed from lengths in mixed units, so allow for rounding
+// at the edges of a box.
+const EPSILON: f64 = 1e-9;
+
+/// A selection of nodes, as parsed from text like
+/// `sinks(hue) & tag(ceiling) & within(/rooms/kitchen)`.
+#[derive(Clone, Debug)]
+pub struct Query {
+    term: Term,
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Sinks(Option<String>),
+    Sources(Option<String>),
+    Tag(String),
+    Path(Vec<String>),
+    Within(ConcretePath),
+    WithinBox(Dimension2, Dimension2),
+    Value(Token, Value),
+    Not(Box<Term>),
+    And(Box<Term>, Box<Term>),
+    Or(Box<Term>, Box<Term>),
+}
+
+impl FromStr for Query {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut parser = QueryParser {
+            chars: s.chars().collect(),
+            offset: 0,
+        };
+        let term = parser.parse_or()?;
+        parser.skip_space();
+        require!(
+            parser.is_empty(),
+            Parse,
+            "unexpected {} in query at column {}",
+            parser.chars[parser.offset],
+            parser.offset + 1
+        );
+        Ok(Self { term })
+    }
+}
+
+impl Query {
+    /// The paths of every node in `tree` that this query selects, in order.
+    pub fn select(&self, tree: &Tree) -> Fallible<Vec<ConcretePath>> {
+        let mut matching = Vec::new();
+        for child in tree.root().sorted_children()? {
+            self.select_under(&child, None, tree, &mut matching)?;
+        }
+        matching.sort();
+        Ok(matching)
+    }
+
+    // `origin` is the location of the nearest ancestor that has one, relative
+    // to the root, in meters.
+    fn select_under(
+        &self,
+        node: &NodeRef,
+        origin: Option<(f64, f64)>,
+        tree: &Tree,
+        matching: &mut Vec<ConcretePath>,
+    ) -> Fallible<()> {
+        let position = match (origin, node.location()?) {
+            (origin, Some(location)) => {
+                let (x0, y0) = origin.unwrap_or((0., 0.));
+                let (x, y) = location.meters();
+                Some((x0 + x, y0 + y))
+            }
+            (origin, None) => origin,
+        };
+        if self.term.matches(node, position, tree)? {
+            matching.push(node.path()?);
+        }
+        for child in node.sorted_children()? {
+            self.select_under(&child, position, tree, matching)?;
+        }
+        Ok(())
+    }
+}
+
+impl Term {
+    fn matches(&self, node: &NodeRef, position: Option<(f64, f64)>, tree: &Tree) -> Fallible<bool> {
+        Ok(match self {
+            Term::Sinks(kind) => kind_matches(node.maybe_sink_kind()?, kind),
+            Term::Sources(kind) => kind_matches(node.maybe_source_kind()?, kind),
+            Term::Tag(tag) => node.has_tag(tag)?,
+            Term::Path(glob) => glob_matches(glob, &node.path()?.components()),
+            Term::Within(ancestor) => {
+                let path = node.path()?;
+                path.starts_with(ancestor) && path != *ancestor
+            }
+            Term::WithinBox(corner, size) => match position {
+                Some((x, y)) => {
+                    let (x0, y0) = corner.meters();
+                    let (w, h) = size.meters();
+                    x >= x0 - EPSILON
+                        && x <= x0 + w + EPSILON
+                        && y >= y0 - EPSILON
+                        && y <= y0 + h + EPSILON
+                }
+                None => false,
+            },
+            Term::Value(op, literal) => node
+                .compute(tree)
+                .and_then(|value| value.apply(op, literal)?.as_boolean())
+                .unwrap_or(false),
+            Term::Not(term) => !term.matches(node, position, tree)?,
+            Term::And(a, b) => {
+                a.matches(node, position, tree)? && b.matches(node, position, tree)?
+            }
+            Term::Or(a, b) => {
+                a.matches(node, position, tree)? || b.matches(node, position, tree)?
+            }
+        })
+    }
+}
+
+fn kind_matches(found: Option<String>, wanted: &Option<String>) -> bool {
+    match (found, wanted) {
+        (Some(found), Some(wanted)) => &found == wanted,
+        (found, None) => found.is_some(),
+        (None, _) => false,
+    }
+}
+
+fn glob_matches(glob: &[String], components: &[&str]) -> bool {
+    match glob.split_first() {
+        None => components.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=components.len()).any(|skip| glob_matches(rest, &components[skip..]))
+        }
+        Some((first, rest)) => match components.split_first() {
+            Some((name, names)) => (first == "*" || first == name) && glob_matches(rest, names),
+            None => false,
+        },
+    }
+}
+
+struct QueryParser {
+    chars: Vec<char>,
+    offset: usize,
+}
+
+impl QueryParser {
+    fn parse_or(&mut self) -> Fallible<Term> {
+        let mut term = self.parse_and()?;
+        while self.eat('|') {
+            term = Term::Or(Box::new(term), Box::new(self.parse_and()?));
+        }
+        Ok(term)
+    }
+
+    fn parse_and(&mut self) -> Fallible<Term> {
+        let mut term = self.parse_unary()?;
+        while self.eat('&') {
+            term = Term::And(Box::new(term), Box::new(self.parse_unary()?));
+        }
+        Ok(term)
+    }
+
+    fn parse_unary(&mut self) -> Fallible<Term> {
+        if self.eat('!') {
+            return Ok(Term::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat('(') {
+            let term = self.parse_or()?;
+            require!(self.eat(')'), Parse, "expected ) in query");
+            return Ok(term);
+        }
+        self.parse_term()
+    }
+
+    fn parse_term(&mut self) -> Fallible<Term> {
+        self.skip_space();
+        let start = self.offset;
+        while !self.is_empty() && self.chars[self.offset].is_ascii_alphabetic() {
+            self.offset += 1;
+        }
+        let name = self.chars[start..self.offset].iter().collect::<String>();
+        require!(
+            !name.is_empty(),
+            Parse,
+            "expected a query term at column {}",
+            start + 1
+        );
+        if name == "value" {
+            return self.parse_value();
+        }
+        let arg = if self.eat('(') {
+            let start = self.offset;
+            while !self.is_empty() && self.chars[self.offset] != ')' {
+                self.offset += 1;
+            }
+            require!(
+                !self.is_empty(),
+                Parse,
+                "expected ) after {}( in query",
+                name
+            );
+            let arg = self.chars[start..self.offset].iter().collect::<String>();
+            self.offset += 1;
+            Some(arg.trim().to_owned())
+        } else {
+            None
+        };
+        Ok(match (name.as_str(), arg) {
+            ("sinks", kind) => Term::Sinks(kind),
+            ("sources", kind) => Term::Sources(kind),
+            ("tag", Some(tag)) => Term::Tag(tag),
+            ("path", Some(glob)) => {
+                require!(
+                    glob.starts_with('/'),
+                    Parse,
+                    "path({}) in query must be absolute",
+                    glob
+                );
+                Term::Path(
+                    glob.split('/')
+                        .filter(|c| !c.is_empty())
+                        .map(|c| c.to_owned())
+                        .collect(),
+                )
+            }
+            ("within", Some(place)) if place.starts_with('/') => {
+                Term::Within(ConcretePath::from_str(&place)?)
+            }
+            ("within", Some(place)) => {
+                let (tokens, _) = LineTokenizer::tokenize_line(&place, 1)?;
+                let tokens = tokens.into_iter().map(|t| t.token).collect::<Vec<_>>();
+                match tokens.as_slice() {
+                    [Token::Location(corner), Token::Size(size)] => Term::WithinBox(*corner, *size),
+                    _ => raise!(
+                        Parse,
+                        "expected a path or @location <>size in within({})",
+                        place
+                    ),
+                }
+            }
+            ("tag", None) | ("path", None) | ("within", None) => {
+                raise!(Parse, "expected ( after {} in query", name)
+            }
+            _ => raise!(
+                Parse,
+                "unknown query term '{}': expected sinks, sources, tag, path, within, or value",
+                name
+            ),
+        })
+    }
+
+    // A comparison of the node's value with a literal, such as `value >= 2`.
+    fn parse_value(&mut self) -> Fallible<Term> {
+        self.skip_space();
+        let start = self.offset;
+        let mut in_string = false;
+        while !self.is_empty() {
+            match self.chars[self.offset] {
+                '"' => in_string = !in_string,
+                '&' | '|' | ')' if !in_string => break,
+                _ => {}
+            }
+            self.offset += 1;
+        }
+        let text = self.chars[start..self.offset].iter().collect::<String>();
+        let (tokens, _) = LineTokenizer::tokenize_line(text.trim(), 1)?;
+        let tokens = tokens.into_iter().map(|t| t.token).collect::<Vec<_>>();
+        let (op, literal) = match tokens.as_slice() {
+            [op, literal] => (op.to_owned(), literal),
+            _ => raise!(
+                Parse,
+                "expected an operator and a value after value in query"
+            ),
+        };
+        require!(
+            matches!(
+                op,
+                Token::Equals
+                    | Token::NotEquals
+                    | Token::LessThan
+                    | Token::LessThanOrEquals
+                    | Token::GreaterThan
+                    | Token::GreaterThanOrEquals
+            ),
+            Parse,
+            "expected a comparison after value in query"
+        );
+        let literal = match literal {
+            Token::BooleanTerm(b) => Value::from_boolean(*b),
+            Token::IntegerTerm(i) => Value::from_integer(*i),
+            Token::FloatTerm(f) => Value::from_float(*f),
+            Token::StringTerm(s) => Value::new_str(s),
+            _ => raise!(Parse, "expected a literal to compare value with in query"),
+        };
+        Ok(Term::Value(op, literal))
+    }
+
+    // Consume `c`, after any spaces, if it is next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        if !self.is_empty() && self.chars[self.offset] == c {
+            self.offset += 1;
+            return true;
+        }
+        false
+    }
+
+    fn skip_space(&mut self) {
+        while !self.is_empty() && self.chars[self.offset] == ' ' {
+            self.offset += 1;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.chars.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{error::Error, tree::TreeBuilder};
+
+    const HOUSE: &str = r#"
+rooms
+    kitchen @0x0 <>12'x10'
+        ceiling $hue @6'x5' #ceiling #dimmable <- "on"
+        counter $hue @11'x1' <- "off"
+        switch ^switch #wall
+            default <- 2
+    bedroom @12'x0' <>12'x10'
+        ceiling $hue @6'x5' #ceiling <- "on"
+        closet @-2'x0' <>2'x5'
+            lamp $hue @1'x1' #dimmable <- "off"
+"#;
+
+    fn select(tree: &Tree, query: &str) -> Fallible<Vec<String>> {
+        Ok(tree.query(query)?.iter().map(|p| p.to_string()).collect())
+    }
+
+    #[test]
+    fn test_query_terms() -> Fallible<()> {
+        let tree = TreeBuilder::default().build_from_str(HOUSE)?;
+        let kitchen_ceiling = "/rooms/kitchen/ceiling";
+        let bedroom_ceiling = "/rooms/bedroom/ceiling";
+        let cases: Vec<(&str, Vec<&str>)> = vec![
+            (
+                "sinks(hue) & tag(ceiling) & within(/rooms/kitchen)",
+                vec![kitchen_ceiling],
+            ),
+            ("sources", vec!["/rooms/kitchen/switch"]),
+            ("sources(motion)", vec![]),
+            ("tag(ceiling)", vec![bedroom_ceiling, kitchen_ceiling]),
+            (
+                "tag(dimmable) | tag(wall)",
+                vec![
+                    "/rooms/bedroom/closet/lamp",
+                    kitchen_ceiling,
+                    "/rooms/kitchen/switch",
+                ],
+            ),
+            (
+                "path(/rooms/*/ceiling)",
+                vec![bedroom_ceiling, kitchen_ceiling],
+            ),
+            ("path(/**/lamp)", vec!["/rooms/bedroom/closet/lamp"]),
+            (
+                "sinks & !(value == \"on\")",
+                vec!["/rooms/bedroom/closet/lamp", "/rooms/kitchen/counter"],
+            ),
+            ("sources & value >= 2", vec!["/rooms/kitchen/switch"]),
+            // The closet lamp is at 12' - 2' + 1', so it is in the kitchen.
+            (
+                "sinks & within(@0x0 <>12'x10')",
+                vec![
+                    "/rooms/bedroom/closet/lamp",
+                    kitchen_ceiling,
+                    "/rooms/kitchen/counter",
+                ],
+            ),
+        ];
+        for (query, expect) in cases {
+            assert_eq!(select(&tree, query)?, expect, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_errors() {
+        let cases = [
+            ("sinks(hue) &", "expected a query term at column 13"),
+            ("lights(hue)", "unknown query term 'lights'"),
+            ("tag", "expected ( after tag in query"),
+            ("(sinks", "expected ) in query"),
+            ("path(rooms/*)", "path(rooms/*) in query must be absolute"),
+            (
+                "within(3x3)",
+                "expected a path or @location <>size in within(3x3)",
+            ),
+            (
+                "value on",
+                "expected an operator and a value after value in query",
+            ),
+            ("sinks sources", "unexpected s in query at column 7"),
+        ];
+        for (query, message) in &cases {
+            let err = Query::from_str(query).expect_err(query);
+            assert!(
+                Error::find(&err).unwrap().message().starts_with(message),
+                "{}: {}",
+                query,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    path::ConcretePath,
+    tree::{Tree, TreeBuilder},
+    value::Value,
+};
+use failure::Fallible;
+use std::str::FromStr;
+
+/// A script of events and the values that should follow from them, for
+/// testing a tree; each step is an error if it does not go as expected:
+///
+/// ```text
+/// Scenario::new(TreeBuilder::default(), source)?
+///     .event("/rooms/bedroom/switch", Value::new_str("low"))?
+///     .expect("/rooms/bedroom/level", Value::new_str("low"))?;
+/// ```
+pub struct Scenario {
+    tree: Tree,
+}
+
+impl Scenario {
+    pub fn new(builder: TreeBuilder, source: &str) -> Fallible<Self> {
+        Ok(Self {
+            tree: builder.build_from_str(source)?,
+        })
+    }
+
+    /// Send `value` to the source at `path`, as if it came from the device.
+    pub fn event(&mut self, path: &str, value: Value) -> Fallible<&mut Self> {
+        self.tree
+            .handle_event(&ConcretePath::from_str(path)?, value)?;
+        Ok(self)
+    }
+
+    /// Fail unless the node at `path` now computes to `value`.
+    pub fn expect(&mut self, path: &str, value: Value) -> Fallible<&mut Self> {
+        let found = self.tree.lookup(path)?.compute(&self.tree)?;
+        require!(
+            found == value,
+            Runtime,
+            "expected {} at {}, but found {}",
+            value,
+            path,
+            found
+        );
+        Ok(self)
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn test_scenario_reports_mismatch() -> Fallible<()> {
+        let mut scenario = Scenario::new(TreeBuilder::default(), "a <- 1\n")?;
+        let err = scenario.expect("/a", Value::from_integer(2)).err().unwrap();
+        assert_eq!(
+            Error::find(&err).unwrap().message(),
+            "expected 2i64 at /a, but found 1i64"
+        );
+        Ok(())
+    }
+}
@@ -3,16 +3,17 @@
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
     bif::NativeFunc,
-    graph::Graph,
+    function::{Function, Functions},
     parser::TreeParser,
-    path::{ConcretePath, ScriptPath},
+    path::{ConcretePath, PathComponent, ScriptPath},
+    program::{Op, Program},
     tokenizer::Token,
-    tree::{NodeRef, Tree},
-    value::Value,
+    tree::{NodeId, NodeRef, Tree, TreeBuilder},
+    value::{Value, ValueData, ValueType},
 };
-use failure::{bail, ensure, err_msg, Fallible};
+use failure::Fallible;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 use tracing::trace;
 
 #[derive(Clone, Debug)]
@@ -20,6 +21,7 @@ pub(super) enum Expr {
     Add(Box<Expr>, Box<Expr>),
     And(Box<Expr>, Box<Expr>),
     Call(Box<dyn NativeFunc + Send + Sync>, Box<Expr>),
+    CallFunction(Arc<Function>, Vec<Expr>),
     Divide(Box<Expr>, Box<Expr>),
     Equal(Box<Expr>, Box<Expr>),
     GreaterThan(Box<Expr>, Box<Expr>),
@@ -33,104 +35,468 @@ pub(super) enum Expr {
     Or(Box<Expr>, Box<Expr>),
     Subtract(Box<Expr>, Box<Expr>),
     Latch(Box<Expr>, Box<Expr>),
+    List(Vec<Expr>),
+    // The parameter at this index, in the body of a function.
+    Param(usize),
     Value(Value),
 }
 
-macro_rules! map_values {
-    ($self:ident, $f:ident, $reduce:expr, $($args:ident),*) => {
-        match $self {
-            Expr::Add(a, b) => {
-                $reduce(Token::Add, a.$f($($args),*)?, b.$f($($args),*)?)
+impl Expr {
+    // The reference interpreter, which compiled programs are checked against.
+    // `args` are the arguments of the function whose body this is in, if any.
+    #[cfg(test)]
+    pub fn compute(&self, tree: &Tree, args: &[Value]) -> Fallible<Value> {
+        if let Some((tok, a, b)) = self.binary_operands() {
+            let lhs = a.compute(tree, args)?;
+            let rhs = b.compute(tree, args)?;
+            trace!("compute: reduce {:?} {:?} {:?}", lhs, tok, rhs);
+            return lhs.apply(&tok, &rhs);
+        }
+        match self {
+            Expr::Call(fun, a) => fun.compute(a.compute(tree, args)?, tree),
+            Expr::CallFunction(fun, items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    values.push(item.compute(tree, args)?);
+                }
+                fun.body().compute(tree, &values)
+            }
+            Expr::Negate(a) => a.compute(tree, args)?.negate(),
+            Expr::List(items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    values.push(item.compute(tree, args)?);
+                }
+                Ok(Value::from_list(values))
+            }
+            Expr::Param(i) => Ok(args[*i].to_owned()),
+            Expr::Value(v) => v.compute(tree),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn find_all_possible_inputs(
+        &self,
+        tree: &Tree,
+        out: &mut Vec<ConcretePath>,
+    ) -> Fallible<()> {
+        trace!("Expr::find_all_possible_inputs({:?})", self);
+        if let Some((_, a, b)) = self.binary_operands() {
+            a.find_all_possible_inputs(tree, out)?;
+            return b.find_all_possible_inputs(tree, out);
+        }
+        match self {
+            Expr::Call(_, a) | Expr::Negate(a) => a.find_all_possible_inputs(tree, out),
+            // Whatever the body of a function reads, each call to it reads.
+            Expr::CallFunction(fun, items) => {
+                for item in items {
+                    item.find_all_possible_inputs(tree, out)?;
+                }
+                fun.body().find_all_possible_inputs(tree, out)
             }
-            Expr::And(a, b) => {
-                $reduce(Token::And, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::List(items) => {
+                for item in items {
+                    item.find_all_possible_inputs(tree, out)?;
+                }
+                Ok(())
             }
+            Expr::Param(_) => Ok(()),
+            Expr::Value(v) => v.find_all_possible_inputs(tree, out),
+            _ => unreachable!(),
+        }
+    }
+
+    fn compile(&self, program: &mut Program, tree: &Tree) -> Fallible<()> {
+        if let Some((tok, a, b)) = self.binary_operands() {
+            a.compile(program, tree)?;
+            b.compile(program, tree)?;
+            program.push(Op::Apply(tok));
+            return Ok(());
+        }
+        match self {
             Expr::Call(fun, a) => {
-                fun.$f(a.$f($($args),*)?, $($args),*)
+                a.compile(program, tree)?;
+                program.push(Op::Call(fun.to_owned()));
             }
-            Expr::Divide(a, b) => {
-                $reduce(Token::Divide, a.$f($($args),*)?, b.$f($($args),*)?)
+            // The body of a function is compiled in place at each call, to run
+            // with the arguments in a frame of their own.
+            Expr::CallFunction(fun, items) => {
+                for item in items {
+                    item.compile(program, tree)?;
+                }
+                program.push(Op::Enter(items.len()));
+                fun.body().compile(program, tree)?;
+                program.push(Op::Leave);
             }
-            Expr::Equal(a, b) => {
-                $reduce(Token::Equals, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::Negate(a) => {
+                a.compile(program, tree)?;
+                program.push(Op::Negate);
             }
-            Expr::GreaterThan(a, b) => {
-                $reduce(Token::GreaterThan, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::List(items) => {
+                for item in items {
+                    item.compile(program, tree)?;
+                }
+                program.push(Op::List(items.len()));
             }
-            Expr::GreaterThanOrEqual(a, b) => {
-                $reduce(Token::GreaterThanOrEquals, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::Param(i) => {
+                program.push(Op::LoadParam(*i));
             }
-            Expr::LessThan(a, b) => {
-                $reduce(Token::LessThan, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::Value(v) => {
+                if let ValueData::Path(ref p) = v.data {
+                    program.push_path(p, v.generation(), tree)?;
+                } else {
+                    program.push(Op::Push(v.to_owned()));
+                }
             }
-            Expr::LessThanOrEqual(a, b) => {
-                $reduce(Token::LessThanOrEquals, a.$f($($args),*)?, b.$f($($args),*)?)
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    // Check that every call is passed an argument of a type that it takes,
+    // where that type is knowable from the script alone, and return the type
+    // of this expression, if known. `params` are the types of the arguments
+    // of the function whose body this is in, where known.
+    pub(super) fn check_types(&self, params: &[Option<ValueType>]) -> Fallible<Option<ValueType>> {
+        Ok(match self {
+            Expr::Call(fun, a) => fun.check_arg_type(a.check_types(params)?)?,
+            // A function is checked again with what each call passes it.
+            Expr::CallFunction(fun, items) => {
+                let mut types = Vec::new();
+                for item in items {
+                    types.push(item.check_types(params)?);
+                }
+                fun.body().check_types(&types)?
             }
-            Expr::Modulo(a, b) => {
-                $reduce(Token::Modulo, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::Negate(a) => a.check_types(params)?,
+            Expr::List(items) => {
+                for item in items {
+                    item.check_types(params)?;
+                }
+                Some(ValueType::List)
             }
-            Expr::Multiply(a, b) => {
-                $reduce(Token::Multiply, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::Param(i) => params[*i],
+            // The type behind a path is only known once it is computed, but
+            // a glob always gives a list.
+            Expr::Value(Value {
+                data: ValueData::Path(p),
+                ..
+            }) if p.is_glob() => Some(ValueType::List),
+            Expr::Value(v) if v.is_path() => None,
+            Expr::Value(v) => v.value_type(),
+            _ => {
+                let (tok, a, b) = self.binary_operands().expect("binary operator");
+                let types = [a.check_types(params)?, b.check_types(params)?];
+                // An operator over a list applies to each item.
+                if types.contains(&Some(ValueType::List)) {
+                    return Ok(Some(ValueType::List));
+                }
+                match tok {
+                    Token::Equals
+                    | Token::NotEquals
+                    | Token::GreaterThan
+                    | Token::GreaterThanOrEquals
+                    | Token::LessThan
+                    | Token::LessThanOrEquals
+                    | Token::And
+                    | Token::Or => Some(ValueType::Boolean),
+                    _ => None,
+                }
             }
-            Expr::Negate(a) => {
-                a.$f($($args),*)
+        })
+    }
+
+    // The expression that applies the binary operator `tok`.
+    fn binary(tok: Token, a: Expr, b: Expr) -> Fallible<Expr> {
+        let (a, b) = (Box::new(a), Box::new(b));
+        Ok(match tok {
+            Token::Add => Expr::Add(a, b),
+            Token::And => Expr::And(a, b),
+            Token::Divide => Expr::Divide(a, b),
+            Token::Equals => Expr::Equal(a, b),
+            Token::GreaterThan => Expr::GreaterThan(a, b),
+            Token::GreaterThanOrEquals => Expr::GreaterThanOrEqual(a, b),
+            Token::LessThan => Expr::LessThan(a, b),
+            Token::LessThanOrEquals => Expr::LessThanOrEqual(a, b),
+            Token::Modulo => Expr::Modulo(a, b),
+            Token::Multiply => Expr::Multiply(a, b),
+            Token::NotEquals => Expr::NotEqual(a, b),
+            Token::Or => Expr::Or(a, b),
+            Token::Subtract => Expr::Subtract(a, b),
+            Token::Latch => Expr::Latch(a, b),
+            _ => raise!(Parse, "unexpected token {:?} in binop position", tok),
+        })
+    }
+
+    // The value of this expression, if it is a constant other than a path.
+    fn literal(&self) -> Option<&Value> {
+        match self {
+            Expr::Value(v) if !v.is_path() => Some(v),
+            _ => None,
+        }
+    }
+
+    // A copy of this expression with everything that is known before the
+    // tree runs worked out ahead of time, noting each thing folded in `folds`.
+    // Anything that fails is left for the program to fail on when it runs. In
+    // the body of a function, `args` are the arguments of one call to it.
+    pub(super) fn fold(
+        &self,
+        folder: &mut Folder,
+        args: &[Value],
+        folds: &mut Vec<String>,
+    ) -> Fallible<Expr> {
+        if let Some((tok, a, b)) = self.binary_operands() {
+            let (a, b) = (a.fold(folder, args, folds)?, b.fold(folder, args, folds)?);
+            if let (Some(lhs), Some(rhs)) = (a.literal(), b.literal()) {
+                if let Ok(v) = lhs.apply(&tok, rhs) {
+                    return Ok(Expr::Value(v));
+                }
             }
-            Expr::NotEqual(a, b) => {
-                $reduce(Token::NotEquals, a.$f($($args),*)?, b.$f($($args),*)?)
+            return Expr::binary(tok, a, b);
+        }
+        Ok(match self {
+            Expr::Call(fun, a) => {
+                let a = a.fold(folder, args, folds)?;
+                if let Some(arg) = a.literal().filter(|_| fun.signature().pure) {
+                    if let Ok(v) = fun.compute(arg.to_owned(), folder.tree()) {
+                        return Ok(Expr::Value(v));
+                    }
+                }
+                Expr::Call(fun.to_owned(), Box::new(a))
             }
-            Expr::Or(a, b) => {
-                $reduce(Token::Or, a.$f($($args),*)?, b.$f($($args),*)?)
+            // A call whose arguments are all known is folded if its body is.
+            Expr::CallFunction(fun, items) => {
+                let mut folded = Vec::new();
+                for item in items {
+                    folded.push(item.fold(folder, args, folds)?);
+                }
+                let values = folded
+                    .iter()
+                    .map(|item| item.literal().cloned())
+                    .collect::<Option<Vec<_>>>();
+                if let Some(values) = values {
+                    let mut body_folds = Vec::new();
+                    let body = fun.body().fold(folder, &values, &mut body_folds)?;
+                    if let Stmt::ExprStmt(Expr::Value(v)) = body {
+                        if !v.is_path() {
+                            folds.extend(body_folds);
+                            return Ok(Expr::Value(v));
+                        }
+                    }
+                }
+                Expr::CallFunction(fun.to_owned(), folded)
             }
-            Expr::Subtract(a, b) => {
-                $reduce(Token::Subtract, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::Negate(a) => {
+                let a = a.fold(folder, args, folds)?;
+                if let Some(Ok(v)) = a.literal().map(Value::negate) {
+                    return Ok(Expr::Value(v));
+                }
+                Expr::Negate(Box::new(a))
             }
-            Expr::Latch(a, b) => {
-                $reduce(Token::Latch, a.$f($($args),*)?, b.$f($($args),*)?)
+            Expr::List(items) => {
+                let mut folded = Vec::new();
+                for item in items {
+                    folded.push(item.fold(folder, args, folds)?);
+                }
+                match folded
+                    .iter()
+                    .map(|item| item.literal().cloned())
+                    .collect::<Option<Vec<_>>>()
+                {
+                    Some(values) => Expr::Value(Value::from_list(values)),
+                    None => Expr::List(folded),
+                }
             }
-            Expr::Value(v) => {
-                v.$f($($args),*)
+            Expr::Param(i) => match args.get(*i) {
+                Some(v) => Expr::Value(v.to_owned()),
+                None => Expr::Param(*i),
+            },
+            Expr::Value(v) => match v.data {
+                ValueData::Path(ref p) if !p.is_glob() => {
+                    let (folded, generation) = folder.fold_path(p, v.generation(), folds)?;
+                    if let Ok(concrete) = folded.as_concrete() {
+                        if let Some(value) = folder.constant_at(&concrete)? {
+                            let value = value.with_generation(generation);
+                            folds.push(format!("read {} as {}", p, value));
+                            return Ok(Expr::Value(value));
+                        }
+                    }
+                    Expr::Value(Value::from_path(folded).with_generation(generation))
+                }
+                _ => Expr::Value(v.to_owned()),
+            },
+            _ => unreachable!(),
+        })
+    }
+
+    fn binary_operands(&self) -> Option<(Token, &Expr, &Expr)> {
+        Some(match self {
+            Expr::Add(a, b) => (Token::Add, a, b),
+            Expr::And(a, b) => (Token::And, a, b),
+            Expr::Divide(a, b) => (Token::Divide, a, b),
+            Expr::Equal(a, b) => (Token::Equals, a, b),
+            Expr::GreaterThan(a, b) => (Token::GreaterThan, a, b),
+            Expr::GreaterThanOrEqual(a, b) => (Token::GreaterThanOrEquals, a, b),
+            Expr::LessThan(a, b) => (Token::LessThan, a, b),
+            Expr::LessThanOrEqual(a, b) => (Token::LessThanOrEquals, a, b),
+            Expr::Modulo(a, b) => (Token::Modulo, a, b),
+            Expr::Multiply(a, b) => (Token::Multiply, a, b),
+            Expr::NotEqual(a, b) => (Token::NotEquals, a, b),
+            Expr::Or(a, b) => (Token::Or, a, b),
+            Expr::Subtract(a, b) => (Token::Subtract, a, b),
+            Expr::Latch(a, b) => (Token::Latch, a, b),
+            Expr::Call(..)
+            | Expr::CallFunction(..)
+            | Expr::Negate(_)
+            | Expr::List(_)
+            | Expr::Param(_)
+            | Expr::Value(_) => return None,
+        })
+    }
+
+    fn for_each_value(&self, f: &mut dyn FnMut(&Value)) {
+        match self {
+            Expr::Call(_, a) | Expr::Negate(a) => a.for_each_value(f),
+            Expr::CallFunction(fun, items) => {
+                for item in items {
+                    item.for_each_value(f);
+                }
+                fun.body().for_each_value(f);
+            }
+            Expr::List(items) => {
+                for item in items {
+                    item.for_each_value(f);
+                }
+            }
+            Expr::Param(_) => {}
+            Expr::Value(v) => f(v),
+            _ => {
+                if let Some((_, a, b)) = self.binary_operands() {
+                    a.for_each_value(f);
+                    b.for_each_value(f);
+                }
             }
         }
-    };
-}
-
-impl Expr {
-    pub fn compute(&self, tree: &Tree) -> Fallible<Value> {
-        map_values!(
-            self,
-            compute,
-            |tok, lhs: Value, rhs: Value| {
-                trace!("compute: reduce {:?} {:?} {:?}", lhs, tok, rhs);
-                lhs.apply(&tok, &rhs)
-            },
-            tree
-        )
     }
 
-    pub fn find_all_possible_inputs(
+    // Virtually interpret this expression over every value its inputs may take.
+    // Returns None if the set of results is not knowable ahead of time. In the
+    // body of a function, `args` are the arguments of one call to it.
+    pub fn possible_values(
         &self,
         tree: &Tree,
-        out: &mut Vec<ConcretePath>,
-    ) -> Fallible<()> {
-        trace!("Expr::find_all_possible_inputs({:?})", self);
-        map_values!(
-            self,
-            find_all_possible_inputs,
-            |_tok, _a, _b| Ok(()),
-            tree,
-            out
-        )
+        visiting: &mut Vec<ConcretePath>,
+        args: &[Value],
+    ) -> Fallible<Option<Vec<Value>>> {
+        Ok(match self {
+            Expr::Value(v) => v.possible_values(tree, visiting)?,
+            Expr::Negate(_) => None,
+            // A list literal has a single value if each of its items does.
+            Expr::List(items) => {
+                let mut list = Vec::new();
+                for item in items {
+                    match item.possible_values(tree, visiting, args)? {
+                        Some(ref mut values) if values.len() == 1 => list.append(values),
+                        _ => return Ok(None),
+                    }
+                }
+                Some(vec![Value::from_list(list)])
+            }
+            Expr::Call(fun, a) => match a.possible_values(tree, visiting, args)? {
+                Some(domain) => fun.map_domain(domain, tree)?,
+                None => None,
+            },
+            // Run the body over every combination of the values its
+            // arguments may take.
+            Expr::CallFunction(fun, items) => {
+                let mut calls = vec![Vec::new()];
+                for item in items {
+                    let values = match item.possible_values(tree, visiting, args)? {
+                        Some(values) => values,
+                        None => return Ok(None),
+                    };
+                    if calls.len() * values.len() > MAX_POSSIBLE_VALUES {
+                        return Ok(None);
+                    }
+                    calls = calls
+                        .iter()
+                        .flat_map(|call| {
+                            values.iter().map(move |v| {
+                                let mut call = call.to_owned();
+                                call.push(v.to_owned());
+                                call
+                            })
+                        })
+                        .collect();
+                }
+                let mut out = Vec::new();
+                for call in &calls {
+                    match fun.body().possible_values(tree, visiting, call)? {
+                        Some(values) => {
+                            for v in values {
+                                push_unique(&mut out, v);
+                            }
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                Some(out)
+            }
+            Expr::Param(i) => Some(vec![args[*i].to_owned()]),
+            _ => {
+                let (tok, a, b) = self.binary_operands().expect("binary operator");
+                let lhs = a.possible_values(tree, visiting, args)?;
+                let rhs = b.possible_values(tree, visiting, args)?;
+                match (lhs, rhs) {
+                    (Some(lhs), Some(rhs)) => reduce_possible_values(&tok, lhs, rhs),
+                    _ => None,
+                }
+            }
+        })
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+// Beyond this many distinct values, treat the set of results as unknown.
+pub(super) const MAX_POSSIBLE_VALUES: usize = 1024;
+
+fn push_unique(values: &mut Vec<Value>, value: Value) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+fn reduce_possible_values(tok: &Token, lhs: Vec<Value>, rhs: Vec<Value>) -> Option<Vec<Value>> {
+    let mut out = Vec::new();
+    if *tok == Token::Latch {
+        // Either side may have been the most recent to change.
+        for v in lhs.into_iter().chain(rhs) {
+            push_unique(&mut out, v);
+        }
+        return Some(out);
+    }
+    for a in &lhs {
+        for b in &rhs {
+            // Combinations that fail at runtime produce nothing.
+            if let Ok(v) = a.apply(tok, b) {
+                push_unique(&mut out, v);
+            }
+            if out.len() > MAX_POSSIBLE_VALUES {
+                return None;
+            }
+        }
+    }
+    Some(out)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum CompilationPhase {
     NeedInputMap,
     Ready,
 }
 
-#[derive(Debug)]
-struct IfStatement {
+#[derive(Clone, Debug)]
+pub(super) struct IfStatement {
     cases: Vec<(Option<Expr>, Script)>,
 }
 
@@ -139,19 +505,54 @@ impl IfStatement {
         Self { cases }
     }
 
-    pub fn compute(&self, tree: &Tree) -> Fallible<Value> {
+    #[cfg(test)]
+    pub fn compute(&self, tree: &Tree, args: &[Value]) -> Fallible<Value> {
         for (expr, stmt) in &self.cases {
             if let Some(e) = expr {
-                let cond = e.compute(tree)?;
-                ensure!(cond.is_boolean(), "if statement conditions must be boolean");
+                let cond = e.compute(tree, args)?;
+                if !cond.is_boolean() {
+                    return Err(crate::error::Error::Type(crate::error::ErrorContext::new(
+                        "if statement conditions must be boolean".to_owned(),
+                    ))
+                    .with_value(cond)
+                    .into());
+                }
                 if cond == Value::from_boolean(true) {
-                    return Ok(stmt.suite.compute(tree)?);
+                    return stmt.suite.compute(tree, args);
                 }
             } else {
-                return Ok(stmt.compute(tree)?);
+                return stmt.suite.compute(tree, args);
             }
         }
-        bail!("reached end of if conditions without at statement")
+        raise!(Runtime, "reached end of if conditions without at statement")
+    }
+
+    // Each conditional arm tests its condition, skipping to the next arm if
+    // it is false, or runs its block and jumps to the end.
+    fn compile(&self, program: &mut Program, tree: &Tree) -> Fallible<()> {
+        require!(
+            matches!(self.cases.last(), Some((None, _))),
+            Parse,
+            "if statements must have an else block"
+        );
+        let mut ends = Vec::new();
+        for (expr, stmt) in &self.cases {
+            if let Some(e) = expr {
+                e.compile(program, tree)?;
+                let skip = program.push(Op::JumpUnless(0));
+                stmt.suite.compile(program, tree)?;
+                ends.push(program.push(Op::Jump(0)));
+                let next = program.next_offset();
+                program.patch_jump(skip, next);
+            } else {
+                stmt.suite.compile(program, tree)?;
+            }
+        }
+        let end = program.next_offset();
+        for jump in ends {
+            program.patch_jump(jump, end);
+        }
+        Ok(())
     }
 
     pub fn find_all_possible_inputs(
@@ -168,24 +569,157 @@ impl IfStatement {
         Ok(())
     }
 
-    fn mark_ready(&mut self) {
+    // The type of the statement is known if every arm has the same one.
+    fn check_types(&self, params: &[Option<ValueType>]) -> Fallible<Option<ValueType>> {
+        let mut out = None;
+        for (expr, stmt) in &self.cases {
+            if let Some(e) = expr {
+                e.check_types(params)?;
+            }
+            let ty = stmt.suite.check_types(params)?;
+            out = Some(match out {
+                Some(prior) if prior != ty => None,
+                _ => ty,
+            });
+        }
+        Ok(out.flatten())
+    }
+
+    fn set_phase(&mut self, phase: CompilationPhase) {
         for (_, stmt) in self.cases.iter_mut() {
-            stmt.mark_ready();
+            stmt.set_phase(phase);
+        }
+    }
+
+    pub fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+        args: &[Value],
+    ) -> Fallible<Option<Vec<Value>>> {
+        let mut out = Vec::new();
+        for (_, stmt) in &self.cases {
+            match stmt.suite.possible_values(tree, visiting, args)? {
+                Some(values) => {
+                    for v in values {
+                        push_unique(&mut out, v);
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(out))
+    }
+
+    pub fn find_unreachable_arms(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+        out: &mut Vec<String>,
+    ) -> Fallible<()> {
+        let mut always_taken = None;
+        for (i, (expr, stmt)) in self.cases.iter().enumerate() {
+            let arm = match (i, expr) {
+                (0, _) => "`if` arm".to_owned(),
+                (_, Some(_)) => format!("`elif` arm {}", i),
+                (_, None) => "`else` arm".to_owned(),
+            };
+            if let Some(prior) = always_taken {
+                out.push(format!(
+                    "{} is unreachable: the condition of arm {} is always true",
+                    arm, prior
+                ));
+                continue;
+            }
+            if let Some(e) = expr {
+                let conds = e.possible_values(tree, visiting, &[])?;
+                if let Some(conds) = conds {
+                    if conds.iter().all(|c| *c == Value::from_boolean(false)) {
+                        out.push(format!(
+                            "{} is unreachable: its condition is always false",
+                            arm
+                        ));
+                        continue;
+                    }
+                    if conds.iter().all(|c| *c == Value::from_boolean(true)) {
+                        always_taken = Some(i);
+                    }
+                }
+            }
+            stmt.suite.find_unreachable_arms(tree, visiting, out)?;
+        }
+        Ok(())
+    }
+
+    // Arms whose conditions are known to be false are dropped, and one known
+    // to be true becomes the else arm, and so the whole statement if it is
+    // the first arm left.
+    fn fold(&self, folder: &mut Folder, args: &[Value], folds: &mut Vec<String>) -> Fallible<Stmt> {
+        let mut cases = Vec::new();
+        for (i, (expr, stmt)) in self.cases.iter().enumerate() {
+            let cond = match expr {
+                Some(e) => match e.fold(folder, args, folds)? {
+                    Expr::Value(v) if v.is_boolean() => {
+                        if !v.as_boolean()? {
+                            folds.push(format!("dropped arm {}, which is never taken", i));
+                            continue;
+                        }
+                        folds.push(format!("took arm {}, which is always taken", i));
+                        None
+                    }
+                    e => Some(e),
+                },
+                None => None,
+            };
+            let suite = stmt.suite.fold(folder, args, folds)?;
+            let last = cond.is_none();
+            cases.push((cond, Script::new(suite)));
+            if last {
+                break;
+            }
+        }
+        if let [(None, _)] = cases.as_slice() {
+            return Ok(cases.pop().unwrap().1.suite);
+        }
+        Ok(Stmt::IfStmt(IfStatement::new(cases)))
+    }
+
+    fn for_each_value(&self, f: &mut dyn FnMut(&Value)) {
+        for (expr, stmt) in &self.cases {
+            if let Some(e) = expr {
+                e.for_each_value(f);
+            }
+            stmt.suite.for_each_value(f);
         }
     }
 }
 
-#[derive(Debug)]
-enum Stmt {
+#[derive(Clone, Debug)]
+pub(super) enum Stmt {
     ExprStmt(Expr),
     IfStmt(IfStatement),
 }
 
 impl Stmt {
-    pub fn compute(&self, tree: &Tree) -> Fallible<Value> {
+    #[cfg(test)]
+    pub fn compute(&self, tree: &Tree, args: &[Value]) -> Fallible<Value> {
+        match self {
+            Self::ExprStmt(e) => e.compute(tree, args),
+            Self::IfStmt(s) => s.compute(tree, args),
+        }
+    }
+
+    fn compile(&self, program: &mut Program, tree: &Tree) -> Fallible<()> {
+        match self {
+            Self::ExprStmt(e) => e.compile(program, tree),
+            Self::IfStmt(s) => s.compile(program, tree),
+        }
+    }
+
+    fn fold(&self, folder: &mut Folder, args: &[Value], folds: &mut Vec<String>) -> Fallible<Stmt> {
         match self {
-            Self::ExprStmt(e) => e.compute(tree),
-            Self::IfStmt(s) => s.compute(tree),
+            Self::ExprStmt(e) => Ok(Self::ExprStmt(e.fold(folder, args, folds)?)),
+            Self::IfStmt(s) => s.fold(folder, args, folds),
         }
     }
 
@@ -200,54 +734,129 @@ impl Stmt {
         }
     }
 
-    fn mark_ready(&mut self) {
+    pub fn check_types(&self, params: &[Option<ValueType>]) -> Fallible<Option<ValueType>> {
+        match self {
+            Self::ExprStmt(e) => e.check_types(params),
+            Self::IfStmt(s) => s.check_types(params),
+        }
+    }
+
+    fn set_phase(&mut self, phase: CompilationPhase) {
         match self {
             Self::ExprStmt(_) => {}
-            Self::IfStmt(s) => s.mark_ready(),
+            Self::IfStmt(s) => s.set_phase(phase),
+        }
+    }
+
+    pub fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+        args: &[Value],
+    ) -> Fallible<Option<Vec<Value>>> {
+        match self {
+            Self::ExprStmt(e) => e.possible_values(tree, visiting, args),
+            Self::IfStmt(s) => s.possible_values(tree, visiting, args),
+        }
+    }
+
+    pub fn find_unreachable_arms(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+        out: &mut Vec<String>,
+    ) -> Fallible<()> {
+        match self {
+            Self::ExprStmt(_) => Ok(()),
+            Self::IfStmt(s) => s.find_unreachable_arms(tree, visiting, out),
+        }
+    }
+
+    fn for_each_value(&self, f: &mut dyn FnMut(&Value)) {
+        match self {
+            Self::ExprStmt(e) => e.for_each_value(f),
+            Self::IfStmt(s) => s.for_each_value(f),
         }
     }
 }
 
 /// The code embedded under a comes-from (<- or <-\) operator in the tree.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Script {
     suite: Stmt,
     phase: CompilationPhase,
-    input_map: HashMap<ConcretePath, NodeRef>,
+    input_map: HashMap<ConcretePath, NodeId>,
+    program: Option<Program>,
+    // What folding the script worked out ahead of time, for debugging.
+    folds: Vec<String>,
 }
 
 impl Script {
-    pub fn inline_from_tokens(
+    fn new(suite: Stmt) -> Self {
+        Script {
+            suite,
+            phase: CompilationPhase::NeedInputMap,
+            input_map: HashMap::new(),
+            program: None,
+            folds: Vec::new(),
+        }
+    }
+
+    pub(crate) fn inline_from_tokens(
         path: String,
         tokens: &[Token],
-        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        functions: &Functions,
     ) -> Fallible<Self> {
-        let mut parser = ExprParser::from_tokens(path, tokens, nifs);
-        let expr = parser.eparser()?;
-        let script = Script {
-            suite: Stmt::ExprStmt(expr),
-            phase: CompilationPhase::NeedInputMap,
-            input_map: HashMap::new(),
-        };
-        Ok(script)
+        let scope = Scope::global(functions);
+        let mut parser = ExprParser::from_tokens(path, tokens, &scope);
+        Ok(Script::new(Stmt::ExprStmt(parser.eparser()?)))
     }
 
-    pub fn block_from_tokens(
+    pub(crate) fn block_from_tokens(
         path: String,
         tokens: &[Token],
-        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        functions: &Functions,
     ) -> Fallible<Self> {
-        match tokens[0].maybe_name() {
-            Some("if") => Self::if_from_tokens(path, tokens, nifs),
+        let scope = Scope::global(functions);
+        Ok(Script::new(Self::stmt_from_tokens(path, tokens, &scope)?))
+    }
+
+    // The value of an expression that is known before the tree is built, such
+    // as the items of a `for` in the tree, so may not read any node.
+    pub(crate) fn constant_from_tokens(tokens: &[Token], functions: &Functions) -> Fallible<Value> {
+        let mut script = Self::inline_from_tokens("/".to_owned(), tokens, functions)?;
+        let mut read = None;
+        script.suite.for_each_value(&mut |v| {
+            if v.is_path() && read.is_none() {
+                read = Some(v.to_string());
+            }
+        });
+        if let Some(path) = read {
+            raise!(
+                Parse,
+                "{} is not known until the tree is built, so cannot be read here",
+                path
+            );
+        }
+        let tree = TreeBuilder::empty();
+        let input_map = script.build_input_map(&tree)?;
+        script.install_input_map(input_map)?;
+        script.compile(&tree)?;
+        script.compute(&tree)
+    }
+
+    // Parse the body of a block, which is either an if statement or a single
+    // expression.
+    pub(super) fn stmt_from_tokens(
+        path: String,
+        tokens: &[Token],
+        scope: &Scope,
+    ) -> Fallible<Stmt> {
+        match tokens.first().and_then(Token::maybe_name) {
+            Some("if") => Self::if_from_tokens(path, tokens, scope),
             _ => {
-                let mut parser = ExprParser::from_tokens(path, tokens, nifs);
-                let expr = parser.eparser()?;
-                let script = Script {
-                    suite: Stmt::ExprStmt(expr),
-                    phase: CompilationPhase::NeedInputMap,
-                    input_map: HashMap::new(),
-                };
-                Ok(script)
+                let mut parser = ExprParser::from_tokens(path, tokens, scope);
+                Ok(Stmt::ExprStmt(parser.eparser()?))
             }
         }
     }
@@ -258,115 +867,311 @@ impl Script {
                 return Ok(i);
             }
         }
-        bail!("did not find requested token: {:?}", end_token)
+        raise!(Parse, "did not find requested token: {:?}", end_token)
     }
 
     fn find_start_of_block(tokens: &[Token]) -> Fallible<usize> {
         Self::find_token(tokens, &Token::StartOfBlock)
     }
 
-    fn if_from_tokens(
-        path: String,
-        tokens: &[Token],
-        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
-    ) -> Fallible<Self> {
+    // Check for the colon, newline, and indent that open a block at `offset`
+    // and return the offset of the first token in the block.
+    pub(super) fn expect_block(tokens: &[Token], offset: usize) -> Fallible<usize> {
+        require!(
+            tokens.get(offset) == Some(&Token::StartOfBlock),
+            Parse,
+            "expect SOB"
+        );
+        require!(
+            tokens.get(offset + 1) == Some(&Token::Newline),
+            Parse,
+            "expect newline"
+        );
+        require!(
+            tokens.get(offset + 2) == Some(&Token::Indent),
+            Parse,
+            "expect indent"
+        );
+        Ok(offset + 3)
+    }
+
+    fn if_from_tokens(path: String, tokens: &[Token], scope: &Scope) -> Fallible<Stmt> {
         let mut cases: Vec<(Option<Expr>, Script)> = Vec::new();
 
         // if and block
         let cond_end = Self::find_start_of_block(tokens)?;
         let condition_tokens = &tokens[1..cond_end];
         let if_condition =
-            ExprParser::from_tokens(path.clone(), condition_tokens, nifs).eparser()?;
-        ensure!(tokens[cond_end + 0] == Token::StartOfBlock, "expect SOB");
-        ensure!(tokens[cond_end + 1] == Token::Newline, "expect newline");
-        ensure!(tokens[cond_end + 2] == Token::Indent, "expect indent");
-        let cond_end = cond_end + 3;
+            ExprParser::from_tokens(path.clone(), condition_tokens, scope).eparser()?;
+        let cond_end = Self::expect_block(tokens, cond_end)?;
         let block_end = cond_end + TreeParser::find_matching_dedent(&tokens[cond_end..]);
         let block_tokens = &tokens[cond_end..block_end];
-        let block_script = Script::block_from_tokens(path.clone(), block_tokens, nifs)?;
+        let block_script = Script::new(Self::stmt_from_tokens(path.clone(), block_tokens, scope)?);
         cases.push((Some(if_condition), block_script));
 
         // Elifs and blocks
         let mut offset = block_end;
-        while offset < tokens.len() && tokens[offset].maybe_name() == Some("elif") {
+        while tokens.get(offset).and_then(Token::maybe_name) == Some("elif") {
             let cond_end = offset + 1 + Self::find_start_of_block(&tokens[offset + 1..])?;
             let condition_tokens = &tokens[offset + 1..cond_end];
             let if_condition =
-                ExprParser::from_tokens(path.clone(), condition_tokens, nifs).eparser()?;
-            ensure!(tokens[cond_end + 0] == Token::StartOfBlock, "expect SOB");
-            ensure!(tokens[cond_end + 1] == Token::Newline, "expect newline");
-            ensure!(tokens[cond_end + 2] == Token::Indent, "expect indent");
-            let cond_end = cond_end + 3;
+                ExprParser::from_tokens(path.clone(), condition_tokens, scope).eparser()?;
+            let cond_end = Self::expect_block(tokens, cond_end)?;
             let block_end = cond_end + TreeParser::find_matching_dedent(&tokens[cond_end..]);
             let block_tokens = &tokens[cond_end..block_end];
-            let block_script = Script::block_from_tokens(path.clone(), block_tokens, nifs)?;
+            let block_script =
+                Script::new(Self::stmt_from_tokens(path.clone(), block_tokens, scope)?);
             cases.push((Some(if_condition), block_script));
             offset = block_end;
         }
 
-        ensure!(
-            tokens[offset].maybe_name() == Some("else"),
+        require!(
+            tokens.get(offset).and_then(Token::maybe_name) == Some("else"),
+            Parse,
             "if statements must have an else block"
         );
-        offset += 1;
-        ensure!(tokens[offset + 0] == Token::StartOfBlock, "expect SOB");
-        ensure!(tokens[offset + 1] == Token::Newline, "expect newline");
-        ensure!(tokens[offset + 2] == Token::Indent, "expect indent");
-        offset += 3;
+        let offset = Self::expect_block(tokens, offset + 1)?;
         let block_end = offset + TreeParser::find_matching_dedent(&tokens[offset..]);
         let block_tokens = &tokens[offset..block_end];
-        let block_script = Script::block_from_tokens(path.clone(), block_tokens, nifs)?;
+        let block_script = Script::new(Self::stmt_from_tokens(path, block_tokens, scope)?);
         cases.push((None, block_script));
 
-        let script = Script {
-            suite: Stmt::IfStmt(IfStatement::new(cases)),
-            phase: CompilationPhase::NeedInputMap,
-            input_map: HashMap::new(),
-        };
-        Ok(script)
+        Ok(Stmt::IfStmt(IfStatement::new(cases)))
     }
 
     // Note that we have to have a separate build and install phase because otherwise we'd be borrowed
     // mutable when searching for inputs and double-borrow if any children are referenced.
-    pub fn build_input_map(&self, tree: &Tree) -> Fallible<HashMap<ConcretePath, NodeRef>> {
+    pub fn build_input_map(&self, tree: &Tree) -> Fallible<HashMap<ConcretePath, NodeId>> {
         assert_eq!(self.phase, CompilationPhase::NeedInputMap);
+        self.suite.check_types(&[])?;
         let mut inputs = Vec::new();
         self.suite.find_all_possible_inputs(tree, &mut inputs)?;
         let mut input_map = HashMap::new();
         for input in inputs.drain(..) {
-            let node = tree.lookup_path(&input)?;
-            input_map.insert(input, node);
+            let node = match tree.lookup_path(&input) {
+                Ok(node) => node,
+                Err(_) => raise!(Link, "reads {}, which does not exist", input),
+            };
+            input_map.insert(input, node.id());
         }
         Ok(input_map)
     }
 
-    pub fn install_input_map(&mut self, input_map: HashMap<ConcretePath, NodeRef>) -> Fallible<()> {
+    pub fn install_input_map(&mut self, input_map: HashMap<ConcretePath, NodeId>) -> Fallible<()> {
         assert_eq!(self.phase, CompilationPhase::NeedInputMap);
         self.input_map = input_map;
-        self.suite.mark_ready();
-        self.mark_ready();
+        self.set_phase(CompilationPhase::Ready);
         Ok(())
     }
 
-    fn mark_ready(&mut self) {
-        self.phase = CompilationPhase::Ready;
+    // Forget the inputs and program, so that the script can be linked again
+    // after the tree around it has changed.
+    pub fn unlink(&mut self) {
+        self.input_map.clear();
+        self.program = None;
+        self.folds.clear();
+        self.set_phase(CompilationPhase::NeedInputMap);
     }
 
-    pub fn populate_flow_graph(&self, tgt_node: &NodeRef, graph: &mut Graph) -> Fallible<()> {
-        for src_node in self.input_map.values() {
-            graph.add_edge(src_node, tgt_node);
-        }
+    fn set_phase(&mut self, phase: CompilationPhase) {
+        self.phase = phase;
+        self.suite.set_phase(phase);
+    }
+
+    // Lower the script to a program, once its inputs have been linked. Static
+    // paths are resolved to their nodes here, so that only {...} lookups are
+    // walked when the script runs.
+    pub fn compile(&mut self, tree: &Tree) -> Fallible<()> {
+        require!(
+            self.phase == CompilationPhase::Ready,
+            Link,
+            "the inputs of a script must be linked before it is compiled"
+        );
+        let mut program = Program::default();
+        self.suite.compile(&mut program, tree)?;
+        self.program = Some(program);
+        self.folds.clear();
         Ok(())
     }
 
+    // Compile the script again, with whatever is known before the tree runs
+    // folded out of it, and return its value if that is all of it. The script
+    // itself is kept as written, for everything that reasons about it.
+    pub(super) fn fold(&mut self, folder: &mut Folder) -> Fallible<Option<Value>> {
+        require!(
+            self.phase == CompilationPhase::Ready,
+            Link,
+            "the inputs of a script must be linked before it is folded"
+        );
+        let mut folds = Vec::new();
+        let suite = self.suite.fold(folder, &[], &mut folds)?;
+        let mut program = Program::default();
+        suite.compile(&mut program, folder.tree())?;
+        self.program = Some(program);
+        self.folds = folds;
+        Ok(match suite {
+            Stmt::ExprStmt(Expr::Value(v)) if !v.is_path() => Some(v),
+            _ => None,
+        })
+    }
+
+    pub fn folds(&self) -> &[String] {
+        &self.folds
+    }
+
+    // A script that reads no other nodes is a constant.
+    pub fn is_constant(&self) -> bool {
+        self.phase == CompilationPhase::Ready && self.input_map.is_empty()
+    }
+
+    pub fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        self.suite.possible_values(tree, visiting, &[])
+    }
+
+    pub fn find_unreachable_arms(&self, tree: &Tree) -> Fallible<Vec<String>> {
+        let mut out = Vec::new();
+        self.suite
+            .find_unreachable_arms(tree, &mut Vec::new(), &mut out)?;
+        Ok(out)
+    }
+
+    // Every path in the script that contains a {...} lookup.
+    pub fn dynamic_paths(&self) -> Vec<ScriptPath> {
+        let mut out = Vec::new();
+        self.suite.for_each_value(&mut |v| {
+            if let ValueData::Path(ref p) = v.data {
+                if !p.is_concrete() {
+                    out.push(p.to_owned());
+                }
+            }
+        });
+        out
+    }
+
+    // The nodes this script reads, and whether each is only read through
+    // some possible value of a {...} lookup.
+    pub fn input_ids(&self) -> Vec<(NodeId, bool)> {
+        let mut static_inputs = Vec::new();
+        self.suite.for_each_value(&mut |v| {
+            if let ValueData::Path(ref p) = v.data {
+                p.find_concrete_inputs(&mut static_inputs).ok();
+            }
+        });
+        self.input_map
+            .iter()
+            .map(|(path, &id)| (id, !static_inputs.contains(path)))
+            .collect()
+    }
+
     pub(super) fn compute(&self, tree: &Tree) -> Fallible<Value> {
-        ensure!(
+        require!(
             self.phase == CompilationPhase::Ready,
-            "runtime error: attempting script usage before ready: {:?} => {:?}",
+            Runtime,
+            "attempting script usage before ready: {:?} => {:?}",
             self.phase,
             self.suite
         );
-        self.suite.compute(tree)
+        let value = match self.program {
+            Some(ref program) => program.run(tree),
+            None => raise!(
+                Runtime,
+                "attempting script usage before compiling: {:?}",
+                self.suite
+            ),
+        };
+        #[cfg(test)]
+        self.check_against_interpreter(tree, &value);
+        value
+    }
+
+    // Every test that computes a script also checks that the compiled program
+    // agrees with walking the AST.
+    #[cfg(test)]
+    fn check_against_interpreter(&self, tree: &Tree, compiled: &Fallible<Value>) {
+        match (compiled, self.suite.compute(tree, &[])) {
+            (Ok(a), Ok(b)) => {
+                assert_eq!(a, &b, "compiled and interpreted values differ");
+                assert_eq!(a.generation(), b.generation(), "generations differ");
+            }
+            (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string()),
+            (a, b) => panic!("compiled {:?}, but interpreted {:?}", a, b),
+        }
+    }
+}
+
+/// Folds scripts across the tree, finding the value of each node that can be
+/// known before the tree runs on the way.
+pub(super) struct Folder<'a> {
+    tree: &'a Tree,
+    // The value of every node folded so far, if it is a constant.
+    constants: HashMap<NodeId, Option<Value>>,
+}
+
+impl<'a> Folder<'a> {
+    pub fn new(tree: &'a Tree) -> Self {
+        Self {
+            tree,
+            constants: HashMap::new(),
+        }
+    }
+
+    pub fn tree(&self) -> &'a Tree {
+        self.tree
+    }
+
+    // The value of `node`, if it is a script that folds to a constant. The
+    // script is folded on the way, if it was not already.
+    pub fn constant(&mut self, node: &NodeRef) -> Fallible<Option<Value>> {
+        if let Some(value) = self.constants.get(&node.id()) {
+            return Ok(value.to_owned());
+        }
+        self.constants.insert(node.id(), None);
+        let value = node.fold(self)?;
+        self.constants.insert(node.id(), value.clone());
+        Ok(value)
+    }
+
+    fn constant_at(&mut self, path: &ConcretePath) -> Fallible<Option<Value>> {
+        match self.tree.lookup_path(path) {
+            Ok(node) => self.constant(&node),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Replace each {...} lookup in `path` that reads a constant with the name
+    // it looks up, and return the path with the generation that looking
+    // those names up would have given it.
+    fn fold_path(
+        &mut self,
+        path: &ScriptPath,
+        mut generation: usize,
+        folds: &mut Vec<String>,
+    ) -> Fallible<(ScriptPath, usize)> {
+        let mut components = Vec::new();
+        for component in path.components() {
+            if let PathComponent::Lookup(inner) = component {
+                let (folded, inner_gen) = self.fold_path(inner, 0, folds)?;
+                if let Ok(concrete) = folded.as_concrete() {
+                    if let Some(value) = self.constant_at(&concrete)? {
+                        if let Ok(name) = value.as_path_component() {
+                            folds.push(format!("looked up {} in {} as {}", component, path, name));
+                            generation = generation.max(value.generation().max(inner_gen));
+                            components.push(PathComponent::Name(name));
+                            continue;
+                        }
+                    }
+                }
+                generation = generation.max(inner_gen);
+                components.push(PathComponent::Lookup(folded));
+                continue;
+            }
+            components.push(component.to_owned());
+        }
+        Ok((ScriptPath::from_components(components), generation))
     }
 }
 
@@ -393,12 +1198,7 @@ impl Operator {
     }
 
     fn maybe_op(t: &Token, arity: usize) -> Option<&Operator> {
-        for i in OPERATORS.iter() {
-            if t == &i.token && arity == i.arity {
-                return Some(i);
-            }
-        }
-        None
+        OPERATORS.iter().find(|i| t == &i.token && arity == i.arity)
     }
 
     fn op(t: &Token, arity: usize) -> &Operator {
@@ -419,134 +1219,153 @@ impl Operator {
 }
 
 lazy_static! {
-    static ref OPERATORS: Vec<Operator> = {
-        let mut v = Vec::new();
-        v.push(Operator::new(Token::Divide, 15, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Modulo, 15, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Multiply, 15, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Subtract, 14, 1, None));
-        v.push(Operator::new(Token::Subtract, 13, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Add, 13, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::GreaterThan, 12, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::LessThan, 12, 2, Some(Assoc::Left)));
-        v.push(Operator::new(
-            Token::GreaterThanOrEquals,
-            12,
-            2,
-            Some(Assoc::Left),
-        ));
-        v.push(Operator::new(
-            Token::LessThanOrEquals,
-            12,
-            2,
-            Some(Assoc::Left),
-        ));
-        v.push(Operator::new(Token::Equals, 11, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::NotEquals, 11, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::And, 10, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Or, 9, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Latch, 8, 2, Some(Assoc::Left)));
-        v
-    };
+    static ref OPERATORS: Vec<Operator> = vec![
+        Operator::new(Token::Divide, 15, 2, Some(Assoc::Left)),
+        Operator::new(Token::Modulo, 15, 2, Some(Assoc::Left)),
+        Operator::new(Token::Multiply, 15, 2, Some(Assoc::Left)),
+        Operator::new(Token::Subtract, 14, 1, None),
+        Operator::new(Token::Subtract, 13, 2, Some(Assoc::Left)),
+        Operator::new(Token::Add, 13, 2, Some(Assoc::Left)),
+        Operator::new(Token::GreaterThan, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::LessThan, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::GreaterThanOrEquals, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::LessThanOrEquals, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::Equals, 11, 2, Some(Assoc::Left)),
+        Operator::new(Token::NotEquals, 11, 2, Some(Assoc::Left)),
+        Operator::new(Token::And, 10, 2, Some(Assoc::Left)),
+        Operator::new(Token::Or, 9, 2, Some(Assoc::Left)),
+        Operator::new(Token::Latch, 8, 2, Some(Assoc::Left)),
+    ];
+}
+
+// What the names in a script refer to: the functions it may call and, in the
+// body of a function, the parameters of that function.
+pub(super) struct Scope<'a> {
+    functions: &'a Functions,
+    params: Option<&'a [String]>,
+}
+
+impl<'a> Scope<'a> {
+    pub fn global(functions: &'a Functions) -> Self {
+        Self {
+            functions,
+            params: None,
+        }
+    }
+
+    pub fn function(functions: &'a Functions, params: &'a [String]) -> Self {
+        Self {
+            functions,
+            params: Some(params),
+        }
+    }
 }
 
 struct ExprParser<'a> {
     path: String,
     tokens: &'a [Token],
     offset: usize,
-    nifs: &'a HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+    scope: &'a Scope<'a>,
 }
 
 // Uses textbook precedence climbing.
 impl<'a> ExprParser<'a> {
-    fn from_tokens(
-        path: String,
-        tokens: &'a [Token],
-        nifs: &'a HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
-    ) -> Self {
+    fn from_tokens(path: String, tokens: &'a [Token], scope: &'a Scope<'a>) -> Self {
         Self {
             path,
             tokens,
             offset: 0,
-            nifs,
+            scope,
         }
     }
 
     fn eparser(&mut self) -> Fallible<Expr> {
         let e = self.exp_p(0)?;
-        ensure!(
+        require!(
             self.tokens[self.offset..].iter().all(|t| [
                 Token::Newline,
                 Token::Indent,
                 Token::Dedent
             ]
             .contains(t)),
-            "parse error: extra non-whitespace tokens after script"
+            Parse,
+            "extra non-whitespace tokens after script"
         );
         Ok(e)
     }
 
-    fn peek(&self) -> &Token {
-        &self.tokens[self.offset]
+    fn peek(&self) -> Fallible<&Token> {
+        match self.tokens.get(self.offset) {
+            Some(t) => Ok(t),
+            None => raise!(Parse, "unexpected end of script"),
+        }
     }
 
-    fn pop(&mut self) -> Token {
-        let op = self.tokens[self.offset].clone();
+    fn pop(&mut self) -> Fallible<Token> {
+        let op = self.peek()?.clone();
         self.offset += 1;
-        op
+        Ok(op)
     }
 
     fn exp_p(&mut self, p: usize) -> Fallible<Expr> {
         let mut t = self.p()?;
-        while self.offset < self.tokens.len()
-            && Operator::is_bin_op(&self.tokens[self.offset])
-            && Operator::precedence_of(self.peek(), 2) >= p
+        while self
+            .tokens
+            .get(self.offset)
+            .is_some_and(|t| Operator::is_bin_op(t) && Operator::precedence_of(t, 2) >= p)
         {
-            let op = self.pop();
+            let op = self.pop()?;
             let q = match Operator::assoc_of(&op) {
                 Assoc::Left => Operator::precedence_of(&op, 2) + 1,
                 //Assoc::Right => Operator::precedence_of(&op, 2),
             };
             let t1 = self.exp_p(q)?;
-            t = match op {
-                Token::Add => Expr::Add(Box::new(t), Box::new(t1)),
-                Token::And => Expr::And(Box::new(t), Box::new(t1)),
-                Token::Divide => Expr::Divide(Box::new(t), Box::new(t1)),
-                Token::Equals => Expr::Equal(Box::new(t), Box::new(t1)),
-                Token::GreaterThan => Expr::GreaterThan(Box::new(t), Box::new(t1)),
-                Token::GreaterThanOrEquals => Expr::GreaterThanOrEqual(Box::new(t), Box::new(t1)),
-                Token::LessThan => Expr::LessThan(Box::new(t), Box::new(t1)),
-                Token::LessThanOrEquals => Expr::LessThanOrEqual(Box::new(t), Box::new(t1)),
-                Token::Modulo => Expr::Modulo(Box::new(t), Box::new(t1)),
-                Token::Multiply => Expr::Multiply(Box::new(t), Box::new(t1)),
-                Token::NotEquals => Expr::NotEqual(Box::new(t), Box::new(t1)),
-                Token::Or => Expr::Or(Box::new(t), Box::new(t1)),
-                Token::Subtract => Expr::Subtract(Box::new(t), Box::new(t1)),
-                Token::Latch => Expr::Latch(Box::new(t), Box::new(t1)),
-                _ => panic!("unexpected token {:?} in binop position", op),
-            };
+            t = Expr::binary(op, t, t1)?;
         }
 
         Ok(t)
     }
 
     fn p(&mut self) -> Fallible<Expr> {
-        Ok(match self.pop() {
+        Ok(match self.pop()? {
             Token::BooleanTerm(b) => Expr::Value(Value::from_boolean(b)),
             Token::FloatTerm(f) => Expr::Value(Value::from_float(f)),
             Token::IntegerTerm(i) => Expr::Value(Value::from_integer(i)),
-            Token::PathTerm(p) => Expr::Value(Value::from_path(ScriptPath::from_str_at_path(
-                &self.path, &p,
-            )?)),
+            Token::PathTerm(p) => {
+                // A function may be called from anywhere in the tree.
+                require!(
+                    self.scope.params.is_none() || p.starts_with('/'),
+                    Parse,
+                    "paths in functions must be absolute: {}",
+                    p
+                );
+                Expr::Value(Value::from_path(ScriptPath::from_str_at_path(
+                    &self.path, &p,
+                )?))
+            }
             Token::StringTerm(s) => Expr::Value(Value::from_string(s)),
             Token::LeftParen => {
                 let t = self.exp_p(0)?;
-                ensure!(
-                    self.pop() == Token::RightParen,
-                    "parse error: expected right paren after sub-expression"
+                require!(
+                    self.pop()? == Token::RightParen,
+                    Parse,
+                    "expected right paren after sub-expression"
                 );
                 t
             }
+            Token::LeftBracket => {
+                let mut items = Vec::new();
+                while *self.peek()? != Token::RightBracket {
+                    items.push(self.exp_p(0)?);
+                    match self.pop()? {
+                        Token::Comma => {}
+                        Token::RightBracket => return Ok(Expr::List(items)),
+                        t => raise!(Parse, "expected , or ] in list, not {:?}", t),
+                    }
+                }
+                self.pop()?;
+                Expr::List(items)
+            }
             Token::Subtract => {
                 let op = Operator::op(&Token::Subtract, 1);
                 let q = op.precedence;
@@ -554,25 +1373,49 @@ impl<'a> ExprParser<'a> {
                 Expr::Negate(Box::new(t))
             }
             Token::NameTerm(name) => {
-                ensure!(
-                    self.pop() == Token::LeftParen,
-                    "parse error: expected () in call to {}",
-                    name
-                );
-                let t = self.exp_p(0)?;
-                ensure!(
-                    self.pop() == Token::RightParen,
-                    "parse error: expected right paren after call to {}",
-                    name
+                if self.tokens.get(self.offset) != Some(&Token::LeftParen) {
+                    let params = self.scope.params.unwrap_or_default();
+                    return match params.iter().position(|p| *p == name) {
+                        Some(i) => Ok(Expr::Param(i)),
+                        None => raise!(Parse, "unknown name {}", name),
+                    };
+                }
+                self.pop()?;
+                let mut args = Vec::new();
+                while *self.peek()? != Token::RightParen {
+                    args.push(self.exp_p(0)?);
+                    match self.peek()? {
+                        Token::Comma => self.offset += 1,
+                        Token::RightParen => {}
+                        t => raise!(Parse, "expected , or ) in call to {}, not {:?}", name, t),
+                    }
+                }
+                self.pop()?;
+                if let Some(nif) = self.scope.functions.native(&name) {
+                    require!(
+                        args.len() == 1,
+                        Parse,
+                        "{} takes 1 argument, but was given {}",
+                        name,
+                        args.len()
+                    );
+                    return Ok(Expr::Call(nif.box_clone(), Box::new(args.remove(0))));
+                }
+                let fun = match self.scope.functions.defined(&name) {
+                    Some(fun) => fun.to_owned(),
+                    None => raise!(Parse, "no such function {}", name),
+                };
+                require!(
+                    args.len() == fun.arity(),
+                    Parse,
+                    "{} takes {} arguments, but was given {}",
+                    name,
+                    fun.arity(),
+                    args.len()
                 );
-                let nif = self
-                    .nifs
-                    .get(&name)
-                    .ok_or_else(|| err_msg(format!("parse error: no such function {}", name)))?
-                    .clone();
-                Expr::Call(nif, Box::new(t))
-            }
-            t => panic!("parse error: unexpected token {:?}", t),
+                Expr::CallFunction(fun, args)
+            }
+            t => raise!(Parse, "unexpected token {:?} in expression", t),
         })
     }
 }
@@ -580,18 +1423,23 @@ impl<'a> ExprParser<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{float::Float, tokenizer::TreeTokenizer, tree::TreeBuilder};
+    use crate::{error::Error, float::Float, tokenizer::TreeTokenizer};
+    use failure::ensure;
 
     fn do_compute(expr: &str) -> Fallible<Value> {
         let tok = TreeTokenizer::tokenize(&format!("a <- {}", expr))?;
-        let mut script =
-            Script::inline_from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())?;
+        let mut script = Script::inline_from_tokens(
+            "/a".to_owned(),
+            &tok[2..tok.len() - 1],
+            &Functions::default(),
+        )?;
         let tree = TreeBuilder::empty();
         let input_map = script.build_input_map(&tree)?;
         ensure!(
             script.install_input_map(input_map).is_ok(),
             "typecheck failure"
         );
+        script.compile(&tree)?;
         script.compute(&tree)
     }
 
@@ -616,34 +1464,178 @@ mod test {
 
     #[test]
     fn test_script_failures() -> Fallible<()> {
-        let expect = vec!["1 + 2.", "true + false", r#" "2" - "3" "#];
+        let expect = [
+            "1 + true",
+            "true + false",
+            r#" "2" - "3" "#,
+            r#" 1 < "2" "#,
+            "-true",
+            r#" -"a" "#,
+        ];
         for expr in expect.iter() {
             assert!(do_compute(expr).is_err());
         }
         Ok(())
     }
 
+    // Each row is an expression and its value, or None if it must fail with
+    // a runtime error.
+    fn check_table(expect: &[(&str, Option<Value>)]) -> Fallible<()> {
+        for (expr, value) in expect {
+            match (do_compute(expr), value) {
+                (Ok(v), Some(value)) => assert_eq!(v, *value, "{}", expr),
+                (Err(e), None) => assert!(
+                    matches!(Error::find(&e), Some(Error::Runtime(_))),
+                    "{}: {}",
+                    expr,
+                    e
+                ),
+                (v, _) => panic!("{}: unexpected {:?}", expr, v),
+            }
+        }
+        Ok(())
+    }
+
+    fn int(i: i64) -> Option<Value> {
+        Some(Value::from_integer(i))
+    }
+
+    fn float(f: f64) -> Option<Value> {
+        Some(Value::from_float(Float::new(f).unwrap()))
+    }
+
+    fn boolean(b: bool) -> Option<Value> {
+        Some(Value::from_boolean(b))
+    }
+
+    #[test]
+    fn test_script_checked_integers() -> Fallible<()> {
+        check_table(&[
+            ("9223372036854775806 + 1", int(i64::MAX)),
+            ("9223372036854775807 + 1", None),
+            ("-9223372036854775807 - 1", int(i64::MIN)),
+            ("-9223372036854775807 - 2", None),
+            ("4611686018427387904 * 2", None),
+            ("-4611686018427387904 * 2", int(i64::MIN)),
+            ("1 / 0", None),
+            ("1 % 0", None),
+            ("(-9223372036854775807 - 1) % -1", None),
+            ("0 / 5", float(0.)),
+            ("-(2 + 3)", int(-5)),
+            ("-(-2)", int(2)),
+            ("-(0 - 9223372036854775807)", int(i64::MAX)),
+            ("-(-9223372036854775807 - 1)", None),
+        ])
+    }
+
+    #[test]
+    fn test_script_mixed_numbers() -> Fallible<()> {
+        check_table(&[
+            ("1 + 2.5", float(3.5)),
+            ("2.5 + 1", float(3.5)),
+            ("3 - 0.5", float(2.5)),
+            ("2 * 0.25", float(0.5)),
+            ("1 / 4.", float(0.25)),
+            ("7 % 2.5", float(2.)),
+            ("1 == 1.", boolean(true)),
+            ("1 != 1.5", boolean(true)),
+            ("2 > 1.5", boolean(true)),
+            ("1.5 < 1", boolean(false)),
+            ("2 >= 2.", boolean(true)),
+            ("1. <= 1", boolean(true)),
+            ("1 :: 2.", float(1.)),
+            ("1. / 0", None),
+            ("-(2.5)", float(-2.5)),
+            ("-(1 + 0.5)", float(-1.5)),
+            ("1 - -(0.5)", float(1.5)),
+        ])
+    }
+
+    #[test]
+    fn test_script_modulo_sign() -> Fallible<()> {
+        check_table(&[
+            ("7 % 3", int(1)),
+            ("-7 % 3", int(2)),
+            ("7 % -3", int(-2)),
+            ("-7 % -3", int(-1)),
+            ("-6 % 3", int(0)),
+            ("-1 % 60", int(59)),
+            ("-7.5 % 2.", float(0.5)),
+            ("7.5 % -2.", float(-0.5)),
+        ])
+    }
+
+    #[test]
+    fn test_script_string_ordering() -> Fallible<()> {
+        check_table(&[
+            (r#" "a" < "b" "#, boolean(true)),
+            (r#" "b" < "a" "#, boolean(false)),
+            (r#" "ab" > "a" "#, boolean(true)),
+            (r#" "B" < "a" "#, boolean(true)),
+            (r#" "a" >= "a" "#, boolean(true)),
+            (r#" "a" <= "b" "#, boolean(true)),
+            (r#" "a" != "b" "#, boolean(true)),
+            (r#" "" < "a" "#, boolean(true)),
+        ])
+    }
+
     #[test]
     fn test_script_or() -> Fallible<()> {
         let tok = TreeTokenizer::tokenize("a <- true || true")?;
-        ExprParser::from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())
-            .eparser()?;
+        let functions = Functions::default();
+        ExprParser::from_tokens(
+            "/a".to_owned(),
+            &tok[2..tok.len() - 1],
+            &Scope::global(&functions),
+        )
+        .eparser()?;
         Ok(())
     }
 
     #[test]
     fn test_script_inputs() -> Fallible<()> {
         let tok = TreeTokenizer::tokenize("a <- /foo/bar/baz")?;
-        ExprParser::from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())
-            .eparser()?;
+        let functions = Functions::default();
+        ExprParser::from_tokens(
+            "/a".to_owned(),
+            &tok[2..tok.len() - 1],
+            &Scope::global(&functions),
+        )
+        .eparser()?;
         Ok(())
     }
 
     #[test]
     fn test_script_negate() -> Fallible<()> {
         let tok = TreeTokenizer::tokenize("a <- -/foo/bar/baz")?;
-        ExprParser::from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())
-            .eparser()?;
+        let functions = Functions::default();
+        ExprParser::from_tokens(
+            "/a".to_owned(),
+            &tok[2..tok.len() - 1],
+            &Scope::global(&functions),
+        )
+        .eparser()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_list_broadcast() -> Fallible<()> {
+        let list = |v: Vec<i64>| {
+            Some(Value::from_list(
+                v.into_iter().map(Value::from_integer).collect(),
+            ))
+        };
+        check_table(&[
+            ("[1, 2] + 1", list(vec![2, 3])),
+            ("10 - [1, 2]", list(vec![9, 8])),
+            ("[1, 2] * [3, 4]", list(vec![3, 8])),
+            ("[1] + [1, 2]", None),
+            ("-[1, -2]", list(vec![-1, 2])),
+        ])?;
+        assert_eq!(
+            do_compute("[1, 2] == 2")?,
+            Value::from_list(vec![Value::from_boolean(false), Value::from_boolean(true)])
+        );
         Ok(())
     }
 }
@@ -0,0 +1,239 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+//
+// Serde support, behind the `serde` feature.
+//
+// Values encode as the plainest thing the format offers: booleans, integers,
+// floats, strings, and lists map directly onto their counterparts, so a value
+// round trips through the JSON a device speaks without any wrapping. Paths are
+// the exception; they would be indistinguishable from strings, so they encode
+// as a map with a single "path" key. Paths and dimensions otherwise encode as
+// the same text that the parser reads.
+use crate::{
+    float::Float,
+    path::{ConcretePath, ScriptPath},
+    physical::Dimension2,
+    value::{Value, ValueData},
+};
+use serde::{
+    de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor},
+    ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer},
+};
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+const PATH_KEY: &str = "path";
+
+impl Serialize for Float {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.value)
+    }
+}
+
+impl<'de> Deserialize<'de> for Float {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = f64::deserialize(deserializer)?;
+        Float::new(value).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ConcretePath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConcretePath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        ConcretePath::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ScriptPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ScriptPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if !s.starts_with('/') {
+            return Err(de::Error::custom(format!("path is not absolute: {}", s)));
+        }
+        ScriptPath::from_str_at_path("/", &s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Dimension2 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Dimension2 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Dimension2::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ValueData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ValueData::Boolean(b) => serializer.serialize_bool(*b),
+            ValueData::Float(f) => f.serialize(serializer),
+            ValueData::Integer(i) => serializer.serialize_i64(*i),
+            ValueData::String(s) => serializer.serialize_str(s),
+            ValueData::List(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            ValueData::Path(path) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(PATH_KEY, path)?;
+                map.end()
+            }
+            ValueData::InputFlag => Err(ser::Error::custom(
+                "input flags are placeholders and cannot be serialized",
+            )),
+        }
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a boolean, number, string, list, or path")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::from_boolean(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::from_integer(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        i64::try_from(v)
+            .map(Value::from_integer)
+            .map_err(|_| E::custom(format!("integer out of range: {}", v)))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Float::new(v).map(Value::from_float).map_err(E::custom)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::new_str(v))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::from_string(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element::<Value>()? {
+            items.push(item);
+        }
+        Ok(Value::from_list(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let path = match map.next_key::<String>()? {
+            Some(key) if key == PATH_KEY => map.next_value::<ScriptPath>()?,
+            Some(key) => return Err(de::Error::unknown_field(&key, &[PATH_KEY])),
+            None => return Err(de::Error::missing_field(PATH_KEY)),
+        };
+        if let Some(key) = map.next_key::<String>()? {
+            return Err(de::Error::unknown_field(&key, &[PATH_KEY]));
+        }
+        Ok(Value::from_path(path))
+    }
+}
+
+impl<'de> Deserialize<'de> for ValueData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Value::deserialize(deserializer)?.data)
+    }
+}
+
+// The generation is bookkeeping for latching within one tree, so it is not
+// part of the encoding: a deserialized value is as old as a fresh one.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.data.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure::Fallible;
+    use serde_json::json;
+
+    #[test]
+    fn test_serde_value_round_trip() -> Fallible<()> {
+        let values = vec![
+            Value::from_boolean(true),
+            Value::from_integer(-42),
+            Value::from_float(Float::new(1.5)?),
+            Value::new_str("foo"),
+            Value::from_list(vec![Value::from_integer(1), Value::new_str("a")]),
+            Value::from_path(ScriptPath::from_str_at_path("/", "/a/{/b/c}/d")?),
+        ];
+        for value in values {
+            let s = serde_json::to_string(&value)?;
+            assert_eq!(serde_json::from_str::<Value>(&s)?, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_value_is_plain_json() -> Fallible<()> {
+        let value = Value::from_list(vec![
+            Value::from_boolean(false),
+            Value::from_integer(2),
+            Value::from_float(Float::new(0.5)?),
+            Value::new_str("on"),
+        ]);
+        assert_eq!(serde_json::to_value(&value)?, json!([false, 2, 0.5, "on"]));
+        let path = Value::from_path(ScriptPath::from_str_at_path("/", "/a/b")?);
+        assert_eq!(serde_json::to_value(&path)?, json!({"path": "/a/b"}));
+        assert!(serde_json::to_value(Value::input_flag()).is_err());
+        assert!(serde_json::from_value::<Value>(json!(null)).is_err());
+        assert!(serde_json::from_value::<Value>(json!({"paths": "/a"})).is_err());
+        assert!(serde_json::from_value::<Value>(json!(u64::MAX)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_paths_and_dimensions() -> Fallible<()> {
+        let path = ConcretePath::from_str("/a/b")?;
+        assert_eq!(serde_json::to_value(&path)?, json!("/a/b"));
+        assert_eq!(serde_json::from_value::<ConcretePath>(json!("/a/b"))?, path);
+        assert!(serde_json::from_value::<ConcretePath>(json!("a/b")).is_err());
+        assert!(serde_json::from_value::<ScriptPath>(json!("a/b")).is_err());
+
+        let dim = Dimension2::from_str("2'x6\"")?;
+        let s = serde_json::to_string(&dim)?;
+        assert_eq!(serde_json::from_str::<Dimension2>(&s)?, dim);
+        assert!(serde_json::from_value::<Dimension2>(json!("@1x1")).is_err());
+        Ok(())
+    }
+}
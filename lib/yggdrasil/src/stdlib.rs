@@ -0,0 +1,188 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+//
+// The standard library: .ygg modules built into the crate, so that every house
+// does not need to write its own palettes and switch tables. They are imported
+// as std/<name>.ygg, which is the current version, or std/v<N>/<name>.ygg to
+// pin a version; only the current version is shipped.
+use failure::Fallible;
+
+/// The version of the standard library that std/<name>.ygg refers to.
+pub const STD_VERSION: u32 = 1;
+
+const MODULES: &[(&str, &str)] = &[
+    ("hue-light.ygg", include_str!("../std/hue-light.ygg")),
+    ("palette.ygg", include_str!("../std/palette.ygg")),
+    ("room.ygg", include_str!("../std/room.ygg")),
+    ("switch.ygg", include_str!("../std/switch.ygg")),
+];
+
+// The source of the standard module that `filename` names, or None if it does
+// not name one.
+pub(crate) fn module(filename: &str) -> Fallible<Option<&'static str>> {
+    let name = match filename.strip_prefix("std/") {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let name = match name.split_once('/') {
+        Some((version, name)) => {
+            require!(
+                version == format!("v{}", STD_VERSION),
+                Parse,
+                "cannot import {}: this is version {} of the standard library",
+                filename,
+                STD_VERSION
+            );
+            name
+        }
+        None => name,
+    };
+    match MODULES.iter().find(|(n, _)| *n == name) {
+        Some((_, source)) => Ok(Some(source)),
+        None => raise!(
+            Parse,
+            "there is no module {} in the standard library",
+            filename
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{error::Error, scenario::Scenario, tree::TreeBuilder, value::Value};
+    use failure::Fallible;
+
+    fn scenario(source: &str) -> Fallible<Scenario> {
+        Scenario::new(TreeBuilder::default(), source)
+    }
+
+    fn s(v: &str) -> Value {
+        Value::new_str(v)
+    }
+
+    #[test]
+    fn test_std_palette() -> Fallible<()> {
+        scenario(
+            r#"
+import(std/palette.ygg) as palette
+mode ^knob
+    domain <- ["global-on", "global-off", "emergency"]
+    default <- "global-on"
+hue <- /palette/hue/{./mode}/low
+effect <- /palette/glow-effect/moonlight
+"#,
+        )?
+        .expect("/hue", s("bhs(64, 34495, 254)"))?
+        .expect("/effect", s("flame"))?
+        .event("/mode", s("global-off"))?
+        .expect("/hue", s("none"))?
+        .event("/mode", s("emergency"))?
+        .expect("/hue", s("bhs(254, 0, 254)"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_std_switch() -> Fallible<()> {
+        scenario(
+            r#"
+import(std/switch.ygg) as semantics
+knifeswitch ^knob
+    domain <- ["up", "open", "down"]
+    default <- "up"
+button ^glowswitch
+    domain <- [0, 1, 2, 3]
+    default <- 3
+emer <- /semantics/knifeswitch-emergency/{./knifeswitch}
+ctrl <- /semantics/knifeswitch-control/{./knifeswitch}
+level <- /semantics/glowswitch/{./button}
+"#,
+        )?
+        .expect("/emer", s("global-on"))?
+        .expect("/level", s("off"))?
+        .event("/knifeswitch", s("down"))?
+        .expect("/emer", s("emergency"))?
+        .expect("/ctrl", s("global-off"))?
+        .event("/button", Value::from_integer(2))?
+        .expect("/level", s("moonlight"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_std_room() -> Fallible<()> {
+        scenario(
+            r#"
+rooms
+    bedroom
+        import(std/room.ygg)
+    office
+        import(std/room.ygg)
+"#,
+        )?
+        .expect("/rooms/bedroom/level", s("off"))?
+        .event("/rooms/bedroom/switch", s("low"))?
+        .expect("/rooms/bedroom/level", s("off"))?
+        .event("/rooms/bedroom/occupied", Value::from_boolean(true))?
+        .expect("/rooms/bedroom/level", s("low"))?
+        .expect("/rooms/office/level", s("off"))?
+        .event("/rooms/bedroom/occupied", Value::from_boolean(false))?
+        .expect("/rooms/bedroom/level", s("off"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_std_hue_light() -> Fallible<()> {
+        scenario(
+            r#"
+import(std/v1/palette.ygg) as palette
+import(std/switch.ygg) as semantics
+knifeswitch ^knob
+    domain <- ["up", "open", "down"]
+    default <- "up"
+mode <- /semantics/knifeswitch-emergency/{./knifeswitch}
+rooms
+    bedroom
+        import(std/room.ygg)
+        import(std/hue-light.ygg) as ceiling
+"#,
+        )?
+        .expect("/rooms/bedroom/ceiling/color", s("none"))?
+        .event("/rooms/bedroom/occupied", Value::from_boolean(true))?
+        .event("/rooms/bedroom/switch", s("on"))?
+        .expect("/rooms/bedroom/ceiling/color", s("bhs(254, 34495, 254)"))?
+        .event("/knifeswitch", s("down"))?
+        .expect("/rooms/bedroom/ceiling/color", s("bhs(254, 0, 254)"))?
+        .event("/knifeswitch", s("open"))?
+        .expect("/rooms/bedroom/ceiling/color", s("none"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_std_errors() {
+        let cases = [
+            (
+                "import(std/v2/room.ygg)\n",
+                "cannot import std/v2/room.ygg: this is version 1 of the standard library",
+            ),
+            (
+                "import(std/lamp.ygg)\n",
+                "there is no module std/lamp.ygg in the standard library",
+            ),
+            (
+                "import(std/room.ygg)\nswitch <- \"on\"\n",
+                "there is already a node at /switch",
+            ),
+        ];
+        for (source, message) in &cases {
+            let err = scenario(source).err().expect(source);
+            assert!(
+                Error::find_all(&err)
+                    .iter()
+                    .any(|e| e.message().contains(message)),
+                "{}: {}",
+                source,
+                err
+            );
+        }
+    }
+}
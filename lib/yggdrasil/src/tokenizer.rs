@@ -1,8 +1,12 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
-use crate::{float::Float, physical::Dimension2};
-use failure::{bail, ensure, Fallible};
+use crate::{
+    error::{Error, ErrorContext, Span},
+    float::Float,
+    physical::Dimension2,
+};
+use failure::Fallible;
 use tracing::trace;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -22,6 +26,7 @@ pub enum Token {
     ComesFromInline,      // <-
     ComesFromBlock,       // <-\
     UseTemplate(String),  // !
+    Tag(String),          // #
 
     // Operators
     Add,                 // +
@@ -40,6 +45,9 @@ pub enum Token {
     Latch,               // ::
     LeftParen,           // (
     RightParen,          // )
+    LeftBracket,         // [
+    RightBracket,        // ]
+    Comma,               // ,
 
     // Terminals
     NameTerm(String),   // [a-zA-Z][a-zA-Z0-9]*
@@ -55,7 +63,7 @@ impl Token {
     fn path_string(&self) -> Fallible<&str> {
         match self {
             Token::PathTerm(s) => Ok(s),
-            _ => bail!("tokenize error: expected a path token"),
+            _ => raise!(Tokenize, "expected a path token"),
         }
     }
 
@@ -67,59 +75,213 @@ impl Token {
     }
 }
 
+/// A token and the place in the source where it starts.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+impl SpannedToken {
+    fn new(token: Token, line: usize, column: usize) -> Self {
+        Self {
+            token,
+            span: Span { line, column },
+        }
+    }
+}
+
 pub struct TreeTokenizer {}
 
 impl TreeTokenizer {
-    #[allow(clippy::comparison_chain)]
     pub fn tokenize(s: &str) -> Fallible<Vec<Token>> {
+        Ok(Self::tokenize_spanned(s)?
+            .into_iter()
+            .map(|t| t.token)
+            .collect())
+    }
+
+    // As tokenize, but keeping where each token starts. A line continues onto
+    // the next if it ends with a \, or while a ( or [ on it is still open. The
+    // indentation of a continued line is not significant. A line that fails
+    // to tokenize is skipped, so that every bad line is reported together.
+    #[allow(clippy::comparison_chain)]
+    pub(crate) fn tokenize_spanned(s: &str) -> Fallible<Vec<SpannedToken>> {
         let mut tokens = Vec::new();
+        let mut errors = Vec::new();
 
         let mut indent = vec![0];
-        for line_raw in s.lines() {
-            let line = LineTokenizer::trim_comment(line_raw);
+        let mut open: Vec<Span> = Vec::new();
+        let mut continued = false;
+        let mut last_line = 0;
+        let mut comments = Comments::default();
+        for (number, line_raw) in s.lines().enumerate() {
+            let number = number + 1;
+            let code = match comments.start(line_raw) {
+                Some(offset) => &line_raw[..offset],
+                None => line_raw,
+            };
+            let line = code.trim_end().to_owned();
             if line.is_empty() {
                 continue;
             }
+            last_line = number;
 
             let last_level = *indent.last().unwrap();
             let current_level = LineTokenizer::leading_whitespace(&line);
-            if current_level > last_level {
+            if continued {
+                // The indentation belongs to the line being continued.
+            } else if current_level > last_level {
                 indent.push(current_level);
-                tokens.push(Token::Indent);
+                tokens.push(SpannedToken::new(Token::Indent, number, 1));
             } else if current_level < last_level {
                 if let Ok(offset) = indent.binary_search(&current_level) {
                     let cnt = indent.len() - offset - 1;
                     for _ in 0..cnt {
                         indent.pop();
-                        tokens.push(Token::Dedent);
+                        tokens.push(SpannedToken::new(Token::Dedent, number, 1));
                     }
                 } else {
-                    bail!("dedent not aligned with a prior indent level");
+                    errors.push(tokenize_error(
+                        "dedent not aligned with a prior indent level",
+                        number,
+                        1,
+                    ));
+                    comments.end_line(&line, false);
+                    continue;
                 }
             }
 
-            let mut lt = LineTokenizer {
-                chars: line.chars().collect::<Vec<char>>(),
-                offset: 0,
+            let (line_tokens, explicit) = match LineTokenizer::tokenize_line(&line, number) {
+                Ok(out) => out,
+                Err(err) => {
+                    errors.push(err);
+                    open.clear();
+                    continued = false;
+                    comments.end_line(&line, false);
+                    continue;
+                }
             };
-            while !lt.is_empty() {
-                lt.skip_space();
-                let token = lt.tokenize_one()?;
-                tokens.push(token);
+            for t in &line_tokens {
+                match t.token {
+                    Token::LeftParen | Token::LeftBracket => open.push(t.span),
+                    Token::RightParen | Token::RightBracket => {
+                        open.pop();
+                    }
+                    _ => {}
+                }
             }
-            tokens.push(Token::Newline);
+            tokens.extend(line_tokens);
+            continued = explicit || !open.is_empty();
+            comments.end_line(&line, continued);
+            if !continued {
+                let end = line.chars().count() + 1;
+                tokens.push(SpannedToken::new(Token::Newline, number, end));
+            }
+        }
+        if let Some(&span) = open.first() {
+            errors.push(tokenize_error("unclosed ( or [", span.line, span.column));
+        } else if continued {
+            errors.push(tokenize_error(
+                "line continuation at end of input",
+                last_line,
+                1,
+            ));
         }
 
+        if !errors.is_empty() {
+            return Err(Error::combine(errors));
+        }
         Ok(tokens)
     }
 }
 
+fn tokenize_error(message: &str, line: usize, column: usize) -> failure::Error {
+    Error::annotate_span(
+        Error::Tokenize(ErrorContext::new(message.to_owned())).into(),
+        Span { line, column },
+    )
+}
+
+// Finds the comment on each line of a source in turn, knowing which lines
+// are in a script: the rest of a line after <-, lines continued from it, and
+// the block under a <-\\.
+#[derive(Default)]
+pub(crate) struct Comments {
+    // The indentation of the line that the current line continues, if any.
+    level: usize,
+    continued: bool,
+    // Whether the end of the line so far is in a script.
+    script: bool,
+    // The indentation of the node whose <-\\ block is being read.
+    block: Option<usize>,
+}
+
+impl Comments {
+    // The byte offset of the # that starts a comment on the next line.
+    pub(crate) fn start(&mut self, line_raw: &str) -> Option<usize> {
+        if !self.continued {
+            let level = LineTokenizer::leading_whitespace(line_raw);
+            let code = !line_raw.trim().is_empty() && !line_raw.trim_start().starts_with('#');
+            if code {
+                if self.block.is_some_and(|block| level <= block) {
+                    self.block = None;
+                }
+                self.level = level;
+            }
+            self.script = self.block.is_some();
+        }
+        let (start, script) = LineTokenizer::comment_start(line_raw, self.script);
+        self.script = script;
+        start
+    }
+
+    // Record the end of a line that had code, given its code and whether it
+    // continues onto the next line.
+    pub(crate) fn end_line(&mut self, code: &str, continued: bool) {
+        if self.block.is_none() && code.trim_end().ends_with("<-\\") {
+            self.block = Some(self.level);
+        }
+        self.continued = continued;
+    }
+}
+
 pub struct LineTokenizer {
     chars: Vec<char>,
     offset: usize,
 }
 
 impl LineTokenizer {
+    // Tokenize a single line with comments already removed, with spans at the
+    // given line of the source. Also returns whether the line ends with a \,
+    // continuing it onto the next.
+    pub(crate) fn tokenize_line(line: &str, number: usize) -> Fallible<(Vec<SpannedToken>, bool)> {
+        let mut tokens = Vec::new();
+        let mut lt = LineTokenizer {
+            chars: line.chars().collect::<Vec<char>>(),
+            offset: 0,
+        };
+        while !lt.is_empty() {
+            lt.skip_space();
+            let start = lt.offset;
+            if lt.maybe_peek(0) == Some('\\') && lt.chars[lt.offset + 1..].iter().all(|&c| c == ' ')
+            {
+                return Ok((tokens, true));
+            }
+            let token = lt.tokenize_one().map_err(|e| {
+                Error::annotate_span(
+                    e,
+                    Span {
+                        line: number,
+                        column: start + 1,
+                    },
+                )
+            })?;
+            tokens.push(SpannedToken::new(token, number, start + 1));
+        }
+        Ok((tokens, false))
+    }
+
     fn skip_space(&mut self) {
         while self.maybe_peek(0) == Some(' ') {
             self.offset += 1;
@@ -139,9 +301,11 @@ impl LineTokenizer {
             '.' => self.tokenize_path(),
             '^' => self.tokenize_source(),
             '$' => self.tokenize_sink(),
+            '#' => self.tokenize_tag(),
             '!' => self.tokenize_use_template_or_not_eq(),
             '@' => self.tokenize_location(),
             '"' => self.tokenize_string(),
+            '`' => Ok(Token::NameTerm(self.tokenize_quoted_name()?)),
             '<' => self.tokenize_comes_from_or_less_than_or_size(),
             '>' => self.tokenize_greater_than(),
             '|' | '&' => self.tokenize_operator_2(),
@@ -155,6 +319,18 @@ impl LineTokenizer {
                 self.offset += 1;
                 Ok(Token::RightParen)
             }
+            '[' => {
+                self.offset += 1;
+                Ok(Token::LeftBracket)
+            }
+            ']' => {
+                self.offset += 1;
+                Ok(Token::RightBracket)
+            }
+            ',' => {
+                self.offset += 1;
+                Ok(Token::Comma)
+            }
             '+' => {
                 self.offset += 1;
                 Ok(Token::Add)
@@ -168,12 +344,13 @@ impl LineTokenizer {
                 Ok(Token::Modulo)
             }
             '=' => {
-                ensure!(self.peek(1)? == '=', "expected double == sign");
+                require!(self.peek(1)? == '=', Tokenize, "expected double == sign");
                 self.offset += 2;
                 Ok(Token::Equals)
             }
-            _ => bail!(
-                "tokenize error: expected a sigil or name, found: {}",
+            _ => raise!(
+                Tokenize,
+                "expected a sigil or name, found: {}",
                 self.chars[self.offset]
             ),
         }?;
@@ -198,17 +375,19 @@ impl LineTokenizer {
 
     fn tokenize_import(&mut self) -> Fallible<Token> {
         self.skip_space();
-        ensure!(
+        require!(
             self.peek(0)? == '(',
-            "tokenize error: expected import to take an argument in ()"
+            Tokenize,
+            "expected import to take an argument in ()"
         );
         self.offset += 1;
         self.skip_space();
         let filename = self.tokenize_path()?;
         self.skip_space();
-        ensure!(
+        require!(
             self.peek(0)? == ')',
-            "tokenize error: expected closing ) in import"
+            Tokenize,
+            "expected closing ) in import"
         );
         self.offset += 1;
         Ok(Token::ImportTerm(filename.path_string()?.to_owned()))
@@ -218,8 +397,9 @@ impl LineTokenizer {
         self.skip_space();
         let _name = self.tokenize_identifier()?;
         self.skip_space();
-        ensure!(
+        require!(
             self.peek(0)? == '[',
+            Tokenize,
             "expected template to be surrounded with []"
         );
         self.offset += 1;
@@ -234,9 +414,11 @@ impl LineTokenizer {
         Ok(Token::Template)
     }
 
+    // A minus is part of a number only when a digit follows it; otherwise it
+    // subtracts, or negates what follows, as in -/a or -(1 + 2).
     fn tokenize_subtract_or_number(&mut self) -> Fallible<Token> {
         if let Some(c) = self.maybe_peek(1) {
-            if c == ' ' || c == '/' || c == '.' {
+            if !c.is_ascii_digit() {
                 self.offset += 1;
                 return Ok(Token::Subtract);
             }
@@ -277,27 +459,33 @@ impl LineTokenizer {
         }
         let s = self.chars[start..self.offset].iter().collect::<String>();
         if contains_dot {
-            return Ok(Token::FloatTerm(Float::new(
-                negative as f64 * s.parse::<f64>()?,
-            )?));
+            return match s.parse::<f64>() {
+                Ok(f) => Ok(Token::FloatTerm(Float::new(negative as f64 * f)?)),
+                Err(_) => raise!(Tokenize, "invalid number: {}", s),
+            };
+        }
+        match s.parse::<i64>() {
+            Ok(i) => Ok(Token::IntegerTerm(negative * i)),
+            Err(_) => raise!(Tokenize, "invalid number: {}", s),
         }
-        Ok(Token::IntegerTerm(negative * s.parse::<i64>()?))
     }
 
     fn tokenize_source(&mut self) -> Fallible<Token> {
-        assert!(self.peek(0)? == '^');
         self.offset += 1;
         Ok(Token::Source(self.tokenize_identifier()?))
     }
 
     fn tokenize_sink(&mut self) -> Fallible<Token> {
-        assert!(self.peek(0)? == '$');
         self.offset += 1;
         Ok(Token::Sink(self.tokenize_identifier()?))
     }
 
+    fn tokenize_tag(&mut self) -> Fallible<Token> {
+        self.offset += 1;
+        Ok(Token::Tag(self.tokenize_identifier()?))
+    }
+
     fn tokenize_absolute_path_or_division(&mut self) -> Fallible<Token> {
-        assert!(self.peek(0)? == '/');
         match self.maybe_peek(1) {
             None | Some(' ') => self.tokenize_division(),
             _ => self.tokenize_path(),
@@ -319,7 +507,11 @@ impl LineTokenizer {
     }
 
     fn tokenize_location(&mut self) -> Fallible<Token> {
-        ensure!(self.peek(0)? == '@', "expected location start token");
+        require!(
+            self.peek(0)? == '@',
+            Tokenize,
+            "expected location start token"
+        );
         self.offset += 1;
         let start = self.offset;
         while !self.is_empty() {
@@ -333,8 +525,8 @@ impl LineTokenizer {
     }
 
     fn tokenize_size(&mut self) -> Fallible<Token> {
-        ensure!(self.peek(0)? == '<', "expected size start token0");
-        ensure!(self.peek(1)? == '>', "expected size start token1");
+        require!(self.peek(0)? == '<', Tokenize, "expected size start token0");
+        require!(self.peek(1)? == '>', Tokenize, "expected size start token1");
         self.offset += 2;
         let start = self.offset;
         while !self.is_empty() {
@@ -348,30 +540,88 @@ impl LineTokenizer {
     }
 
     fn tokenize_string(&mut self) -> Fallible<Token> {
-        assert_eq!(self.peek(0)?, '"');
-        let mut out = Vec::new();
+        let mut out = String::new();
         self.offset += 1;
         while !self.is_empty() {
             match self.chars[self.offset] {
                 '\\' => {
-                    ensure!(
-                        self.peek(1)? == '"',
-                        "tokenize error: unsupported \\ escape"
-                    );
-
-                    // Skip the following quote.
-                    out.push('"');
                     self.offset += 1;
+                    out.push(self.tokenize_escape()?);
                 }
                 '"' => {
                     self.offset += 1;
-                    return Ok(Token::StringTerm(out.iter().collect::<String>()));
+                    return Ok(Token::StringTerm(out));
+                }
+                c => {
+                    out.push(c);
+                    self.offset += 1;
+                }
+            }
+        }
+        raise!(Tokenize, "unmatched \"")
+    }
+
+    // The character escaped by the \ just before the offset: one of \", \\,
+    // \n, \t, or \u{...} with up to six hex digits.
+    fn tokenize_escape(&mut self) -> Fallible<char> {
+        let c = self.peek(0)?;
+        self.offset += 1;
+        Ok(match c {
+            '"' => '"',
+            '\\' => '\\',
+            'n' => '\n',
+            't' => '\t',
+            'u' => {
+                require!(
+                    self.maybe_peek(0) == Some('{'),
+                    Tokenize,
+                    "expected {{ after \\u"
+                );
+                self.offset += 1;
+                let start = self.offset;
+                while self.maybe_peek(0).is_some_and(|c| c.is_ascii_hexdigit()) {
+                    self.offset += 1;
+                }
+                let digits = self.chars[start..self.offset].iter().collect::<String>();
+                require!(
+                    self.maybe_peek(0) == Some('}'),
+                    Tokenize,
+                    "expected }} to end \\u{{{}",
+                    digits
+                );
+                self.offset += 1;
+                let code = match u32::from_str_radix(&digits, 16) {
+                    Ok(code) if digits.len() <= 6 => code,
+                    _ => raise!(Tokenize, "invalid \\u escape: {}", digits),
+                };
+                match std::char::from_u32(code) {
+                    Some(c) => c,
+                    None => raise!(Tokenize, "\\u{{{}}} is not a character", digits),
                 }
-                c => out.push(c),
             }
+            c => raise!(Tokenize, "unsupported escape: \\{}", c),
+        })
+    }
+
+    // A name in backquotes, which may be a keyword or contain any character
+    // except for a backquote or /.
+    fn tokenize_quoted_name(&mut self) -> Fallible<String> {
+        self.offset += 1;
+        let start = self.offset;
+        while self.maybe_peek(0).is_some_and(|c| c != '`') {
             self.offset += 1;
         }
-        bail!("tokenize error: unmatched \"")
+        require!(!self.is_empty(), Tokenize, "unmatched `");
+        let name = self.chars[start..self.offset].iter().collect::<String>();
+        self.offset += 1;
+        require!(!name.is_empty(), Tokenize, "empty quoted name");
+        require!(
+            !name.contains('/'),
+            Tokenize,
+            "quoted names may not contain /: {}",
+            name
+        );
+        Ok(name)
     }
 
     fn tokenize_comes_from_or_less_than_or_size(&mut self) -> Fallible<Token> {
@@ -401,24 +651,19 @@ impl LineTokenizer {
     }
 
     fn tokenize_greater_than(&mut self) -> Fallible<Token> {
-        assert!(self.peek(0)? == '>');
-        if self.peek(1)? == '=' {
+        if self.maybe_peek(1) == Some('=') {
+            self.offset += 2;
             return Ok(Token::GreaterThanOrEquals);
         }
+        self.offset += 1;
         Ok(Token::GreaterThan)
     }
 
     fn tokenize_operator_2(&mut self) -> Fallible<Token> {
-        let t = match self.peek(1)? {
-            '&' => {
-                assert!(self.peek(0)? == '&');
-                Token::And
-            }
-            '|' => {
-                assert!(self.peek(0)? == '|');
-                Token::Or
-            }
-            _ => bail!("tokenize error: expected && or ||"),
+        let t = match (self.peek(0)?, self.maybe_peek(1)) {
+            ('&', Some('&')) => Token::And,
+            ('|', Some('|')) => Token::Or,
+            _ => raise!(Tokenize, "expected && or ||"),
         };
         self.offset += 2;
         Ok(t)
@@ -433,6 +678,14 @@ impl LineTokenizer {
                 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '/' | '.' | '{' | '}' => {
                     self.offset += 1
                 }
+                // A glob is a whole component: /rooms/*/color.
+                '*' if self.offset > start && self.chars[self.offset - 1] == '/' => {
+                    self.offset += 1
+                }
+                // Keep any quoted component as written, quotes and all.
+                '`' => {
+                    self.tokenize_quoted_name()?;
+                }
                 _ => break,
             }
         }
@@ -444,7 +697,7 @@ impl LineTokenizer {
         let start = self.offset;
         while !self.is_empty() {
             match self.chars[self.offset] {
-                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => self.offset += 1,
+                c if is_name_char(c) => self.offset += 1,
                 _ => break,
             }
         }
@@ -459,22 +712,71 @@ impl LineTokenizer {
     }
 
     fn peek(&self, n: usize) -> Fallible<char> {
-        ensure!(
+        require!(
             self.offset + n < self.chars.len(),
-            "tokenize error: out of input too soon"
+            Tokenize,
+            "out of input too soon"
         );
         Ok(self.chars[self.offset + n])
     }
 
-    fn trim_comment(line_raw: &str) -> String {
+    pub(crate) fn trim_comment(line_raw: &str) -> String {
         let mut line = line_raw.to_owned();
-        if let Some(offset) = line_raw.find('#') {
+        if let Some(offset) = Self::comment_start(line_raw, false).0 {
             line.truncate(offset);
         }
         line.trim_end().to_owned()
     }
 
-    fn leading_whitespace(s: &str) -> usize {
+    // The byte offset of the # that starts a comment on this line, if any,
+    // and whether the line ends in a script. In a script, which is anything
+    // after a <-, any # starts a comment. Before it, a # that follows a space
+    // and is a name followed by the end of the line or another sigil is a
+    // tag, as in `lamp $hue #ceiling <- 1`, unless it is the first thing on
+    // the line. A # in a string or a quoted name is just text.
+    pub(crate) fn comment_start(line_raw: &str, mut script: bool) -> (Option<usize>, bool) {
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut quoted = false;
+        let mut prev = ' ';
+        let mut chars = line_raw.char_indices().peekable();
+        while let Some((offset, c)) = chars.next() {
+            if in_string {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_string = false,
+                    _ => {}
+                }
+            } else if quoted {
+                quoted = c != '`';
+            } else if c == '"' {
+                in_string = true;
+            } else if c == '`' {
+                quoted = true;
+            } else if c == '<' && chars.peek().is_some_and(|&(_, n)| n == '-') {
+                script = true;
+            } else if c == '#' {
+                let leading = line_raw[..offset].trim().is_empty();
+                if script || leading || prev != ' ' || !Self::is_tag(&line_raw[offset + 1..]) {
+                    return (Some(offset), script);
+                }
+            }
+            prev = c;
+        }
+        (None, script)
+    }
+
+    // Whether the text after a # reads as a tag name and nothing more.
+    fn is_tag(rest: &str) -> bool {
+        if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return false;
+        }
+        let after = rest.trim_start_matches(is_name_char).trim_start();
+        after.is_empty() || after.starts_with(['$', '@', '^', '!', '#', '<', '\\'])
+    }
+
+    pub(crate) fn leading_whitespace(s: &str) -> usize {
         let mut cnt = 0;
         for c in s.chars() {
             if c != ' ' {
@@ -486,9 +788,45 @@ impl LineTokenizer {
     }
 }
 
+// Braces are for the {name} of a `for` loop, which is substituted into the
+// names in its block: light-{i}.
+fn is_name_char(c: char) -> bool {
+    matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '{' | '}')
+}
+
+// A node name as written in the tree, in backquotes if it would otherwise be
+// read as something else.
+pub(crate) fn quote_name(name: &str) -> String {
+    let bare = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(is_name_char)
+        && !["true", "false", "import", "template"].contains(&name);
+    if bare {
+        name.to_owned()
+    } else {
+        format!("`{}`", name)
+    }
+}
+
+// A node name as written in a path, in backquotes if it would otherwise be
+// read as something else.
+pub(crate) fn quote_path_name(name: &str) -> String {
+    // Braces in a path are a lookup, not part of a name.
+    let bare = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| is_name_char(c) && c != '{' && c != '}');
+    if bare {
+        name.to_owned()
+    } else {
+        format!("`{}`", name)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Dimension2, Fallible, Float, Token, TreeTokenizer as TT};
+    use super::{Dimension2, Fallible, Float, Span, Token, TreeTokenizer as TT};
 
     #[test]
     fn test_tokenize_dedent1() {
@@ -672,6 +1010,25 @@ d";
         );
     }
 
+    #[test]
+    fn test_tokenize_greater() {
+        assert_eq!(
+            TT::tokenize(">").unwrap(),
+            vec![Token::GreaterThan, Token::Newline]
+        );
+        assert_eq!(
+            TT::tokenize("1 >= 0>foo").unwrap(),
+            vec![
+                Token::IntegerTerm(1),
+                Token::GreaterThanOrEquals,
+                Token::IntegerTerm(0),
+                Token::GreaterThan,
+                Token::NameTerm("foo".to_owned()),
+                Token::Newline,
+            ]
+        );
+    }
+
     #[test]
     fn test_tokenize_div() {
         assert_eq!(
@@ -761,6 +1118,26 @@ d";
         );
     }
 
+    #[test]
+    fn test_tokenize_list() -> Fallible<()> {
+        assert_eq!(
+            TT::tokenize(r#"domain <- ["up",0, ./a]"#)?,
+            vec![
+                Token::NameTerm("domain".to_owned()),
+                Token::ComesFromInline,
+                Token::LeftBracket,
+                Token::StringTerm("up".to_owned()),
+                Token::Comma,
+                Token::IntegerTerm(0),
+                Token::Comma,
+                Token::PathTerm("./a".to_owned()),
+                Token::RightBracket,
+                Token::Newline,
+            ]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_tokenize_import() -> Fallible<()> {
         assert_eq!(
@@ -769,4 +1146,219 @@ d";
         );
         Ok(())
     }
+
+    #[test]
+    fn test_tokenize_line_continuation() -> Fallible<()> {
+        let expect = vec![
+            Token::NameTerm("a".to_owned()),
+            Token::ComesFromInline,
+            Token::IntegerTerm(1),
+            Token::Add,
+            Token::IntegerTerm(2),
+            Token::Newline,
+            Token::NameTerm("b".to_owned()),
+            Token::Newline,
+        ];
+        assert_eq!(TT::tokenize("a <- 1 + \\\n        2\nb")?, expect);
+        assert_eq!(TT::tokenize("a <- 1 + \\  \n2\nb")?, expect);
+        assert!(TT::tokenize("a <- 1 + \\\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_bracket_continuation() -> Fallible<()> {
+        let s = "
+a
+    b <- (1 +
+  2) * [3,
+            4][0]
+    c
+";
+        let toks = TT::tokenize(s)?;
+        assert_eq!(toks.iter().filter(|&t| t == &Token::Newline).count(), 3);
+        assert_eq!(toks.iter().filter(|&t| t == &Token::Indent).count(), 1);
+        assert_eq!(toks.last(), Some(&Token::Newline));
+        assert!(TT::tokenize("a <- (1 +\nb <- 2").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_tags() -> Fallible<()> {
+        assert_eq!(
+            TT::tokenize("a $hue #ceiling #dimmable # trailing")?,
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::Sink("hue".to_owned()),
+                Token::Tag("ceiling".to_owned()),
+                Token::Tag("dimmable".to_owned()),
+                Token::Newline,
+            ]
+        );
+        // A # leading a line, not after a space, or in a string is no tag.
+        assert_eq!(TT::tokenize("#header")?, vec![]);
+        assert_eq!(
+            TT::tokenize("a <- \"#x\"#x")?,
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::ComesFromInline,
+                Token::StringTerm("#x".to_owned()),
+                Token::Newline,
+            ]
+        );
+        // After a <-, and in the lines of a script, any # starts a comment.
+        let a_is_1 = vec![
+            Token::NameTerm("a".to_owned()),
+            Token::ComesFromInline,
+            Token::IntegerTerm(1),
+            Token::Newline,
+        ];
+        assert_eq!(TT::tokenize("a <- 1 #note")?, a_is_1);
+        assert_eq!(
+            TT::tokenize("a <- (\n    1 #note\n)")?,
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::ComesFromInline,
+                Token::LeftParen,
+                Token::IntegerTerm(1),
+                Token::RightParen,
+                Token::Newline,
+            ]
+        );
+        assert_eq!(
+            TT::tokenize("a <-\\\n    1 #note\nb #x")?,
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::ComesFromBlock,
+                Token::Newline,
+                Token::Indent,
+                Token::IntegerTerm(1),
+                Token::Newline,
+                Token::Dedent,
+                Token::NameTerm("b".to_owned()),
+                Token::Tag("x".to_owned()),
+                Token::Newline,
+            ]
+        );
+        // A # that is followed by more than a name is a comment, as is one in
+        // a quoted name.
+        assert_eq!(
+            TT::tokenize("a $hue #todo fix this <- 1")?,
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::Sink("hue".to_owned()),
+                Token::Newline,
+            ]
+        );
+        assert_eq!(
+            TT::tokenize("`a #b` #c <- 1")?,
+            vec![
+                Token::NameTerm("a #b".to_owned()),
+                Token::Tag("c".to_owned()),
+                Token::ComesFromInline,
+                Token::IntegerTerm(1),
+                Token::Newline,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_string_escapes() -> Fallible<()> {
+        assert_eq!(
+            TT::tokenize(r#""a\\b\n\t\u{48}\u{1F600}""#)?,
+            vec![
+                Token::StringTerm("a\\b\n\tH\u{1F600}".to_owned()),
+                Token::Newline
+            ]
+        );
+        assert!(TT::tokenize(r#""\q""#).is_err());
+        assert!(TT::tokenize(r#""\u{}""#).is_err());
+        assert!(TT::tokenize(r#""\u{110000}""#).is_err());
+        assert!(TT::tokenize(r#""\u{41""#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_quoted_name() -> Fallible<()> {
+        assert_eq!(
+            TT::tokenize("`living room` <- `true`")?,
+            vec![
+                Token::NameTerm("living room".to_owned()),
+                Token::ComesFromInline,
+                Token::NameTerm("true".to_owned()),
+                Token::Newline
+            ]
+        );
+        assert!(TT::tokenize("``").is_err());
+        assert!(TT::tokenize("`a/b`").is_err());
+        assert!(TT::tokenize("`a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_spans() -> Fallible<()> {
+        let spans = TT::tokenize_spanned("a\n    b <- (1 +\n  2)")?
+            .into_iter()
+            .map(|t| (t.span.line, t.span.column))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                (1, 1),
+                (1, 2),
+                (2, 1),
+                (2, 5),
+                (2, 7),
+                (2, 10),
+                (2, 11),
+                (2, 13),
+                (3, 3),
+                (3, 4),
+                (3, 5),
+            ]
+        );
+        let err = TT::tokenize("a <- 1 +\n    b <- (1 + ?)").err().unwrap();
+        let err = super::Error::find(&err).unwrap();
+        assert_eq!(
+            err.span(),
+            Some(Span {
+                line: 2,
+                column: 15
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_reports_every_error() {
+        let s = "a <- ?\nb <- 1 &| 2\nc\n    d\n  e <- (1 +\nf <- \"\\q\"\n";
+        let err = TT::tokenize(s).err().unwrap();
+        let spans = super::Error::find_all(&err)
+            .iter()
+            .map(|e| e.span().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                Span { line: 1, column: 6 },
+                Span { line: 2, column: 8 },
+                Span { line: 5, column: 1 },
+                Span { line: 6, column: 6 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_glob() -> Fallible<()> {
+        assert_eq!(
+            TT::tokenize("/rooms/*/color*2")?,
+            vec![
+                Token::PathTerm("/rooms/*/color".to_owned()),
+                Token::Multiply,
+                Token::IntegerTerm(2),
+                Token::Newline,
+            ]
+        );
+        Ok(())
+    }
 }
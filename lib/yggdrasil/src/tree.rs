@@ -2,22 +2,26 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
-    bif::{tostr::ToStr, NativeFunc},
+    bif::{self, NativeFunc},
+    error::{Error, ErrorContext, Span},
+    function::Functions,
     graph::Graph,
     parser::TreeParser,
     path::{ConcretePath, PathComponent, ScriptPath},
     physical::Dimension2,
-    script::Script,
+    query::Query,
+    script::{Folder, Script},
     value::Value,
 };
-use failure::{bail, ensure, Fallible};
+use failure::Fallible;
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    borrow::Cow,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     default::Default,
-    fs,
+    fmt, fs, mem,
     path::Path,
     str::FromStr,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 use tracing::{error, trace, trace_span, warn};
 
@@ -28,9 +32,13 @@ pub struct TreeBuilder {
     // Add builtin functions to `nifs` before loading. (default: true)
     add_builtin_nifs: bool,
 
-    // Handle an import of the given name by supplying a tree rather than
+    // Handle an import of the given name by supplying its source rather than
     // searching in the filesystem.
-    import_interceptors: HashMap<String, Tree>,
+    import_interceptors: HashMap<String, String>,
+
+    // Fold constants and mark the nodes no sink reads after linking.
+    // (default: true)
+    optimize: bool,
 }
 
 impl Default for TreeBuilder {
@@ -39,6 +47,7 @@ impl Default for TreeBuilder {
             nifs: HashMap::new(),
             add_builtin_nifs: true,
             import_interceptors: HashMap::new(),
+            optimize: true,
         }
     }
 }
@@ -54,12 +63,8 @@ impl TreeBuilder {
     }
 
     pub fn intercept_import(mut self, name: &str, content: &str) -> Fallible<TreeBuilder> {
-        let tree = Tree {
-            root: NodeRef::new(Node::new(ConcretePath::new_root())),
-            generation: 0,
-        };
-        let tree = TreeParser::from_str(tree, content, &self.nifs, &HashMap::new())?;
-        self.import_interceptors.insert(name.to_owned(), tree);
+        self.import_interceptors
+            .insert(name.to_owned(), content.to_owned());
         Ok(self)
     }
 
@@ -68,11 +73,23 @@ impl TreeBuilder {
         Ok(self)
     }
 
+    pub fn without_optimization(mut self) -> Fallible<TreeBuilder> {
+        self.optimize = false;
+        Ok(self)
+    }
+
     pub fn empty() -> Tree {
-        Tree {
-            root: NodeRef::new(Node::new(ConcretePath::new_root())),
-            generation: 0,
+        Tree::new_empty()
+    }
+
+    // The functions that scripts may call: the builtins, unless turned off,
+    // and those of the embedding.
+    fn functions(&self) -> Functions {
+        let mut nifs = self.nifs.clone();
+        if self.add_builtin_nifs {
+            bif::add_builtins(&mut nifs);
         }
+        Functions::new(nifs)
     }
 
     pub fn build_from_file(self, path: &Path) -> Fallible<Tree> {
@@ -80,30 +97,361 @@ impl TreeBuilder {
         self.build_from_str(&contents)
     }
 
-    pub fn build_from_str(mut self, s: &str) -> Fallible<Tree> {
-        if self.add_builtin_nifs {
-            self.nifs.insert("str".to_owned(), Box::new(ToStr));
+    pub fn build_from_str(self, s: &str) -> Fallible<Tree> {
+        let mut tree = TreeParser::from_str(
+            Tree::new_empty(),
+            s,
+            &self.functions(),
+            &self.import_interceptors,
+        )?;
+        tree.optimize = self.optimize;
+        let root = tree.root();
+        tree.link_and_validate_inputs()
+            .and_then(Tree::map_inputs_to_outputs)
+            .and_then(Tree::check_cycles)
+            .and_then(Tree::check_source_domains)
+            .and_then(Tree::optimize)
+            .map_err(|e| annotate_node_span(e, &root))
+    }
+}
+
+/// A handle to a node, valid within the tree that created it until the node
+/// is removed. It is the node's slot in the tree and the epoch of the slot, as
+/// slots are reused by nodes added later.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NodeId(usize, u32);
+
+impl NodeId {
+    const ROOT: NodeId = NodeId(0, 0);
+}
+
+// Every node in a tree, indexed by NodeId. Nodes refer to their parent,
+// children, and inputs by id, so that nothing in the arena holds a reference
+// back to the arena; it is freed when the last Tree or NodeRef into it drops.
+#[derive(Debug)]
+struct Arena {
+    nodes: Vec<Node>,
+    // In step with nodes.
+    slots: Vec<Slot>,
+    // Slots whose nodes were removed from the tree, to reuse.
+    free: Vec<usize>,
+    // How many modules the nodes have come from: the tree's own and one for
+    // each import.
+    modules: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    // How many nodes have been removed from the slot; only ids with the
+    // current epoch name the node in it.
+    epoch: u32,
+    // Whether the node can be reached from the root.
+    attached: bool,
+}
+
+impl Arena {
+    fn new(root: Node) -> Self {
+        Self {
+            nodes: vec![root],
+            slots: vec![Slot {
+                epoch: 0,
+                attached: true,
+            }],
+            free: Vec::new(),
+            modules: 1,
         }
+    }
 
-        let tree = Tree {
-            root: NodeRef::new(Node::new(ConcretePath::new_root())),
-            generation: 0,
+    fn new_module(&mut self) -> usize {
+        self.modules += 1;
+        self.modules - 1
+    }
+
+    // The module that the node at `id` is private to, if it or any node it is
+    // under is private.
+    fn private_to(&self, mut id: NodeId) -> Option<usize> {
+        loop {
+            let node = self.get(id);
+            if node.private {
+                return Some(node.module);
+            }
+            id = node.parent?;
+        }
+    }
+
+    // The node at `id`, which the tree itself holds, and so must be there.
+    fn get(&self, id: NodeId) -> &Node {
+        debug_assert!(self.check(id).is_ok());
+        &self.nodes[id.0]
+    }
+
+    fn get_mut(&mut self, id: NodeId) -> &mut Node {
+        debug_assert!(self.check(id).is_ok());
+        &mut self.nodes[id.0]
+    }
+
+    // The node at `id`, from a handle held outside the tree, which may have
+    // outlived the node.
+    fn node(&self, id: NodeId) -> Fallible<&Node> {
+        self.check(id)?;
+        Ok(&self.nodes[id.0])
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> Fallible<&mut Node> {
+        self.check(id)?;
+        Ok(&mut self.nodes[id.0])
+    }
+
+    fn check(&self, id: NodeId) -> Fallible<()> {
+        require!(
+            self.slots.get(id.0).is_some_and(|slot| slot.epoch == id.1),
+            Dataflow,
+            "{:?} names a node that was removed from the tree",
+            id
+        );
+        Ok(())
+    }
+
+    // The node at `id` and everything under it, each before its children.
+    fn collect_subtree(&self, id: NodeId, out: &mut Vec<NodeId>) {
+        out.push(id);
+        for &child in self.get(id).children.values() {
+            self.collect_subtree(child, out);
+        }
+    }
+
+    fn child_of(&self, id: NodeId, name: &str) -> Option<NodeId> {
+        match name {
+            "." => Some(id),
+            ".." => self.get(id).parent,
+            _ => self.get(id).children.get(name).copied(),
+        }
+    }
+
+    fn add_child(&mut self, parent: NodeId, name: &str) -> NodeId {
+        let mut child = Node::new(self.get(parent).path.new_child(name));
+        child.parent = Some(parent);
+        child.module = self.get(parent).module;
+        let slot = Slot {
+            epoch: 0,
+            attached: self.is_attached(parent),
+        };
+        let id = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = child;
+                self.slots[index].attached = slot.attached;
+                NodeId(index, self.slots[index].epoch)
+            }
+            None => {
+                self.nodes.push(child);
+                self.slots.push(slot);
+                NodeId(self.nodes.len() - 1, 0)
+            }
         };
+        self.get_mut(parent).children.insert(name.to_owned(), id);
+        id
+    }
+
+    // Nodes removed from the tree stay in the arena until the edit that
+    // removed them is done with, but can no longer be reached from the root.
+    fn is_attached(&self, id: NodeId) -> bool {
+        debug_assert!(self.check(id).is_ok());
+        self.slots[id.0].attached
+    }
 
-        let tree = TreeParser::from_str(tree, s, &self.nifs, &self.import_interceptors)?
-            .link_and_validate_inputs()?
-            .map_inputs_to_outputs()?;
+    fn set_attached(&mut self, id: NodeId, attached: bool) {
+        debug_assert!(self.check(id).is_ok());
+        self.slots[id.0].attached = attached;
+        let children = self.get(id).children.values().copied().collect::<Vec<_>>();
+        for child in children {
+            self.set_attached(child, attached);
+        }
+    }
+
+    // Empty the slots of the detached node at `id` and everything under it,
+    // for new nodes to reuse, and return the ids they had.
+    fn free_subtree(&mut self, id: NodeId, freed: &mut Vec<NodeId>) {
+        debug_assert!(!self.is_attached(id));
+        let node = mem::replace(&mut self.nodes[id.0], Node::new(ConcretePath::new_root()));
+        for &child in node.children.values() {
+            self.free_subtree(child, freed);
+        }
+        self.slots[id.0].epoch += 1;
+        self.free.push(id.0);
+        freed.push(id);
+    }
+}
+
+// A change to the tree made at runtime. Applying an edit returns the edit
+// that undoes it.
+enum Edit {
+    Attach(NodeId, NodeId),
+    Detach(NodeId, NodeId),
+    Input(NodeId, Option<NodeInput>, Option<Value>),
+    Sink(NodeId, Option<String>),
+}
+
+impl Edit {
+    fn apply(self, arena: &mut Arena) -> Edit {
+        match self {
+            Edit::Attach(parent, child) => {
+                let name = arena.get(child).path.basename().to_owned();
+                arena.get_mut(parent).children.insert(name, child);
+                arena.set_attached(child, arena.is_attached(parent));
+                Edit::Detach(parent, child)
+            }
+            Edit::Detach(parent, child) => {
+                let name = arena.get(child).path.basename().to_owned();
+                arena.get_mut(parent).children.remove(&name);
+                arena.set_attached(child, false);
+                Edit::Attach(parent, child)
+            }
+            Edit::Input(id, mut input, mut cache) => {
+                let node = arena.get_mut(id);
+                mem::swap(&mut node.input, &mut input);
+                mem::swap(node.cache.get_mut().unwrap(), &mut cache);
+                node.linked_and_validated = false;
+                Edit::Input(id, input, cache)
+            }
+            Edit::Sink(id, mut sink) => {
+                mem::swap(&mut arena.get_mut(id).sink, &mut sink);
+                Edit::Sink(id, sink)
+            }
+        }
+    }
+}
 
-        Ok(tree)
+// Point an error that names a node, but not a place in the source, at the
+// node's declaration.
+fn annotate_node_span(err: failure::Error, root: &NodeRef) -> failure::Error {
+    let span = Error::find(&err)
+        .and_then(|e| e.path())
+        .and_then(|path| root.lookup_path(&path.components()).ok())
+        .and_then(|node| node.span().ok().flatten());
+    match span {
+        Some(span) => Error::annotate_span(err, span),
+        None => err,
     }
 }
 
+fn sort_and_dedup(nodes: &mut Vec<NodeRef>) {
+    nodes.sort_by_key(|node| node.id);
+    nodes.dedup_by_key(|node| node.id);
+}
+
 pub struct Tree {
-    root: NodeRef,
+    arena: Arc<RwLock<Arena>>,
     generation: usize,
+
+    // Edges from every node to the nodes that read it; built after linking.
+    graph: Graph,
+
+    // The functions scripts were built with, for scripts added by edits.
+    functions: Functions,
+    optimize: bool,
+
+    // The nodes with dynamic lookups, for edits that add or remove nodes
+    // those lookups may find.
+    lookups: Lookups,
+
+    // The possible values of nodes found so far in a link pass.
+    possible_values: Mutex<PossibleValues>,
+}
+
+// The nodes with dynamic lookups, by the static prefix of each lookup, along
+// with the prefixes of each such node, so that entries can be dropped when
+// its script changes.
+#[derive(Default)]
+struct Lookups {
+    by_prefix: BTreeMap<ConcretePath, HashSet<NodeId>>,
+    prefixes: HashMap<NodeId, Vec<ConcretePath>>,
+}
+
+impl Lookups {
+    // Replace the entries for `node` with those of its current script, or
+    // drop them if it is no longer in the tree.
+    fn update(&mut self, node: &NodeRef, attached: bool) -> Fallible<()> {
+        for prefix in self.prefixes.remove(&node.id).unwrap_or_default() {
+            if let Some(ids) = self.by_prefix.get_mut(&prefix) {
+                ids.remove(&node.id);
+                if ids.is_empty() {
+                    self.by_prefix.remove(&prefix);
+                }
+            }
+        }
+        if !attached {
+            return Ok(());
+        }
+        let prefixes = node
+            .dynamic_paths()?
+            .iter()
+            .map(|path| path.static_prefix())
+            .collect::<Vec<_>>();
+        if prefixes.is_empty() {
+            return Ok(());
+        }
+        for prefix in &prefixes {
+            self.by_prefix
+                .entry(prefix.clone())
+                .or_default()
+                .insert(node.id);
+        }
+        self.prefixes.insert(node.id, prefixes);
+        Ok(())
+    }
+
+    // The nodes with a lookup that may find a node at or under `path`: those
+    // whose prefix is above `path`, or under it.
+    fn reaching(&self, path: &ConcretePath) -> Vec<NodeId> {
+        let mut out = Vec::new();
+        let mut above = path.clone();
+        while above != ConcretePath::new_root() {
+            above = above.parent();
+            out.extend(self.by_prefix.get(&above).into_iter().flatten());
+        }
+        for (prefix, ids) in self.by_prefix.range(path.clone()..) {
+            if !prefix.starts_with(path) {
+                break;
+            }
+            out.extend(ids);
+        }
+        out
+    }
+}
+
+// Chains of lookups reach the same nodes along many paths, so remember their
+// possible values for the length of a link pass rather than interpreting them
+// again for each path, which grows exponentially with the length of a chain.
+// A value is only kept if finding it did not stop at a node that was already
+// being visited, as it then depends on where the search began. The pass ends
+// before anything changes a script.
+#[derive(Default)]
+struct PossibleValues {
+    active: bool,
+    values: HashMap<NodeId, Option<Vec<Value>>>,
+    // How many times a search has stopped at a node already being visited.
+    cuts: usize,
 }
 
 impl Tree {
+    fn new_empty() -> Self {
+        Self::new_at(ConcretePath::new_root())
+    }
+
+    // A tree whose root sits at `path`, so that relative paths in the scripts
+    // parsed into it resolve as if it were grafted there.
+    pub(super) fn new_at(path: ConcretePath) -> Self {
+        Tree {
+            arena: Arc::new(RwLock::new(Arena::new(Node::new(path)))),
+            generation: 0,
+            graph: Graph::new_empty(),
+            functions: Functions::default(),
+            optimize: false,
+            lookups: Lookups::default(),
+            possible_values: Mutex::new(PossibleValues::default()),
+        }
+    }
+
     pub fn handle_event(
         &mut self,
         path: &ConcretePath,
@@ -113,14 +461,47 @@ impl Tree {
         value.set_generation(self.generation);
 
         let source = self.lookup_path(path)?;
-        source.handle_event(value)?; // cache the value
-        let sink_nodes = source.get_sink_nodes_observing()?;
+        if let Some(domain) = source.source_domain(self)? {
+            if !domain.contains(&value) {
+                let message = format!("{} is not in the domain of {}", value, path);
+                return Err(Error::Runtime(ErrorContext::new(message))
+                    .at(path.clone())
+                    .with_value(value)
+                    .into());
+            }
+        }
+
+        // Cache the value, then drop everything downstream of the source
+        // before computing any of it, so that no sink can see a stale
+        // intermediate value.
+        let mut sinks = Vec::new();
+        {
+            let arena = self.arena.read().unwrap();
+            let node = arena.node(source.id)?;
+            let observers = match node.input {
+                Some(NodeInput::Source(_, ref observers)) => observers,
+                _ => {
+                    let message = "received event on non-source node".to_owned();
+                    return Err(Error::Runtime(ErrorContext::new(message))
+                        .at(path.clone())
+                        .with_value(value)
+                        .into());
+                }
+            };
+            *node.cache.lock().unwrap() = Some(value);
+            for &id in observers.iter() {
+                let node = arena.get(id);
+                node.invalidate();
+                if let Some(ref kind) = node.sink {
+                    sinks.push((id, kind.to_owned(), node.path.clone()));
+                }
+            }
+        }
 
         let mut groups = HashMap::new();
-        for node in &sink_nodes {
-            let next_value = node.compute(self)?;
-            let kind = node.sink_kind()?;
-            let value = (node.path(), next_value);
+        for (id, kind, path) in sinks {
+            let next_value = source.at(id).compute(self)?;
+            let value = (path, next_value);
             match groups.entry(kind) {
                 Entry::Vacant(e) => {
                     e.insert(vec![value]);
@@ -134,7 +515,17 @@ impl Tree {
     }
 
     pub fn root(&self) -> NodeRef {
-        self.root.clone()
+        NodeRef {
+            arena: self.arena.clone(),
+            id: NodeId::ROOT,
+        }
+    }
+
+    pub(super) fn node(&self, id: NodeId) -> NodeRef {
+        NodeRef {
+            arena: self.arena.clone(),
+            id,
+        }
     }
 
     pub fn lookup(&self, path: &str) -> Fallible<NodeRef> {
@@ -143,70 +534,662 @@ impl Tree {
     }
 
     pub fn lookup_path(&self, path: &ConcretePath) -> Fallible<NodeRef> {
-        self.root.lookup_path(&path.components[0..])
+        self.root().lookup_path(&path.components())
     }
 
     pub fn lookup_dynamic_path(&self, gen: usize, path: &ScriptPath) -> Fallible<(NodeRef, usize)> {
-        self.root
-            .lookup_dynamic_path(gen, &path.components[0..], self)
+        self.root()
+            .lookup_dynamic_path(gen, path.components(), self)
     }
 
     // After the tree has been built, visit all nodes looking up references and
     // storing those references directly in the inputs list per script.
     fn link_and_validate_inputs(self) -> Fallible<Tree> {
-        self.root.link_and_validate_inputs(&self)?;
+        self.in_link_pass(|| self.root().link_and_validate_inputs(&self))?;
         Ok(self)
     }
 
-    fn map_inputs_to_outputs(self) -> Fallible<Tree> {
+    // Run `f` as one link pass, remembering possible values until it ends.
+    fn in_link_pass<T>(&self, f: impl FnOnce() -> Fallible<T>) -> Fallible<T> {
+        self.possible_values.lock().unwrap().active = true;
+        let result = f();
+        *self.possible_values.lock().unwrap() = PossibleValues::default();
+        result
+    }
+
+    pub(super) fn cached_possible_values(&self, id: NodeId) -> Option<Option<Vec<Value>>> {
+        self.possible_values
+            .lock()
+            .unwrap()
+            .values
+            .get(&id)
+            .cloned()
+    }
+
+    pub(super) fn possible_value_cuts(&self) -> usize {
+        self.possible_values.lock().unwrap().cuts
+    }
+
+    pub(super) fn cut_possible_values(&self) {
+        self.possible_values.lock().unwrap().cuts += 1;
+    }
+
+    // Remember the possible values of `id`, unless a search stopped at a
+    // node already being visited since there were `cuts` such stops.
+    pub(super) fn cache_possible_values(
+        &self,
+        id: NodeId,
+        cuts: usize,
+        values: &Option<Vec<Value>>,
+    ) {
+        let mut cache = self.possible_values.lock().unwrap();
+        if cache.active && cache.cuts == cuts {
+            cache.values.insert(id, values.to_owned());
+        }
+    }
+
+    fn map_inputs_to_outputs(mut self) -> Fallible<Tree> {
         let mut graph = Graph::new_empty();
-        let mut sinks = Vec::new();
         self.root().populate_flow_graph(&mut graph)?;
-        self.root().find_all_sinks(&mut sinks)?;
-        self.root().flow_input_to_output(&sinks, &graph)?;
+        self.root().flow_input_to_output(&graph)?;
+        self.graph = graph;
+        let mut nodes = Vec::new();
+        self.root().collect_subtree(&mut nodes)?;
+        for node in &nodes {
+            self.lookups.update(node, true)?;
+        }
+        Ok(self)
+    }
+
+    // Reject any node that may read itself, directly or through other nodes,
+    // for some possible value of the lookups along the way.
+    fn check_cycles(self) -> Fallible<Tree> {
+        self.ensure_acyclic()?;
+        Ok(self)
+    }
+
+    fn ensure_acyclic(&self) -> Fallible<()> {
+        let cycles = self.graph.find_cycles()?;
+        if cycles.is_empty() {
+            return Ok(());
+        }
+        let mut reports = Vec::new();
+        for cycle in &cycles {
+            // Edges run from each input to its reader, so walk backwards to
+            // list who reads whom.
+            let mut lines = Vec::new();
+            let len = cycle.len();
+            for i in 0..len {
+                let reader = &cycle[(len - i) % len];
+                let input = &cycle[(2 * len - i - 1) % len];
+                let how = if self.graph.is_dynamic_edge(input, reader)? {
+                    " through a lookup"
+                } else {
+                    ""
+                };
+                lines.push(format!(
+                    "    {} reads {}{}",
+                    reader.path()?,
+                    input.path()?,
+                    how
+                ));
+            }
+            reports.push(lines.join("\n"));
+        }
+        raise!(
+            Dataflow,
+            "found cycles in the dataflow graph:\n{}",
+            reports.join("\n\n")
+        )
+    }
+
+    // Prove that every lookup fed by a source with a declared domain finds a
+    // child for every value in that domain.
+    fn check_source_domains(self) -> Fallible<Tree> {
+        let mut errors = Vec::new();
+        self.in_link_pass(|| self.root().check_source_domains(&self, &mut errors))?;
+        Self::report_uncovered_domains(errors)?;
         Ok(self)
     }
 
+    // Fold what is known ahead of time out of the scripts that sinks read, and
+    // leave the nodes that no sink reads out of what events visit.
+    fn optimize(self) -> Fallible<Tree> {
+        if !self.optimize {
+            return Ok(self);
+        }
+        let mut nodes = Vec::new();
+        self.root().collect_subtree(&mut nodes)?;
+        self.mark_dead(&nodes)?;
+        self.fold(&nodes)?;
+        for node in &nodes {
+            node.prune_observers()?;
+        }
+        Ok(self)
+    }
+
+    // A script is dead if no sink reads it, even through a lookup. Dead nodes
+    // are computed afresh whenever they are asked for, rather than cached,
+    // so that events need not visit them.
+    fn mark_dead(&self, nodes: &[NodeRef]) -> Fallible<()> {
+        for node in nodes {
+            if !self.graph.contains(node) {
+                continue;
+            }
+            let mut read = node.maybe_sink_kind()?.is_some();
+            for n in self.graph.reachable_nodes(node)? {
+                read = read || n.maybe_sink_kind()?.is_some();
+            }
+            node.set_dead(node.has_script()? && !read)?;
+        }
+        Ok(())
+    }
+
+    fn fold(&self, nodes: &[NodeRef]) -> Fallible<()> {
+        let mut folder = Folder::new(self);
+        for node in nodes {
+            if node.has_script()? && !node.is_dead()? {
+                folder.constant(node)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// What building the tree worked out ahead of time, one line per node
+    /// and thing folded, and which nodes no sink reads, for debugging.
+    pub fn optimizations(&self) -> Fallible<Vec<String>> {
+        let mut nodes = Vec::new();
+        self.root().collect_subtree(&mut nodes)?;
+        let mut nodes = nodes
+            .into_iter()
+            .map(|node| Ok((node.path()?, node)))
+            .collect::<Fallible<Vec<_>>>()?;
+        nodes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut out = Vec::new();
+        for (path, node) in nodes {
+            if node.is_dead()? {
+                out.push(format!("{}: not read by any sink", path));
+            }
+            for fold in node.folds()? {
+                out.push(format!("{}: {}", path, fold));
+            }
+        }
+        Ok(out)
+    }
+
+    fn report_uncovered_domains(mut errors: Vec<String>) -> Fallible<()> {
+        if !errors.is_empty() {
+            errors.sort();
+            errors.dedup();
+            raise!(
+                Dataflow,
+                "found lookups that do not cover their source domains:\n    {}",
+                errors.join("\n    ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Every node, including sinks, whose value may change when the node at
+    /// `path` changes. This includes nodes that only read `path` through some
+    /// possible value of a dynamic lookup.
+    pub fn dependents_of(&self, path: &ConcretePath) -> Fallible<Vec<ConcretePath>> {
+        let node = self.lookup_path(path)?;
+        self.graph
+            .reachable_nodes(&node)?
+            .iter()
+            .map(|n| n.path())
+            .collect()
+    }
+
+    /// Every source and constant that the node at `path` may read, either
+    /// directly or through any possible target of a dynamic lookup.
+    pub fn dependencies_of(&self, path: &ConcretePath) -> Fallible<Vec<ConcretePath>> {
+        let mut paths = Vec::new();
+        for n in self.graph.upstream_nodes(&self.lookup_path(path)?)? {
+            if n.is_source()? || n.is_constant()? {
+                paths.push(n.path()?);
+            }
+        }
+        Ok(paths)
+    }
+
+    pub(super) fn graph(&self) -> &Graph {
+        &self.graph
+    }
+
+    pub(super) fn functions(&self) -> &Functions {
+        &self.functions
+    }
+
+    pub(super) fn set_functions(&mut self, functions: Functions) {
+        self.functions = functions;
+    }
+
     pub fn find_sinks(&self, name: &str) -> Vec<ConcretePath> {
-        let mut matching = Vec::new();
-        self.root().find_sinks(name, &mut matching);
-        matching
+        self.find_paths(|node| node.sink.as_deref() == Some(name))
     }
 
     pub fn find_sources(&self, name: &str) -> Vec<ConcretePath> {
-        let mut matching = Vec::new();
-        self.root().find_sources(name, &mut matching);
-        matching
+        self.find_paths(|node| match node.input {
+            Some(NodeInput::Source(ref kind, _)) => kind == name,
+            _ => false,
+        })
+    }
+
+    // The paths of every node in the tree for which `f` is true.
+    fn find_paths(&self, f: impl Fn(&Node) -> bool) -> Vec<ConcretePath> {
+        let arena = self.arena.read().unwrap();
+        let mut ids = Vec::new();
+        arena.collect_subtree(NodeId::ROOT, &mut ids);
+        ids.into_iter()
+            .map(|id| arena.get(id))
+            .filter(|node| f(node))
+            .map(|node| node.path.clone())
+            .collect()
+    }
+
+    /// The paths of the nodes that `query` selects, in order; see `Query`.
+    pub fn query(&self, query: &str) -> Fallible<Vec<ConcretePath>> {
+        Query::from_str(query)?.select(self)
+    }
+
+    /// Parse `source`, which is written like a tree file, and add the nodes
+    /// it describes under the node at `parent`. Relative paths in its scripts
+    /// are relative to where the nodes land. The new nodes are linked into the
+    /// running tree, along with any existing lookups that may now find them.
+    pub fn add_nodes(&mut self, parent: &ConcretePath, source: &str) -> Fallible<()> {
+        let parent = self.lookup_path(parent)?;
+        let snippet = TreeParser::from_str(
+            Tree::new_at(parent.path()?),
+            source,
+            &self.functions,
+            &HashMap::new(),
+        )?;
+        let names = snippet.root().child_names()?;
+        for name in &names {
+            require!(
+                parent.child_at(name)?.is_none(),
+                Link,
+                "cannot add {}; it already exists",
+                parent.path()?.new_child(name)
+            );
+        }
+        parent.insert_subtree(&snippet.root())?;
+        let added = names
+            .iter()
+            .map(|name| parent.child(name).map(|child| child.id))
+            .collect::<Fallible<Vec<_>>>()?;
+        let undo = added
+            .iter()
+            .map(|&child| Edit::Detach(parent.id, child))
+            .collect();
+        self.commit(&added, Some(parent.id), undo)
+    }
+
+    /// Remove the node at `path`, and everything under it, from the tree.
+    /// This fails, leaving the tree as it was, if any remaining node reads it.
+    /// Once it succeeds, a NodeRef to a removed node fails with a dataflow
+    /// error wherever it is used, even after its slot goes to the next node.
+    pub fn remove_node(&mut self, path: &ConcretePath) -> Fallible<()> {
+        let node = self.lookup_path(path)?;
+        let parent = match node.parent()? {
+            Some(parent) => parent,
+            None => raise!(Link, "cannot remove the root of the tree"),
+        };
+        self.edit(node.id, Some(parent.id), Edit::Detach(parent.id, node.id))
+    }
+
+    /// Replace the input of the node at `path` with the script `text`, which
+    /// is written as it would be after `<-` in a tree file, or indented as a
+    /// block if it has more than one line.
+    pub fn replace_script(&mut self, path: &ConcretePath, text: &str) -> Fallible<()> {
+        let node = self.lookup_path(path)?;
+        require!(
+            node.parent()?.is_some(),
+            Link,
+            "the root of the tree cannot have a script"
+        );
+        let source = if text.trim().contains('\n') {
+            let lines = text
+                .trim()
+                .lines()
+                .map(|line| format!("    {}", line))
+                .collect::<Vec<_>>();
+            format!("{} <-\\\n{}\n", node.name()?, lines.join("\n"))
+        } else {
+            format!("{} <- {}\n", node.name()?, text.trim())
+        };
+        let snippet = TreeParser::from_str(
+            Tree::new_at(path.parent()),
+            &source,
+            &self.functions,
+            &HashMap::new(),
+        )?;
+        let input = snippet
+            .root()
+            .child(&node.name()?)?
+            .write(|n| n.input.take())?;
+        require!(
+            matches!(input, Some(NodeInput::Script(_))),
+            Parse,
+            "expected a script for {}",
+            path
+        );
+        self.edit(node.id, None, Edit::Input(node.id, input, None))
+    }
+
+    /// Make the node at `path` a source of the given kind, replacing any
+    /// script it had.
+    pub fn attach_source(&mut self, path: &ConcretePath, kind: &str) -> Fallible<()> {
+        let node = self.lookup_path(path)?;
+        let input = NodeInput::Source(kind.to_owned(), Vec::new());
+        self.edit(node.id, None, Edit::Input(node.id, Some(input), None))
+    }
+
+    /// Remove the script or source from the node at `path`.
+    pub fn detach_input(&mut self, path: &ConcretePath) -> Fallible<()> {
+        let node = self.lookup_path(path)?;
+        self.edit(node.id, None, Edit::Input(node.id, None, None))
+    }
+
+    /// Make the node at `path` a sink of the given kind.
+    pub fn attach_sink(&mut self, path: &ConcretePath, kind: &str) -> Fallible<()> {
+        let node = self.lookup_path(path)?;
+        self.edit(node.id, None, Edit::Sink(node.id, Some(kind.to_owned())))
+    }
+
+    pub fn detach_sink(&mut self, path: &ConcretePath) -> Fallible<()> {
+        let node = self.lookup_path(path)?;
+        self.edit(node.id, None, Edit::Sink(node.id, None))
+    }
+
+    fn edit(&mut self, changed: NodeId, reshaped: Option<NodeId>, edit: Edit) -> Fallible<()> {
+        let undo = edit.apply(&mut self.arena.write().unwrap());
+        self.commit(&[changed], reshaped, vec![undo])
+    }
+
+    // Bring the links, graph, and observers up to date after the nodes in
+    // `changed` were edited, or were added to or removed from `reshaped`. If
+    // the tree is no longer valid, apply `undo` to put it back as it was.
+    fn commit(
+        &mut self,
+        changed: &[NodeId],
+        reshaped: Option<NodeId>,
+        undo: Vec<Edit>,
+    ) -> Fallible<()> {
+        let (edited, relink) = self.affected_by(changed, reshaped)?;
+        let err = match self.relink(&edited, &relink) {
+            Ok(()) => {
+                self.reclaim(&undo);
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        let redo = {
+            let mut arena = self.arena.write().unwrap();
+            undo.into_iter()
+                .rev()
+                .map(|edit| edit.apply(&mut arena))
+                .collect::<Vec<_>>()
+        };
+        let (mut restored, mut restored_relink) = self.affected_by(changed, reshaped)?;
+        restored.extend(edited);
+        restored_relink.extend(relink);
+        if let Err(restore_err) = self.relink(&restored, &restored_relink) {
+            raise!(
+                Dataflow,
+                "failed to restore the tree ({}) after a rejected edit: {}",
+                restore_err,
+                err
+            );
+        }
+        self.reclaim(&redo);
+        Err(err)
+    }
+
+    // Free the nodes that were removed from the tree, now that the edits that
+    // removed them are done with; `undo` holds the edits that would put them
+    // back.
+    fn reclaim(&mut self, undo: &[Edit]) {
+        let mut freed = Vec::new();
+        let mut arena = self.arena.write().unwrap();
+        for edit in undo {
+            if let Edit::Attach(_, child) = *edit {
+                arena.free_subtree(child, &mut freed);
+            }
+        }
+        for id in freed {
+            self.graph.remove_node(id);
+        }
+    }
+
+    // Find the nodes an edit touched, and the scripts that may read something
+    // different because of it.
+    fn affected_by(
+        &self,
+        changed: &[NodeId],
+        reshaped: Option<NodeId>,
+    ) -> Fallible<(Vec<NodeRef>, Vec<NodeRef>)> {
+        let mut edited = Vec::new();
+        for &id in changed {
+            let node = self.node(id);
+            node.collect_subtree(&mut edited)?;
+            // A source's default is one of its inputs.
+            if let Some(parent) = node.parent()? {
+                if parent.is_source()? {
+                    edited.push(parent);
+                }
+            }
+        }
+
+        let mut relink = Vec::new();
+        for node in &edited {
+            if node.has_script()? {
+                relink.push(node.to_owned());
+            }
+            if !self.graph.contains(node) {
+                continue;
+            }
+            for reader in self.graph.successors(node)? {
+                if reader.has_script()? {
+                    relink.push(reader);
+                }
+            }
+            for reader in self.graph.reachable_nodes(node)? {
+                if !reader.dynamic_paths()?.is_empty() {
+                    relink.push(reader);
+                }
+            }
+        }
+
+        // Lookups under the parent that gained or lost children may now
+        // resolve to different nodes.
+        if let Some(parent) = reshaped {
+            let parent = self.node(parent).path()?;
+            for id in self.lookups.reaching(&parent) {
+                relink.push(self.node(id));
+            }
+        }
+
+        sort_and_dedup(&mut edited);
+        sort_and_dedup(&mut relink);
+        Ok((edited, relink))
+    }
+
+    fn collect_upstream(&self, nodes: &[NodeRef], out: &mut Vec<NodeRef>) -> Fallible<()> {
+        for node in nodes {
+            out.push(node.to_owned());
+            out.extend(self.graph.upstream_nodes(node)?);
+        }
+        Ok(())
+    }
+
+    fn relink(&mut self, edited: &[NodeRef], relink: &[NodeRef]) -> Fallible<()> {
+        let arena = self.arena.clone();
+        let attached = move |node: &NodeRef| arena.read().unwrap().is_attached(node.id);
+
+        // Link every touched node that is still in the tree, and replace the
+        // edges into all of them.
+        let mut touched = edited.iter().chain(relink).cloned().collect();
+        sort_and_dedup(&mut touched);
+        for node in &touched {
+            self.lookups.update(node, attached(node))?;
+        }
+        for node in relink.iter().filter(|node| attached(node)) {
+            node.unlink()?;
+        }
+        for node in &touched {
+            self.graph.add_node(node);
+        }
+
+        // Any source upstream of a touched node, before or after the edit,
+        // may now reach different nodes.
+        let mut sources = Vec::new();
+        self.collect_upstream(&touched, &mut sources)?;
+        self.graph
+            .remove_edges_into(&touched.iter().map(|node| node.id).collect());
+        touched.retain(|node| attached(node));
+        for node in &touched {
+            self.in_link_pass(|| node.link_and_validate_inputs(self))?;
+            node.add_input_edges(&mut self.graph)?;
+        }
+        self.ensure_acyclic()?;
+
+        self.collect_upstream(&touched, &mut sources)?;
+        sources.retain(|node| attached(node));
+        if self.optimize {
+            self.mark_dead(&sources)?;
+        }
+        let mut live = Vec::new();
+        for node in sources {
+            if node.is_source()? {
+                live.push(node);
+            }
+        }
+        let mut sources = live;
+        sort_and_dedup(&mut sources);
+        for source in &sources {
+            source.set_observers(&self.graph)?;
+        }
+
+        let mut errors = Vec::new();
+        self.in_link_pass(|| {
+            for node in touched.iter().chain(&sources) {
+                node.check_source_domain(self, &mut errors)?;
+            }
+            Ok(())
+        })?;
+        Self::report_uncovered_domains(errors)?;
+
+        // Fold again everything that may have folded something that changed.
+        if self.optimize {
+            let mut stale = touched.clone();
+            for node in &touched {
+                stale.extend(self.graph.reachable_nodes(node)?);
+            }
+            sort_and_dedup(&mut stale);
+            self.fold(&stale)?;
+        }
+
+        // Drop any value that was computed from the old shape of the tree.
+        for node in &touched {
+            node.read(|n| n.invalidate())?;
+            for reader in self.graph.reachable_nodes(node)? {
+                reader.read(|n| n.invalidate())?;
+            }
+        }
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct NodeRef(Arc<RwLock<Node>>);
+/// A node in some tree. This is a cheap handle: it holds the tree's storage
+/// open and names a node in it, so it stays valid after the tree is dropped.
+#[derive(Clone)]
+pub struct NodeRef {
+    arena: Arc<RwLock<Arena>>,
+    id: NodeId,
+}
+
+impl fmt::Debug for NodeRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path_str() {
+            Ok(path) => write!(f, "NodeRef({:?} @ {})", self.id, path),
+            Err(_) => write!(f, "NodeRef({:?}, removed)", self.id),
+        }
+    }
+}
 
 impl NodeRef {
-    pub fn new(node: Node) -> Self {
-        let self_ref = NodeRef(Arc::new(RwLock::new(node)));
-        self_ref
-            .0
-            .write()
-            .unwrap()
-            .children
-            .insert(".".to_owned(), self_ref.clone());
-        self_ref
+    /// Create a new tree containing only `node`, and return its root. A node
+    /// cannot exist outside of a tree, so this is not `new`: the node is the
+    /// root of a tree of its own, not a free node to attach elsewhere.
+    pub fn new_tree(node: Node) -> Self {
+        NodeRef {
+            arena: Arc::new(RwLock::new(Arena::new(node))),
+            id: NodeId::ROOT,
+        }
     }
 
-    pub fn lookup_path(&self, parts: &[String]) -> Fallible<NodeRef> {
-        if parts.is_empty() {
-            return Ok(self.to_owned());
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    // Another node in the same tree.
+    pub(super) fn at(&self, id: NodeId) -> NodeRef {
+        NodeRef {
+            arena: self.arena.clone(),
+            id,
         }
-        if let Some(child) = self.child_at(&parts[0]) {
-            return child.lookup_path(&parts[1..]);
+    }
+
+    // Run `f` on this node, under one lock on the tree, or fail if the node
+    // has been removed from it.
+    fn read<T>(&self, f: impl FnOnce(&Node) -> T) -> Fallible<T> {
+        Ok(f(self.arena.read().unwrap().node(self.id)?))
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut Node) -> T) -> Fallible<T> {
+        Ok(f(self.arena.write().unwrap().node_mut(self.id)?))
+    }
+
+    pub fn parent(&self) -> Fallible<Option<NodeRef>> {
+        Ok(self.read(|node| node.parent)?.map(|id| self.at(id)))
+    }
+
+    fn children(&self) -> Fallible<Vec<NodeRef>> {
+        let ids = self.read(|node| node.children.values().copied().collect::<Vec<_>>())?;
+        Ok(ids.iter().map(|&id| self.at(id)).collect())
+    }
+
+    pub(super) fn sorted_children(&self) -> Fallible<Vec<NodeRef>> {
+        let mut children = self.read(|node| {
+            node.children
+                .iter()
+                .map(|(name, &id)| (name.to_owned(), id))
+                .collect::<Vec<_>>()
+        })?;
+        children.sort();
+        Ok(children.iter().map(|&(_, id)| self.at(id)).collect())
+    }
+
+    pub fn lookup_path(&self, parts: &[&str]) -> Fallible<NodeRef> {
+        let arena = self.arena.read().unwrap();
+        arena.check(self.id)?;
+        let mut id = self.id;
+        for (i, part) in parts.iter().enumerate() {
+            id = match arena.child_of(id, part) {
+                Some(child) => child,
+                None => raise!(
+                    Runtime,
+                    "lookup on path that does not exist; at {}; rem: {:?}",
+                    arena.get(id).path,
+                    &parts[i..]
+                ),
+            };
         }
-        bail!(
-            "runtime error: lookup on path that does not exist; at {}; rem: {:?}",
-            self.path_str(),
-            parts
-        )
+        Ok(self.at(id))
     }
 
     pub fn lookup_dynamic_path(
@@ -215,167 +1198,287 @@ impl NodeRef {
         parts: &[PathComponent],
         tree: &Tree,
     ) -> Fallible<(NodeRef, usize)> {
-        let span = trace_span!("lookup", "{}", self.path_str());
+        let span = trace_span!("lookup", "{}", self.path_str()?);
         let _ = span.enter();
 
         trace!(
             "lookup_dynamic @ {}, remainder: {:?}",
-            self.path_str(),
+            self.path_str()?,
             parts
         );
         let (child_name, child_gen) = match &parts[0] {
-            PathComponent::Name(n) => (n.to_owned(), 0),
+            PathComponent::Name(n) => (Cow::Borrowed(n.as_str()), 0),
             PathComponent::Lookup(p) => {
                 let (node, sub_gen) = tree.lookup_dynamic_path(gen, p)?;
                 let value = node.compute(tree)?;
                 (
-                    value.as_path_component()?,
+                    Cow::Owned(value.as_path_component()?),
                     value.generation().max(sub_gen.max(gen)),
                 )
             }
+            PathComponent::Glob => raise!(Runtime, "a glob cannot name a single node"),
         };
-        if let Some(child) = self.child_at(&child_name) {
+        if let Some(child) = self.child_at(&child_name)? {
             if parts.len() == 1 {
                 return Ok((child, child_gen.max(gen)));
             }
-            return Ok(child.lookup_dynamic_path(child_gen.max(gen), &parts[1..], tree)?);
+            return child.lookup_dynamic_path(child_gen.max(gen), &parts[1..], tree);
         }
-        bail!(format!(
+        let message = format!(
             "invalid path: did not find path component '{}' @ {}",
             child_name,
-            self.path_str()
-        ))
+            self.path_str()?
+        );
+        Err(Error::Runtime(ErrorContext::new(message))
+            .with_value(Value::new_str(&child_name))
+            .into())
     }
 
-    fn find_sinks(&self, sink_name: &str, matching: &mut Vec<ConcretePath>) {
-        if let Some(name) = self.maybe_sink_kind() {
-            if name == sink_name {
-                matching.push(self.path());
-            }
-        }
-        for (name, child) in &self.0.read().unwrap().children {
-            if name == "." || name == ".." {
-                continue;
-            }
-            child.find_sinks(sink_name, matching);
+    // The value of a path with globs, from this node: a list of the values of
+    // every node it matches, in order of their paths. A glob that matches no
+    // node, or a name or key that a matched node has no child for, leaves
+    // that node out.
+    pub fn compute_glob_path(
+        &self,
+        gen: usize,
+        parts: &[PathComponent],
+        tree: &Tree,
+    ) -> Fallible<Value> {
+        let mut matches = Vec::new();
+        self.match_glob_path(gen, parts, tree, &mut matches)?;
+        let mut matches = matches
+            .into_iter()
+            .map(|(node, node_gen)| Ok((node.path()?, node, node_gen)))
+            .collect::<Fallible<Vec<_>>>()?;
+        matches.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+        let mut list_gen = gen;
+        let mut items = Vec::new();
+        for (_, node, node_gen) in matches {
+            let value = node.compute(tree)?.with_generation(node_gen);
+            list_gen = list_gen.max(value.generation());
+            items.push(value);
         }
+        Ok(Value::from_list(items).with_generation(list_gen))
     }
 
-    fn find_sources(&self, source_name: &str, matching: &mut Vec<ConcretePath>) {
-        if let Some(name) = self.maybe_source_kind() {
-            if name == source_name {
-                matching.push(self.path());
+    fn match_glob_path(
+        &self,
+        gen: usize,
+        parts: &[PathComponent],
+        tree: &Tree,
+        out: &mut Vec<(NodeRef, usize)>,
+    ) -> Fallible<()> {
+        let (part, rest) = match parts.split_first() {
+            Some(split) => split,
+            None => {
+                out.push((self.to_owned(), gen));
+                return Ok(());
             }
-        }
-        for (name, child) in &self.0.read().unwrap().children {
-            if name == "." || name == ".." {
-                continue;
+        };
+        match part {
+            PathComponent::Name(name) => {
+                if let Some(child) = self.child_at(name)? {
+                    child.match_glob_path(gen, rest, tree, out)?;
+                }
+            }
+            PathComponent::Lookup(p) => {
+                let (node, sub_gen) = tree.lookup_dynamic_path(gen, p)?;
+                let value = node.compute(tree)?;
+                let child_gen = value.generation().max(sub_gen.max(gen));
+                if let Some(child) = self.child_at(&value.as_path_component()?)? {
+                    child.match_glob_path(child_gen, rest, tree, out)?;
+                }
+            }
+            PathComponent::Glob => {
+                for child in self.children()? {
+                    child.match_glob_path(gen, rest, tree, out)?;
+                }
             }
-            child.find_sources(source_name, matching);
         }
+        Ok(())
     }
 
     pub fn add_child(&self, name: &str) -> Fallible<NodeRef> {
-        let child = self.0.write().unwrap().create_new_child(name)?;
-        child
-            .0
-            .write()
-            .unwrap()
-            .children
-            .insert(".".to_owned(), child.clone());
-        child
-            .0
-            .write()
-            .unwrap()
-            .children
-            .insert("..".to_owned(), self.clone());
-        Ok(child)
+        require!(
+            self.child_at(name)?.is_none(),
+            Parse,
+            "there is already a node at {}",
+            self.path()?.new_child(name)
+        );
+        let id = self.arena.write().unwrap().add_child(self.id, name);
+        Ok(self.at(id))
     }
 
-    pub fn child_names(&self) -> Vec<String> {
-        self.0
-            .read()
-            .unwrap()
-            .children
-            .keys()
-            .filter(|&f| f != "." && f != "..")
-            .cloned()
-            .collect::<Vec<_>>()
+    pub fn child_names(&self) -> Fallible<Vec<String>> {
+        self.read(|node| node.children.keys().cloned().collect::<Vec<_>>())
     }
 
     pub fn child(&self, name: &str) -> Fallible<NodeRef> {
-        ensure!(
-            self.0.read().unwrap().children.contains_key(name),
-            "did not find child"
-        );
-        Ok(self.0.read().unwrap().children[name].clone())
+        match self.child_at(name)? {
+            Some(child) => Ok(child),
+            None => raise!(
+                Runtime,
+                "did not find child {} @ {}",
+                name,
+                self.path_str()?
+            ),
+        }
+    }
+
+    pub fn name(&self) -> Fallible<String> {
+        self.read(|node| node.path.basename().to_owned())
+    }
+
+    /// Where this node is declared in the source it was parsed from, if any.
+    pub fn span(&self) -> Fallible<Option<Span>> {
+        self.read(|node| node.span)
+    }
+
+    pub(super) fn set_span(&self, span: Span) -> Fallible<()> {
+        self.write(|node| node.span = Some(span))
+    }
+
+    pub(super) fn collect_subtree(&self, out: &mut Vec<NodeRef>) -> Fallible<()> {
+        let arena = self.arena.read().unwrap();
+        arena.check(self.id)?;
+        let mut ids = Vec::new();
+        arena.collect_subtree(self.id, &mut ids);
+        out.extend(ids.into_iter().map(|id| self.at(id)));
+        Ok(())
+    }
+
+    // Forget how this node was linked, so that linking it again finds its
+    // inputs afresh.
+    fn unlink(&self) -> Fallible<()> {
+        self.write(|node| {
+            node.linked_and_validated = false;
+            if let Some(NodeInput::Script(ref mut script)) = node.input {
+                Arc::make_mut(script).unlink();
+            }
+        })
     }
 
-    pub fn name(&self) -> String {
-        self.0.read().unwrap().name.clone()
+    // Reject reading a node that is private to a module other than ours.
+    fn check_may_read(&self, inputs: &HashMap<ConcretePath, NodeId>) -> Fallible<()> {
+        let arena = self.arena.read().unwrap();
+        let module = arena.node(self.id)?.module;
+        for (path, &id) in inputs {
+            if let Some(owner) = arena.private_to(id) {
+                require!(
+                    owner == module,
+                    Link,
+                    "reads {}, which is private to the file it is in",
+                    path
+                );
+            }
+        }
+        Ok(())
     }
 
     pub(super) fn link_and_validate_inputs(&self, tree: &Tree) -> Fallible<()> {
-        let path = self.path_str();
-        let span = trace_span!("link", "{}", self.path_str());
+        let path = self.path_str()?;
+        let span = trace_span!("link", "{}", path);
         let _ = span.enter();
-        if self.0.read().unwrap().linked_and_validated {
+        let already_linked = self.write(|node| {
+            let linked = node.linked_and_validated;
+            node.linked_and_validated = true;
+            linked
+        })?;
+        if already_linked {
             return Ok(());
         }
-        self.0.write().unwrap().linked_and_validated = true;
 
-        // Note: this pattern is a little funky! Normally we'd match to test
-        // these conditions, but if we did that the borrow would last over the
-        // body, which would disallow us from re-borrowing mutably inside.
-        if self.has_script() {
-            // Collect input map while borrowed read-only, so that we can find children.
-            let data = if let Some(NodeInput::Script(ref script)) = self.0.read().unwrap().input {
-                trace!("build input map @ {}", path);
-                script.build_input_map(tree)?
-            } else {
-                unreachable!();
-            };
-            if self.0.read().unwrap().sink.is_some() {
-                trace!("input map for ${}", path);
-                for inp in data.keys() {
-                    trace!("    {}", inp.to_string());
+        // Take the script out of the tree while we look for its inputs, so
+        // that linking the nodes it reads can update the tree.
+        if self.has_script()? {
+            let mut input = self.write(|node| node.input.take())?;
+            let linked = match input {
+                Some(NodeInput::Script(ref mut script)) => {
+                    let script = Arc::make_mut(script);
+                    trace!("build input map @ {}", path);
+                    script.build_input_map(tree).and_then(|data| {
+                        self.check_may_read(&data)?;
+                        if self.maybe_sink_kind()?.is_some() {
+                            trace!("input map for ${}", path);
+                            for inp in data.keys() {
+                                trace!("    {}", inp.to_string());
+                            }
+                        }
+                        trace!("install input map @ {}", path);
+                        script.install_input_map(data)?;
+                        script.compile(tree)
+                    })
                 }
-            }
-
-            // Re-borrow read-write to install the input map we built above.
-            if let Some(NodeInput::Script(ref mut script)) = self.0.write().unwrap().input {
-                trace!("install input map @ {}", path);
-                script.install_input_map(data)?;
-            }
+                _ => unreachable!(),
+            };
+            self.write(|node| node.input = input)?;
+            let at = self.path()?;
+            linked.map_err(|e| Error::annotate_path(e, at))?;
         }
 
         // Recurse into our children. Use sorted order so results are stable.
-        let mut children: Vec<String> = self
-            .0
-            .read()
-            .unwrap()
-            .children
-            .keys()
-            .filter(|s| *s != "." && *s != "..")
-            .map(|s| s.to_owned())
-            .collect::<_>();
-        children.sort();
-        for name in &children {
-            let child = &self.0.read().unwrap().children[name];
+        for child in self.sorted_children()? {
             child.link_and_validate_inputs(tree)?;
         }
 
         Ok(())
     }
 
-    fn find_all_sinks(&self, sinks: &mut Vec<NodeRef>) -> Fallible<()> {
-        for (name, child) in &self.0.read().unwrap().children {
-            if name == "." || name == ".." {
-                continue;
+    fn check_source_domains(&self, tree: &Tree, errors: &mut Vec<String>) -> Fallible<()> {
+        for child in self.children()? {
+            child.check_source_domains(tree, errors)?;
+        }
+        self.check_source_domain(tree, errors)
+    }
+
+    // As check_source_domains, for this node alone.
+    fn check_source_domain(&self, tree: &Tree, errors: &mut Vec<String>) -> Fallible<()> {
+        if let Some(domain) = self.source_domain(tree)? {
+            if let Some(default) = self.child_at("default")? {
+                if let Some(values) = default.possible_values(tree, &mut Vec::new())? {
+                    for v in values.iter().filter(|v| !domain.contains(v)) {
+                        errors.push(format!(
+                            "{}: default {} is not in the domain of the source",
+                            default.path_str()?,
+                            v
+                        ));
+                    }
+                }
             }
+        }
+
+        let mut unmatched = Vec::new();
+        for path in self.dynamic_paths()? {
+            path.find_unmatched_keys(tree, &mut unmatched)?;
+        }
+        for key in unmatched {
+            if Self::is_fed_by_source_domain(&key.lookup, tree)? {
+                errors.push(format!("{}: {}", self.path_str()?, key));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_fed_by_source_domain(lookup: &ScriptPath, tree: &Tree) -> Fallible<bool> {
+        for path in lookup.devirtualize(tree)? {
+            if let Ok(node) = tree.lookup_path(&path) {
+                let mut upstream = tree.graph.upstream_nodes(&node)?;
+                upstream.push(node);
+                for n in &upstream {
+                    if n.is_source()? && n.child_at("domain")?.is_some() {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    pub(super) fn find_all_sinks(&self, sinks: &mut Vec<NodeRef>) -> Fallible<()> {
+        for child in self.children()? {
             child.find_all_sinks(sinks)?;
         }
-        if self.0.read().unwrap().sink.is_some() {
+        if self.maybe_sink_kind()?.is_some() {
             sinks.push(self.to_owned());
         }
         Ok(())
@@ -383,142 +1486,290 @@ impl NodeRef {
 
     fn populate_flow_graph(&self, graph: &mut Graph) -> Fallible<()> {
         graph.add_node(self);
-        for (name, child) in &self.0.read().unwrap().children {
-            if name == "." || name == ".." {
-                continue;
-            }
+        for child in self.children()? {
             child.populate_flow_graph(graph)?;
         }
+        self.add_input_edges(graph)
+    }
 
-        if let Some(NodeInput::Script(ref script)) = self.0.read().unwrap().input {
-            script.populate_flow_graph(self, graph)?;
+    // Add an edge to this node from everything it reads.
+    fn add_input_edges(&self, graph: &mut Graph) -> Fallible<()> {
+        let inputs = self.read(|node| match node.input {
+            Some(NodeInput::Script(ref script)) => script.input_ids(),
+            _ => Vec::new(),
+        })?;
+        for (id, dynamic) in inputs {
+            if dynamic {
+                graph.add_dynamic_edge(&self.at(id), self);
+            } else {
+                graph.add_edge(&self.at(id), self);
+            }
         }
 
+        // A source reads its default until the first event arrives.
+        if self.is_source()? {
+            if let Some(default) = self.child_at("default")? {
+                graph.add_edge(&default, self);
+            }
+        }
         Ok(())
     }
 
-    fn flow_input_to_output(&self, sinks: &[NodeRef], graph: &Graph) -> Fallible<()> {
-        for (name, child) in &self.0.read().unwrap().children {
-            if name == "." || name == ".." {
-                continue;
-            }
-            child.flow_input_to_output(sinks, graph)?;
+    fn flow_input_to_output(&self, graph: &Graph) -> Fallible<()> {
+        for child in self.children()? {
+            child.flow_input_to_output(graph)?;
         }
 
-        let mut maybe_connected_sinks = None;
-        if let Some(NodeInput::Source(_, _)) = self.0.read().unwrap().input {
-            let connected = graph.connected_nodes(self, sinks)?;
-            if connected.is_empty() {
-                warn!(
-                    "dataflow warning: source at {} is not connected to any sinks",
-                    self.path_str()
-                );
-            }
-            maybe_connected_sinks = Some(connected);
-        };
+        if self.is_source()? {
+            self.set_observers(graph)?;
+        }
+        Ok(())
+    }
 
-        if let Some(mut connected_sinks) = maybe_connected_sinks {
-            if let Some(NodeInput::Source(_, ref mut sinks)) = self.0.write().unwrap().input {
-                assert!(
-                    sinks.is_empty(),
-                    "dataflow error: found connected sinks at {}, but sinks already set",
-                    self.path_str()
-                );
-                sinks.append(&mut connected_sinks);
-            } else {
-                panic!("expected source to not mutate")
-            }
+    // Store every node downstream of this source, in the order to compute
+    // them in, replacing any that were found before.
+    fn set_observers(&self, graph: &Graph) -> Fallible<()> {
+        let affected = graph.evaluation_order(self)?;
+        let (connected, live) = {
+            let arena = self.arena.read().unwrap();
+            let connected = affected.iter().any(|n| arena.get(n.id).sink.is_some());
+            let live = affected
+                .iter()
+                .filter(|n| !arena.get(n.id).dead)
+                .map(|n| n.id)
+                .collect();
+            (connected, live)
+        };
+        if !connected {
+            warn!(
+                "dataflow warning: source at {} is not connected to any sinks",
+                self.path_str()?
+            );
         }
+        self.write(|node| {
+            if let Some(NodeInput::Source(_, ref mut observers)) = node.input {
+                *observers = live;
+            }
+        })
+    }
 
+    // Stop events at this source, if it is one, from visiting dead nodes.
+    fn prune_observers(&self) -> Fallible<()> {
+        let mut arena = self.arena.write().unwrap();
+        let observers = match arena.node(self.id)?.input {
+            Some(NodeInput::Source(_, ref observers)) => observers.to_owned(),
+            _ => return Ok(()),
+        };
+        let live = observers
+            .into_iter()
+            .filter(|&id| !arena.get(id).dead)
+            .collect();
+        if let Some(NodeInput::Source(_, ref mut observers)) = arena.get_mut(self.id).input {
+            *observers = live;
+        }
         Ok(())
     }
 
-    fn has_script(&self) -> bool {
-        if let Some(NodeInput::Script(_)) = self.0.read().unwrap().input {
-            return true;
-        }
-        false
+    pub(super) fn has_script(&self) -> Fallible<bool> {
+        self.read(|node| matches!(node.input, Some(NodeInput::Script(_))))
     }
 
-    fn child_at(&self, name: &str) -> Option<NodeRef> {
-        self.0
-            .read()
-            .unwrap()
-            .children
-            .get(name)
-            .map(|v| v.to_owned())
+    pub(super) fn child_at(&self, name: &str) -> Fallible<Option<NodeRef>> {
+        let arena = self.arena.read().unwrap();
+        arena.check(self.id)?;
+        Ok(arena.child_of(self.id, name).map(|id| self.at(id)))
+    }
+
+    pub fn path(&self) -> Fallible<ConcretePath> {
+        self.read(|node| node.path.clone())
+    }
+
+    pub fn path_str(&self) -> Fallible<String> {
+        self.read(|node| node.path.to_string())
+    }
+
+    pub fn location(&self) -> Fallible<Option<Dimension2>> {
+        self.read(|node| node.location)
+    }
+
+    pub fn set_location(&self, loc: Dimension2) -> Fallible<()> {
+        self.write(|node| {
+            require!(
+                node.location.is_none(),
+                Parse,
+                "location has already been set @ {}",
+                node.path
+            );
+            node.location = Some(loc);
+            Ok(())
+        })?
+    }
+
+    pub fn dimensions(&self) -> Fallible<Option<Dimension2>> {
+        self.read(|node| node.dimensions)
+    }
+
+    pub fn set_dimensions(&self, dim: Dimension2) -> Fallible<()> {
+        self.write(|node| {
+            require!(
+                node.dimensions.is_none(),
+                Parse,
+                "dimensions have already been set @ {}",
+                node.path
+            );
+            node.dimensions = Some(dim);
+            Ok(())
+        })?
+    }
+
+    /// The tags on this node, as written after # in the tree.
+    pub fn tags(&self) -> Fallible<Vec<String>> {
+        self.read(|node| node.tags.clone())
+    }
+
+    pub fn has_tag(&self, tag: &str) -> Fallible<bool> {
+        self.read(|node| node.tags.iter().any(|t| t == tag))
+    }
+
+    pub fn add_tag(&self, tag: &str) -> Fallible<()> {
+        self.write(|node| {
+            require!(
+                !node.tags.iter().any(|t| t == tag),
+                Parse,
+                "tag #{} set twice @ {}",
+                tag,
+                node.path
+            );
+            node.tags.push(tag.to_owned());
+            Ok(())
+        })?
     }
 
-    pub fn path(&self) -> ConcretePath {
-        self.0.read().unwrap().path.clone()
+    pub fn set_source(&self, from: &str) -> Fallible<()> {
+        self.write(|node| {
+            require!(
+                node.input.is_none(),
+                Parse,
+                "input was set twice @ {}",
+                node.path
+            );
+            node.input = Some(NodeInput::Source(from.to_owned(), Vec::new()));
+            Ok(())
+        })?
     }
 
-    pub fn path_str(&self) -> String {
-        self.0.read().unwrap().path.to_string()
+    pub fn set_sink(&self, tgt: &str) -> Fallible<()> {
+        self.write(|node| {
+            require!(node.sink.is_none(), Parse, "sink set twice @ {}", node.path);
+            node.sink = Some(tgt.to_owned());
+            Ok(())
+        })?
     }
 
-    pub(super) fn handle_event(&self, value: Value) -> Fallible<()> {
-        ensure!(self.is_source(), "received event on non-source node");
-        let mut node = self.0.write().unwrap();
-        node.cache = Some(value);
-        Ok(())
+    // Copy the children of `subtree` under this node, as nodes of this
+    // node's module.
+    pub fn insert_subtree(&self, subtree: &NodeRef) -> Fallible<()> {
+        let mut modules = HashMap::new();
+        modules.insert(0, self.read(|node| node.module)?);
+        self.copy_children(subtree, &mut modules)
     }
 
-    pub fn location(&self) -> Option<Dimension2> {
-        self.0.read().unwrap().location
+    // Copy the children of `subtree` under this node, as nodes of a module of
+    // their own, so that only they may read any of them that are private.
+    pub fn import_subtree(&self, subtree: &NodeRef) -> Fallible<()> {
+        self.copy_children(subtree, &mut HashMap::new())
     }
 
-    pub fn set_location(&self, loc: Dimension2) -> Fallible<()> {
-        ensure!(
-            self.0.read().unwrap().location.is_none(),
-            "location has already been set"
+    // Copy the children of `subtree`, which belongs to some other tree, under
+    // this node. Nothing in the copies has been linked yet, so the ids in
+    // their inputs are all empty and stay valid. `modules` maps the modules
+    // of the subtree to those of this tree.
+    fn copy_children(
+        &self,
+        subtree: &NodeRef,
+        modules: &mut HashMap<usize, usize>,
+    ) -> Fallible<()> {
+        require!(
+            !Arc::ptr_eq(&self.arena, &subtree.arena),
+            Parse,
+            "cannot insert a tree into itself @ {}",
+            self.path_str()?
         );
-        self.0.write().unwrap().location = Some(loc);
+        for child in subtree.sorted_children()? {
+            let copy = self.add_child(&child.name()?)?;
+            let (location, dimensions, tags, input, sink, private, module) =
+                child.read(|node| {
+                    (
+                        node.location,
+                        node.dimensions,
+                        node.tags.clone(),
+                        node.input.clone(),
+                        node.sink.clone(),
+                        node.private,
+                        node.module,
+                    )
+                })?;
+            let module = *modules
+                .entry(module)
+                .or_insert_with(|| self.arena.write().unwrap().new_module());
+            copy.write(|node| {
+                node.location = location;
+                node.dimensions = dimensions;
+                node.tags = tags;
+                node.input = input;
+                node.sink = sink;
+                node.private = private;
+                node.module = module;
+            })?;
+            copy.copy_children(&child, modules)?;
+        }
         Ok(())
     }
 
-    pub fn dimensions(&self) -> Option<Dimension2> {
-        self.0.read().unwrap().dimensions
+    /// Whether only the nodes of the file that this node is in may read it.
+    pub fn is_private(&self) -> Fallible<bool> {
+        self.read(|node| node.private)
     }
 
-    pub fn set_dimensions(&self, dim: Dimension2) -> Fallible<()> {
-        ensure!(
-            self.0.read().unwrap().dimensions.is_none(),
-            "dimensions have already been set"
-        );
-        self.0.write().unwrap().dimensions = Some(dim);
-        Ok(())
+    pub(super) fn set_private(&self) -> Fallible<()> {
+        self.write(|node| node.private = true)
     }
 
-    pub fn set_source(&self, from: &str) -> Fallible<()> {
-        ensure!(
-            self.0.read().unwrap().input.is_none(),
-            "parse error: input was set twice @ {}",
-            self.0.read().unwrap().path
-        );
-        self.0.write().unwrap().input = Some(NodeInput::Source(from.to_owned(), Vec::new()));
-        Ok(())
+    pub fn is_dead(&self) -> Fallible<bool> {
+        self.read(|node| node.dead)
     }
 
-    pub fn set_sink(&self, tgt: &str) -> Fallible<()> {
-        ensure!(
-            self.0.read().unwrap().sink.is_none(),
-            "parse error: sink set twice @ {}",
-            self.0.read().unwrap().path
-        );
-        self.0.write().unwrap().sink = Some(tgt.to_owned());
-        Ok(())
+    fn set_dead(&self, dead: bool) -> Fallible<()> {
+        self.write(|node| {
+            if dead && !node.dead {
+                node.invalidate();
+            }
+            node.dead = dead;
+        })
     }
 
-    pub fn insert_subtree(&self, subtree: &NodeRef) -> Fallible<()> {
-        for (name, child) in &subtree.0.read().unwrap().children {
-            self.0
-                .write()
-                .unwrap()
-                .children
-                .insert(name.to_owned(), child.to_owned());
+    // Fold the script of this node, if it has one, and return its value if
+    // that is a constant. The script is taken out of the tree while folding,
+    // as with linking, so that folding the nodes it reads can update them.
+    pub(super) fn fold(&self, folder: &mut Folder) -> Fallible<Option<Value>> {
+        if !self.has_script()? {
+            return Ok(None);
         }
-        Ok(())
+        let mut input = self.write(|node| node.input.take())?;
+        let value = match input {
+            Some(NodeInput::Script(ref mut script)) => Arc::make_mut(script).fold(folder),
+            _ => unreachable!(),
+        };
+        self.write(|node| node.input = input)?;
+        let at = self.path()?;
+        value.map_err(|e| Error::annotate_path(e, at))
+    }
+
+    fn folds(&self) -> Fallible<Vec<String>> {
+        self.read(|node| match node.input {
+            Some(NodeInput::Script(ref script)) => script.folds().to_vec(),
+            _ => Vec::new(),
+        })
     }
 
     pub fn apply_template(&self, template: &NodeRef) -> Fallible<()> {
@@ -537,139 +1788,288 @@ impl NodeRef {
         // Optional output data binding.
         // sink: Option<(String, SinkRef)>,
 
-        if let Some(dim) = template.location() {
+        if let Some(dim) = template.location()? {
             self.set_location(dim)?;
         }
         Ok(())
     }
 
     pub fn set_script(&self, script: Script) -> Fallible<()> {
-        ensure!(
-            self.0.read().unwrap().input.is_none(),
-            "parse error: input was set twice at {}",
-            self.0.read().unwrap().path
-        );
-        self.0.write().unwrap().input = Some(NodeInput::Script(script));
-        Ok(())
+        self.write(|node| {
+            require!(
+                node.input.is_none(),
+                Parse,
+                "input was set twice at {}",
+                node.path
+            );
+            node.input = Some(NodeInput::Script(Arc::new(script)));
+            Ok(())
+        })?
     }
 
     pub fn compute(&self, tree: &Tree) -> Fallible<Value> {
-        let span = trace_span!("compute", "{}", self.path_str());
-        let _ = span.enter();
-
-        // FIXME: we need to make this entire path mut, so that we can write back the
-        // FIXME: computed entries as we compute them. For now, we'll be re-computing
-        // FIXME: intermediate nodes. Note: The cache *is* populated for source nodes
-        // FIXME: by handle_event, which is mut.
-        if let Some(ref cached_value) = self.0.read().unwrap().cache {
-            assert!(self.is_source());
-            return Ok(cached_value.to_owned());
-        }
-
-        let path = self.path_str();
-        trace!("computing @ {}", path);
-        match self.0.read().unwrap().input {
-            None => bail!("runtime error: computing a non-input path @ {}", path),
-            Some(NodeInput::Script(ref script)) => script.compute(tree),
-            Some(NodeInput::Source(_, _)) => match tree.lookup_path(&(self.path() / "default")) {
-                Ok(default_node) => default_node.compute(tree),
-                Err(_) => {
-                    error!("source '{}' not ready and no default set", self.path_str());
-                    bail!("source '{}' not ready and no default set", self.path_str())
+        // Sources are cached by handle_event. Scripts are cached the first
+        // time they are computed, until an event on some source they read
+        // invalidates them. Take what the node needs out of the arena, and let
+        // go of it before running the script, which computes the nodes it
+        // reads in turn.
+        let (script, path) = {
+            let arena = self.arena.read().unwrap();
+            let node = arena.node(self.id)?;
+            if let Some(ref cached_value) = *node.cache.lock().unwrap() {
+                return Ok(cached_value.to_owned());
+            }
+            let span = trace_span!("compute", "{}", node.path);
+            let _ = span.enter();
+            trace!("computing @ {}", node.path);
+            match node.input {
+                None => {
+                    let message = format!("computing a non-input path @ {}", node.path);
+                    return Err(Error::Runtime(ErrorContext::new(message))
+                        .at(node.path.clone())
+                        .into());
                 }
-            },
-        }
+                Some(NodeInput::Script(ref script)) => (script.clone(), node.path.clone()),
+                // Do not cache the default, so that it cannot mask a real event.
+                Some(NodeInput::Source(_, _)) => {
+                    let default = node.children.get("default").copied();
+                    let path = node.path.clone();
+                    drop(arena);
+                    return match default {
+                        Some(default) => self.at(default).compute(tree),
+                        None => {
+                            error!("source '{}' not ready and no default set", path);
+                            let message = format!("source '{}' not ready and no default set", path);
+                            Err(Error::Runtime(ErrorContext::new(message)).at(path).into())
+                        }
+                    };
+                }
+            }
+        };
+        let value = script
+            .compute(tree)
+            .map_err(|e| Error::annotate_path(e, path))?;
+        self.read(|node| {
+            if !node.dead {
+                *node.cache.lock().unwrap() = Some(value.clone());
+            }
+        })?;
+        Ok(value)
+    }
+
+    fn observers(&self) -> Fallible<Vec<NodeId>> {
+        self.read(|node| {
+            if let Some(NodeInput::Source(_, ref observers)) = node.input {
+                return Ok(observers.to_owned());
+            }
+            raise!(
+                Runtime,
+                "invalid event; occurred on node {} with no source",
+                node.path
+            )
+        })?
+    }
+
+    /// Every node whose value depends on this source, in an order where each
+    /// node comes after everything it reads.
+    pub fn get_nodes_observing(&self) -> Fallible<Vec<NodeRef>> {
+        Ok(self.observers()?.iter().map(|&id| self.at(id)).collect())
     }
 
     pub fn get_sink_nodes_observing(&self) -> Fallible<Vec<NodeRef>> {
-        if let Some(NodeInput::Source(_, ref sinks)) = self.0.read().unwrap().input {
-            return Ok(sinks.to_owned());
-        }
-        bail!(
-            "runtime: invalid event; occurred on node {} with no source",
-            self.path_str()
-        )
+        let observing = self.get_nodes_observing()?;
+        let arena = self.arena.read().unwrap();
+        Ok(observing
+            .into_iter()
+            .filter(|n| arena.get(n.id).sink.is_some())
+            .collect())
     }
 
     pub fn sink_kind(&self) -> Fallible<String> {
-        if let Some(ref kind) = self.0.read().unwrap().sink {
-            return Ok(kind.to_owned());
+        if let Some(kind) = self.maybe_sink_kind()? {
+            return Ok(kind);
         }
-        bail!(
-            "runtime: tried to get sink kind of the non-sink node at {}",
-            self.path_str()
+        raise!(
+            Runtime,
+            "tried to get sink kind of the non-sink node at {}",
+            self.path_str()?
         )
     }
 
-    pub fn maybe_sink_kind(&self) -> Option<String> {
-        if let Some(ref kind) = self.0.read().unwrap().sink {
-            return Some(kind.to_owned());
+    pub fn maybe_sink_kind(&self) -> Fallible<Option<String>> {
+        self.read(|node| node.sink.clone())
+    }
+
+    pub fn is_source(&self) -> Fallible<bool> {
+        self.read(|node| node.is_source())
+    }
+
+    pub fn is_constant(&self) -> Fallible<bool> {
+        self.read(|node| match node.input {
+            Some(NodeInput::Script(ref script)) => script.is_constant(),
+            _ => false,
+        })
+    }
+
+    // The values this node may produce, if knowable without running the tree.
+    // `visiting` holds the nodes we are already inside of, to stop on cycles.
+    pub(super) fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        let path = self.path()?;
+        if visiting.contains(&path) {
+            tree.cut_possible_values();
+            return Ok(None);
+        }
+        if let Some(values) = tree.cached_possible_values(self.id) {
+            return Ok(values);
         }
-        None
+        let cuts = tree.possible_value_cuts();
+        visiting.push(path);
+        let values = match self.script()? {
+            Some(script) => script.possible_values(tree, visiting)?,
+            None => self.source_domain(tree)?,
+        };
+        visiting.pop();
+        // A node with no input may be one whose script is out for linking.
+        if self.has_script()? || self.is_source()? {
+            tree.cache_possible_values(self.id, cuts, &values);
+        }
+        Ok(values)
     }
 
-    pub fn is_source(&self) -> bool {
-        if let Some(NodeInput::Source(_, _)) = self.0.read().unwrap().input {
-            return true;
+    /// The values a source declares it may produce, from a constant list in
+    /// its `domain` child, e.g. `domain <- ["up", "open", "down"]`.
+    pub fn source_domain(&self, tree: &Tree) -> Fallible<Option<Vec<Value>>> {
+        let domain = self.read(|node| {
+            if !node.is_source() {
+                return None;
+            }
+            node.children.get("domain").copied()
+        })?;
+        let domain = match domain {
+            Some(id) => self.at(id),
+            None => return Ok(None),
+        };
+        // Use virtual interpretation, rather than compute, so that this works
+        // while we are still linking inputs.
+        let values = domain.possible_values(tree, &mut Vec::new())?;
+        match values.as_deref() {
+            Some([list]) if list.is_list() => Ok(Some(list.as_list()?.to_vec())),
+            _ => raise!(
+                Dataflow,
+                "the domain of {} must be a constant list",
+                self.path_str()?
+            ),
         }
-        false
     }
 
-    pub fn maybe_source_kind(&self) -> Option<String> {
-        if let Some(NodeInput::Source(ref kind, _)) = self.0.read().unwrap().input {
-            return Some(kind.to_owned());
+    pub(super) fn find_unreachable_arms(&self, tree: &Tree) -> Fallible<Vec<String>> {
+        match self.script()? {
+            Some(script) => script.find_unreachable_arms(tree),
+            None => Ok(Vec::new()),
         }
-        None
+    }
+
+    // The script of this node, if it has one, to run without holding the
+    // arena.
+    fn script(&self) -> Fallible<Option<Arc<Script>>> {
+        self.read(|node| match node.input {
+            Some(NodeInput::Script(ref script)) => Some(script.clone()),
+            _ => None,
+        })
+    }
+
+    pub(super) fn dynamic_paths(&self) -> Fallible<Vec<ScriptPath>> {
+        self.read(|node| match node.input {
+            Some(NodeInput::Script(ref script)) => script.dynamic_paths(),
+            _ => Vec::new(),
+        })
+    }
+
+    pub fn maybe_source_kind(&self) -> Fallible<Option<String>> {
+        self.read(|node| match node.input {
+            Some(NodeInput::Source(ref kind, _)) => Some(kind.to_owned()),
+            _ => None,
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum NodeInput {
-    Source(String, Vec<NodeRef>),
-    Script(Script),
+    // The kind of source and every node it affects, in evaluation order.
+    Source(String, Vec<NodeId>),
+    // Shared, so that it can be run without holding the arena; changed with
+    // Arc::make_mut, which only copies it if it is being run.
+    Script(Arc<Script>),
 }
 
 #[derive(Debug)]
 pub struct Node {
     // The tree structure.
-    name: String,
     path: ConcretePath,
-    children: HashMap<String, NodeRef>,
+    parent: Option<NodeId>,
+    children: HashMap<String, NodeId>,
     linked_and_validated: bool,
 
     // Simple sigils.
     location: Option<Dimension2>,
     dimensions: Option<Dimension2>,
+    tags: Vec<String>,
 
     // Input data binding can either be an external system or a computed value
     // pulling inputs from external systems and other computed values. Or
     // nothing; it's fine for a node to just be structural.
     input: Option<NodeInput>,
-    cache: Option<Value>,
+    cache: Mutex<Option<Value>>,
 
     // Optional output data binding.
     sink: Option<String>,
+
+    // Where the node's name is in the source text. Not carried along when a
+    // node is copied, as the copy's source is somewhere else.
+    span: Option<Span>,
+
+    // The file that the node came from, as an index into the modules of its
+    // arena, and whether only nodes from that file may read it.
+    module: usize,
+    private: bool,
+
+    // Whether no sink reads this node, so that events do not visit it.
+    dead: bool,
 }
 
 impl Node {
     pub fn new(path: ConcretePath) -> Self {
         Node {
-            name: path.basename().to_owned(),
             path,
+            parent: None,
             children: HashMap::new(),
             linked_and_validated: false,
             location: None,
             dimensions: None,
+            tags: Vec::new(),
             input: None,
-            cache: None,
+            cache: Mutex::new(None),
             sink: None,
+            span: None,
+            module: 0,
+            private: false,
+            dead: false,
         }
     }
 
-    fn create_new_child(&mut self, name: &str) -> Fallible<NodeRef> {
-        let child = NodeRef::new(Node::new(self.path.new_child(name)));
-        self.children.insert(name.to_owned(), child.clone());
-        Ok(child)
+    fn is_source(&self) -> bool {
+        matches!(self.input, Some(NodeInput::Source(_, _)))
+    }
+
+    // Forget the computed value of a script. Sources keep the last event.
+    fn invalidate(&self) {
+        if !self.is_source() {
+            *self.cache.lock().unwrap() = None;
+        }
     }
 }
 
@@ -680,24 +2080,24 @@ mod tests {
     #[test]
     fn test_build_tree() -> Fallible<()> {
         let tree = TreeBuilder::empty();
-        assert_eq!(None, tree.root().location());
+        assert_eq!(None, tree.root().location()?);
 
         let d10 = Dimension2::from_str("10x10")?;
         let d20 = Dimension2::from_str("20x20")?;
 
         let child = tree.lookup("/")?.add_child("foopy")?;
         child.set_location(d10)?;
-        assert_eq!(d10, child.location().unwrap());
+        assert_eq!(d10, child.location()?.unwrap());
 
         let child = tree.lookup("/foopy")?.add_child("barmy")?;
         child.set_location(d20)?;
         child.set_dimensions(d20)?;
-        assert_eq!(d20, child.location().unwrap());
+        assert_eq!(d20, child.location()?.unwrap());
 
-        assert_eq!(d10, tree.lookup("/foopy")?.location().unwrap());
-        assert_eq!(d20, tree.lookup("/foopy/barmy")?.location().unwrap());
-        assert_eq!(d20, tree.lookup("/foopy/barmy")?.location().unwrap());
-        assert_eq!(d10, tree.lookup("/foopy")?.location().unwrap());
+        assert_eq!(d10, tree.lookup("/foopy")?.location()?.unwrap());
+        assert_eq!(d20, tree.lookup("/foopy/barmy")?.location()?.unwrap());
+        assert_eq!(d20, tree.lookup("/foopy/barmy")?.location()?.unwrap());
+        assert_eq!(d10, tree.lookup("/foopy")?.location()?.unwrap());
         Ok(())
     }
 
@@ -735,6 +2135,232 @@ bar
         Ok(())
     }
 
+    #[test]
+    fn test_tree_dependents() -> Fallible<()> {
+        let s = r#"
+palette
+    on <- "bright"
+    off <- "dark"
+switch ^src1
+    default <- "off"
+room
+    color <- /palette/{/switch}
+    light $sink <- ./color
+hall $sink <- "static"
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        assert_eq!(
+            tree.dependents_of(&p("/palette/on"))?,
+            vec![p("/room/color"), p("/room/light")]
+        );
+        assert_eq!(
+            tree.dependents_of(&p("/switch/default"))?,
+            vec![p("/room/color"), p("/room/light"), p("/switch")]
+        );
+        assert!(tree.dependents_of(&p("/hall"))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_dependencies() -> Fallible<()> {
+        let s = r#"
+palette
+    on <- "bright"
+    off <- "dark"
+switch ^src1
+    default <- "off"
+room
+    color <- /palette/{/switch}
+    light $sink <- ./color
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        assert_eq!(
+            tree.dependencies_of(&p("/room/light"))?,
+            vec![
+                p("/palette/off"),
+                p("/palette/on"),
+                p("/switch"),
+                p("/switch/default")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_event_invalidates_intermediates() -> Fallible<()> {
+        let s = r#"
+switch ^button
+    default <- 1
+double <- ./switch * 2
+light $hue <- ./double + 1
+other ^button
+    default <- 10
+mixed $hue <- ./double + ./other
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        let i = Value::from_integer;
+        let sorted = |mut v: Vec<(ConcretePath, Value)>| {
+            v.sort_by(|(a, _), (b, _)| a.cmp(b));
+            v
+        };
+        assert_eq!(tree.lookup("/mixed")?.compute(&tree)?, i(12));
+
+        let updates = tree.handle_event(&p("/switch"), i(5))?;
+        assert_eq!(
+            sorted(updates["hue"].to_owned()),
+            vec![(p("/light"), i(11)), (p("/mixed"), i(20))]
+        );
+        let updates = tree.handle_event(&p("/other"), i(1))?;
+        assert_eq!(updates["hue"], vec![(p("/mixed"), i(11))]);
+        let updates = tree.handle_event(&p("/switch"), i(2))?;
+        assert_eq!(
+            sorted(updates["hue"].to_owned()),
+            vec![(p("/light"), i(5)), (p("/mixed"), i(5))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_devirtualize_known_keys() -> Fallible<()> {
+        let s = r#"
+palette
+    on <- "bright"
+    low <- "dim"
+    off <- "dark"
+mode <- "on"
+switch ^button
+    domain <- ["low", "off"]
+    default <- "off"
+fixed $hue <- /palette/{/mode}
+light $hue <- /palette/{/switch}
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        assert_eq!(tree.dependents_of(&p("/palette/on"))?, vec![p("/fixed")]);
+        assert_eq!(tree.dependents_of(&p("/palette/low"))?, vec![p("/light")]);
+        assert_eq!(
+            tree.dependencies_of(&p("/light"))?,
+            vec![
+                p("/palette/low"),
+                p("/palette/off"),
+                p("/switch"),
+                p("/switch/default")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_devirtualize_long_chain() -> Fallible<()> {
+        // Each key is looked up through the one before it, which every table
+        // entry also reads: without remembering possible values, this takes
+        // time exponential in the length of the chain.
+        let mut s = "k0 ^switch\n    domain <- [\"a\", \"b\"]\n    default <- \"b\"\n".to_owned();
+        for i in 1..=30 {
+            s += &format!("t{i}\n    a <- /k{j}\n    b <- /k{j}\n", i = i, j = i - 1);
+            s += &format!("k{i} $out <- /t{i}/{{/k{j}}}\n", i = i, j = i - 1);
+        }
+        let tree = TreeBuilder::default().build_from_str(&s)?;
+        assert_eq!(tree.lookup("/k30")?.compute(&tree)?, Value::new_str("b"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_devirtualize_cycle() -> Fallible<()> {
+        let s = r#"
+t
+    x <- "x"
+    y <- "y"
+a <- /t/{/b}
+b <- /t/{/a}
+"#;
+        // The build must terminate, and report the cycle between the keys.
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "dataflow error: found cycles in the dataflow graph:\n    /a reads /b\n    /b reads /a"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_cycle_through_lookup() -> Fallible<()> {
+        let s = r#"
+t
+    x <- 2
+    y <- 1
+    z <- /a
+key ^switch
+    domain <- ["x", "y"]
+    default <- "x"
+a <- /t/{/key}
+"#;
+        assert!(TreeBuilder::default().build_from_str(s).is_ok());
+
+        // Only the key "y" closes the loop, but the build must still reject it.
+        let s = s.replace("y <- 1", "y <- /t/z");
+        let err = TreeBuilder::default().build_from_str(&s).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "dataflow error: found cycles in the dataflow graph:\n    /a reads /t/y through a lookup\n    /t/y reads /t/z\n    /t/z reads /a"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_source_domain() -> Fallible<()> {
+        let s = r#"
+glowswitch
+    0 <- "on"
+    1 <- "low"
+    2 <- "moonlight"
+button ^redstone
+    domain <- [0, 1, 2, 3]
+    default <- 3
+light $hue <- /glowswitch/{/button}
+"#;
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        assert!(err.to_string().contains(
+            "/light: lookup {/button} may produce '3', but /glowswitch has no child named '3'"
+        ));
+
+        let s = s.replace(
+            "    2 <- \"moonlight\"",
+            "    2 <- \"moonlight\"\n    3 <- \"off\"",
+        );
+        let mut tree = TreeBuilder::default().build_from_str(&s)?;
+        assert_eq!(
+            tree.lookup("/button")?.source_domain(&tree)?,
+            Some((0..4).map(Value::from_integer).collect())
+        );
+        let p = ConcretePath::from_str("/button")?;
+        assert!(tree.handle_event(&p, Value::from_integer(4)).is_err());
+        let updates = tree.handle_event(&p, Value::from_integer(1))?;
+        assert_eq!(updates["hue"][0].1, Value::new_str("low"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_source_domain_default() -> Fallible<()> {
+        let s = r#"
+knifeswitch ^redstone
+    domain <- ["up", "open", "down"]
+    default <- "sideways"
+light $hue <- /knifeswitch
+"#;
+        assert!(TreeBuilder::default().build_from_str(s).is_err());
+        let s = r#"
+knifeswitch ^redstone
+    domain <- "up"
+light $hue <- /knifeswitch
+"#;
+        assert!(TreeBuilder::default().build_from_str(s).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_tree_import_str() -> Fallible<()> {
         let test_ygg = r#"
@@ -777,4 +2403,370 @@ foo <- /mnt/a/b/c
         );
         Ok(())
     }
+
+    #[test]
+    fn test_tree_import_copies_nodes() -> Fallible<()> {
+        let test_ygg = r#"
+a
+    b <- "hello"
+"#;
+        let s = r#"
+left
+    import(test.ygg)
+right
+    import(test.ygg)
+"#;
+        let tree = TreeBuilder::default()
+            .intercept_import("test.ygg", test_ygg)?
+            .build_from_str(s)?;
+        let left = tree.lookup("/left/a/b")?;
+        assert_eq!(left.path_str()?, "/left/a/b");
+        assert_ne!(left.id(), tree.lookup("/right/a/b")?.id());
+        assert_eq!(left.compute(&tree)?, Value::new_str("hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_drop_frees_nodes() -> Fallible<()> {
+        let s = r#"
+switch ^button
+    default <- "off"
+light $hue <- ./switch
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let light = tree.lookup("/light")?;
+        let arena = Arc::downgrade(&tree.arena);
+        drop(tree);
+        assert!(arena.upgrade().is_some());
+        drop(light);
+        assert!(arena.upgrade().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_add_nodes_at_runtime() -> Fallible<()> {
+        let s = r#"
+room
+    switch ^button
+        default <- "off"
+    light $hue <- ./switch
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        tree.add_nodes(
+            &p("/room"),
+            r#"
+dimmer ^zwave
+    default <- 0
+lamp $hue <- ./dimmer + 1
+"#,
+        )?;
+        let updates = tree.handle_event(&p("/room/dimmer"), Value::from_integer(4))?;
+        assert_eq!(
+            updates["hue"],
+            vec![(p("/room/lamp"), Value::from_integer(5))]
+        );
+        assert_eq!(tree.find_sources("zwave"), vec![p("/room/dimmer")]);
+
+        // Names must not collide with existing nodes.
+        assert!(tree.add_nodes(&p("/room"), "light <- 1").is_err());
+        assert_eq!(
+            tree.lookup("/room/light")?.compute(&tree)?,
+            Value::new_str("off")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_add_nodes_relinks_lookups() -> Fallible<()> {
+        let s = r#"
+scenes
+    day <- "bright"
+scene ^button
+    default <- "day"
+light $hue <- /scenes/{/scene}
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        tree.add_nodes(&p("/scenes"), "night <- \"dim\"")?;
+        let updates = tree.handle_event(&p("/scene"), Value::new_str("night"))?;
+        assert_eq!(updates["hue"], vec![(p("/light"), Value::new_str("dim"))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_remove_node_at_runtime() -> Fallible<()> {
+        let s = r#"
+switch ^button
+    default <- 1
+light $hue <- ./switch
+extra $hue <- ./switch + 1
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+
+        // Nothing may be left reading a removed node.
+        assert!(tree.remove_node(&p("/switch")).is_err());
+        let updates = tree.handle_event(&p("/switch"), Value::from_integer(2))?;
+        assert_eq!(updates["hue"].len(), 2);
+
+        let extra = tree.lookup("/extra")?;
+        tree.remove_node(&p("/extra"))?;
+        assert!(tree.lookup("/extra").is_err());
+        tree.add_nodes(&p("/"), "spare <- 1")?;
+        let err = extra.compute(&tree).err().unwrap();
+        match Error::find(&err) {
+            Some(Error::Dataflow(ctx)) => {
+                assert!(ctx.message.contains(&format!("{:?}", extra.id())))
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(extra.path().is_err());
+        let updates = tree.handle_event(&p("/switch"), Value::from_integer(3))?;
+        assert_eq!(updates["hue"], vec![(p("/light"), Value::from_integer(3))]);
+        assert!(tree.remove_node(&p("/")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_remove_node_reuses_slots() -> Fallible<()> {
+        let s = r#"
+scenes
+    day <- "bright"
+    night <- "dim"
+scene ^button
+    domain <- ["day", "night"]
+    default <- "day"
+light $hue <- /scenes/{/scene}
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        let slots = |tree: &Tree| tree.arena.read().unwrap().nodes.len();
+        let old = tree.lookup("/scenes/day")?;
+        tree.add_nodes(&p("/scenes"), "spare <- 1")?;
+        let mut size = None;
+        for _ in 0..10 {
+            tree.remove_node(&p("/scenes/spare"))?;
+            tree.add_nodes(&p("/scenes"), "spare <- 2")?;
+            // Rejected edits give back the nodes they added, too.
+            assert!(tree.add_nodes(&p("/scenes"), "bad <- /nowhere").is_err());
+            assert_eq!(*size.get_or_insert(slots(&tree)), slots(&tree));
+        }
+        assert_eq!(
+            tree.lookup("/scenes/spare")?.compute(&tree)?,
+            Value::from_integer(2)
+        );
+
+        // The lookup would no longer cover the domain of its source.
+        assert!(tree.remove_node(&p("/scenes/night")).is_err());
+        let updates = tree.handle_event(&p("/scene"), Value::new_str("night"))?;
+        assert_eq!(updates["hue"], vec![(p("/light"), Value::new_str("dim"))]);
+        assert_eq!(old.compute(&tree)?, Value::new_str("bright"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_replace_script_at_runtime() -> Fallible<()> {
+        let s = r#"
+switch ^button
+    default <- 1
+other ^button
+    default <- 10
+light $hue <- ./switch
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        assert_eq!(
+            tree.lookup("/light")?.compute(&tree)?,
+            Value::from_integer(1)
+        );
+
+        tree.replace_script(&p("/light"), "./other * 2")?;
+        assert_eq!(
+            tree.lookup("/light")?.compute(&tree)?,
+            Value::from_integer(20)
+        );
+        assert!(tree
+            .handle_event(&p("/switch"), Value::from_integer(2))?
+            .is_empty());
+        let updates = tree.handle_event(&p("/other"), Value::from_integer(3))?;
+        assert_eq!(updates["hue"], vec![(p("/light"), Value::from_integer(6))]);
+
+        tree.replace_script(
+            &p("/light"),
+            "if ./switch == 2:\n    \"on\"\nelse:\n    \"off\"",
+        )?;
+        assert_eq!(tree.lookup("/light")?.compute(&tree)?, Value::new_str("on"));
+
+        tree.detach_sink(&p("/light"))?;
+        assert!(tree
+            .handle_event(&p("/switch"), Value::from_integer(1))?
+            .is_empty());
+        tree.attach_sink(&p("/light"), "hue")?;
+        let updates = tree.handle_event(&p("/switch"), Value::from_integer(2))?;
+        assert_eq!(updates["hue"], vec![(p("/light"), Value::new_str("on"))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_edit_rejects_cycle() -> Fallible<()> {
+        let s = r#"
+switch ^button
+    default <- 1
+a <- ./switch
+b $hue <- ./a
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        let err = tree.replace_script(&p("/a"), "./b").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "dataflow error: found cycles in the dataflow graph:\n    /a reads /b\n    /b reads /a"
+        );
+        let updates = tree.handle_event(&p("/switch"), Value::from_integer(2))?;
+        assert_eq!(updates["hue"], vec![(p("/b"), Value::from_integer(2))]);
+
+        // A source has no inputs, so replacing a script with one breaks
+        // nothing.
+        tree.attach_source(&p("/a"), "button")?;
+        assert!(tree
+            .handle_event(&p("/switch"), Value::from_integer(3))?
+            .is_empty());
+        let updates = tree.handle_event(&p("/a"), Value::from_integer(4))?;
+        assert_eq!(updates["hue"], vec![(p("/b"), Value::from_integer(4))]);
+        Ok(())
+    }
+
+    const FOLDABLE: &str = r#"
+def double(n):
+    n * 2
+mode <- "day"
+levels
+    on <- 50
+    off <- 0
+switch ^button
+    domain <- ["on", "off"]
+    default <- "off"
+palette
+    day
+        on <- "bright"
+        off <- "dark"
+    night
+        on <- "dim"
+        off <- "dark"
+light $hue <- /palette/{/mode}/{./switch}
+lamp $hue <-\
+    if ./mode == "night":
+        double(/levels/off)
+    else:
+        double(/levels/on) + 1
+latched $hue <- ./light :: ./switch
+unread <- ./switch + "!"
+"#;
+
+    #[test]
+    fn test_tree_optimize_matches_unoptimized() -> Fallible<()> {
+        let mut optimized = TreeBuilder::default().build_from_str(FOLDABLE)?;
+        let mut plain = TreeBuilder::default()
+            .without_optimization()?
+            .build_from_str(FOLDABLE)?;
+        assert!(plain.optimizations()?.is_empty());
+        let switch = ConcretePath::from_str("/switch")?;
+        for value in &["on", "off", "on"] {
+            let a = optimized.handle_event(&switch, Value::new_str(value))?;
+            let b = plain.handle_event(&switch, Value::new_str(value))?;
+            assert_eq!(a, b);
+            let mut nodes = Vec::new();
+            plain.root().collect_subtree(&mut nodes)?;
+            for node in &nodes {
+                if !node.has_script()? && !node.is_source()? {
+                    continue;
+                }
+                let path = node.path_str()?;
+                let a = optimized.lookup(&path)?.compute(&optimized)?;
+                assert_eq!(a, node.compute(&plain)?, "{}", path);
+                assert_eq!(a.generation(), node.compute(&plain)?.generation());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_tags() -> Fallible<()> {
+        let tree = TreeBuilder::default()
+            .intercept_import("lamp.ygg", "bulb $hue #dimmable <- \"on\"\n")?
+            .build_from_str("a #ceiling #dimmable\n    import(lamp.ygg)\n")?;
+        assert_eq!(tree.lookup("/a")?.tags()?, vec!["ceiling", "dimmable"]);
+        assert!(tree.lookup("/a/bulb")?.has_tag("dimmable")?);
+        assert!(!tree.lookup("/a/bulb")?.has_tag("ceiling")?);
+        assert!(TreeBuilder::default()
+            .build_from_str("a #ceiling #ceiling\n")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_optimizations() -> Fallible<()> {
+        let mut tree = TreeBuilder::default().build_from_str(FOLDABLE)?;
+        let report = tree.optimizations()?;
+        for line in &[
+            "/lamp: dropped arm 0, which is never taken",
+            "/lamp: read /levels/on as 50i64",
+            "/light: looked up {/mode} in /palette/{/mode}/{/switch} as day",
+            "/switch/domain: not read by any sink",
+            "/unread: not read by any sink",
+        ] {
+            assert!(
+                report.contains(&line.to_string()),
+                "{}: {:#?}",
+                line,
+                report
+            );
+        }
+        assert!(!tree.lookup("/light")?.is_dead()?);
+
+        // A dead node is not visited by events, so is computed afresh.
+        let switch = ConcretePath::from_str("/switch")?;
+        assert_eq!(
+            tree.lookup("/unread")?.compute(&tree)?,
+            Value::new_str("off!")
+        );
+        let updates = tree.handle_event(&switch, Value::new_str("on"))?;
+        assert_eq!(updates["hue"].len(), 2);
+        assert_eq!(
+            tree.lookup("/unread")?.compute(&tree)?,
+            Value::new_str("on!")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_optimize_after_edits() -> Fallible<()> {
+        let mut tree = TreeBuilder::default().build_from_str(FOLDABLE)?;
+        let p = |s: &str| ConcretePath::from_str(s).unwrap();
+        let value = |tree: &Tree, path: &str| tree.lookup(path)?.compute(tree);
+        assert_eq!(value(&tree, "/lamp")?, Value::from_integer(101));
+        assert_eq!(value(&tree, "/light")?, Value::new_str("dark"));
+
+        // Whatever folded the old value of a constant folds the new one.
+        tree.replace_script(&p("/levels/on"), "10")?;
+        assert_eq!(value(&tree, "/lamp")?, Value::from_integer(21));
+        tree.replace_script(&p("/mode"), "\"night\"")?;
+        assert_eq!(value(&tree, "/lamp")?, Value::from_integer(0));
+        tree.handle_event(&p("/switch"), Value::new_str("on"))?;
+        assert_eq!(value(&tree, "/light")?, Value::new_str("dim"));
+
+        // A node that a sink stops reading is dead, and one that a new sink
+        // reads is not.
+        tree.detach_sink(&p("/light"))?;
+        assert!(!tree.lookup("/light")?.is_dead()?);
+        tree.detach_sink(&p("/latched"))?;
+        assert!(tree.lookup("/light")?.is_dead()?);
+        tree.attach_sink(&p("/unread"), "hue")?;
+        assert!(!tree.lookup("/unread")?.is_dead()?);
+        let updates = tree.handle_event(&p("/switch"), Value::new_str("off"))?;
+        assert!(updates["hue"].contains(&(p("/unread"), Value::new_str("off!"))));
+        assert_eq!(value(&tree, "/light")?, Value::new_str("dark"));
+        Ok(())
+    }
 }
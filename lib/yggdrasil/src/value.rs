@@ -2,12 +2,15 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
+    error::{Error, ErrorContext},
     float::Float,
-    path::{ConcretePath, ScriptPath},
+    formatter::format_token,
+    path::{ConcretePath, PathComponent, ScriptPath},
+    script::MAX_POSSIBLE_VALUES,
     tokenizer::Token,
     tree::Tree,
 };
-use failure::{bail, ensure, Fallible};
+use failure::Fallible;
 use std::{convert::From, fmt};
 use tracing::trace;
 
@@ -18,9 +21,35 @@ pub enum ValueData {
     Integer(i64),
     Path(ScriptPath),
     String(String),
+    List(Vec<Value>),
     InputFlag, // Our Any type
 }
 
+/// The type of a value, as a native function declares what it takes and gives.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValueType {
+    Boolean,
+    Float,
+    Integer,
+    Path,
+    String,
+    List,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ValueType::Boolean => "boolean",
+            ValueType::Float => "float",
+            ValueType::Integer => "integer",
+            ValueType::Path => "path",
+            ValueType::String => "string",
+            ValueType::List => "list",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 fn latch<T>(lhs: &Value, rhs: &Value, a: T, b: T) -> T {
     trace!("latch {} :: {}", lhs.generation, rhs.generation);
     if lhs.generation() >= rhs.generation() {
@@ -30,12 +59,22 @@ fn latch<T>(lhs: &Value, rhs: &Value, a: T, b: T) -> T {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct Value {
     pub data: ValueData,
     generation: usize,
 }
 
+// The generation only tracks recency for latching; two values with the same
+// data are the same value, no matter when they were produced.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.data == other.data
+    }
+}
+
+impl Eq for Value {}
+
 impl Value {
     pub fn from_boolean(b: bool) -> Self {
         Self {
@@ -72,6 +111,13 @@ impl Value {
         }
     }
 
+    pub fn from_list(values: Vec<Value>) -> Self {
+        Self {
+            data: ValueData::List(values),
+            generation: 0,
+        }
+    }
+
     pub fn input_flag() -> Self {
         Self {
             data: ValueData::InputFlag,
@@ -99,29 +145,116 @@ impl Value {
         self
     }
 
+    #[cfg(test)]
     pub(super) fn compute(&self, tree: &Tree) -> Fallible<Value> {
         if let ValueData::Path(ref p) = self.data {
+            if p.is_glob() {
+                return tree
+                    .root()
+                    .compute_glob_path(self.generation, p.components(), tree);
+            }
             let (noderef, path_gen) = tree.lookup_dynamic_path(self.generation, p)?;
             return Ok(noderef.compute(tree)?.with_generation(path_gen));
         }
         Ok(self.to_owned())
     }
 
+    // Apply a binary operator. Both sides must have the same type, except that
+    // an integer mixed with a float is promoted to a float first. Integer
+    // arithmetic is checked: overflow and division by zero are errors rather
+    // than wrapping or panicking. The remainder of `%` takes the sign of the
+    // divisor, as for a floored division, so -1 % 60 is 59 and 1 % -60 is -59.
+    // Strings order by comparing their characters in turn. Between a list and
+    // another value, the operator applies to each item in turn, and between
+    // two lists of the same length, to each pair of items, so that a glob
+    // may be compared as a whole: `/rooms/*/color == "on"`. Unary minus is
+    // `negate`, below, and is checked in the same way.
     pub(super) fn apply(&self, tok: &Token, other: &Value) -> Fallible<Value> {
-        ensure!(
-            !self.is_path(),
-            "runtime error: attempting to apply a non-path"
-        );
-        ensure!(
-            !other.is_path(),
-            "runtime error: attempting to apply a non-path"
-        );
+        require!(!self.is_path(), Type, "attempting to apply a path");
+        require!(!other.is_path(), Type, "attempting to apply a path");
+        Ok(match (&self.data, &other.data) {
+            (ValueData::List(_), _) | (_, ValueData::List(_)) => self.apply_each(tok, other)?,
+            (ValueData::Integer(_), ValueData::Float(_)) => {
+                Self::apply_float(tok, &self.promote()?, other)?
+            }
+            (ValueData::Float(_), ValueData::Integer(_)) => {
+                Self::apply_float(tok, self, &other.promote()?)?
+            }
+            _ => self.apply_same(tok, other)?,
+        })
+    }
+
+    // Negate a number, or each item of a list. Negating the least integer
+    // overflows, and is an error like any other overflow.
+    pub(super) fn negate(&self) -> Fallible<Value> {
+        let data = match &self.data {
+            ValueData::Integer(i) => match i.checked_neg() {
+                Some(n) => ValueData::Integer(n),
+                None => raise!(Runtime, "integer overflow in -{}", i),
+            },
+            ValueData::Float(f) => ValueData::Float(f.checked_neg()?),
+            ValueData::List(items) => ValueData::List(
+                items
+                    .iter()
+                    .map(|item| item.negate())
+                    .collect::<Fallible<Vec<_>>>()?,
+            ),
+            ValueData::Path(_) => raise!(Type, "attempting to negate a path"),
+            _ => return Err(self.type_error("only numbers may be negated")),
+        };
+        Ok(Value {
+            data,
+            generation: self.generation,
+        })
+    }
+
+    fn apply_each(&self, tok: &Token, other: &Value) -> Fallible<Value> {
+        let items = match (&self.data, &other.data) {
+            (ValueData::List(a), ValueData::List(b)) => {
+                require!(
+                    a.len() == b.len(),
+                    Runtime,
+                    "cannot apply {} to lists of {} and {} items",
+                    format_token(tok),
+                    a.len(),
+                    b.len()
+                );
+                a.iter()
+                    .zip(b)
+                    .map(|(a, b)| a.apply(tok, b))
+                    .collect::<Fallible<Vec<_>>>()?
+            }
+            (ValueData::List(a), _) => a
+                .iter()
+                .map(|a| a.apply(tok, other))
+                .collect::<Fallible<Vec<_>>>()?,
+            (_, ValueData::List(b)) => b
+                .iter()
+                .map(|b| self.apply(tok, b))
+                .collect::<Fallible<Vec<_>>>()?,
+            _ => unreachable!(),
+        };
+        Ok(Value::from_list(items).with_generation(self.generation().max(other.generation())))
+    }
+
+    // An integer as the nearest float, with the same generation.
+    fn promote(&self) -> Fallible<Value> {
+        Ok(Value {
+            data: ValueData::Float(Float::new(self.as_integer()? as f64)?),
+            generation: self.generation,
+        })
+    }
+
+    fn apply_same(&self, tok: &Token, other: &Value) -> Fallible<Value> {
         Ok(match self.data {
             ValueData::Boolean(_) => Self::apply_boolean(tok, self, other)?,
             ValueData::Integer(_) => Self::apply_integer(tok, self, other)?,
             ValueData::Float(_) => Self::apply_float(tok, self, other)?,
             ValueData::String(_) => Self::apply_string(tok, self, other)?,
-            _ => bail!("runtime error: apply reached a path node"),
+            ValueData::List(_) => {
+                return Err(self.type_error(format!("{:?} is not a valid operation on a list", tok)))
+            }
+            _ => raise!(Type, "apply reached a path node"),
         })
     }
 
@@ -134,10 +267,9 @@ impl Value {
             Token::Latch => latch(lhs, rhs, a, b),
             Token::Equals => a == b,
             Token::NotEquals => a != b,
-            _ => bail!(
-                "runtime error: {:?} is not a valid operation on a bool",
-                tok
-            ),
+            _ => {
+                return Err(lhs.type_error(format!("{:?} is not a valid operation on a bool", tok)))
+            }
         };
         Ok(Value::from_boolean(next).with_generation(lhs.generation().max(rhs.generation())))
     }
@@ -145,12 +277,37 @@ impl Value {
     pub(super) fn apply_integer(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
         let a = lhs.as_integer()?;
         let b = rhs.as_integer()?;
+        let checked = |result: Option<i64>| match result {
+            Some(i) => Ok(ValueData::Integer(i)),
+            None => Err(Error::Runtime(ErrorContext::new(format!(
+                "integer overflow in {} {} {}",
+                a,
+                format_token(tok),
+                b
+            )))),
+        };
+        require!(
+            b != 0 || !matches!(tok, Token::Divide | Token::Modulo),
+            Runtime,
+            "division by zero in {} {} {}",
+            a,
+            format_token(tok),
+            b
+        );
         let data = match tok {
-            Token::Add => ValueData::Integer(a + b),
-            Token::Subtract => ValueData::Integer(a - b),
-            Token::Multiply => ValueData::Integer(a * b),
-            Token::Divide => ValueData::Float(Float::new(a as f64)? / Float::new(b as f64)?),
-            Token::Modulo => ValueData::Integer(a % b),
+            Token::Add => checked(a.checked_add(b))?,
+            Token::Subtract => checked(a.checked_sub(b))?,
+            Token::Multiply => checked(a.checked_mul(b))?,
+            Token::Divide => {
+                ValueData::Float(Float::new(a as f64)?.checked_div(Float::new(b as f64)?)?)
+            }
+            Token::Modulo => checked(a.checked_rem(b).map(|r| {
+                if r != 0 && (r < 0) != (b < 0) {
+                    r + b
+                } else {
+                    r
+                }
+            }))?,
             Token::Latch => ValueData::Integer(latch(lhs, rhs, a, b)),
             Token::Equals => ValueData::Boolean(a == b),
             Token::NotEquals => ValueData::Boolean(a != b),
@@ -158,10 +315,11 @@ impl Value {
             Token::LessThan => ValueData::Boolean(a < b),
             Token::GreaterThanOrEquals => ValueData::Boolean(a >= b),
             Token::LessThanOrEquals => ValueData::Boolean(a <= b),
-            _ => bail!(
-                "runtime error: {:?} is not a valid operation on an integer",
-                tok
-            ),
+            _ => {
+                return Err(
+                    lhs.type_error(format!("{:?} is not a valid operation on an integer", tok))
+                )
+            }
         };
         Ok(Value {
             data,
@@ -173,10 +331,11 @@ impl Value {
         let a = lhs.as_float()?;
         let b = rhs.as_float()?;
         let data = match tok {
-            Token::Add => ValueData::Float(a + b),
-            Token::Subtract => ValueData::Float(a - b),
-            Token::Multiply => ValueData::Float(a * b),
-            Token::Divide => ValueData::Float(a / b),
+            Token::Add => ValueData::Float(a.checked_add(b)?),
+            Token::Subtract => ValueData::Float(a.checked_sub(b)?),
+            Token::Multiply => ValueData::Float(a.checked_mul(b)?),
+            Token::Divide => ValueData::Float(a.checked_div(b)?),
+            Token::Modulo => ValueData::Float(a.checked_rem(b)?),
             Token::Latch => ValueData::Float(latch(lhs, rhs, a, b)),
             Token::Equals => ValueData::Boolean(a == b),
             Token::NotEquals => ValueData::Boolean(a != b),
@@ -184,10 +343,9 @@ impl Value {
             Token::LessThan => ValueData::Boolean(a < b),
             Token::GreaterThanOrEquals => ValueData::Boolean(a >= b),
             Token::LessThanOrEquals => ValueData::Boolean(a <= b),
-            _ => bail!(
-                "runtime error: {:?} is not a valid operation on a float",
-                tok
-            ),
+            _ => {
+                return Err(lhs.type_error(format!("{:?} is not a valid operation on a float", tok)))
+            }
         };
         Ok(Value {
             data,
@@ -201,11 +359,17 @@ impl Value {
         let data = match tok {
             Token::Add => ValueData::String(a + &b),
             Token::Equals => ValueData::Boolean(a == b),
+            Token::NotEquals => ValueData::Boolean(a != b),
+            Token::GreaterThan => ValueData::Boolean(a > b),
+            Token::LessThan => ValueData::Boolean(a < b),
+            Token::GreaterThanOrEquals => ValueData::Boolean(a >= b),
+            Token::LessThanOrEquals => ValueData::Boolean(a <= b),
             Token::Latch => ValueData::String(latch(lhs, rhs, a, b)),
-            _ => bail!(
-                "runtime error: {:?} is not a valid operation on a string",
-                tok
-            ),
+            _ => {
+                return Err(
+                    lhs.type_error(format!("{:?} is not a valid operation on a string", tok))
+                )
+            }
         };
         Ok(Value {
             data,
@@ -248,36 +412,70 @@ impl Value {
         false
     }
 
+    pub fn is_list(&self) -> bool {
+        if let ValueData::List(_) = self.data {
+            return true;
+        }
+        false
+    }
+
     pub fn is_input_flag(&self) -> bool {
         self.data == ValueData::InputFlag
     }
 
+    // The input flag stands in for any value, so has no type of its own.
+    pub fn value_type(&self) -> Option<ValueType> {
+        Some(match self.data {
+            ValueData::Boolean(_) => ValueType::Boolean,
+            ValueData::Float(_) => ValueType::Float,
+            ValueData::Integer(_) => ValueType::Integer,
+            ValueData::Path(_) => ValueType::Path,
+            ValueData::String(_) => ValueType::String,
+            ValueData::List(_) => ValueType::List,
+            ValueData::InputFlag => return None,
+        })
+    }
+
+    // A type error about this value.
+    fn type_error(&self, message: impl Into<String>) -> failure::Error {
+        Error::Type(ErrorContext::new(message.into()))
+            .with_value(self.to_owned())
+            .into()
+    }
+
     pub fn as_boolean(&self) -> Fallible<bool> {
         if let ValueData::Boolean(b) = self.data {
             return Ok(b);
         }
-        bail!("runtime error: attempted to use a non-boolean value in boolean context")
+        Err(self.type_error("attempted to use a non-boolean value in boolean context"))
     }
 
     pub fn as_integer(&self) -> Fallible<i64> {
         if let ValueData::Integer(i) = self.data {
             return Ok(i);
         }
-        bail!("runtime error: attempted to use a non-integer value in integer context")
+        Err(self.type_error("attempted to use a non-integer value in integer context"))
     }
 
     pub fn as_float(&self) -> Fallible<Float> {
         if let ValueData::Float(f) = self.data {
             return Ok(f);
         }
-        bail!("runtime error: attempted to use a non-float value in float context")
+        Err(self.type_error("attempted to use a non-float value in float context"))
     }
 
     pub fn as_string(&self) -> Fallible<String> {
         if let ValueData::String(ref s) = self.data {
             return Ok(s.to_owned());
         }
-        bail!("runtime error: attempted to use a non-stringvalue in string context")
+        Err(self.type_error("attempted to use a non-string value in string context"))
+    }
+
+    pub fn as_list(&self) -> Fallible<&[Value]> {
+        if let ValueData::List(ref values) = self.data {
+            return Ok(values);
+        }
+        Err(self.type_error("attempted to use a non-list value in list context"))
     }
 
     pub fn as_path_component(&self) -> Fallible<String> {
@@ -286,10 +484,13 @@ impl Value {
             ValueData::Boolean(b) => Ok(b.to_string()),
             ValueData::String(ref s) => Ok(s.to_owned()),
             ValueData::Float(_) => {
-                bail!("runtime error: a float value cannot be used as a path component")
+                Err(self.type_error("a float value cannot be used as a path component"))
+            }
+            ValueData::Path(_) => Err(self.type_error("did not expect a path as path component")),
+            ValueData::List(_) => {
+                Err(self.type_error("a list value cannot be used as a path component"))
             }
-            ValueData::Path(_) => bail!("runtime error: did not expect a path as path component"),
-            ValueData::InputFlag => bail!("runtime error: input flag in as_path_component"),
+            ValueData::InputFlag => raise!(Type, "input flag in as_path_component"),
         }
     }
 
@@ -306,7 +507,10 @@ impl Value {
             let mut concrete_inputs = Vec::new();
             path.find_concrete_inputs(&mut concrete_inputs)?;
             for concrete in &concrete_inputs {
-                tree.lookup_path(concrete)?.link_and_validate_inputs(tree)?;
+                match tree.lookup_path(concrete) {
+                    Ok(node) => node.link_and_validate_inputs(tree)?,
+                    Err(_) => raise!(Link, "reads {}, which does not exist", concrete),
+                }
             }
 
             // Devirtualization is eager; expansions may result in paths that do not actually
@@ -321,12 +525,91 @@ impl Value {
             // Collect both direct and indirect inputs at this value.
             out.append(&mut direct_inputs);
             out.append(&mut concrete_inputs);
+
+            // A dynamic lookup nested in this path reads every node that it
+            // may itself resolve to.
+            for component in path.components() {
+                if let PathComponent::Lookup(inner) = component {
+                    if !inner.is_concrete() {
+                        Value::from_path(inner.to_owned()).find_all_possible_inputs(tree, out)?;
+                    }
+                }
+            }
         }
         Ok(())
     }
+
+    // Every value this may take when computed: itself for a literal, or the
+    // union over every node a path may point at. For a glob, every list of
+    // the values that the nodes it matches may take together.
+    pub fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        let path = match self.data {
+            ValueData::Path(ref path) => path,
+            _ => return Ok(Some(vec![self.to_owned()])),
+        };
+        if path.is_glob() {
+            return Self::possible_glob_values(path, tree, visiting);
+        }
+        let mut out: Vec<Value> = Vec::new();
+        for concrete in path.devirtualize_within(tree, visiting)? {
+            let node = match tree.lookup_path(&concrete) {
+                Ok(node) => node,
+                Err(_) => continue,
+            };
+            match node.possible_values(tree, visiting)? {
+                Some(values) => {
+                    for v in values {
+                        if !out.contains(&v) {
+                            out.push(v);
+                        }
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(out))
+    }
+
+    fn possible_glob_values(
+        path: &ScriptPath,
+        tree: &Tree,
+        visiting: &mut Vec<ConcretePath>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        let mut matches = path.devirtualize_within(tree, visiting)?;
+        matches.sort();
+        let mut lists = vec![Vec::new()];
+        for concrete in matches {
+            let node = match tree.lookup_path(&concrete) {
+                Ok(node) => node,
+                Err(_) => continue,
+            };
+            let values = match node.possible_values(tree, visiting)? {
+                Some(values) => values,
+                None => return Ok(None),
+            };
+            if lists.len() * values.len() > MAX_POSSIBLE_VALUES {
+                return Ok(None);
+            }
+            lists = lists
+                .iter()
+                .flat_map(|list| {
+                    values.iter().map(move |v| {
+                        let mut list = list.to_owned();
+                        list.push(v.to_owned());
+                        list
+                    })
+                })
+                .collect();
+        }
+        Ok(Some(lists.into_iter().map(Value::from_list).collect()))
+    }
 }
 
-impl<'a> From<&'a str> for Value {
+impl From<&str> for Value {
     fn from(t: &str) -> Value {
         Value {
             data: ValueData::String(t.to_owned()),
@@ -343,7 +626,30 @@ impl fmt::Display for Value {
             ValueData::Float(v) => write!(f, "{}f64", v),
             ValueData::String(ref s) => write!(f, "\"{}\"", s),
             ValueData::Path(ref p) => write!(f, "{}", p),
+            ValueData::List(ref values) => {
+                let parts = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", parts.join(", "))
+            }
             ValueData::InputFlag => write!(f, "InputFlag"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_value_eq_ignores_generation() {
+        let old = Value::new_str("on");
+        let new = Value::new_str("on").with_generation(3);
+        assert_eq!(old, new);
+        assert_ne!(old, Value::new_str("off").with_generation(0));
+        // The generation still decides which side of a latch wins.
+        assert_eq!(
+            latch(&new, &old, 1, 2),
+            1,
+            "the newer value should be taken"
+        );
+    }
+}
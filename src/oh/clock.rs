@@ -144,7 +144,7 @@ impl ClockServer {
                 ClockInterval::from_str(&interval)?,
                 ClockWrap::from_str(&wrap)?,
             );
-            clock_map.insert(path.to_owned(), clock_def);
+            clock_map.insert(path.clone(), clock_def);
         }
 
         let (mailbox, mut mailbox_receiver) = channel(16);
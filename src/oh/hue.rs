@@ -143,7 +143,7 @@ impl HueBridge {
 
         let mut path_map = HashMap::new();
         for path in &tree.find_sinks("hue").await? {
-            path_map.insert(path.to_owned(), path.basename().to_owned());
+            path_map.insert(path.clone(), path.basename().to_owned());
         }
 
         Ok(Self {
@@ -228,7 +228,7 @@ impl HueBridge {
             by_value
                 .entry(value.as_string()?)
                 .or_insert_with(|| vec![])
-                .push(path.to_owned());
+                .push(path.clone());
         }
         Ok(by_value)
     }
@@ -54,7 +54,7 @@ impl LegacyMcu {
                     .as_string()?
                     .parse::<IpAddr>()?;
                 trace!("Mapping {} => {}", ip_addr, source_path);
-                path_map.insert(ip_addr, source_path.to_owned());
+                path_map.insert(ip_addr, source_path.clone());
             }
 
             let make_svc = make_service_fn(move |socket: &AddrStream| {
@@ -14,7 +14,7 @@ use hyper::{
     client::{Client, HttpConnector},
     Body, Response, Uri,
 };
-use json::{object, JsonValue};
+use serde_json::{json, Map, Value as JsonValue};
 use std::{
     boxed::Box,
     collections::HashMap,
@@ -30,7 +30,7 @@ use tokio_tungstenite::{connect_async, WebSocketStream};
 use tracing::{error, info, trace, warn};
 use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message};
 use url::Url;
-use yggdrasil::{ConcretePath, Float, Value};
+use yggdrasil::{ConcretePath, Value};
 
 #[derive(Debug, Clone)]
 enum PropertyKind {
@@ -118,51 +118,6 @@ impl RedstoneHttpClient {
     }
 }
 
-fn value_from_json(json: &JsonValue) -> Fallible<Value> {
-    Ok(match json {
-        JsonValue::Boolean(b) => Value::from_boolean(*b),
-        JsonValue::Short(s) => Value::new_str(&s),
-        JsonValue::String(s) => Value::new_str(&s),
-        JsonValue::Number(n) => {
-            let (sign, mantissa, exponent) = n.as_parts();
-            match (sign, exponent) {
-                (true, 0) => {
-                    if mantissa < i64::max_value() as u64 {
-                        Value::from_integer(mantissa as i64)
-                    } else {
-                        Value::from_float(Float::new((*n).into())?)
-                    }
-                }
-                (false, 0) => {
-                    if mantissa < (-i64::min_value()) as u64 {
-                        Value::from_integer(-(mantissa as i64))
-                    } else {
-                        Value::from_float(Float::new((*n).into())?)
-                    }
-                }
-                _ => Value::from_float(Float::new((*n).into())?),
-            }
-        }
-        _ => bail!("non-value float in value_from_json"),
-    })
-}
-
-fn value_to_json(value: &Value) -> Fallible<JsonValue> {
-    if value.is_boolean() {
-        return Ok(JsonValue::Boolean(value.as_boolean()?));
-    }
-    if value.is_integer() {
-        return Ok(JsonValue::Number(value.as_integer()?.into()));
-    }
-    if value.is_float() {
-        return Ok(JsonValue::Number(value.as_float()?.value.into()));
-    }
-    if value.is_string() {
-        return Ok(JsonValue::String(value.as_string()?));
-    }
-    bail!("cannot format value {} as json", value)
-}
-
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum LoopStatus {
     Okay,
@@ -227,16 +182,14 @@ impl DeviceServer {
                 );
                 let client = RedstoneHttpClient::new(&device.url)?;
                 let body = client.get(&format!("/properties/{}", property)).await?;
-                let json = json::parse(&body)?;
-                let value = value_from_json(&json[property])?;
+                let json: JsonValue = serde_json::from_str(&body)?;
+                let value: Value = serde_json::from_value(json[property].clone())?;
                 trace!(
                     "device: setting initial state of {} to {}",
                     device.path,
                     value
                 );
-                let updates = tree
-                    .handle_event(&(device.path.clone() / property), value)
-                    .await?;
+                let updates = tree.handle_event(&(&device.path / property), value).await?;
                 update.apply_updates(updates).await?;
             }
         }
@@ -283,11 +236,12 @@ impl DeviceServer {
                     match maybe_message {
                         Some(DeviceProtocol::SetProperty(prop, val)) => {
                             info!("device: set_property {}: {} -> {:?}", device.url, prop, val);
-                            let mut message = object!(
-                                "messageType" => "setProperty",
-                                "data" => object!()
-                            );
-                            message["data"].insert(&prop, value_to_json(&val)?)?;
+                            let mut data = Map::new();
+                            data.insert(prop, serde_json::to_value(&val)?);
+                            let message = json!({
+                                "messageType": "setProperty",
+                                "data": data
+                            });
                             let send = ws_stream.send(Message::text(message.to_string()));
                             send.await?;
                         }
@@ -353,20 +307,15 @@ impl DeviceServer {
                     json_text.len()
                 );
                 device.touch();
-                let body = json::parse(&json_text)?;
-                ensure!(body.has_key("messageType"));
-                ensure!(body.has_key("data"));
+                let body: JsonValue = serde_json::from_str(&json_text)?;
+                ensure!(body.get("messageType").is_some());
+                ensure!(body.get("data").is_some());
                 let data = &body["data"];
                 for property in &device.source_properties {
-                    if data.has_key(property) {
-                        let value = &data[property];
+                    if let Some(value) = data.get(property) {
                         trace!("device: setting {}/{} to {}", device.path, property, value);
-                        let updates = tree
-                            .handle_event(
-                                &(device.path.clone() / property),
-                                value_from_json(value)?,
-                            )
-                            .await?;
+                        let value: Value = serde_json::from_value(value.clone())?;
+                        let updates = tree.handle_event(&(&device.path / property), value).await?;
                         update.apply_updates(updates).await?;
                     }
                 }
@@ -497,7 +446,7 @@ impl RedstoneServer {
         devices
             .entry(url.clone())
             .or_insert(RedstoneDevice {
-                path: base_path.parent().to_owned(),
+                path: base_path.parent(),
                 url,
                 source_properties: Vec::new(),
                 sink_properties: Vec::new(),
@@ -7,7 +7,7 @@ use tokio::{
     sync::{mpsc, mpsc::Receiver, oneshot},
     task::{spawn, JoinHandle},
 };
-use tracing::error;
+use tracing::{error, warn};
 use yggdrasil::{ConcretePath, Tree, TreeBuilder, Value};
 
 #[derive(Debug)]
@@ -24,18 +24,17 @@ impl TreeServer {
             let mut tree = match TreeBuilder::default().build_from_file(&filename) {
                 Ok(tree) => tree,
                 Err(e) => {
+                    // Report every problem found, so they can all be fixed at once.
                     error!("Failed to parse configuration:");
-                    error!("{:?}", e.backtrace());
+                    for line in e.to_string().lines() {
+                        error!("    {}", line);
+                    }
                     bail!("failed to parse configuration")
                 }
             };
 
             while let Some(message) = mailbox_receiver.recv().await {
-                let result = Self::handle_message(message, &mut mailbox_receiver, &mut tree);
-                if let Err(e) = result {
-                    error!("Error: {}", e);
-                    error!("{}", e.backtrace());
-                }
+                Self::handle_message(message, &mut mailbox_receiver, &mut tree)?;
             }
 
             Ok(())
@@ -63,18 +62,11 @@ impl TreeServer {
                 tx.send(tree.lookup_path(&path).is_ok()).ok();
             }
             TreeServerProtocol::Compute(path, tx) => {
-                tx.send(tree.lookup_path(&path)?.compute(&tree)?).ok();
+                let result = tree.lookup_path(&path).and_then(|node| node.compute(tree));
+                Self::reply(tx, result)?;
             }
             TreeServerProtocol::HandleEvent(path, value, tx) => {
-                match tree.handle_event(&path, value) {
-                    Ok(result) => {
-                        tx.send(result).ok();
-                    }
-                    Err(e) => {
-                        error!("failed to handle_event: {}", e);
-                        tx.send(HashMap::new()).ok();
-                    }
-                }
+                Self::reply(tx, tree.handle_event(&path, value))?;
             }
             TreeServerProtocol::Finish => {
                 mailbox_receiver.close();
@@ -83,6 +75,30 @@ impl TreeServer {
         Ok(())
     }
 
+    // Send the result of a request back to the caller, then decide whether to
+    // keep serving. A bad value leaves the tree intact, so the caller gets the
+    // error and the server carries on. Anything else means the tree can't be
+    // trusted: the caller still gets the error, but the server stops.
+    fn reply<T>(tx: oneshot::Sender<Fallible<T>>, result: Fallible<T>) -> Fallible<()> {
+        let failure = result.as_ref().err().map(|e| {
+            let recoverable = yggdrasil::Error::find(e).is_some_and(|e| e.is_recoverable());
+            (recoverable, e.to_string(), e.backtrace().to_string())
+        });
+        tx.send(result).ok();
+        match failure {
+            Some((true, message, _)) => {
+                warn!("Error: {}", message);
+                Ok(())
+            }
+            Some((false, message, backtrace)) => {
+                error!("Fatal error: {}", message);
+                error!("{}", backtrace);
+                bail!("{}", message)
+            }
+            None => Ok(()),
+        }
+    }
+
     pub async fn join(self) -> Fallible<()> {
         self.task.await??;
         Ok(())
@@ -93,6 +109,9 @@ impl TreeServer {
     }
 }
 
+// The new value of each sink that an event reached, grouped by sink kind.
+type Updates = HashMap<String, Vec<(ConcretePath, Value)>>;
+
 #[derive(Debug, Clone)]
 pub struct TreeMailbox {
     mailbox: mpsc::Sender<TreeServerProtocol>,
@@ -103,12 +122,8 @@ enum TreeServerProtocol {
     FindSources(String, oneshot::Sender<Vec<ConcretePath>>),
     FindSinks(String, oneshot::Sender<Vec<ConcretePath>>),
     PathExists(ConcretePath, oneshot::Sender<bool>),
-    Compute(ConcretePath, oneshot::Sender<Value>),
-    HandleEvent(
-        ConcretePath,
-        Value,
-        oneshot::Sender<HashMap<String, Vec<(ConcretePath, Value)>>>,
-    ),
+    Compute(ConcretePath, oneshot::Sender<Fallible<Value>>),
+    HandleEvent(ConcretePath, Value, oneshot::Sender<Fallible<Updates>>),
     Finish,
 }
 
@@ -132,7 +147,7 @@ impl TreeMailbox {
     pub async fn path_exists(&mut self, path: &ConcretePath) -> Fallible<bool> {
         let (tx, rx) = oneshot::channel();
         self.mailbox
-            .send(TreeServerProtocol::PathExists(path.to_owned(), tx))
+            .send(TreeServerProtocol::PathExists(path.clone(), tx))
             .await?;
         Ok(rx.await?)
     }
@@ -140,21 +155,17 @@ impl TreeMailbox {
     pub async fn compute(&mut self, path: &ConcretePath) -> Fallible<Value> {
         let (tx, rx) = oneshot::channel();
         self.mailbox
-            .send(TreeServerProtocol::Compute(path.to_owned(), tx))
+            .send(TreeServerProtocol::Compute(path.clone(), tx))
             .await?;
-        Ok(rx.await?)
+        rx.await?
     }
 
-    pub async fn handle_event(
-        &mut self,
-        path: &ConcretePath,
-        event: Value,
-    ) -> Fallible<HashMap<String, Vec<(ConcretePath, Value)>>> {
+    pub async fn handle_event(&mut self, path: &ConcretePath, event: Value) -> Fallible<Updates> {
         let (tx, rx) = oneshot::channel();
         self.mailbox
-            .send(TreeServerProtocol::HandleEvent(path.to_owned(), event, tx))
+            .send(TreeServerProtocol::HandleEvent(path.clone(), event, tx))
             .await?;
-        Ok(rx.await?)
+        rx.await?
     }
 
     pub async fn finish(&mut self) -> Fallible<()> {
@@ -54,7 +54,7 @@ impl UpdateServer {
                                 let mut redstone = redstone.to_owned();
                                 for (path, value) in values {
                                     match redstone
-                                        .set_property(path.to_owned(), value.to_owned())
+                                        .set_property(path.clone(), value.to_owned())
                                         .await
                                     {
                                         Ok(_) => {}